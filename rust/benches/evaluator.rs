@@ -15,10 +15,13 @@
 #![feature(test)]
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 
 use synapse::push::{
-    evaluator::PushRuleEvaluator, Condition, EventMatchCondition, FilteredPushRules, JsonValue,
-    PushRules, SimpleJsonValue,
+    evaluator::{EvaluatorInputs, PushRuleEvaluator},
+    utils::GLOB_MATCHER_CACHE,
+    Condition, EventMatchCondition, EventMatchKey, FilteredPushRules, FlattenedKey, JsonValue,
+    PushRule, PushRules, SimpleJsonValue,
 };
 use test::Bencher;
 
@@ -28,38 +31,71 @@ extern crate test;
 fn bench_match_exact(b: &mut Bencher) {
     let flattened_keys = [
         (
-            "type".to_string(),
+            "type".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.text"))),
         ),
         (
-            "room_id".to_string(),
+            "room_id".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("!room:server"))),
         ),
         (
-            "content.body".to_string(),
+            "content.body".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("test message"))),
         ),
     ]
     .into_iter()
     .collect();
 
-    let eval = PushRuleEvaluator::py_new(
+    let eval = PushRuleEvaluator::new(EvaluatorInputs {
         flattened_keys,
-        false,
-        10,
-        Some(0),
-        Default::default(),
-        Default::default(),
-        true,
-        vec![],
-        false,
-    )
-    .unwrap();
+        room_member_count: 10,
+        sender_power_level: Some(0),
+        related_event_match_enabled: true,
+        ..Default::default()
+    });
 
     let condition = Condition::Known(synapse::push::KnownCondition::EventMatch(
         EventMatchCondition {
-            key: "room_id".into(),
+            key: EventMatchKey::Single("room_id".into()),
             pattern: "!room:server".into(),
+            ignore_quotes: false,
+            match_words: None,
+            contains: false,
+        },
+    ));
+
+    let matched = eval.match_condition(&condition, None, None).unwrap();
+    assert!(matched, "Didn't match");
+
+    b.iter(|| eval.match_condition(&condition, None, None).unwrap());
+}
+
+#[bench]
+fn bench_match_msgtype(b: &mut Bencher) {
+    let eval = PushRuleEvaluator::new(EvaluatorInputs {
+        flattened_keys: [
+            (
+                "type".into(),
+                JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.room.message"))),
+            ),
+            (
+                "content.msgtype".into(),
+                JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.notice"))),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        room_member_count: 10,
+        ..Default::default()
+    });
+
+    let condition = Condition::Known(synapse::push::KnownCondition::EventMatch(
+        EventMatchCondition {
+            key: EventMatchKey::Single("content.msgtype".into()),
+            pattern: "m.notice".into(),
+            ignore_quotes: false,
+            match_words: None,
+            contains: false,
         },
     ));
 
@@ -73,38 +109,36 @@ fn bench_match_exact(b: &mut Bencher) {
 fn bench_match_word(b: &mut Bencher) {
     let flattened_keys = [
         (
-            "type".to_string(),
+            "type".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.text"))),
         ),
         (
-            "room_id".to_string(),
+            "room_id".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("!room:server"))),
         ),
         (
-            "content.body".to_string(),
+            "content.body".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("test message"))),
         ),
     ]
     .into_iter()
     .collect();
 
-    let eval = PushRuleEvaluator::py_new(
+    let eval = PushRuleEvaluator::new(EvaluatorInputs {
         flattened_keys,
-        false,
-        10,
-        Some(0),
-        Default::default(),
-        Default::default(),
-        true,
-        vec![],
-        false,
-    )
-    .unwrap();
+        room_member_count: 10,
+        sender_power_level: Some(0),
+        related_event_match_enabled: true,
+        ..Default::default()
+    });
 
     let condition = Condition::Known(synapse::push::KnownCondition::EventMatch(
         EventMatchCondition {
-            key: "content.body".into(),
+            key: EventMatchKey::Single("content.body".into()),
             pattern: "test".into(),
+            ignore_quotes: false,
+            match_words: None,
+            contains: false,
         },
     ));
 
@@ -118,38 +152,36 @@ fn bench_match_word(b: &mut Bencher) {
 fn bench_match_word_miss(b: &mut Bencher) {
     let flattened_keys = [
         (
-            "type".to_string(),
+            "type".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.text"))),
         ),
         (
-            "room_id".to_string(),
+            "room_id".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("!room:server"))),
         ),
         (
-            "content.body".to_string(),
+            "content.body".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("test message"))),
         ),
     ]
     .into_iter()
     .collect();
 
-    let eval = PushRuleEvaluator::py_new(
+    let eval = PushRuleEvaluator::new(EvaluatorInputs {
         flattened_keys,
-        false,
-        10,
-        Some(0),
-        Default::default(),
-        Default::default(),
-        true,
-        vec![],
-        false,
-    )
-    .unwrap();
+        room_member_count: 10,
+        sender_power_level: Some(0),
+        related_event_match_enabled: true,
+        ..Default::default()
+    });
 
     let condition = Condition::Known(synapse::push::KnownCondition::EventMatch(
         EventMatchCondition {
-            key: "content.body".into(),
+            key: EventMatchKey::Single("content.body".into()),
             pattern: "foobar".into(),
+            ignore_quotes: false,
+            match_words: None,
+            contains: false,
         },
     ));
 
@@ -163,36 +195,248 @@ fn bench_match_word_miss(b: &mut Bencher) {
 fn bench_eval_message(b: &mut Bencher) {
     let flattened_keys = [
         (
-            "type".to_string(),
+            "type".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.text"))),
         ),
         (
-            "room_id".to_string(),
+            "room_id".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("!room:server"))),
         ),
         (
-            "content.body".to_string(),
+            "content.body".into(),
             JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("test message"))),
         ),
     ]
     .into_iter()
     .collect();
 
-    let eval = PushRuleEvaluator::py_new(
+    let eval = PushRuleEvaluator::new(EvaluatorInputs {
         flattened_keys,
+        room_member_count: 10,
+        sender_power_level: Some(0),
+        related_event_match_enabled: true,
+        ..Default::default()
+    });
+
+    let rules = FilteredPushRules::py_new(
+        PushRules::new(Vec::new()),
+        Default::default(),
+        false,
         false,
-        10,
-        Some(0),
+        false,
+        false,
+    );
+
+    b.iter(|| eval.run(&rules, Some("bob"), Some("person")));
+}
+
+/// Builds an evaluator for a message in a large room, and a user with many
+/// content rules.
+fn large_room_with_content_rules() -> (PushRuleEvaluator, FilteredPushRules) {
+    let flattened_keys = [
+        (
+            "type".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.room.message"))),
+        ),
+        (
+            "room_id".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("!room:server"))),
+        ),
+        (
+            "content.body".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed(
+                "has anyone seen the release notes for the next version yet?",
+            ))),
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let eval = PushRuleEvaluator::new(EvaluatorInputs {
+        flattened_keys,
+        room_member_count: 3000,
+        sender_power_level: Some(0),
+        related_event_match_enabled: true,
+        ..Default::default()
+    });
+
+    let content_rules = (0..50)
+        .map(|i| {
+            PushRule::from_db(
+                format!("global/content/keyword{i}"),
+                4,
+                &format!(
+                    r#"[{{"kind": "event_match", "key": "content.body", "pattern": "keyword{i}*"}}]"#
+                ),
+                r#"["notify"]"#,
+            )
+            .unwrap()
+        })
+        .collect();
+
+    let rules = FilteredPushRules::py_new(
+        PushRules::new(content_rules),
         Default::default(),
+        false,
+        false,
+        false,
+        false,
+    );
+
+    (eval, rules)
+}
+
+#[bench]
+fn bench_eval_content_rules(b: &mut Bencher) {
+    let (eval, rules) = large_room_with_content_rules();
+
+    b.iter(|| eval.run(&rules, Some("bob"), Some("person")));
+}
+
+#[bench]
+fn bench_eval_content_rules_uncached(b: &mut Bencher) {
+    let (eval, rules) = large_room_with_content_rules();
+
+    b.iter(|| {
+        GLOB_MATCHER_CACHE.clear();
+        eval.run(&rules, Some("bob"), Some("person"))
+    });
+}
+
+#[bench]
+fn bench_eval_content_rules_precompiled(b: &mut Bencher) {
+    let (eval, rules) = large_room_with_content_rules();
+    let rules = rules.precompiled();
+
+    b.iter(|| eval.run(&rules, Some("bob"), Some("person")));
+}
+
+#[bench]
+fn bench_precompile_content_rules(b: &mut Bencher) {
+    let (_, rules) = large_room_with_content_rules();
+
+    b.iter(|| rules.precompiled());
+}
+
+/// Builds an evaluator for a message mentioning one of the many keywords which
+/// the user highlights with content rules.
+fn message_with_keyword_rules() -> (PushRuleEvaluator, FilteredPushRules) {
+    let eval = PushRuleEvaluator::new(EvaluatorInputs {
+        flattened_keys: [
+            (
+                "type".into(),
+                JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.room.message"))),
+            ),
+            (
+                "content.body".into(),
+                JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed(
+                    "has anyone seen the notes for Project42 yet? they're due soon",
+                ))),
+            ),
+        ]
+        .into_iter()
+        .collect(),
+        room_member_count: 3000,
+        ..Default::default()
+    });
+
+    let content_rules = (0..50)
+        .map(|i| {
+            PushRule::from_db(
+                format!("global/content/project{i}"),
+                4,
+                &format!(
+                    r#"[{{"kind": "event_match", "key": "content.body", "pattern": "project{i}"}}]"#
+                ),
+                r#"["notify", {"set_tweak": "highlight"}]"#,
+            )
+            .unwrap()
+        })
+        .collect();
+
+    let rules = FilteredPushRules::py_new(
+        PushRules::new(content_rules),
         Default::default(),
-        true,
-        vec![],
         false,
-    )
-    .unwrap();
+        false,
+        false,
+        false,
+    );
+
+    (eval, rules)
+}
+
+#[bench]
+fn bench_eval_keyword_rules(b: &mut Bencher) {
+    let (eval, rules) = message_with_keyword_rules();
+
+    b.iter(|| eval.run(&rules, Some("bob"), Some("person")));
+}
+
+#[bench]
+fn bench_eval_keyword_rules_combined(b: &mut Bencher) {
+    let (eval, rules) = message_with_keyword_rules();
+    let rules = rules.precompiled();
+    assert_eq!(
+        eval.run(&rules, Some("bob"), Some("person")),
+        eval.run(&message_with_keyword_rules().1, Some("bob"), Some("person")),
+    );
+
+    b.iter(|| eval.run(&rules, Some("bob"), Some("person")));
+}
+
+/// Builds an evaluator for a notice, and a user with many override rules which
+/// share most of their conditions, as is common for bots and bridges.
+fn notice_with_repeated_conditions() -> (PushRuleEvaluator, FilteredPushRules) {
+    let flattened_keys = [
+        (
+            "type".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.room.message"))),
+        ),
+        (
+            "room_id".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("!room:server"))),
+        ),
+        (
+            "content.msgtype".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.notice"))),
+        ),
+        (
+            "content.body".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("build finished"))),
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let eval = PushRuleEvaluator::new(EvaluatorInputs {
+        flattened_keys,
+        room_member_count: 10,
+        sender_power_level: Some(0),
+        related_event_match_enabled: true,
+        ..Default::default()
+    });
+
+    let override_rules = (0..50)
+        .map(|i| {
+            PushRule::from_db(
+                format!("global/override/bot{i}"),
+                5,
+                &format!(
+                    r#"[
+                        {{"kind": "event_match", "key": "type", "pattern": "m.room.message"}},
+                        {{"kind": "event_match", "key": "content.msgtype", "pattern": "m.notice"}},
+                        {{"kind": "event_match", "key": "room_id", "pattern": "!bot{i}:server"}}
+                    ]"#
+                ),
+                r#"["dont_notify"]"#,
+            )
+            .unwrap()
+        })
+        .collect();
 
     let rules = FilteredPushRules::py_new(
-        PushRules::new(Vec::new()),
+        PushRules::new(override_rules),
         Default::default(),
         false,
         false,
@@ -200,5 +444,126 @@ fn bench_eval_message(b: &mut Bencher) {
         false,
     );
 
+    (eval, rules)
+}
+
+#[bench]
+fn bench_eval_repeated_conditions(b: &mut Bencher) {
+    let (eval, rules) = notice_with_repeated_conditions();
+
     b.iter(|| eval.run(&rules, Some("bob"), Some("person")));
 }
+
+#[bench]
+fn bench_eval_repeated_conditions_uncached(b: &mut Bencher) {
+    let (eval, rules) = notice_with_repeated_conditions();
+
+    b.iter(|| {
+        GLOB_MATCHER_CACHE.clear();
+        eval.run(&rules, Some("bob"), Some("person"))
+    });
+}
+
+/// Builds evaluators for a batch of messages mentioning the user, so that they
+/// match a rule with several actions, along with the (base) rules.
+fn mentions_batch() -> (Vec<PushRuleEvaluator>, FilteredPushRules) {
+    let evals = (0..100)
+        .map(|i| {
+            let flattened_keys = [
+                ("type", "m.room.message".to_string()),
+                ("content.msgtype", "m.text".to_string()),
+                ("content.body", format!("person, see message {i}")),
+            ]
+            .into_iter()
+            .map(|(key, value)| {
+                (
+                    FlattenedKey::from(key),
+                    JsonValue::Value(SimpleJsonValue::Str(Cow::Owned(value))),
+                )
+            })
+            .collect();
+
+            PushRuleEvaluator::new(EvaluatorInputs {
+                flattened_keys,
+                room_member_count: 10,
+                ..Default::default()
+            })
+        })
+        .collect();
+
+    let rules = FilteredPushRules::py_new(
+        PushRules::new(Vec::new()),
+        Default::default(),
+        false,
+        false,
+        false,
+        false,
+    );
+
+    (evals, rules)
+}
+
+#[bench]
+fn bench_eval_batch_owned(b: &mut Bencher) {
+    let (evals, rules) = mentions_batch();
+
+    b.iter(|| {
+        evals
+            .iter()
+            .map(|eval| eval.run(&rules, Some("bob"), Some("person")).len())
+            .sum::<usize>()
+    });
+}
+
+#[bench]
+fn bench_eval_batch_borrowed(b: &mut Bencher) {
+    let (evals, rules) = mentions_batch();
+
+    b.iter(|| {
+        evals
+            .iter()
+            .map(|eval| {
+                eval.run_borrowed(&rules, Some("bob"), Some("person"))
+                    .count()
+            })
+            .sum::<usize>()
+    });
+}
+
+/// The keys of a typical flattened message event, as passed from Python.
+const MESSAGE_EVENT_KEYS: &[&str] = &[
+    "auth_events",
+    "content.body",
+    "content.msgtype",
+    "depth",
+    "hashes.sha256",
+    "origin_server_ts",
+    "prev_events",
+    "room_id",
+    "sender",
+    "signatures.example\\.org.ed25519:a_abcd",
+    "type",
+    "unsigned.age",
+];
+
+/// Builds the flattened keys of a batch of message events.
+fn flatten_batch<K: Ord>(key: impl Fn(&str) -> K) -> Vec<BTreeMap<K, JsonValue>> {
+    (0..100)
+        .map(|_| {
+            MESSAGE_EVENT_KEYS
+                .iter()
+                .map(|k| (key(k), JsonValue::Value(SimpleJsonValue::Null)))
+                .collect()
+        })
+        .collect()
+}
+
+#[bench]
+fn bench_flatten_batch_string_keys(b: &mut Bencher) {
+    b.iter(|| flatten_batch(str::to_string));
+}
+
+#[bench]
+fn bench_flatten_batch_flattened_keys(b: &mut Bencher) {
+    b.iter(|| flatten_batch(|key| FlattenedKey::from(key)));
+}
@@ -14,7 +14,13 @@
 
 #![feature(test)]
 
-use synapse::push::utils::{glob_to_regex, GlobMatchType};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Barrier};
+
+use synapse::push::utils::{
+    glob_to_regex, GlobBackend, GlobMatchType, GlobMatcher, NfaGlobBackend, RegexGlobBackend,
+    ThreadLocalGlobMatcherCache, GLOB_MATCHER_CACHE,
+};
 use test::Bencher;
 
 extern crate test;
@@ -38,3 +44,71 @@ fn bench_whole_wildcard_run(b: &mut Bencher) {
 fn bench_word_wildcard_run(b: &mut Bencher) {
     b.iter(|| glob_to_regex("test***??*?*?foo", GlobMatchType::Whole));
 }
+
+#[bench]
+fn bench_regex_backend_word_match(b: &mut Bencher) {
+    let matcher = RegexGlobBackend::compile("te?t*", GlobMatchType::Word).unwrap();
+    b.iter(|| GlobMatcher::is_match(&matcher, "this is a test message"));
+}
+
+#[bench]
+fn bench_nfa_backend_word_match(b: &mut Bencher) {
+    let matcher = NfaGlobBackend::compile("te?t*", GlobMatchType::Word).unwrap();
+    b.iter(|| matcher.is_match("this is a test message"));
+}
+
+/// The number of threads matching concurrently in the cache benchmarks.
+const CONCURRENT_THREADS: usize = 8;
+
+/// Benchmarks matching a handful of globs from several long lived threads at
+/// once, using the given function to match.
+fn bench_concurrently(b: &mut Bencher, is_match: fn(&str, &str) -> bool) {
+    let start = Arc::new(Barrier::new(CONCURRENT_THREADS + 1));
+    let done = Arc::new(Barrier::new(CONCURRENT_THREADS + 1));
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let threads: Vec<_> = (0..CONCURRENT_THREADS)
+        .map(|_| {
+            let (start, done, stop) = (start.clone(), done.clone(), stop.clone());
+            std::thread::spawn(move || loop {
+                start.wait();
+                if stop.load(Ordering::Acquire) {
+                    return;
+                }
+                for _ in 0..1_000 {
+                    for glob in ["coffee", "tea*", "wa?er", "juice", "milk*shake"] {
+                        test::black_box(is_match(glob, "would anyone like tea or water?"));
+                    }
+                }
+                done.wait();
+            })
+        })
+        .collect();
+
+    b.iter(|| {
+        start.wait();
+        done.wait();
+    });
+
+    stop.store(true, Ordering::Release);
+    start.wait();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[bench]
+fn bench_mutex_glob_cache_concurrent(b: &mut Bencher) {
+    bench_concurrently(b, |glob, haystack| {
+        GLOB_MATCHER_CACHE
+            .is_match(glob, GlobMatchType::Word, haystack)
+            .unwrap()
+    });
+}
+
+#[bench]
+fn bench_thread_local_glob_cache_concurrent(b: &mut Bencher) {
+    bench_concurrently(b, |glob, haystack| {
+        ThreadLocalGlobMatcherCache::is_match(glob, GlobMatchType::Word, haystack).unwrap()
+    });
+}
@@ -26,9 +26,17 @@ use crate::push::SetTweak;
 use crate::push::TweakValue;
 use crate::push::{Action, EventPropertyIsCondition, SimpleJsonValue};
 use crate::push::{Condition, EventMatchTypeCondition};
-use crate::push::{EventMatchCondition, EventMatchPatternType};
+use crate::push::{EventMatchCondition, EventMatchKey, EventMatchPatternType};
 use crate::push::{EventPropertyIsTypeCondition, PushRule};
 
+/// The version of the base rules defined in this module, which must be bumped
+/// whenever they change so that callers can tell which rules they're using.
+pub const BASE_RULES_VERSION: &str = "1";
+
+/// The versions of the base rules which can be loaded with
+/// `super::base_rules`. Only the current version is defined.
+pub const BASE_RULES_VERSIONS: &[&str] = &[BASE_RULES_VERSION];
+
 const HIGHLIGHT_ACTION: Action = Action::SetTweak(SetTweak {
     set_tweak: Cow::Borrowed("highlight"),
     value: None,
@@ -68,8 +76,11 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
         priority_class: 5,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.encrypted"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[Action::Notify]),
@@ -81,8 +92,11 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
         priority_class: 5,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("content.msgtype"),
+                key: EventMatchKey::Single(Cow::Borrowed("content.msgtype")),
                 pattern: Cow::Borrowed("m.notice"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[]),
@@ -94,12 +108,18 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
         priority_class: 5,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.member"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("content.membership"),
+                key: EventMatchKey::Single(Cow::Borrowed("content.membership")),
                 pattern: Cow::Borrowed("invite"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::EventMatchType(EventMatchTypeCondition {
                 key: Cow::Borrowed("state_key"),
@@ -115,8 +135,11 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
         priority_class: 5,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.member"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[]),
@@ -183,8 +206,11 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
                 key: Cow::Borrowed("room"),
             }),
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("content.body"),
+                key: EventMatchKey::Single(Cow::Borrowed("content.body")),
                 pattern: Cow::Borrowed("@room"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
         ]),
         actions: Cow::Borrowed(&[Action::Notify, HIGHLIGHT_ACTION]),
@@ -196,12 +222,18 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
         priority_class: 5,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.tombstone"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("state_key"),
+                key: EventMatchKey::Single(Cow::Borrowed("state_key")),
                 pattern: Cow::Borrowed(""),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
         ]),
         actions: Cow::Borrowed(&[Action::Notify, HIGHLIGHT_ACTION]),
@@ -213,8 +245,11 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
         priority_class: 5,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.reaction"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[]),
@@ -226,12 +261,18 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
         priority_class: 5,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.server_acl"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("state_key"),
+                key: EventMatchKey::Single(Cow::Borrowed("state_key")),
                 pattern: Cow::Borrowed(""),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
         ]),
         actions: Cow::Borrowed(&[]),
@@ -258,8 +299,11 @@ pub const BASE_APPEND_OVERRIDE_RULES: &[PushRule] = &[
         priority_class: 5,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("org.matrix.msc3381.poll.response"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[]),
@@ -288,8 +332,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.call.invite"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[Action::Notify, RING_ACTION, HIGHLIGHT_FALSE_ACTION]),
@@ -301,8 +348,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.message"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::RoomMemberCount {
                 is: Some(Cow::Borrowed("2")),
@@ -317,8 +367,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.encrypted"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::RoomMemberCount {
                 is: Some(Cow::Borrowed("2")),
@@ -335,9 +388,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("org.matrix.msc1767.encrypted"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::RoomMemberCount {
                 is: Some(Cow::Borrowed("2")),
@@ -359,9 +415,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("org.matrix.msc1767.message"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::RoomMemberCount {
                 is: Some(Cow::Borrowed("2")),
@@ -383,9 +442,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("org.matrix.msc1767.file"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::RoomMemberCount {
                 is: Some(Cow::Borrowed("2")),
@@ -407,9 +469,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("org.matrix.msc1767.image"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::RoomMemberCount {
                 is: Some(Cow::Borrowed("2")),
@@ -431,9 +496,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("org.matrix.msc1767.video"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::RoomMemberCount {
                 is: Some(Cow::Borrowed("2")),
@@ -455,9 +523,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("org.matrix.msc1767.audio"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::RoomMemberCount {
                 is: Some(Cow::Borrowed("2")),
@@ -477,8 +548,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.message"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[Action::Notify, HIGHLIGHT_FALSE_ACTION]),
@@ -490,8 +564,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("m.room.encrypted"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[Action::Notify, HIGHLIGHT_FALSE_ACTION]),
@@ -503,9 +580,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("m.encrypted"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             // MSC3933: Add condition on top of template rule - see MSC.
             Condition::Known(KnownCondition::RoomVersionSupports {
@@ -522,9 +602,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("m.message"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             // MSC3933: Add condition on top of template rule - see MSC.
             Condition::Known(KnownCondition::RoomVersionSupports {
@@ -541,9 +624,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("m.file"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             // MSC3933: Add condition on top of template rule - see MSC.
             Condition::Known(KnownCondition::RoomVersionSupports {
@@ -560,9 +646,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("m.image"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             // MSC3933: Add condition on top of template rule - see MSC.
             Condition::Known(KnownCondition::RoomVersionSupports {
@@ -579,9 +668,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("m.video"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             // MSC3933: Add condition on top of template rule - see MSC.
             Condition::Known(KnownCondition::RoomVersionSupports {
@@ -598,9 +690,12 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 // MSC3933: Type changed from template rule - see MSC.
                 pattern: Cow::Borrowed("m.audio"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             // MSC3933: Add condition on top of template rule - see MSC.
             Condition::Known(KnownCondition::RoomVersionSupports {
@@ -617,16 +712,25 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("im.vector.modular.widgets"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("content.type"),
+                key: EventMatchKey::Single(Cow::Borrowed("content.type")),
                 pattern: Cow::Borrowed("jitsi"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("state_key"),
+                key: EventMatchKey::Single(Cow::Borrowed("state_key")),
                 pattern: Cow::Borrowed("*"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
         ]),
         actions: Cow::Borrowed(&[Action::Notify, HIGHLIGHT_FALSE_ACTION]),
@@ -641,8 +745,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
                 is: Some(Cow::Borrowed("2")),
             }),
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("org.matrix.msc3381.poll.start"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
         ]),
         actions: Cow::Borrowed(&[Action::Notify, SOUND_ACTION]),
@@ -654,8 +761,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("org.matrix.msc3381.poll.start"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[Action::Notify]),
@@ -670,8 +780,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
                 is: Some(Cow::Borrowed("2")),
             }),
             Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("org.matrix.msc3381.poll.end"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             })),
         ]),
         actions: Cow::Borrowed(&[Action::Notify, SOUND_ACTION]),
@@ -683,8 +796,11 @@ pub const BASE_APPEND_UNDERRIDE_RULES: &[PushRule] = &[
         priority_class: 1,
         conditions: Cow::Borrowed(&[Condition::Known(KnownCondition::EventMatch(
             EventMatchCondition {
-                key: Cow::Borrowed("type"),
+                key: EventMatchKey::Single(Cow::Borrowed("type")),
                 pattern: Cow::Borrowed("org.matrix.msc3381.poll.end"),
+                ignore_quotes: false,
+                match_words: None,
+                contains: false,
             },
         ))]),
         actions: Cow::Borrowed(&[Action::Notify]),
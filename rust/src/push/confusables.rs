@@ -0,0 +1,137 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Folding of confusable characters (homoglyphs), so that text impersonating
+//! other text with lookalike characters can be matched.
+//!
+//! This is a small subset of the Unicode confusables (UTS #39): the lowercase
+//! letters of other scripts which are commonly substituted for Latin letters,
+//! fullwidth forms, and invisible characters. Text is expected to have been
+//! case-folded (see `utils::fold_case`) first, so only lowercase letters are
+//! listed.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+
+/// Characters which look like (sequences of) ASCII characters, and the ASCII
+/// they look like. Characters which are invisible map to nothing.
+const CONFUSABLES: &[(char, &str)] = &[
+    // Latin
+    ('ı', "i"), // dotless i
+    ('ȷ', "j"), // dotless j
+    ('ɑ', "a"),
+    ('ɡ', "g"),
+    ('ɩ', "i"),
+    ('ɪ', "i"),
+    ('ʀ', "r"),
+    ('ℓ', "l"),
+    ('ǀ', "l"),
+    // Cyrillic
+    ('а', "a"),
+    ('в', "b"),
+    ('ԁ', "d"),
+    ('е', "e"),
+    ('һ', "h"),
+    ('і', "i"),
+    ('ј', "j"),
+    ('к', "k"),
+    ('ӏ', "l"),
+    ('м', "m"),
+    ('н', "h"),
+    ('о', "o"),
+    ('р', "p"),
+    ('ԛ', "q"),
+    ('ѕ', "s"),
+    ('т', "t"),
+    ('с', "c"),
+    ('у', "y"),
+    ('ԝ', "w"),
+    ('х', "x"),
+    // Greek
+    ('α', "a"),
+    ('β', "b"),
+    ('ε', "e"),
+    ('η', "n"),
+    ('ι', "i"),
+    ('κ', "k"),
+    ('ν', "v"),
+    ('ο', "o"),
+    ('ρ', "p"),
+    ('τ', "t"),
+    ('υ', "u"),
+    ('χ', "x"),
+    // Invisible
+    ('\u{00AD}', ""), // soft hyphen
+    ('\u{200B}', ""), // zero width space
+    ('\u{200C}', ""), // zero width non-joiner
+    ('\u{200D}', ""), // zero width joiner
+    ('\u{2060}', ""), // word joiner
+    ('\u{FEFF}', ""), // zero width no-break space
+];
+
+/// The fullwidth forms of the printable ASCII characters, which are offset
+/// from them by `FULLWIDTH_OFFSET`.
+const FULLWIDTH_FORMS: std::ops::RangeInclusive<char> = '\u{FF01}'..='\u{FF5E}';
+
+/// The offset of a fullwidth form from its ASCII character.
+const FULLWIDTH_OFFSET: u32 = 0xFEE0;
+
+lazy_static! {
+    static ref CONFUSABLES_MAP: HashMap<char, &'static str> = CONFUSABLES.iter().copied().collect();
+}
+
+/// The ASCII the character looks like, if it's confusable.
+fn prototype(c: char, buf: &mut [u8; 4]) -> Option<&str> {
+    if FULLWIDTH_FORMS.contains(&c) {
+        let c = char::from_u32(c as u32 - FULLWIDTH_OFFSET)?;
+        return Some(c.encode_utf8(buf));
+    }
+    CONFUSABLES_MAP.get(&c).copied()
+}
+
+/// Replaces the confusable characters of the (case-folded) text with the ASCII
+/// they look like, so that e.g. "alıce" (with a dotless i) becomes "alice".
+pub fn fold_confusables(text: &str) -> Cow<'_, str> {
+    let mut buf = [0; 4];
+    if !text.chars().any(|c| prototype(c, &mut buf).is_some()) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut folded = String::with_capacity(text.len());
+    for c in text.chars() {
+        match prototype(c, &mut buf) {
+            Some(ascii) => folded.push_str(ascii),
+            None => folded.push(c),
+        }
+    }
+    Cow::Owned(folded)
+}
+
+#[test]
+fn test_fold_confusables() {
+    assert_eq!(fold_confusables("alice"), "alice");
+    assert!(matches!(fold_confusables("alice"), Cow::Borrowed(_)));
+
+    assert_eq!(fold_confusables("alıce"), "alice");
+    assert_eq!(fold_confusables("аlісе"), "alice");
+    assert_eq!(fold_confusables("bοb"), "bob");
+    assert_eq!(fold_confusables("ａｌｉｃｅ!"), "alice!");
+    assert_eq!(fold_confusables("al\u{200B}ice"), "alice");
+
+    // Other non-ASCII characters are kept.
+    assert_eq!(fold_confusables("zoë"), "zoë");
+    assert_eq!(fold_confusables("東京"), "東京");
+}
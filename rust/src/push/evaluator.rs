@@ -13,24 +13,57 @@
 // limitations under the License.
 
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+use std::ops::Bound;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Error};
+use anyhow::Error;
+use blake2::{Blake2s256, Digest};
 use lazy_static::lazy_static;
 use log::warn;
+use lru::LruCache;
+use pyo3::exceptions::PyTypeError;
 use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyIterator};
+use pythonize::{depythonize, pythonize};
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
 use super::{
-    utils::{get_glob_matcher, get_localpart_from_id, GlobMatchType},
-    Action, Condition, EventPropertyIsCondition, FilteredPushRules, KnownCondition,
-    SimpleJsonValue,
+    confusables::fold_confusables,
+    metrics, resolve_tweaks,
+    sentiment::body_sentiment,
+    sort_actions_canonically,
+    utils::{
+        charge_match_budget, deserialize_flattened, edit_distance, escape_glob, fold_case,
+        get_flattened_value, get_localpart_from_id, get_server_name_from_id,
+        get_wildcard_flattened_values, is_wildcard_key, with_match_budget, CombinedWordMatches,
+        GlobMatchType, InvalidSelectorError, PrecompiledGlobs, SelectorPath,
+        ThreadLocalGlobMatcherCache,
+    },
+    Action, Condition, EventMatchKey, EventPropertyIsCondition, FilteredPushRules, KnownCondition,
+    PushRule, SetTweak, SimpleJsonValue, TweakValue, UnreadCountKind, RATE_LIMIT_TWEAK,
 };
-use crate::push::{EventMatchPatternType, JsonValue};
+use crate::push::{EventMatchPatternType, FlattenedKey, JsonValue};
 
 lazy_static! {
     /// Used to parse the `is` clause in the room member count condition.
-    static ref INEQUALITY_EXPR: Regex = Regex::new(r"^([=<>]*)([0-9]+)$").expect("valid regex");
+    static ref INEQUALITY_EXPR: Regex = Regex::new(r"([=<>]*)\s*([0-9]+)").expect("valid regex");
+
+    /// Matches `@`-prefixed handles which start a word, e.g. `@alice` but not
+    /// the `@` in an email address.
+    static ref AT_HANDLE: Regex = Regex::new(r"(?:^|[^\w@])@\w").expect("valid regex");
+
+    /// Matches Markdown style fenced code blocks and inline code in a plain body.
+    static ref BODY_CODE: Regex = Regex::new(r"```|`[^`\n]+`").expect("valid regex");
+
+    /// Matches code elements in an HTML formatted body.
+    static ref FORMATTED_BODY_CODE: Regex =
+        Regex::new(r"(?i)<(?:code|pre)[\s>]").expect("valid regex");
 
     /// Used to determine which MSC3931 room version feature flags are actually known to
     /// the push evaluator.
@@ -46,8 +79,78 @@ lazy_static! {
         "global/override/.m.rule.roomnotif".to_string(),
         "global/content/.m.rule.contains_user_name".to_string(),
     ];
+
+    /// When each failure to match a condition was last logged, and how many
+    /// times it has failed since, see `log_condition_failure`.
+    static ref CONDITION_FAILURE_LOG: Mutex<LruCache<String, (Instant, u64)>> = Mutex::new(
+        LruCache::new(NonZeroUsize::new(CONDITION_FAILURE_LOG_SIZE).expect("non-zero cache size"))
+    );
 }
 
+/// The minimum time between logging the same failure to match a condition.
+const CONDITION_FAILURE_LOG_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The maximum number of distinct condition failures whose last log time is
+/// remembered.
+const CONDITION_FAILURE_LOG_SIZE: usize = 1000;
+
+/// The event type prefixes used by `is_unstable_event_type` conditions which
+/// don't specify their own.
+const DEFAULT_UNSTABLE_EVENT_TYPE_PREFIXES: &[&str] = &["org.", "im."];
+
+/// The maximum number of the user's recent messages considered by `quotes_me`
+/// conditions.
+const MAX_RECENT_USER_MESSAGES: usize = 20;
+
+/// The maximum length (in characters) of each of the user's recent messages
+/// considered by `quotes_me` conditions. Longer messages are truncated.
+const MAX_RECENT_USER_MESSAGE_LENGTH: usize = 1000;
+
+/// The minimum length (in characters) of display names which are matched
+/// fuzzily, see `PushRuleEvaluator::display_name_max_edit_distance`.
+const MIN_FUZZY_DISPLAY_NAME_LENGTH: usize = 5;
+
+/// The default maximum number of enabled rules evaluated per run, see
+/// `PushRuleEvaluator::max_rules_evaluated`. This is far more than legitimate
+/// users have (the base rules are a few dozen).
+pub const DEFAULT_MAX_RULES_EVALUATED: usize = 10_000;
+
+/// The default maximum length (in characters) of the "content.body" which is
+/// matched against, see `PushRuleEvaluator::max_body_match_length`. Events are
+/// limited to 64 KiB, so this only affects events which exceed that.
+pub const DEFAULT_MAX_BODY_MATCH_LENGTH: usize = 65_536;
+
+/// The flattened key which marks related events that are only related via a
+/// fallback, e.g. the reply fallback of a thread message. Synapse sets it to an
+/// empty string, so only its presence matters.
+const RELATED_EVENT_FALLBACK_KEY: &str = "im.vector.is_falling_back";
+
+/// The flattened key of the ID of the event which an event is related to, e.g.
+/// the root of a thread.
+const RELATES_TO_EVENT_ID_KEY: &str = r"content.m\.relates_to.event_id";
+
+/// The flattened key of the users mentioned by an event. (MSC3952)
+const MENTIONS_USER_IDS_KEY: &str = r"content.m\.mentions.user_ids";
+
+/// The flattened key of the ID of the event which an event replies to.
+const IN_REPLY_TO_EVENT_ID_KEY: &str = r"content.m\.relates_to.m\.in_reply_to.event_id";
+
+/// The flattened key of the flag marking an event's `m.in_reply_to` as a
+/// fallback for clients which don't support threads.
+const IS_FALLING_BACK_KEY: &str = r"content.m\.relates_to.is_falling_back";
+
+/// The flattened key of the type of an event's relation, e.g. `m.replace`.
+const REL_TYPE_KEY: &str = r"content.m\.relates_to.rel_type";
+
+/// The flattened key of the type of a message, which is matched often enough
+/// to have a fast path for literal patterns, see `match_literal_msgtype`.
+const MSGTYPE_KEY: &str = "content.msgtype";
+
+/// The flattened key of the MSC3245 marker that distinguishes voice messages from
+/// other `m.audio` messages. The marker is usually an empty object, so
+/// `_flatten_dict` records its presence with an empty string.
+const VOICE_MESSAGE_MARKER_KEY: &str = r"content.org\.matrix\.msc3245\.voice";
+
 enum RoomVersionFeatures {
     ExtensibleEvents,
 }
@@ -60,16 +163,622 @@ impl RoomVersionFeatures {
     }
 }
 
+/// The reasons a condition can fail to be evaluated, e.g. because the rule it
+/// is part of is malformed.
+#[derive(Debug, thiserror::Error)]
+pub enum ConditionMatchError {
+    /// The pattern of an `event_match` (or similar) condition, or the display
+    /// name or room name being matched, couldn't be compiled to a regex.
+    #[error("bad glob pattern {pattern:?}: {source}")]
+    BadGlob { pattern: String, source: Error },
+    /// An `is` clause, e.g. of a `room_member_count` condition, is malformed.
+    #[error("bad 'is' clause {clause:?}: {reason}")]
+    BadMemberCountClause { clause: String, reason: String },
+    /// The path of an `event_selector` condition is malformed.
+    #[error(transparent)]
+    BadSelector(#[from] InvalidSelectorError),
+    /// A custom condition evaluator failed.
+    #[error("custom condition failed: {0}")]
+    CustomCondition(Error),
+    /// Scanning the event would have exceeded the match budget, see
+    /// `PushRuleEvaluator::condition_match_budget`.
+    #[error(transparent)]
+    MatchBudget(Error),
+}
+
+impl ConditionMatchError {
+    fn bad_glob(pattern: &str) -> impl FnOnce(Error) -> Self + '_ {
+        move |source| ConditionMatchError::BadGlob {
+            pattern: pattern.to_string(),
+            source,
+        }
+    }
+
+    fn bad_clause(clause: &str, reason: impl Into<String>) -> Self {
+        ConditionMatchError::BadMemberCountClause {
+            clause: clause.to_string(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Evaluates conditions of a kind that the push evaluator doesn't understand
+/// natively, allowing experimental conditions without changes to this module.
+pub trait CustomConditionEvaluator: Send + Sync {
+    /// Check if the given condition, in its raw JSON form, matches.
+    fn matches(
+        &self,
+        condition: &Value,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Result<bool, Error>;
+}
+
+impl<F> CustomConditionEvaluator for F
+where
+    F: Fn(&Value, Option<&str>, Option<&str>) -> Result<bool, Error> + Send + Sync,
+{
+    fn matches(
+        &self,
+        condition: &Value,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Result<bool, Error> {
+        self(condition, user_id, display_name)
+    }
+}
+
+/// A custom condition evaluator backed by a Python callable, which is called
+/// with the condition as a dict, the user ID and the display name.
+struct PyCustomConditionEvaluator(PyObject);
+
+impl CustomConditionEvaluator for PyCustomConditionEvaluator {
+    fn matches(
+        &self,
+        condition: &Value,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Result<bool, Error> {
+        Python::with_gil(|py| {
+            let condition = pythonize(py, condition)?;
+            let result = self.0.call1(py, (condition, user_id, display_name))?;
+            Ok(result.extract(py)?)
+        })
+    }
+}
+
+/// The result of evaluating every push rule against an event, see
+/// [`PushRuleEvaluator::explain`].
+#[derive(Serialize, Debug, Clone)]
+pub struct EvaluationReport {
+    /// The ID of the first matching rule, i.e. the rule whose actions apply.
+    pub matched_rule_id: Option<String>,
+    /// The actions that apply, as returned by `run`.
+    pub actions: Vec<Action>,
+    /// The result of each rule, in the order they are executed in.
+    pub rules: Vec<RuleReport>,
+}
+
+/// The result of evaluating a single push rule.
+#[derive(Serialize, Debug, Clone)]
+pub struct RuleReport {
+    pub rule_id: String,
+    pub enabled: bool,
+    /// Whether the rule is enabled and all its conditions match.
+    pub matched: bool,
+    /// Why the rule can't match, whatever its conditions (e.g. it is disabled).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Whether the rule has no conditions, and so matches any event if it is
+    /// enabled.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unconditional: bool,
+    pub conditions: Vec<ConditionReport>,
+    /// The rule's actions, including any `dont_notify` and `coalesce` actions.
+    pub actions: Vec<Action>,
+}
+
+/// The result of evaluating a single condition of a push rule.
+#[derive(Serialize, Debug, Clone)]
+pub struct ConditionReport {
+    pub condition: Condition,
+    pub matched: bool,
+    /// The error encountered evaluating the condition, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The pass/fail result of a single push rule, see
+/// [`PushRuleEvaluator::dry_run`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct RuleEvaluationTrace {
+    pub rule_id: String,
+    pub matched: bool,
+    /// The index of the first condition which didn't match, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_condition: Option<usize>,
+    /// A short description of why the rule didn't match.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Whether the rule has no conditions, see `RuleReport::unconditional`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unconditional: bool,
+    /// The rule's actions, including any `dont_notify` and `coalesce` actions.
+    pub actions: Vec<Action>,
+}
+
+/// Why a push rule didn't match, see [`PushRuleEvaluator::explain_non_match`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct NonMatchExplanation {
+    /// The index of the first condition which didn't match, if the rule didn't
+    /// match because of a condition (rather than e.g. being disabled).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_condition: Option<usize>,
+    /// A human readable description of why the rule didn't match, e.g.
+    /// "content.body did not contain 'foo'".
+    pub reason: String,
+}
+
+/// The outcome of running the push rules against an event, see
+/// [`PushRuleEvaluator::run_outcome`].
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum RunOutcome {
+    /// A rule matched, and its actions (as returned by `run`) apply. These may
+    /// be empty, e.g. if the rule explicitly doesn't notify.
+    Matched {
+        rule_id: String,
+        actions: Vec<Action>,
+    },
+    /// No rule matched, so the default applies: the event doesn't notify.
+    Default,
+}
+
+impl RunOutcome {
+    /// The actions that apply, which are empty by default.
+    pub fn actions(&self) -> &[Action] {
+        match self {
+            RunOutcome::Matched { actions, .. } => actions,
+            RunOutcome::Default => &[],
+        }
+    }
+
+    /// The ID of the rule which matched, if any.
+    pub fn rule_id(&self) -> Option<&str> {
+        match self {
+            RunOutcome::Matched { rule_id, .. } => Some(rule_id),
+            RunOutcome::Default => None,
+        }
+    }
+}
+
+/// A summary of the actions that apply to an event, see
+/// [`PushRuleEvaluator::run_summary`].
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NotificationSummary {
+    /// Whether the event notifies. As with `run`, the legacy `coalesce` action
+    /// doesn't.
+    pub notify: bool,
+    /// Whether the event is highlighted. A `highlight` tweak without a value
+    /// highlights, as per the spec.
+    pub highlight: bool,
+    /// The sound to play, if any.
+    pub sound: Option<String>,
+    /// Notify at most once per this many matching events, if a valid
+    /// `rate_limit` tweak is set (see `RATE_LIMIT_TWEAK`). It's up to the
+    /// caller to count the events.
+    pub rate_limit: Option<u64>,
+}
+
+impl NotificationSummary {
+    /// Summarizes the actions. If a tweak is set more than once the last one
+    /// wins, as per `resolve_tweaks`.
+    pub fn from_actions(actions: &[Action]) -> Self {
+        let mut summary = NotificationSummary::default();
+        for action in actions {
+            match action {
+                Action::Notify => summary.notify = true,
+                Action::SetTweak(tweak) => match &*tweak.set_tweak {
+                    "highlight" => summary.highlight = tweak_highlights(tweak),
+                    "sound" => {
+                        summary.sound = match &tweak.value {
+                            Some(TweakValue::String(sound)) => Some(sound.to_string()),
+                            _ => None,
+                        }
+                    }
+                    RATE_LIMIT_TWEAK => summary.rate_limit = tweak.rate_limit(),
+                    _ => {}
+                },
+                Action::DontNotify | Action::Coalesce | Action::Unknown(_) => {}
+            }
+        }
+        summary
+    }
+}
+
+/// Whether a `highlight` tweak highlights: it does without a value, as per the
+/// spec, or with a value of `true`.
+fn tweak_highlights(tweak: &SetTweak) -> bool {
+    match &tweak.value {
+        None => true,
+        Some(TweakValue::Other(Value::Bool(highlight))) => *highlight,
+        Some(_) => false,
+    }
+}
+
+/// The result of running the push rules against an event while timing them,
+/// see [`PushRuleEvaluator::run_timed`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimedRun {
+    /// The actions, as returned by `run`.
+    pub actions: Vec<Action>,
+    /// How long evaluating the rules took in total.
+    pub elapsed: Duration,
+    /// The ID of the rule which took the longest to evaluate and how long it
+    /// took, or None if no rules were evaluated.
+    pub slowest_rule: Option<(String, Duration)>,
+}
+
+/// The result of running the push rules against an event while counting the
+/// work done, see [`PushRuleEvaluator::run_with_stats`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunStats {
+    /// The actions, as returned by `run`.
+    pub actions: Vec<Action>,
+    /// The number of enabled rules evaluated, up to and including the one
+    /// which matched.
+    pub rules_evaluated: usize,
+    /// The number of conditions evaluated. Conditions repeated across rules
+    /// are only evaluated (and counted) once.
+    pub conditions_evaluated: usize,
+}
+
+/// The result of running the push rules against an event while checking for
+/// disabled rules, see [`PushRuleEvaluator::run_strict`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrictRun {
+    /// The actions, as returned by `run`.
+    pub actions: Vec<Action>,
+    /// The IDs of the disabled rules which would have matched had they been
+    /// enabled, in the order they are executed in.
+    pub disabled_matches: Vec<String>,
+}
+
+/// The unknown conditions found by
+/// [`PushRuleEvaluator::run_strict_conditions`], which would otherwise be
+/// silently treated as not matching.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("unknown push rule conditions: {}", describe_unknown_conditions(.conditions))]
+pub struct UnknownConditionsError {
+    /// The ID of each rule with an unknown condition, along with the raw JSON
+    /// of the condition, in the order the rules are executed in.
+    pub conditions: Vec<(String, Value)>,
+}
+
+/// Lazily yields the related events of an event, as an alternative to passing
+/// them all to the evaluator up front, see
+/// `PushRuleEvaluator::set_related_events_source`.
+pub trait RelatedEventsSource: Send + Sync {
+    /// The related events with the given relation type, flattened in the same
+    /// manner as `related_events_flattened`.
+    fn related_events<'a>(
+        &'a self,
+        rel_type: &str,
+    ) -> Box<dyn Iterator<Item = BTreeMap<FlattenedKey, JsonValue>> + 'a>;
+}
+
+impl<F, I> RelatedEventsSource for F
+where
+    F: Fn(&str) -> I + Send + Sync,
+    I: Iterator<Item = BTreeMap<FlattenedKey, JsonValue>> + 'static,
+{
+    fn related_events<'a>(
+        &'a self,
+        rel_type: &str,
+    ) -> Box<dyn Iterator<Item = BTreeMap<FlattenedKey, JsonValue>> + 'a> {
+        Box::new(self(rel_type))
+    }
+}
+
+/// A related events source backed by a Python callable, which is called with
+/// the relation type and returns an iterable of flattened events. If the
+/// callable fails (including while iterating), the failure is logged and there
+/// are no further events.
+struct PyRelatedEventsSource(PyObject);
+
+impl RelatedEventsSource for PyRelatedEventsSource {
+    fn related_events<'a>(
+        &'a self,
+        rel_type: &str,
+    ) -> Box<dyn Iterator<Item = BTreeMap<FlattenedKey, JsonValue>> + 'a> {
+        let events: PyResult<Py<PyIterator>> = Python::with_gil(|py| {
+            let events = self.0.call1(py, (rel_type,))?;
+            Ok(events.as_ref(py).iter()?.into())
+        });
+        let events = match events {
+            Ok(events) => events,
+            Err(err) => {
+                warn!("Related events source failed {err}");
+                return Box::new(std::iter::empty());
+            }
+        };
+
+        Box::new(std::iter::from_fn(move || {
+            Python::with_gil(|py| {
+                let mut events = events.as_ref(py);
+                match events.next()?.and_then(|event| event.extract()) {
+                    Ok(event) => Some(event),
+                    Err(err) => {
+                        warn!("Related events source failed {err}");
+                        None
+                    }
+                }
+            })
+        }))
+    }
+}
+
+/// The result of re-running the push rules after the related events of an event
+/// changed, see [`PushRuleEvaluator::reevaluate_related_events`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelatedEventsUpdate {
+    /// The outcome with the new related events.
+    pub outcome: RunOutcome,
+    /// Whether the resolved actions differ from the previous outcome.
+    pub changed: bool,
+    /// How many rules were evaluated again.
+    pub rules_evaluated: usize,
+}
+
+/// A function applied to both the display name and the body before checking
+/// `contains_display_name` conditions.
+pub type DisplayNamePreprocessor = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Wraps a Python callable as a `DisplayNamePreprocessor`. If the callable
+/// fails the text is used unchanged.
+fn py_display_name_preprocessor(callback: PyObject) -> DisplayNamePreprocessor {
+    Box::new(move |text| {
+        Python::with_gil(|py| {
+            callback
+                .call1(py, (text,))
+                .and_then(|result| result.extract(py))
+                .unwrap_or_else(|err| {
+                    warn!("Display name preprocessor failed {err}");
+                    text.to_string()
+                })
+        })
+    })
+}
+
+/// How `sender_notification_permission` conditions are evaluated when the
+/// sender's power level isn't known, e.g. for outliers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(
+    from = "UnknownSenderPowerLevelRepr",
+    into = "UnknownSenderPowerLevelRepr"
+)]
+pub enum UnknownSenderPowerLevel {
+    /// The sender doesn't have permission, which is the default.
+    Deny,
+    /// The sender is treated as having the given power level, e.g. 0 or the
+    /// room's default user level.
+    Level(i64),
+    /// The condition is skipped, i.e. the sender has permission.
+    Skip,
+}
+
+impl Default for UnknownSenderPowerLevel {
+    fn default() -> Self {
+        UnknownSenderPowerLevel::Deny
+    }
+}
+
+/// The serialized form of an `UnknownSenderPowerLevel`: either a power level
+/// or one of `"deny"` and `"skip"`, as given from Python.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum UnknownSenderPowerLevelRepr {
+    Level(i64),
+    Fallback(UnknownSenderPowerLevelFallback),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum UnknownSenderPowerLevelFallback {
+    Deny,
+    Skip,
+}
+
+impl From<UnknownSenderPowerLevelRepr> for UnknownSenderPowerLevel {
+    fn from(repr: UnknownSenderPowerLevelRepr) -> Self {
+        match repr {
+            UnknownSenderPowerLevelRepr::Level(level) => UnknownSenderPowerLevel::Level(level),
+            UnknownSenderPowerLevelRepr::Fallback(UnknownSenderPowerLevelFallback::Deny) => {
+                UnknownSenderPowerLevel::Deny
+            }
+            UnknownSenderPowerLevelRepr::Fallback(UnknownSenderPowerLevelFallback::Skip) => {
+                UnknownSenderPowerLevel::Skip
+            }
+        }
+    }
+}
+
+impl From<UnknownSenderPowerLevel> for UnknownSenderPowerLevelRepr {
+    fn from(fallback: UnknownSenderPowerLevel) -> Self {
+        match fallback {
+            UnknownSenderPowerLevel::Level(level) => UnknownSenderPowerLevelRepr::Level(level),
+            UnknownSenderPowerLevel::Deny => {
+                UnknownSenderPowerLevelRepr::Fallback(UnknownSenderPowerLevelFallback::Deny)
+            }
+            UnknownSenderPowerLevel::Skip => {
+                UnknownSenderPowerLevelRepr::Fallback(UnknownSenderPowerLevelFallback::Skip)
+            }
+        }
+    }
+}
+
+/// The inputs a `PushRuleEvaluator` was created with, which can be serialized
+/// to JSON (see `PushRuleEvaluator::inputs_to_json`) to capture the state of an
+/// evaluator, e.g. to reproduce a bug report offline. The fields are the
+/// arguments of `PushRuleEvaluator::py_new`, and missing fields take their
+/// defaults.
+///
+/// Nothing is redacted, so the JSON contains the (flattened) event and its
+/// related events and previous content, the recipient's recent messages and
+/// membership, the room's name, member count and power levels, and the
+/// evaluator's flags. Operators should scrub any personal data from it before
+/// sharing it. Display name preprocessors and custom conditions are callables,
+/// so aren't included.
+///
+/// The inputs are also how evaluators are created from Rust, see
+/// `PushRuleEvaluator::new`. Their `Default` is an event with no keys, in a
+/// room with no members or power levels, with every optional feature disabled.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvaluatorInputs {
+    #[serde(deserialize_with = "deserialize_flattened")]
+    pub flattened_keys: BTreeMap<FlattenedKey, JsonValue>,
+    pub has_mentions: bool,
+    pub room_member_count: u64,
+    pub sender_power_level: Option<i64>,
+    pub notification_power_levels: BTreeMap<String, i64>,
+    pub related_events_flattened: BTreeMap<String, Vec<BTreeMap<FlattenedKey, JsonValue>>>,
+    pub related_event_match_enabled: bool,
+    pub room_version_feature_flags: Vec<String>,
+    pub msc3931_enabled: bool,
+    pub room_notifications_today: Option<u64>,
+    pub sender_event_rate: Option<u64>,
+    pub condition_match_budget: Option<u64>,
+    pub sender_verified: bool,
+    pub room_name: Option<String>,
+    pub device_type: Option<String>,
+    pub body_sentiment_enabled: bool,
+    pub recent_user_messages: BTreeMap<String, Vec<String>>,
+    pub recent_message_hashes: BTreeSet<String>,
+    pub is_thread_start: bool,
+    pub prev_content_flattened: BTreeMap<FlattenedKey, JsonValue>,
+    pub negate_unknown_conditions: bool,
+    pub event_origin_ts: Option<u64>,
+    pub now_ts: Option<u64>,
+    pub msc3952_intentional_mentions: bool,
+    pub relation_counts: BTreeMap<String, u64>,
+    pub relation_count_enabled: bool,
+    pub unknown_condition_matches: bool,
+    pub user_memberships: BTreeMap<String, String>,
+    pub default_notification_power_level: i64,
+    pub display_name_max_edit_distance: Option<usize>,
+    pub thread_depth: Option<u64>,
+    pub max_rules_evaluated: usize,
+    pub max_body_match_length: usize,
+    pub user_power_levels: BTreeMap<String, i64>,
+    pub room_tags: BTreeMap<String, BTreeSet<String>>,
+    pub unknown_sender_power_level: UnknownSenderPowerLevel,
+    pub confusable_display_names: bool,
+    pub aggregations: BTreeMap<String, BTreeMap<String, u64>>,
+    pub aggregation_match_enabled: bool,
+    pub sender_display_name: Option<String>,
+    pub dm_user_ids: BTreeSet<String>,
+    pub server_notices_user_id: Option<String>,
+    pub unread_notification_counts: BTreeMap<String, u64>,
+    pub unread_highlight_counts: BTreeMap<String, u64>,
+}
+
+impl Default for EvaluatorInputs {
+    fn default() -> Self {
+        EvaluatorInputs {
+            flattened_keys: BTreeMap::new(),
+            has_mentions: false,
+            room_member_count: 0,
+            sender_power_level: None,
+            notification_power_levels: BTreeMap::new(),
+            related_events_flattened: BTreeMap::new(),
+            related_event_match_enabled: false,
+            room_version_feature_flags: Vec::new(),
+            msc3931_enabled: false,
+            room_notifications_today: None,
+            sender_event_rate: None,
+            condition_match_budget: None,
+            sender_verified: false,
+            room_name: None,
+            device_type: None,
+            body_sentiment_enabled: false,
+            recent_user_messages: BTreeMap::new(),
+            recent_message_hashes: BTreeSet::new(),
+            is_thread_start: false,
+            prev_content_flattened: BTreeMap::new(),
+            negate_unknown_conditions: false,
+            event_origin_ts: None,
+            now_ts: None,
+            msc3952_intentional_mentions: false,
+            relation_counts: BTreeMap::new(),
+            relation_count_enabled: false,
+            unknown_condition_matches: false,
+            user_memberships: BTreeMap::new(),
+            default_notification_power_level: default_notification_power_level(),
+            display_name_max_edit_distance: None,
+            thread_depth: None,
+            max_rules_evaluated: default_max_rules_evaluated(),
+            max_body_match_length: default_max_body_match_length(),
+            user_power_levels: BTreeMap::new(),
+            room_tags: BTreeMap::new(),
+            unknown_sender_power_level: UnknownSenderPowerLevel::default(),
+            confusable_display_names: false,
+            aggregations: BTreeMap::new(),
+            aggregation_match_enabled: false,
+            sender_display_name: None,
+            dm_user_ids: BTreeSet::new(),
+            server_notices_user_id: None,
+            unread_notification_counts: BTreeMap::new(),
+            unread_highlight_counts: BTreeMap::new(),
+        }
+    }
+}
+
+/// The fields of `EvaluatorInputs` which are positional arguments of
+/// `PushRuleEvaluator::py_new`.
+const REQUIRED_INPUTS: [&str; 9] = [
+    "flattened_keys",
+    "has_mentions",
+    "room_member_count",
+    "sender_power_level",
+    "notification_power_levels",
+    "related_events_flattened",
+    "related_event_match_enabled",
+    "room_version_feature_flags",
+    "msc3931_enabled",
+];
+
+/// The default of `EvaluatorInputs::default_notification_power_level`, as per
+/// the spec.
+fn default_notification_power_level() -> i64 {
+    50
+}
+
+/// The default of `EvaluatorInputs::max_rules_evaluated`.
+fn default_max_rules_evaluated() -> usize {
+    DEFAULT_MAX_RULES_EVALUATED
+}
+
+/// The default of `EvaluatorInputs::max_body_match_length`.
+fn default_max_body_match_length() -> usize {
+    DEFAULT_MAX_BODY_MATCH_LENGTH
+}
+
 /// Allows running a set of push rules against a particular event.
 #[pyclass]
 pub struct PushRuleEvaluator {
     /// A mapping of "flattened" keys to simple JSON values in the event, e.g.
     /// includes things like "type" and "content.msgtype".
-    flattened_keys: BTreeMap<String, JsonValue>,
+    flattened_keys: BTreeMap<FlattenedKey, JsonValue>,
 
     /// The "content.body", if any.
     body: String,
 
+    /// The "content.body" case-folded for glob matching (see `fold_case`), so
+    /// that it is only folded once rather than for every rule. None if the
+    /// event has no body.
+    folded_body: Option<String>,
+
     /// True if the event has a m.mentions property. (Note that this is a separate
     /// flag instead of checking flattened_keys since the m.mentions property
     /// might be an empty map and not appear in flattened_keys.
@@ -81,13 +790,18 @@ pub struct PushRuleEvaluator {
     /// The `notifications` section of the current power levels in the room.
     notification_power_levels: BTreeMap<String, i64>,
 
+    /// The power level required to send a kind of notification which isn't
+    /// listed in `notification_power_levels`. Defaults to 50, as per the spec.
+    default_notification_power_level: i64,
+
     /// The power level of the sender of the event, or None if event is an
     /// outlier.
     sender_power_level: Option<i64>,
 
-    /// The related events, indexed by relation type. Flattened in the same manner as
-    /// `flattened_keys`.
-    related_events_flattened: BTreeMap<String, BTreeMap<String, JsonValue>>,
+    /// The related events, indexed by relation type. An event can have several
+    /// related events of the same type (e.g. annotations). Flattened in the
+    /// same manner as `flattened_keys`.
+    related_events_flattened: BTreeMap<String, Vec<BTreeMap<FlattenedKey, JsonValue>>>,
 
     /// If msc3664, push rules for related events, is enabled.
     related_event_match_enabled: bool,
@@ -98,11 +812,175 @@ pub struct PushRuleEvaluator {
     /// If MSC3931 (room version feature flags) is enabled. Usually controlled by the same
     /// flag as MSC1767 (extensible events core).
     msc3931_enabled: bool,
+
+    /// The number of notifications sent in the room today, if known.
+    room_notifications_today: Option<u64>,
+
+    /// The number of events the sender has recently sent to the user, if
+    /// known.
+    sender_event_rate: Option<u64>,
+
+    /// Whether the device which sent the event is verified.
+    sender_verified: bool,
+
+    /// The display name of the room, if any.
+    room_name: Option<String>,
+
+    /// The type of device being notified (e.g. "mobile" or "desktop"), if
+    /// known. Synapse computes the actions for all of a user's devices at
+    /// once, so never knows it.
+    device_type: Option<String>,
+
+    /// If the heuristic `body_sentiment` condition is enabled.
+    body_sentiment_enabled: bool,
+
+    /// The text of each user's recent messages in the room, by user ID,
+    /// bounded by `MAX_RECENT_USER_MESSAGES` and
+    /// `MAX_RECENT_USER_MESSAGE_LENGTH`.
+    recent_user_messages: BTreeMap<String, Vec<String>>,
+
+    /// The hashes of the bodies of recent messages in the room, as computed by
+    /// `message_body_hash`.
+    recent_message_hashes: BTreeSet<String>,
+
+    /// Whether the event is the first reply in a new thread.
+    is_thread_start: bool,
+
+    /// For state events, the content of the state event being replaced (i.e.
+    /// `prev_content`). Flattened in the same manner as `flattened_keys`, but
+    /// relative to the content, e.g. includes things like "membership".
+    prev_content_flattened: BTreeMap<FlattenedKey, JsonValue>,
+
+    /// Whether `not` conditions wrapping an unrecognized condition match, see
+    /// `match_negated_condition`.
+    negate_unknown_conditions: bool,
+
+    /// The `origin_server_ts` of the event, in milliseconds, if known.
+    event_origin_ts: Option<u64>,
+
+    /// The current time, in milliseconds, if known. Used with
+    /// `event_origin_ts` to calculate the age of the event.
+    now_ts: Option<u64>,
+
+    /// If MSC3952 (intentional mentions) conditions are enabled.
+    msc3952_intentional_mentions: bool,
+
+    /// The number of events related to this event, by relation type (e.g. the
+    /// number of replies in a thread). Relation types which aren't listed have
+    /// no related events. As nothing relates to a new event yet, Synapse gives
+    /// the counts of the event it relates to, including itself.
+    relation_counts: BTreeMap<String, u64>,
+
+    /// If `relation_count` conditions are enabled.
+    relation_count_enabled: bool,
+
+    /// The aggregations of events related to this event (e.g. from bundled
+    /// aggregations), by relation type and then key, e.g. the number of each
+    /// reaction for `m.annotation`. Relation types and keys which aren't listed
+    /// have no aggregation. As with `relation_counts`, Synapse gives the
+    /// aggregations of the event this event relates to, including itself.
+    aggregations: BTreeMap<String, BTreeMap<String, u64>>,
+
+    /// If `has_aggregation` conditions are enabled.
+    aggregation_match_enabled: bool,
+
+    /// The current membership of the room (e.g. "join") of each recipient
+    /// whose membership is known, by user ID.
+    user_memberships: BTreeMap<String, String>,
+
+    /// If set, `contains_display_name` conditions also match words of the body
+    /// within this edit distance of the display name, e.g. to match misspelt
+    /// names. Only display names of at least `MIN_FUZZY_DISPLAY_NAME_LENGTH`
+    /// characters are matched fuzzily, to avoid false positives.
+    display_name_max_edit_distance: Option<usize>,
+
+    /// How deep the event is in its thread, if known and the event is in a
+    /// thread: 0 for the thread root, 1 for replies to it, and so on.
+    thread_depth: Option<u64>,
+
+    /// The display name of the event's sender in the room, if known. Used by
+    /// `sender_display_name_match` conditions.
+    sender_display_name: Option<String>,
+
+    /// The users for whom the room is a direct message (DM), e.g. as listed in
+    /// their `m.direct` account data. Used by `is_direct_message` conditions.
+    dm_user_ids: BTreeSet<String>,
+
+    /// The user ID of the server notices user, if configured. Used by
+    /// `sender_is_server_notices` conditions.
+    server_notices_user_id: Option<String>,
+
+    /// The number of unread notifications in the room of each recipient whose
+    /// count is known, by user ID. Used by `unread_count` conditions.
+    unread_notification_counts: BTreeMap<String, u64>,
+
+    /// The number of unread highlights in the room of each recipient whose
+    /// count is known, by user ID. Used by `unread_count` conditions.
+    unread_highlight_counts: BTreeMap<String, u64>,
+
+    /// The maximum number of enabled rules evaluated per run, which bounds the
+    /// cost of evaluating huge rule sets. Any further rules are treated as not
+    /// matching.
+    max_rules_evaluated: usize,
+
+    /// The maximum length (in characters) of the "content.body" which is
+    /// matched against, which bounds the cost of matching huge bodies. Longer
+    /// bodies are truncated, so e.g. keywords past the limit don't match.
+    max_body_match_length: usize,
+
+    /// The power levels of users in the room, i.e. the `users` section of the
+    /// room's power levels. Used by `user_power_level` conditions.
+    user_power_levels: BTreeMap<String, i64>,
+
+    /// The tags each recipient whose tags are known has given the room (e.g.
+    /// `m.favourite` or `m.lowpriority`), from their account data, by user ID.
+    /// Used by `room_has_tag` conditions, which never match if the tags aren't
+    /// known.
+    room_tags: BTreeMap<String, BTreeSet<String>>,
+
+    /// How `sender_notification_permission` conditions are evaluated when
+    /// `sender_power_level` isn't known. By default the sender doesn't have
+    /// permission.
+    unknown_sender_power_level: UnknownSenderPowerLevel,
+
+    /// Whether `contains_display_name` conditions also match lookalikes of the
+    /// display name, which use confusable characters (e.g. a Cyrillic "а"
+    /// rather than a Latin "a") to impersonate it. See `fold_confusables`.
+    /// This is opt-in, as it can give false positives.
+    confusable_display_names: bool,
+
+    /// Whether unrecognized conditions (i.e. unknown conditions without a
+    /// custom evaluator) match. By default they don't, so rules using them
+    /// never match.
+    unknown_condition_matches: bool,
+
+    /// Applied to the display name and body before matching display names, if
+    /// set.
+    display_name_preprocessor: Option<DisplayNamePreprocessor>,
+
+    /// The number of haystack bytes the globs of a single condition may be
+    /// matched against, see `with_match_budget`. Other scans of the body (e.g.
+    /// for code, handles, sentiment or lookalike display names) count towards
+    /// it too. A condition which would exceed it is treated as not matching.
+    /// Unlimited if None.
+    condition_match_budget: Option<u64>,
+
+    /// Evaluators for otherwise unknown conditions, keyed by the condition
+    /// `kind` they handle.
+    custom_conditions: BTreeMap<String, Box<dyn CustomConditionEvaluator>>,
+
+    /// If set, yields the related events matched by `related_event_match`
+    /// conditions instead of `related_events_flattened`.
+    related_events_source: Option<Box<dyn RelatedEventsSource>>,
 }
 
 #[pymethods]
 impl PushRuleEvaluator {
-    /// Create a new `PushRuleEvaluator`. See struct docstring for details.
+    /// Create a new `PushRuleEvaluator`. See struct docstring for details, and
+    /// `new` for creating one from Rust.
+    ///
+    /// The rest of the inputs are optional keyword arguments, named as the
+    /// fields of `EvaluatorInputs`.
     #[allow(clippy::too_many_arguments)]
     #[new]
     #[pyo3(signature = (
@@ -115,35 +993,108 @@ impl PushRuleEvaluator {
         related_event_match_enabled,
         room_version_feature_flags,
         msc3931_enabled,
+        **inputs,
     ))]
     pub fn py_new(
-        flattened_keys: BTreeMap<String, JsonValue>,
+        flattened_keys: BTreeMap<FlattenedKey, JsonValue>,
         has_mentions: bool,
         room_member_count: u64,
         sender_power_level: Option<i64>,
         notification_power_levels: BTreeMap<String, i64>,
-        related_events_flattened: BTreeMap<String, BTreeMap<String, JsonValue>>,
+        related_events_flattened: BTreeMap<String, Vec<BTreeMap<FlattenedKey, JsonValue>>>,
         related_event_match_enabled: bool,
         room_version_feature_flags: Vec<String>,
         msc3931_enabled: bool,
-    ) -> Result<Self, Error> {
-        let body = match flattened_keys.get("content.body") {
-            Some(JsonValue::Value(SimpleJsonValue::Str(s))) => s.clone().into_owned(),
-            _ => String::new(),
+        inputs: Option<&PyDict>,
+    ) -> PyResult<Self> {
+        let inputs: EvaluatorInputs = match inputs {
+            // Unknown or mistyped keyword arguments are a `TypeError`, as for
+            // any other Python callable.
+            Some(inputs) => {
+                depythonize(inputs).map_err(|err| PyTypeError::new_err(err.to_string()))?
+            }
+            None => EvaluatorInputs::default(),
         };
 
-        Ok(PushRuleEvaluator {
+        Ok(PushRuleEvaluator::new(EvaluatorInputs {
             flattened_keys,
-            body,
             has_mentions,
             room_member_count,
-            notification_power_levels,
             sender_power_level,
+            notification_power_levels,
             related_events_flattened,
             related_event_match_enabled,
             room_version_feature_flags,
             msc3931_enabled,
-        })
+            ..inputs
+        }))
+    }
+
+    /// Serializes the inputs of the evaluator to JSON, see `EvaluatorInputs`.
+    pub fn inputs_to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string(&self.inputs())?)
+    }
+
+    /// Creates an evaluator from inputs serialized by `inputs_to_json`.
+    #[staticmethod]
+    pub fn from_inputs_json(json: &str) -> Result<PushRuleEvaluator, Error> {
+        let inputs: Value = serde_json::from_str(json)?;
+
+        // Only the inputs which are optional arguments of `py_new` may be left
+        // out.
+        if let Some(missing) = REQUIRED_INPUTS
+            .iter()
+            .find(|field| inputs.get(field).is_none())
+        {
+            return Err(anyhow::anyhow!("missing field `{missing}`"));
+        }
+
+        Ok(PushRuleEvaluator::new(serde_json::from_value(inputs)?))
+    }
+
+    /// Set a Python callable which is applied to both the display name and the
+    /// body before checking `contains_display_name` conditions. It must take
+    /// and return a str.
+    #[pyo3(name = "set_display_name_preprocessor")]
+    fn py_set_display_name_preprocessor(&mut self, callback: PyObject) {
+        self.set_display_name_preprocessor(py_display_name_preprocessor(callback));
+    }
+
+    /// Register a Python callable to evaluate conditions of the given `kind`,
+    /// which would otherwise never match.
+    ///
+    /// The callable is passed the condition (as a dict), the user ID and the
+    /// display name, and must return a bool.
+    #[pyo3(name = "register_custom_condition")]
+    fn py_register_custom_condition(&mut self, kind: String, callback: PyObject) {
+        self.register_custom_condition(kind, Box::new(PyCustomConditionEvaluator(callback)));
+    }
+
+    /// Set a Python callable which lazily yields the related events matched by
+    /// `related_event_match` conditions, instead of those the evaluator was
+    /// created with.
+    ///
+    /// The callable is passed the relation type, and must return an iterable
+    /// of flattened events (as for `related_events_flattened`). Iteration stops
+    /// at the first matching event.
+    #[pyo3(name = "set_related_events_source")]
+    fn py_set_related_events_source(&mut self, callback: PyObject) {
+        self.set_related_events_source(Box::new(PyRelatedEventsSource(callback)));
+    }
+
+    /// Update the power level of the sender, e.g. after the room's power
+    /// levels change, without rebuilding the evaluator.
+    pub fn set_sender_power_level(&mut self, sender_power_level: Option<i64>) {
+        self.sender_power_level = sender_power_level;
+    }
+
+    /// Update the `notifications` section of the room's power levels, e.g.
+    /// after they change, without rebuilding the evaluator.
+    pub fn set_notification_power_levels(
+        &mut self,
+        notification_power_levels: BTreeMap<String, i64>,
+    ) {
+        self.notification_power_levels = notification_power_levels;
     }
 
     /// Run the evaluator with the given push rules, for the given user ID and
@@ -160,424 +1111,8232 @@ impl PushRuleEvaluator {
         user_id: Option<&str>,
         display_name: Option<&str>,
     ) -> Vec<Action> {
-        'outer: for (push_rule, enabled) in push_rules.iter() {
-            if !enabled {
-                continue;
-            }
-
-            let rule_id = &push_rule.rule_id().to_string();
-
-            // For backwards-compatibility the legacy mention rules are disabled
-            // if the event contains the 'm.mentions' property.
-            if self.has_mentions
-                && (rule_id == "global/override/.m.rule.contains_display_name"
-                    || rule_id == "global/content/.m.rule.contains_user_name"
-                    || rule_id == "global/override/.m.rule.roomnotif")
-            {
-                continue;
-            }
-
-            let extev_flag = &RoomVersionFeatures::ExtensibleEvents.as_str().to_string();
-            let supports_extensible_events = self.room_version_feature_flags.contains(extev_flag);
-            let safe_from_rver_condition = SAFE_EXTENSIBLE_EVENTS_RULE_IDS.contains(rule_id);
-            let mut has_rver_condition = false;
-
-            for condition in push_rule.conditions.iter() {
-                has_rver_condition |= matches!(
-                    condition,
-                    // per MSC3932, we just need *any* room version condition to match
-                    Condition::Known(KnownCondition::RoomVersionSupports { feature: _ }),
-                );
-
-                match self.match_condition(condition, user_id, display_name) {
-                    Ok(true) => {}
-                    Ok(false) => continue 'outer,
-                    Err(err) => {
-                        warn!("Condition match failed {err}");
-                        continue 'outer;
-                    }
-                }
-            }
-
-            // MSC3932: Disable push rules in extensible event-supporting room versions if they
-            // don't describe *any* MSC3931 room version condition, unless the rule is on the
-            // safe list.
-            if !has_rver_condition && !safe_from_rver_condition && supports_extensible_events {
-                continue;
-            }
-
-            let actions = push_rule
-                .actions
-                .iter()
-                // Filter out "dont_notify" and "coalesce" actions, as we don't store them
-                // (since they result in no action by the pushers).
-                .filter(|a| **a != Action::DontNotify && **a != Action::Coalesce)
-                .cloned()
-                .collect();
+        self.run_with_matched_rule(push_rules, user_id, display_name)
+            .map(|(_, actions)| actions)
+            .unwrap_or_default()
+    }
 
-            return actions;
-        }
+    /// Run the evaluator in the same way as `run`, but also return the ID of
+    /// the rule which matched.
+    ///
+    /// Returns None if no rule matched.
+    pub fn run_with_matched_rule(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Option<(String, Vec<Action>)> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("push_rule_evaluator.run").entered();
 
-        Vec::new()
+        self.matching_rules(push_rules, user_id, display_name)
+            .next()
+            .map(|push_rule| (push_rule.rule_id.to_string(), stored_actions(push_rule)))
     }
 
-    /// Check if the given condition matches.
-    fn matches(
+    /// Run the evaluator in the same way as `run`, but skip the rules with the
+    /// given IDs as if they were disabled.
+    ///
+    /// This only ever suppresses rules: rules which are disabled in the push
+    /// rules stay disabled whether or not they're listed.
+    #[pyo3(signature = (push_rules, user_id, display_name, disabled_rule_ids))]
+    pub fn run_with_disabled_rules(
         &self,
-        condition: Condition,
+        push_rules: &FilteredPushRules,
         user_id: Option<&str>,
         display_name: Option<&str>,
-    ) -> bool {
-        match self.match_condition(&condition, user_id, display_name) {
-            Ok(true) => true,
+        disabled_rule_ids: BTreeSet<String>,
+    ) -> Vec<Action> {
+        self.matching_rules_excluding(push_rules, user_id, display_name, Some(&disabled_rule_ids))
+            .next()
+            .map(stored_actions)
+            .unwrap_or_default()
+    }
+
+    /// Check whether the event would notify the user, i.e. whether `run` would
+    /// return a `notify` action (or a tweak, which implies notification).
+    ///
+    /// This is cheaper than `run` as the actions aren't copied, and the
+    /// evaluation stops at the first matching rule as usual. Note that a match
+    /// of a rule with only `dont_notify` (or no) actions returns false.
+    pub fn has_notify_action(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> bool {
+        self.matching_rules(push_rules, user_id, display_name)
+            .next()
+            .map_or(false, |push_rule| {
+                push_rule
+                    .actions
+                    .iter()
+                    .any(|action| matches!(action, Action::Notify | Action::SetTweak(_)))
+            })
+    }
+
+    /// Check whether the event would be highlighted, i.e. whether the actions
+    /// `run` would return set the `highlight` tweak (see `run_summary`).
+    ///
+    /// As with `has_notify_action` the actions aren't copied. The last
+    /// `highlight` tweak of the matching rule wins, so they're checked from the
+    /// end, stopping at the first one found.
+    pub fn should_highlight(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> bool {
+        self.matching_rules(push_rules, user_id, display_name)
+            .next()
+            .and_then(|push_rule| {
+                push_rule
+                    .actions
+                    .iter()
+                    .rev()
+                    .find_map(|action| match action {
+                        Action::SetTweak(tweak) if tweak.set_tweak == "highlight" => {
+                            Some(tweak_highlights(tweak))
+                        }
+                        _ => None,
+                    })
+            })
+            .unwrap_or(false)
+    }
+
+    /// Run the evaluator in the same way as `run` against each of the given
+    /// flattened events, returning the actions for each event in the same
+    /// order. Each event is given with whether it has an `m.mentions` property,
+    /// see `PushRuleEvaluator::has_mentions`.
+    ///
+    /// The rest of the evaluator's context (e.g. the room member count, power
+    /// levels and related events) is shared between all of the events. As with
+    /// `run`, errors evaluating a rule against one event are logged and treat
+    /// the rule as not matching, so don't affect the other events.
+    pub fn run_batch(
+        &mut self,
+        events: Vec<(BTreeMap<FlattenedKey, JsonValue>, bool)>,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Vec<Vec<Action>> {
+        let original_keys = std::mem::take(&mut self.flattened_keys);
+        let original_has_mentions = self.has_mentions;
+        let original_body = std::mem::take(&mut self.body);
+        let original_folded_body = self.folded_body.take();
+
+        let results = events
+            .into_iter()
+            .map(|(flattened_keys, has_mentions)| {
+                (self.body, self.folded_body) =
+                    body_from_flattened_keys(&flattened_keys, self.max_body_match_length);
+                self.flattened_keys = flattened_keys;
+                self.has_mentions = has_mentions;
+                self.run(push_rules, user_id, display_name)
+            })
+            .collect();
+
+        self.flattened_keys = original_keys;
+        self.has_mentions = original_has_mentions;
+        self.body = original_body;
+        self.folded_body = original_folded_body;
+
+        results
+    }
+
+    /// Run the evaluator in the same way as `run`, but return up to `n` of the
+    /// matching rules (rather than just the first), in the order they should be
+    /// executed in.
+    ///
+    /// Returns the rule ID and actions (as per `run`) of each matching rule.
+    pub fn run_top_n(
+        &self,
+        n: usize,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Vec<(String, Vec<Action>)> {
+        self.matching_rules(push_rules, user_id, display_name)
+            .take(n)
+            .map(|push_rule| (push_rule.rule_id.to_string(), stored_actions(push_rule)))
+            .collect()
+    }
+
+    /// Run the evaluator in the same way as `run`, but return every enabled
+    /// rule which matches (rather than just the first), in the order they
+    /// should be executed in.
+    ///
+    /// Returns the rule ID and actions (as per `run`) of each matching rule.
+    pub fn run_all_matches(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Vec<(String, Vec<Action>)> {
+        self.matching_rules(push_rules, user_id, display_name)
+            .map(|push_rule| (push_rule.rule_id.to_string(), stored_actions(push_rule)))
+            .collect()
+    }
+
+    /// Run the evaluator in the same way as `run`, but return the actions of
+    /// the matching rule unfiltered and in their original order, e.g. to show
+    /// them to the user.
+    ///
+    /// Unlike `run`, the actions may include `dont_notify` and `coalesce`. In
+    /// particular, the list may start with (or only contain) `dont_notify`, so
+    /// callers must not treat a non-empty list as meaning the user should be
+    /// notified.
+    pub fn run_raw(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Vec<Action> {
+        self.matching_rules(push_rules, user_id, display_name)
+            .next()
+            .map(|push_rule| push_rule.actions.to_vec())
+            .unwrap_or_default()
+    }
+
+    /// Run the evaluator in the same way as `run`, but return a single coherent
+    /// list of actions rather than the raw actions of the matching rule.
+    ///
+    /// Conflicting tweaks are resolved, with the last one winning (see
+    /// `resolve_tweaks`). As with `run`, the legacy `dont_notify` and
+    /// `coalesce` actions are dropped, and `notify` is only included once.
+    pub fn run_resolved(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Vec<Action> {
+        let push_rule = match self
+            .matching_rules(push_rules, user_id, display_name)
+            .next()
+        {
+            Some(push_rule) => push_rule,
+            None => return Vec::new(),
+        };
+
+        let mut notify = false;
+        let mut actions = Vec::new();
+        for action in resolve_tweaks(&push_rule.actions) {
+            match action {
+                Action::Notify => {
+                    if !notify {
+                        notify = true;
+                        actions.push(Action::Notify);
+                    }
+                }
+                action if !is_stored_action(&action) => {}
+                action => actions.push(action),
+            }
+        }
+
+        actions
+    }
+
+    /// Run the evaluator in the same way as `run`, but return the actions in
+    /// their canonical order (see `sort_actions_canonically`) rather than the
+    /// order given by the matching rule.
+    pub fn run_canonical(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Vec<Action> {
+        let mut actions = self.run(push_rules, user_id, display_name);
+        sort_actions_canonically(&mut actions);
+        actions
+    }
+
+    /// Runs the evaluator, see `run_outcome`. Returns the outcome as a dict
+    /// with an `outcome` of either `matched` (along with the `rule_id` and
+    /// `actions`) or `default`.
+    #[pyo3(name = "run_outcome")]
+    fn py_run_outcome(
+        &self,
+        py: Python<'_>,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> PyResult<PyObject> {
+        Ok(pythonize(
+            py,
+            &self.run_outcome(push_rules, user_id, display_name),
+        )?)
+    }
+
+    /// Runs the evaluator, see `run_summary`. Returns the summary as a dict
+    /// with `notify`, `highlight`, `sound` and `rate_limit` keys.
+    #[pyo3(name = "run_summary")]
+    fn py_run_summary(
+        &self,
+        py: Python<'_>,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> PyResult<PyObject> {
+        Ok(pythonize(
+            py,
+            &self.run_summary(push_rules, user_id, display_name),
+        )?)
+    }
+
+    /// Runs the evaluator, see `run_tweaks`. Returns the tweaks as a dict of
+    /// their names to their values, which are None if not given.
+    #[pyo3(name = "run_tweaks")]
+    fn py_run_tweaks(
+        &self,
+        py: Python<'_>,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> PyResult<PyObject> {
+        Ok(pythonize(
+            py,
+            &self.run_tweaks(push_rules, user_id, display_name),
+        )?)
+    }
+
+    /// Runs the evaluator while timing it, see `run_timed`. Returns the
+    /// actions, the total elapsed time in seconds and the ID of the slowest
+    /// rule (if any).
+    #[pyo3(name = "run_timed")]
+    fn py_run_timed(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> (Vec<Action>, f64, Option<String>) {
+        let timed_run = self.run_timed(push_rules, user_id, display_name);
+        (
+            timed_run.actions,
+            timed_run.elapsed.as_secs_f64(),
+            timed_run.slowest_rule.map(|(rule_id, _)| rule_id),
+        )
+    }
+
+    /// Runs the evaluator while counting the work done, see `run_with_stats`.
+    /// Returns the actions, the number of rules evaluated and the number of
+    /// conditions evaluated.
+    #[pyo3(name = "run_with_stats")]
+    fn py_run_with_stats(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> (Vec<Action>, usize, usize) {
+        let stats = self.run_with_stats(push_rules, user_id, display_name);
+        (
+            stats.actions,
+            stats.rules_evaluated,
+            stats.conditions_evaluated,
+        )
+    }
+
+    /// Runs the evaluator while checking for disabled rules, see `run_strict`.
+    /// Returns the actions and the IDs of the disabled rules which would have
+    /// matched.
+    #[pyo3(name = "run_strict")]
+    fn py_run_strict(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> (Vec<Action>, Vec<String>) {
+        let strict_run = self.run_strict(push_rules, user_id, display_name);
+        (strict_run.actions, strict_run.disabled_matches)
+    }
+
+    /// Runs the evaluator, failing on unknown conditions, see
+    /// `run_strict_conditions`. Returns the actions as per `run`.
+    #[pyo3(name = "run_strict_conditions")]
+    fn py_run_strict_conditions(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Result<Vec<Action>, Error> {
+        Ok(self.run_strict_conditions(push_rules, user_id, display_name)?)
+    }
+
+    /// Evaluates every rule against the event, see `explain`. Returns the
+    /// report as a dict.
+    #[pyo3(name = "explain")]
+    fn py_explain(
+        &self,
+        py: Python<'_>,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> PyResult<PyObject> {
+        Ok(pythonize(
+            py,
+            &self.explain(push_rules, user_id, display_name),
+        )?)
+    }
+
+    /// Evaluates every rule against the event, see `dry_run`. Returns the
+    /// traces as a list of dicts.
+    #[pyo3(name = "dry_run")]
+    fn py_dry_run(
+        &self,
+        py: Python<'_>,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> PyResult<PyObject> {
+        Ok(pythonize(
+            py,
+            &self.dry_run(push_rules, user_id, display_name),
+        )?)
+    }
+
+    /// Explains why the rule with the given ID doesn't match, see
+    /// `explain_non_match`. Returns a dict, or None if the rule matches.
+    #[pyo3(name = "explain_non_match")]
+    fn py_explain_non_match(
+        &self,
+        py: Python<'_>,
+        rule_id: &str,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> PyResult<PyObject> {
+        let explanation = self.explain_non_match(rule_id, push_rules, user_id, display_name)?;
+        Ok(pythonize(py, &explanation)?)
+    }
+
+    /// Check if the given condition matches.
+    fn matches(
+        &self,
+        condition: Condition,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> bool {
+        match self.match_condition(&condition, user_id, display_name) {
+            Ok(true) => true,
             Ok(false) => false,
             Err(err) => {
                 warn!("Condition match failed {err}");
                 false
             }
         }
-    }
+    }
+
+    /// Check whether a single condition matches the event, without needing a
+    /// rule or rule set, e.g. to preview conditions while authoring a rule.
+    ///
+    /// This is the same as `matches`: errors are logged and treated as the
+    /// condition not matching.
+    pub fn match_single_condition(
+        &self,
+        condition: Condition,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> bool {
+        self.matches(condition, user_id, display_name)
+    }
+}
+
+impl PushRuleEvaluator {
+    /// Creates an evaluator from plain Rust values, e.g. to use it outside of
+    /// Python. `py_new` is a thin wrapper around this.
+    pub fn new(inputs: EvaluatorInputs) -> PushRuleEvaluator {
+        let (body, folded_body) =
+            body_from_flattened_keys(&inputs.flattened_keys, inputs.max_body_match_length);
+
+        PushRuleEvaluator {
+            flattened_keys: inputs.flattened_keys,
+            body,
+            folded_body,
+            has_mentions: inputs.has_mentions,
+            room_member_count: inputs.room_member_count,
+            notification_power_levels: inputs.notification_power_levels,
+            default_notification_power_level: inputs.default_notification_power_level,
+            sender_power_level: inputs.sender_power_level,
+            related_events_flattened: inputs.related_events_flattened,
+            related_event_match_enabled: inputs.related_event_match_enabled,
+            room_version_feature_flags: inputs.room_version_feature_flags,
+            msc3931_enabled: inputs.msc3931_enabled,
+            room_notifications_today: inputs.room_notifications_today,
+            sender_event_rate: inputs.sender_event_rate,
+            condition_match_budget: inputs.condition_match_budget,
+            sender_verified: inputs.sender_verified,
+            room_name: inputs.room_name,
+            device_type: inputs.device_type,
+            body_sentiment_enabled: inputs.body_sentiment_enabled,
+            recent_user_messages: inputs
+                .recent_user_messages
+                .into_iter()
+                .map(|(user_id, messages)| {
+                    let messages = messages
+                        .into_iter()
+                        .take(MAX_RECENT_USER_MESSAGES)
+                        .map(|message| {
+                            message
+                                .chars()
+                                .take(MAX_RECENT_USER_MESSAGE_LENGTH)
+                                .collect()
+                        })
+                        .collect();
+                    (user_id, messages)
+                })
+                .collect(),
+            recent_message_hashes: inputs.recent_message_hashes,
+            is_thread_start: inputs.is_thread_start,
+            prev_content_flattened: inputs.prev_content_flattened,
+            negate_unknown_conditions: inputs.negate_unknown_conditions,
+            event_origin_ts: inputs.event_origin_ts,
+            now_ts: inputs.now_ts,
+            msc3952_intentional_mentions: inputs.msc3952_intentional_mentions,
+            relation_counts: inputs.relation_counts,
+            relation_count_enabled: inputs.relation_count_enabled,
+            unknown_condition_matches: inputs.unknown_condition_matches,
+            user_memberships: inputs.user_memberships,
+            display_name_max_edit_distance: inputs.display_name_max_edit_distance,
+            thread_depth: inputs.thread_depth,
+            max_rules_evaluated: inputs.max_rules_evaluated,
+            max_body_match_length: inputs.max_body_match_length,
+            user_power_levels: inputs.user_power_levels,
+            room_tags: inputs.room_tags,
+            unknown_sender_power_level: inputs.unknown_sender_power_level,
+            confusable_display_names: inputs.confusable_display_names,
+            aggregations: inputs.aggregations,
+            aggregation_match_enabled: inputs.aggregation_match_enabled,
+            sender_display_name: inputs.sender_display_name,
+            dm_user_ids: inputs.dm_user_ids,
+            server_notices_user_id: inputs.server_notices_user_id,
+            unread_notification_counts: inputs.unread_notification_counts,
+            unread_highlight_counts: inputs.unread_highlight_counts,
+            display_name_preprocessor: None,
+            custom_conditions: BTreeMap::new(),
+            related_events_source: None,
+        }
+    }
+
+    /// Returns the inputs the evaluator was created with (as updated by e.g.
+    /// `set_sender_power_level`), from which an identical evaluator can be
+    /// created with `new`.
+    pub fn inputs(&self) -> EvaluatorInputs {
+        EvaluatorInputs {
+            flattened_keys: self.flattened_keys.clone(),
+            has_mentions: self.has_mentions,
+            room_member_count: self.room_member_count,
+            sender_power_level: self.sender_power_level,
+            notification_power_levels: self.notification_power_levels.clone(),
+            related_events_flattened: self.related_events_flattened.clone(),
+            related_event_match_enabled: self.related_event_match_enabled,
+            room_version_feature_flags: self.room_version_feature_flags.clone(),
+            msc3931_enabled: self.msc3931_enabled,
+            room_notifications_today: self.room_notifications_today,
+            sender_event_rate: self.sender_event_rate,
+            condition_match_budget: self.condition_match_budget,
+            sender_verified: self.sender_verified,
+            room_name: self.room_name.clone(),
+            device_type: self.device_type.clone(),
+            body_sentiment_enabled: self.body_sentiment_enabled,
+            recent_user_messages: self.recent_user_messages.clone(),
+            recent_message_hashes: self.recent_message_hashes.clone(),
+            is_thread_start: self.is_thread_start,
+            prev_content_flattened: self.prev_content_flattened.clone(),
+            negate_unknown_conditions: self.negate_unknown_conditions,
+            event_origin_ts: self.event_origin_ts,
+            now_ts: self.now_ts,
+            msc3952_intentional_mentions: self.msc3952_intentional_mentions,
+            relation_counts: self.relation_counts.clone(),
+            relation_count_enabled: self.relation_count_enabled,
+            unknown_condition_matches: self.unknown_condition_matches,
+            user_memberships: self.user_memberships.clone(),
+            default_notification_power_level: self.default_notification_power_level,
+            display_name_max_edit_distance: self.display_name_max_edit_distance,
+            thread_depth: self.thread_depth,
+            max_rules_evaluated: self.max_rules_evaluated,
+            max_body_match_length: self.max_body_match_length,
+            user_power_levels: self.user_power_levels.clone(),
+            room_tags: self.room_tags.clone(),
+            unknown_sender_power_level: self.unknown_sender_power_level,
+            confusable_display_names: self.confusable_display_names,
+            aggregations: self.aggregations.clone(),
+            aggregation_match_enabled: self.aggregation_match_enabled,
+            sender_display_name: self.sender_display_name.clone(),
+            dm_user_ids: self.dm_user_ids.clone(),
+            server_notices_user_id: self.server_notices_user_id.clone(),
+            unread_notification_counts: self.unread_notification_counts.clone(),
+            unread_highlight_counts: self.unread_highlight_counts.clone(),
+        }
+    }
+
+    /// Iterates over the enabled rules which match the event, in the order they
+    /// should be executed in.
+    fn matching_rules<'a, 'r: 'a>(
+        &'a self,
+        push_rules: &'r FilteredPushRules,
+        user_id: Option<&'a str>,
+        display_name: Option<&'a str>,
+    ) -> impl Iterator<Item = &'r PushRule> + 'a {
+        self.matching_rules_excluding(push_rules, user_id, display_name, None)
+    }
+
+    /// Iterates over the enabled rules which match the event, as per
+    /// `matching_rules`, skipping any rules in `disabled_rule_ids`.
+    fn matching_rules_excluding<'a, 'r: 'a>(
+        &'a self,
+        push_rules: &'r FilteredPushRules,
+        user_id: Option<&'a str>,
+        display_name: Option<&'a str>,
+        disabled_rule_ids: Option<&'a BTreeSet<String>>,
+    ) -> impl Iterator<Item = &'r PushRule> + 'a {
+        // Rule sets often repeat the same condition across rules, so remember
+        // the results for this run (and so this event) only.
+        let mut results = ConditionResults::default();
+
+        push_rules
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(push_rule, _)| push_rule)
+            .filter(move |push_rule| {
+                disabled_rule_ids.map_or(true, |disabled_rule_ids| {
+                    !disabled_rule_ids.contains(&*push_rule.rule_id)
+                })
+            })
+            .scan(0, move |rules_evaluated, push_rule| {
+                if self.rule_limit_reached(*rules_evaluated) {
+                    return None;
+                }
+                *rules_evaluated += 1;
+                Some(push_rule)
+            })
+            .filter(move |push_rule| {
+                self.rule_matches(
+                    push_rule,
+                    user_id,
+                    display_name,
+                    push_rules.precompiled_globs(),
+                    &mut results,
+                )
+            })
+    }
+
+    /// Whether no more rules should be evaluated in this run, having already
+    /// evaluated `rules_evaluated` enabled rules. Logs a warning if so, as the
+    /// rest of the rules are treated as not matching.
+    fn rule_limit_reached(&self, rules_evaluated: usize) -> bool {
+        if rules_evaluated < self.max_rules_evaluated {
+            return false;
+        }
+
+        warn!(
+            "Stopped evaluating push rules after {rules_evaluated} rules, treating the rest as not matching"
+        );
+        true
+    }
+
+    /// Check if all the conditions of the given rule match the event.
+    fn rule_matches<'a>(
+        &self,
+        push_rule: &'a PushRule,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+        globs: Option<&'a PrecompiledGlobs>,
+        results: &mut ConditionResults<'a>,
+    ) -> bool {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("push_rule_evaluator.rule", rule_id = %push_rule.rule_id)
+            .entered();
+
+        if self.rule_skip_reason(push_rule).is_some() {
+            return false;
+        }
+
+        // Rules without conditions (e.g. `.m.rule.message`) match any event.
+        if push_rule.conditions.is_empty() {
+            return true;
+        }
+
+        for condition in push_rule.conditions.iter() {
+            let result = results.get_or_insert_with(condition, |body_words| {
+                match self.match_combined_body_word(condition, globs, body_words) {
+                    Some(matches) => Ok(matches),
+                    None => {
+                        self.match_condition_with_globs(condition, user_id, display_name, globs)
+                    }
+                }
+            });
+            match result {
+                Ok(true) => {}
+                Ok(false) => return false,
+                Err(err) => {
+                    log_condition_failure(&push_rule.rule_id, condition, &err);
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Matches an `event_match` condition on the words of the body using the
+    /// rules' `CombinedWordGlobs`, so that the body is matched against all of
+    /// the rules' keywords in a single pass, which is then remembered in
+    /// `body_words` for the rest of the run.
+    ///
+    /// Returns None if the condition can't be matched this way, e.g. as the
+    /// rules weren't precompiled or the pattern depends on the user.
+    fn match_combined_body_word<'a>(
+        &self,
+        condition: &Condition,
+        globs: Option<&'a PrecompiledGlobs>,
+        body_words: &mut Option<CombinedWordMatches<'a>>,
+    ) -> Option<bool> {
+        let event_match = match condition {
+            Condition::Known(KnownCondition::EventMatch(event_match))
+                if !event_match.ignore_quotes =>
+            {
+                event_match
+            }
+            _ => return None,
+        };
+        match &event_match.key {
+            EventMatchKey::Single(key)
+                if key == "content.body" && event_match.match_type(key) == GlobMatchType::Word => {}
+            _ => return None,
+        }
+
+        let folded_body = self.folded_body.as_deref()?;
+        let combined = globs?.combined_words()?;
+        body_words
+            .get_or_insert_with(|| combined.matches_folded(folded_body))
+            .is_match(&event_match.pattern)
+    }
+
+    /// Returns why the given rule can't match this event, whatever its
+    /// conditions, if that is the case.
+    fn rule_skip_reason(&self, push_rule: &PushRule) -> Option<&'static str> {
+        let rule_id = &push_rule.rule_id().to_string();
+
+        // For backwards-compatibility the legacy mention rules are disabled
+        // if the event contains the 'm.mentions' property.
+        if self.has_mentions
+            && (rule_id == "global/override/.m.rule.contains_display_name"
+                || rule_id == "global/content/.m.rule.contains_user_name"
+                || rule_id == "global/override/.m.rule.roomnotif")
+        {
+            return Some("legacy mention rules are disabled for events with m.mentions");
+        }
+
+        let extev_flag = &RoomVersionFeatures::ExtensibleEvents.as_str().to_string();
+        let supports_extensible_events = self.room_version_feature_flags.contains(extev_flag);
+        let safe_from_rver_condition = SAFE_EXTENSIBLE_EVENTS_RULE_IDS.contains(rule_id);
+        let has_rver_condition = push_rule.conditions.iter().any(|condition| {
+            matches!(
+                condition,
+                // per MSC3932, we just need *any* room version condition to match
+                Condition::Known(KnownCondition::RoomVersionSupports { feature: _ }),
+            )
+        });
+
+        // MSC3932: Disable push rules in extensible event-supporting room versions if they
+        // don't describe *any* MSC3931 room version condition, unless the rule is on the
+        // safe list.
+        if !has_rver_condition && !safe_from_rver_condition && supports_extensible_events {
+            return Some(
+                "rules without a room version condition are disabled in this room version",
+            );
+        }
+
+        None
+    }
+
+    /// Run the evaluator in the same way as `run`, but distinguish between a
+    /// matching rule with no actions and no rule matching at all (in which case
+    /// the default of not notifying applies).
+    pub fn run_outcome(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> RunOutcome {
+        match self.run_with_matched_rule(push_rules, user_id, display_name) {
+            Some((rule_id, actions)) => RunOutcome::Matched { rule_id, actions },
+            None => RunOutcome::Default,
+        }
+    }
+
+    /// Run the evaluator in the same way as `run`, but borrow the actions from
+    /// the push rules rather than copying them, for callers which only need to
+    /// look at them.
+    pub fn run_borrowed<'r>(
+        &self,
+        push_rules: &'r FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> impl Iterator<Item = &'r Action> + 'r {
+        self.matching_rules(push_rules, user_id, display_name)
+            .next()
+            .into_iter()
+            .flat_map(|push_rule| push_rule.actions.iter())
+            .filter(|action| is_stored_action(action))
+    }
+
+    /// Run the evaluator in the same way as `run_resolved`, but summarize the
+    /// actions (see `NotificationSummary`) rather than returning them. Use
+    /// `run` if the other tweaks or unrecognized actions are needed.
+    pub fn run_summary(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> NotificationSummary {
+        NotificationSummary::from_actions(&self.run_resolved(push_rules, user_id, display_name))
+    }
+
+    /// Run the evaluator in the same way as `run_resolved`, but return the
+    /// tweaks set by the matching rule, keyed by name. A tweak set without a
+    /// value maps to None.
+    ///
+    /// Only the first matching rule applies, and if it sets a tweak more than
+    /// once the last one wins. Tweaks whose values aren't simple JSON values
+    /// are skipped; use `run` to get them.
+    pub fn run_tweaks(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> BTreeMap<String, Option<SimpleJsonValue>> {
+        let mut tweaks = BTreeMap::new();
+        for action in self.run_resolved(push_rules, user_id, display_name) {
+            if let Action::SetTweak(tweak) = action {
+                let value = match &tweak.value {
+                    None => None,
+                    Some(value) => match value.to_simple() {
+                        Some(value) => Some(value),
+                        None => {
+                            warn!("Skipping tweak {} with a non-simple value", tweak.set_tweak);
+                            continue;
+                        }
+                    },
+                };
+                tweaks.insert(tweak.set_tweak.to_string(), value);
+            }
+        }
+        tweaks
+    }
+
+    /// Run the evaluator in the same way as `run`, but also time the
+    /// evaluation, so that slow rule sets can be logged.
+    ///
+    /// Each rule is timed with a monotonic clock, so this is (slightly) more
+    /// expensive than `run`, which isn't timed.
+    pub fn run_timed(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> TimedRun {
+        let start = Instant::now();
+        let mut results = ConditionResults::default();
+        let mut slowest_rule: Option<(&PushRule, Duration)> = None;
+        let mut matched_rule = None;
+        let mut rules_evaluated = 0;
+
+        for (push_rule, enabled) in push_rules.iter() {
+            if !enabled {
+                continue;
+            }
+            if self.rule_limit_reached(rules_evaluated) {
+                break;
+            }
+            rules_evaluated += 1;
+
+            let rule_start = Instant::now();
+            let matches = self.rule_matches(
+                push_rule,
+                user_id,
+                display_name,
+                push_rules.precompiled_globs(),
+                &mut results,
+            );
+            let rule_elapsed = rule_start.elapsed();
+
+            if slowest_rule.map_or(true, |(_, slowest_elapsed)| rule_elapsed > slowest_elapsed) {
+                slowest_rule = Some((push_rule, rule_elapsed));
+            }
+
+            if matches {
+                matched_rule = Some(push_rule);
+                break;
+            }
+        }
+
+        TimedRun {
+            actions: matched_rule.map(stored_actions).unwrap_or_default(),
+            elapsed: start.elapsed(),
+            slowest_rule: slowest_rule
+                .map(|(push_rule, elapsed)| (push_rule.rule_id.to_string(), elapsed)),
+        }
+    }
+
+    /// Run the evaluator in the same way as `run`, but also count how many
+    /// rules and conditions were evaluated before a rule matched, as that
+    /// varies with the event.
+    pub fn run_with_stats(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> RunStats {
+        let mut results = ConditionResults::default();
+        let mut rules_evaluated = 0;
+        let mut matched_rule = None;
+
+        for (push_rule, enabled) in push_rules.iter() {
+            if !enabled {
+                continue;
+            }
+            if self.rule_limit_reached(rules_evaluated) {
+                break;
+            }
+
+            rules_evaluated += 1;
+            if self.rule_matches(
+                push_rule,
+                user_id,
+                display_name,
+                push_rules.precompiled_globs(),
+                &mut results,
+            ) {
+                matched_rule = Some(push_rule);
+                break;
+            }
+        }
+
+        RunStats {
+            actions: matched_rule.map(stored_actions).unwrap_or_default(),
+            rules_evaluated,
+            conditions_evaluated: results.evaluated,
+        }
+    }
+
+    /// Run the evaluator in the same way as `run`, but also evaluate the
+    /// disabled rules, returning those which would have matched had they been
+    /// enabled. This is meant for tests of stored rule sets, to catch rules
+    /// which are unexpectedly disabled.
+    ///
+    /// Every disabled rule is evaluated, wherever it is relative to the rule
+    /// which matched, unless `max_rules_evaluated` enabled rules are evaluated
+    /// first. Note that rules which are disabled by default (e.g.
+    /// `.m.rule.master`) are included if they match.
+    pub fn run_strict(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> StrictRun {
+        let mut results = ConditionResults::default();
+        let mut rules_evaluated = 0;
+        let mut matched_rule = None;
+        let mut disabled_matches = Vec::new();
+
+        for (push_rule, enabled) in push_rules.iter() {
+            if enabled && matched_rule.is_some() {
+                continue;
+            }
+            if enabled {
+                if self.rule_limit_reached(rules_evaluated) {
+                    break;
+                }
+                rules_evaluated += 1;
+            }
+
+            let matches = self.rule_matches(
+                push_rule,
+                user_id,
+                display_name,
+                push_rules.precompiled_globs(),
+                &mut results,
+            );
+            if !matches {
+                continue;
+            }
+
+            if enabled {
+                matched_rule = Some(push_rule);
+            } else {
+                disabled_matches.push(push_rule.rule_id.to_string());
+            }
+        }
+
+        StrictRun {
+            actions: matched_rule.map(stored_actions).unwrap_or_default(),
+            disabled_matches,
+        }
+    }
+
+    /// Run the evaluator in the same way as `run`, but fail if any of the
+    /// enabled rules has an unknown condition, e.g. because the condition's
+    /// `kind` is misspelled. This is meant for tests of rule sets written by
+    /// tooling, which `run` would silently treat as not matching.
+    ///
+    /// Every enabled rule is checked, whether or not the event reaches it, and
+    /// conditions nested in e.g. `not` are included. Unknown conditions with a
+    /// custom evaluator (see `register_custom_condition`) are fine.
+    pub fn run_strict_conditions(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Result<Vec<Action>, UnknownConditionsError> {
+        let mut conditions = Vec::new();
+        for (push_rule, enabled) in push_rules.iter() {
+            if !enabled {
+                continue;
+            }
+
+            let mut unknown = Vec::new();
+            for condition in push_rule.conditions.iter() {
+                self.collect_unknown_conditions(condition, &mut unknown);
+            }
+            conditions.extend(
+                unknown
+                    .into_iter()
+                    .map(|condition| (push_rule.rule_id.to_string(), condition.clone())),
+            );
+        }
+
+        if !conditions.is_empty() {
+            return Err(UnknownConditionsError { conditions });
+        }
+
+        Ok(self.run(push_rules, user_id, display_name))
+    }
+
+    /// Replace the related events of the event, and work out the new outcome
+    /// given the `previous` outcome of running the same push rules (as
+    /// returned by `run_outcome`).
+    ///
+    /// Only the rules with conditions on the related events can change
+    /// outcome, so the other rules are not evaluated again: those before the
+    /// previously matched rule still don't match, and that rule still matches
+    /// if it doesn't depend on the related events. The rules after it are only
+    /// evaluated if it no longer matches. As with `run`, rules after the first
+    /// `max_rules_evaluated` enabled rules never match.
+    pub fn reevaluate_related_events(
+        &mut self,
+        push_rules: &FilteredPushRules,
+        previous: &RunOutcome,
+        related_events_flattened: BTreeMap<String, Vec<BTreeMap<FlattenedKey, JsonValue>>>,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> RelatedEventsUpdate {
+        self.related_events_flattened = related_events_flattened;
+
+        let mut results = ConditionResults::default();
+        let mut rules_evaluated = 0;
+        // The number of enabled rules `run` would have evaluated so far, which
+        // is what `max_rules_evaluated` limits.
+        let mut rules_reached = 0;
+        let mut matched_rule = None;
+        // Whether the previously matched rule has been passed, after which
+        // every rule has to be evaluated.
+        let mut past_previous = false;
+
+        for (push_rule, enabled) in push_rules.iter() {
+            if !enabled {
+                continue;
+            }
+            if self.rule_limit_reached(rules_reached) {
+                break;
+            }
+            rules_reached += 1;
+
+            let is_previous = previous.rule_id() == Some(&*push_rule.rule_id);
+            let is_related = push_rule.conditions.iter().any(depends_on_related_events);
+            if !past_previous && !is_related {
+                if is_previous {
+                    matched_rule = Some(push_rule);
+                    break;
+                }
+                continue;
+            }
+
+            rules_evaluated += 1;
+            if self.rule_matches(
+                push_rule,
+                user_id,
+                display_name,
+                push_rules.precompiled_globs(),
+                &mut results,
+            ) {
+                matched_rule = Some(push_rule);
+                break;
+            }
+            past_previous |= is_previous;
+        }
+
+        let outcome = match matched_rule {
+            Some(push_rule) => RunOutcome::Matched {
+                rule_id: push_rule.rule_id.to_string(),
+                actions: stored_actions(push_rule),
+            },
+            None => RunOutcome::Default,
+        };
+        RelatedEventsUpdate {
+            changed: outcome.actions() != previous.actions(),
+            outcome,
+            rules_evaluated,
+        }
+    }
+
+    /// Evaluates every rule (and each of its conditions) against the event,
+    /// recording the results. Unlike `run`, evaluation doesn't stop at the
+    /// first matching rule.
+    pub fn explain(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> EvaluationReport {
+        let rules: Vec<RuleReport> = push_rules
+            .iter()
+            .map(|(push_rule, enabled)| {
+                let conditions: Vec<ConditionReport> = push_rule
+                    .conditions
+                    .iter()
+                    .map(|condition| {
+                        let (matched, error) = match self.match_condition_with_globs(
+                            condition,
+                            user_id,
+                            display_name,
+                            push_rules.precompiled_globs(),
+                        ) {
+                            Ok(matched) => (matched, None),
+                            Err(err) => (false, Some(err.to_string())),
+                        };
+                        ConditionReport {
+                            condition: condition.clone(),
+                            matched,
+                            error,
+                        }
+                    })
+                    .collect();
+
+                let reason = if enabled {
+                    self.rule_skip_reason(push_rule)
+                } else {
+                    Some("rule is disabled")
+                };
+
+                RuleReport {
+                    rule_id: push_rule.rule_id.to_string(),
+                    enabled,
+                    matched: reason.is_none() && conditions.iter().all(|c| c.matched),
+                    reason: reason.map(str::to_string),
+                    unconditional: conditions.is_empty(),
+                    conditions,
+                    actions: push_rule.actions.to_vec(),
+                }
+            })
+            .collect();
+
+        let matched_rule = rules.iter().find(|rule| rule.matched);
+
+        EvaluationReport {
+            matched_rule_id: matched_rule.map(|rule| rule.rule_id.clone()),
+            actions: matched_rule
+                .map_or_else(Vec::new, |rule| filter_stored_actions(&rule.actions)),
+            rules,
+        }
+    }
+
+    /// Evaluates every rule against the event, returning whether each matched
+    /// and, if not, the first condition which failed. This is a summary of
+    /// `explain`.
+    pub fn dry_run(
+        &self,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Vec<RuleEvaluationTrace> {
+        self.explain(push_rules, user_id, display_name)
+            .rules
+            .into_iter()
+            .map(|rule| {
+                let failed_condition = rule
+                    .conditions
+                    .iter()
+                    .enumerate()
+                    .find(|(_, condition)| !condition.matched);
+
+                let reason = match (&rule.reason, failed_condition) {
+                    (Some(reason), _) => Some(reason.clone()),
+                    (None, Some((index, condition))) => {
+                        let kind = serde_json::to_value(&condition.condition)
+                            .ok()
+                            .and_then(|value| value.get("kind")?.as_str().map(str::to_string))
+                            .unwrap_or_else(|| "unknown".to_string());
+                        Some(match &condition.error {
+                            Some(error) => format!("condition {index} ({kind}) failed: {error}"),
+                            None => format!("condition {index} ({kind}) did not match"),
+                        })
+                    }
+                    (None, None) => None,
+                };
+
+                RuleEvaluationTrace {
+                    rule_id: rule.rule_id,
+                    matched: rule.matched,
+                    failed_condition: failed_condition.map(|(index, _)| index),
+                    reason,
+                    unconditional: rule.unconditional,
+                    actions: rule.actions,
+                }
+            })
+            .collect()
+    }
+
+    /// Explains why the rule with the given ID doesn't match the event, giving
+    /// the first condition which didn't match and a human readable reason.
+    ///
+    /// Returns None if the rule matches, and fails if there is no such rule.
+    /// Unlike `explain`, only the one rule is evaluated, and its conditions
+    /// only until one doesn't match.
+    pub fn explain_non_match(
+        &self,
+        rule_id: &str,
+        push_rules: &FilteredPushRules,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Result<Option<NonMatchExplanation>, Error> {
+        let (push_rule, enabled) = push_rules
+            .iter()
+            .find(|(push_rule, _)| push_rule.rule_id == rule_id)
+            .ok_or_else(|| anyhow::anyhow!("no push rule with ID {rule_id:?}"))?;
+
+        let reason = if enabled {
+            self.rule_skip_reason(push_rule)
+        } else {
+            Some("rule is disabled")
+        };
+        if let Some(reason) = reason {
+            return Ok(Some(NonMatchExplanation {
+                failed_condition: None,
+                reason: reason.to_string(),
+            }));
+        }
+
+        for (index, condition) in push_rule.conditions.iter().enumerate() {
+            let reason = match self.match_condition_with_globs(
+                condition,
+                user_id,
+                display_name,
+                push_rules.precompiled_globs(),
+            ) {
+                Ok(true) => continue,
+                Ok(false) => self.non_match_reason(condition, user_id, display_name),
+                Err(err) => format!("{} condition failed: {err}", condition_kind(condition)),
+            };
+            return Ok(Some(NonMatchExplanation {
+                failed_condition: Some(index),
+                reason,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    /// A human readable description of why the condition, which didn't match,
+    /// didn't match. Conditions without a more specific description are
+    /// described by their kind.
+    fn non_match_reason(
+        &self,
+        condition: &Condition,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> String {
+        let known_condition = match condition {
+            Condition::Known(known_condition) => known_condition,
+            Condition::Unknown(unknown) if is_malformed_known_condition(unknown) => {
+                return format!("malformed {} condition", condition_kind(condition))
+            }
+            Condition::Unknown(_) => {
+                return format!(
+                    "unrecognized condition kind '{}'",
+                    condition_kind(condition)
+                )
+            }
+        };
+
+        let missing_key = |key: &str| match get_flattened_value(&self.flattened_keys, key) {
+            Some(_) => None,
+            None => Some(format!("event has no {key}")),
+        };
+
+        match known_condition {
+            KnownCondition::EventMatch(event_match) => match &event_match.key {
+                EventMatchKey::Single(key) => missing_key(key).unwrap_or_else(|| {
+                    match event_match.match_type(key) {
+                        GlobMatchType::Whole => {
+                            format!("{key} did not match '{}'", event_match.pattern)
+                        }
+                        _ => format!("{key} did not contain '{}'", event_match.pattern),
+                    }
+                }),
+                EventMatchKey::Any(keys) => format!(
+                    "none of {} matched '{}'",
+                    keys.join(", "),
+                    event_match.pattern
+                ),
+            },
+            KnownCondition::EventMatchType(event_match) => {
+                let (id, description) = match &*event_match.pattern_type {
+                    EventMatchPatternType::UserId => (user_id, "the user's ID"),
+                    EventMatchPatternType::UserLocalpart => (user_id, "the user's localpart"),
+                    EventMatchPatternType::Sender => (Some("sender"), "the sender's ID"),
+                    EventMatchPatternType::SenderLocalpart => {
+                        (Some("sender"), "the sender's localpart")
+                    }
+                };
+                match id {
+                    None => "the user's ID is not known".to_string(),
+                    Some(_) => missing_key(&event_match.key)
+                        .unwrap_or_else(|| format!("{} did not contain {description}", event_match.key)),
+                }
+            }
+            KnownCondition::ContainsDisplayName => match display_name {
+                Some(display_name) => {
+                    format!("content.body did not contain the display name '{display_name}'")
+                }
+                None => "the user's display name is not known".to_string(),
+            },
+            KnownCondition::RoomMemberCount { is: None } => {
+                "room_member_count condition has no 'is' clause".to_string()
+            }
+            KnownCondition::RoomMemberCount { is: Some(is) } => {
+                let bounds = parse_inequality(is)
+                    .map(|bounds| {
+                        bounds
+                            .iter()
+                            .map(|(ineq, rhs)| format!("{ineq} {rhs}"))
+                            .collect::<Vec<_>>()
+                            .join(" and ")
+                    })
+                    .unwrap_or_else(|_| is.to_string());
+                format!(
+                    "room member count {} is not {bounds}",
+                    self.room_member_count
+                )
+            }
+            KnownCondition::SenderNotificationPermission { key } => match (
+                self.sender_power_level,
+                self.unknown_sender_power_level,
+            ) {
+                (Some(sender_power_level), _) => format!(
+                    "sender's power level {sender_power_level} is below the {} required for '{key}' notifications",
+                    self.required_notification_power_level(key)
+                ),
+                (None, UnknownSenderPowerLevel::Level(level)) => format!(
+                    "sender's power level is not known, and the fallback level {level} is below the {} required for '{key}' notifications",
+                    self.required_notification_power_level(key)
+                ),
+                (None, _) => "sender's power level is not known".to_string(),
+            },
+            KnownCondition::EventPropertyIs(event_property_is) => {
+                let key = &event_property_is.key;
+                match self.flattened_keys.get(&**key) {
+                    Some(JsonValue::Value(value)) => format!(
+                        "{key} was {}, not {}",
+                        json_display(value),
+                        json_display(&event_property_is.value)
+                    ),
+                    Some(JsonValue::Array(_)) => format!("{key} is an array"),
+                    None => format!("event has no {key}"),
+                }
+            }
+            KnownCondition::EventPropertyContains(event_property_is) => {
+                let key = &event_property_is.key;
+                match self.flattened_keys.get(&**key) {
+                    Some(JsonValue::Array(_)) => format!(
+                        "{key} did not contain {}",
+                        json_display(&event_property_is.value)
+                    ),
+                    Some(JsonValue::Value(_)) => format!("{key} is not an array"),
+                    None => format!("event has no {key}"),
+                }
+            }
+            KnownCondition::Not { .. } => "negated condition matched".to_string(),
+            known_condition => format!("{} condition did not match", known_condition.kind()),
+        }
+    }
+
+    /// Set the function applied to both the display name and the body before
+    /// checking `contains_display_name` conditions.
+    pub fn set_display_name_preprocessor(&mut self, preprocessor: DisplayNamePreprocessor) {
+        self.display_name_preprocessor = Some(preprocessor);
+    }
+
+    /// Register an evaluator for conditions of the given `kind`. Replaces any
+    /// evaluator previously registered for that kind.
+    pub fn register_custom_condition(
+        &mut self,
+        kind: String,
+        evaluator: Box<dyn CustomConditionEvaluator>,
+    ) {
+        self.custom_conditions.insert(kind, evaluator);
+    }
+
+    /// Set the source of the related events matched by `related_event_match`
+    /// conditions, which is used instead of `related_events_flattened`. This
+    /// avoids loading every related event of events with huge numbers of
+    /// relations (e.g. thousands of reactions), as the events are only
+    /// requested as they're matched, stopping at the first match.
+    ///
+    /// Other conditions on related events still use `related_events_flattened`.
+    pub fn set_related_events_source(&mut self, source: Box<dyn RelatedEventsSource>) {
+        self.related_events_source = Some(source);
+    }
+
+    /// Match a given `Condition` for a push rule.
+    pub fn match_condition(
+        &self,
+        condition: &Condition,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+    ) -> Result<bool, ConditionMatchError> {
+        self.match_condition_with_globs(condition, user_id, display_name, None)
+    }
+
+    /// Match a given `Condition` for a push rule, using any of the given
+    /// precompiled globs rather than compiling them.
+    fn match_condition_with_globs(
+        &self,
+        condition: &Condition,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+        globs: Option<&PrecompiledGlobs>,
+    ) -> Result<bool, ConditionMatchError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!(
+            "push_rule_evaluator.match_condition",
+            kind = match condition {
+                Condition::Known(known) => known.kind(),
+                Condition::Unknown(_) => "unknown",
+            },
+        )
+        .entered();
+
+        let known_condition = match condition {
+            Condition::Known(known) => known,
+            Condition::Unknown(unknown) => {
+                // Dispatch to a custom evaluator if one is registered for this
+                // kind, otherwise unrecognized conditions match only if
+                // configured to. Malformed conditions of a kind we recognize
+                // never match.
+                return match self.custom_condition_evaluator(unknown) {
+                    Some(custom_evaluator) => custom_evaluator
+                        .matches(unknown, user_id, display_name)
+                        .map_err(ConditionMatchError::CustomCondition),
+                    None if is_malformed_known_condition(unknown) => Ok(false),
+                    None => Ok(self.unknown_condition_matches),
+                };
+            }
+        };
+
+        let match_known_condition = || {
+            metrics::time_condition(known_condition, || {
+                self.match_known_condition(known_condition, user_id, display_name, globs)
+            })
+        };
+
+        let budget = match self.condition_match_budget {
+            Some(budget) => budget,
+            None => return match_known_condition(),
+        };
+
+        // Conditions nested in this one share its budget. Matches which would
+        // exceed it are refused, and the condition is treated as not matching.
+        match with_match_budget(budget, match_known_condition) {
+            Some(result) => result,
+            None => {
+                let message = format!(
+                    "{} condition exceeded match budget of {budget} bytes",
+                    known_condition.kind()
+                );
+                match record_condition_failure(&message, Instant::now()) {
+                    Some(0) => warn!("{message}"),
+                    Some(suppressed) => {
+                        warn!("{message} ({suppressed} similar failures not logged)")
+                    }
+                    None => {}
+                }
+                Ok(false)
+            }
+        }
+    }
+
+    /// Returns the registered evaluator for the given unknown condition, if
+    /// any.
+    fn custom_condition_evaluator(&self, unknown: &Value) -> Option<&dyn CustomConditionEvaluator> {
+        unknown
+            .get("kind")
+            .and_then(Value::as_str)
+            .and_then(|kind| self.custom_conditions.get(kind))
+            .map(|custom_evaluator| &**custom_evaluator)
+    }
+
+    /// Collects the unknown conditions without a custom evaluator, including
+    /// those nested in `not` and `any_of` conditions.
+    fn collect_unknown_conditions<'a>(
+        &self,
+        condition: &'a Condition,
+        unknown: &mut Vec<&'a Value>,
+    ) {
+        match condition {
+            Condition::Unknown(value) => {
+                if self.custom_condition_evaluator(value).is_none() {
+                    unknown.push(value);
+                }
+            }
+            Condition::Known(KnownCondition::Not { condition }) => {
+                self.collect_unknown_conditions(condition, unknown)
+            }
+            Condition::Known(KnownCondition::AnyOf { conditions }) => {
+                for condition in conditions.iter() {
+                    self.collect_unknown_conditions(condition, unknown);
+                }
+            }
+            Condition::Known(_) => {}
+        }
+    }
+
+    /// Match the negation of the given condition, as used by `not`.
+    ///
+    /// The negation never matches if the inner condition fails to evaluate, so
+    /// a malformed condition doesn't cause the negation to spuriously match.
+    ///
+    /// Unrecognized inner conditions (i.e. unknown conditions without a custom
+    /// evaluator) can't be evaluated, so would otherwise be treated as not
+    /// matching. By default their negation also doesn't match, i.e. a `not`
+    /// of an unrecognized condition is itself treated as unrecognized. If
+    /// `negate_unknown_conditions` is set they're instead negated like any
+    /// other condition, so the `not` matches. Malformed conditions of a kind we
+    /// recognize are never negated.
+    fn match_negated_condition(
+        &self,
+        condition: &Condition,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+        globs: Option<&PrecompiledGlobs>,
+    ) -> bool {
+        if let Condition::Unknown(unknown) = condition {
+            if self.custom_condition_evaluator(unknown).is_none() {
+                return self.negate_unknown_conditions && !is_malformed_known_condition(unknown);
+            }
+        }
+
+        match self.match_condition_with_globs(condition, user_id, display_name, globs) {
+            Ok(matches) => !matches,
+            Err(err) => {
+                warn!("Negated condition match failed {err}");
+                false
+            }
+        }
+    }
+
+    /// Whether the event is a reply, i.e. has the ID of the event it replies
+    /// to. Thread messages whose reply is only a fallback (for clients which
+    /// don't support threads) aren't replies.
+    fn is_reply(&self) -> bool {
+        let has_event_id = matches!(
+            self.flattened_keys.get(IN_REPLY_TO_EVENT_ID_KEY),
+            Some(JsonValue::Value(SimpleJsonValue::Str(event_id))) if !event_id.is_empty()
+        );
+        let is_falling_back = matches!(
+            self.flattened_keys.get(IS_FALLING_BACK_KEY),
+            Some(JsonValue::Value(SimpleJsonValue::Bool(true)))
+        );
+
+        has_event_id && !is_falling_back
+    }
+
+    /// Evaluates an `any_of` condition, i.e. whether any of the given conditions
+    /// match.
+    ///
+    /// The conditions are evaluated in order, stopping at the first which
+    /// matches, so cheap or likely conditions should come first. A condition
+    /// which fails to evaluate is treated as not matching, so that one
+    /// malformed condition doesn't stop the others from matching. An empty
+    /// list never matches.
+    fn match_any_of(
+        &self,
+        conditions: &[Condition],
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+        globs: Option<&PrecompiledGlobs>,
+    ) -> bool {
+        conditions.iter().any(|condition| {
+            match self.match_condition_with_globs(condition, user_id, display_name, globs) {
+                Ok(matches) => matches,
+                Err(err) => {
+                    warn!("Condition in any_of failed {err}");
+                    false
+                }
+            }
+        })
+    }
+
+    /// Match a given `KnownCondition` for a push rule.
+    fn match_known_condition(
+        &self,
+        known_condition: &KnownCondition,
+        user_id: Option<&str>,
+        display_name: Option<&str>,
+        globs: Option<&PrecompiledGlobs>,
+    ) -> Result<bool, ConditionMatchError> {
+        let result = match known_condition {
+            KnownCondition::EventMatch(event_match) => {
+                let mut matches = false;
+                for key in event_match.key.iter() {
+                    matches = if event_match.ignore_quotes && key == "content.body" {
+                        PrecompiledGlobs::is_match(
+                            globs,
+                            &event_match.pattern,
+                            event_match.match_type(key),
+                            &strip_quoted_lines(&self.body),
+                        )
+                        .map_err(ConditionMatchError::bad_glob(&event_match.pattern))?
+                    } else if let (Some(folded_body), "content.body") = (&self.folded_body, key) {
+                        PrecompiledGlobs::is_match_folded(
+                            globs,
+                            &event_match.pattern,
+                            event_match.match_type(key),
+                            folded_body,
+                        )
+                        .map_err(ConditionMatchError::bad_glob(&event_match.pattern))?
+                    } else {
+                        self.match_event_match(
+                            &self.flattened_keys,
+                            key,
+                            &event_match.pattern,
+                            event_match.match_type(key),
+                            globs,
+                        )?
+                    };
+                    if matches {
+                        break;
+                    }
+                }
+                matches
+            }
+            KnownCondition::EventMatchType(event_match) => {
+                let pattern = if let Some(pattern) =
+                    self.resolve_pattern_type(&event_match.pattern_type, user_id)?
+                {
+                    pattern
+                } else {
+                    return Ok(false);
+                };
+
+                // Use the same pre-folded body as literal `event_match`
+                // patterns, so both are case-folded identically.
+                if let (Some(folded_body), "content.body") = (&self.folded_body, &*event_match.key)
+                {
+                    PrecompiledGlobs::is_match_folded(
+                        None,
+                        pattern,
+                        event_match_type(&event_match.key),
+                        folded_body,
+                    )
+                    .map_err(ConditionMatchError::bad_glob(pattern))?
+                } else {
+                    self.match_event_match(
+                        &self.flattened_keys,
+                        &event_match.key,
+                        pattern,
+                        event_match_type(&event_match.key),
+                        None,
+                    )?
+                }
+            }
+            KnownCondition::EventPropertyIn { key, values } => {
+                match self.flattened_keys.get(&**key) {
+                    Some(JsonValue::Value(value)) => values.contains(value),
+                    _ => false,
+                }
+            }
+            KnownCondition::EventPropertyIs(event_property_is) => {
+                self.match_event_property_is(event_property_is)?
+            }
+            KnownCondition::RelatedEventMatch(event_match) => self.match_related_event_match(
+                &event_match.rel_type.clone(),
+                event_match.include_fallbacks,
+                event_match.key.clone(),
+                event_match.pattern.clone(),
+                globs,
+            )?,
+            KnownCondition::RelatedEventMatchType(event_match) => {
+                let pattern = if let Some(pattern) =
+                    self.resolve_pattern_type(&event_match.pattern_type, user_id)?
+                {
+                    pattern
+                } else {
+                    return Ok(false);
+                };
+
+                self.match_related_event_match(
+                    &event_match.rel_type.clone(),
+                    event_match.include_fallbacks,
+                    Some(event_match.key.clone()),
+                    Some(Cow::Borrowed(pattern)),
+                    None,
+                )?
+            }
+            KnownCondition::EventPropertyContains(event_property_is) => self
+                .match_event_property_contains(
+                    event_property_is.key.clone(),
+                    event_property_is.value.clone(),
+                )?,
+            KnownCondition::ExactEventPropertyContainsType(exact_event_match) => {
+                let pattern = if let Some(pattern) =
+                    self.resolve_pattern_type(&exact_event_match.value_type, user_id)?
+                {
+                    pattern
+                } else {
+                    return Ok(false);
+                };
+
+                self.match_event_property_contains(
+                    exact_event_match.key.clone(),
+                    Cow::Borrowed(&SimpleJsonValue::Str(Cow::Owned(pattern.to_owned()))),
+                )?
+            }
+            KnownCondition::ContainsDisplayName => {
+                if let Some(dn) = display_name {
+                    let (dn, body) = match &self.display_name_preprocessor {
+                        Some(preprocess) => (
+                            Cow::Owned(preprocess(dn)),
+                            Cow::Owned(preprocess(&self.body)),
+                        ),
+                        None => (Cow::Borrowed(dn), Cow::Borrowed(self.body.as_str())),
+                    };
+
+                    let folded_body = match &self.display_name_preprocessor {
+                        Some(_) => Cow::Owned(fold_case(&body)),
+                        None => Cow::Borrowed(self.folded_body.as_deref().unwrap_or_default()),
+                    };
+
+                    let dn = trim_display_name(&dn);
+                    if !dn.is_empty() {
+                        // Display names are matched literally, so e.g. `[bot]`
+                        // isn't a character class.
+                        ThreadLocalGlobMatcherCache::is_match_folded(
+                            &escape_glob(dn),
+                            GlobMatchType::Word,
+                            &folded_body,
+                        )
+                        .map_err(ConditionMatchError::bad_glob(dn))?
+                            || match self.display_name_max_edit_distance {
+                                Some(max_distance) => {
+                                    charge_match_budget(&body)
+                                        .map_err(ConditionMatchError::MatchBudget)?;
+                                    fuzzy_contains_display_name(dn, &body, max_distance)
+                                }
+                                None => false,
+                            }
+                            || (self.confusable_display_names
+                                && self.contains_confusable_display_name(dn, &folded_body)?)
+                    } else {
+                        // We specifically ignore empty display names (including
+                        // those which only look empty), as otherwise they would
+                        // always match.
+                        false
+                    }
+                } else {
+                    false
+                }
+            }
+            KnownCondition::RoomMemberCount { is } => {
+                if let Some(is) = is {
+                    self.match_member_count(is)?
+                } else {
+                    false
+                }
+            }
+            KnownCondition::SenderNotificationPermission { key } => {
+                self.sender_has_notification_permission(key)
+            }
+            KnownCondition::IsUserMention => {
+                self.msc3952_intentional_mentions && self.match_is_user_mention(user_id)
+            }
+            KnownCondition::IsRoomMention => {
+                self.msc3952_intentional_mentions
+                    && matches!(
+                        self.flattened_keys.get(r"content.m\.mentions.room"),
+                        Some(JsonValue::Value(SimpleJsonValue::Bool(true)))
+                    )
+                    && self.sender_has_notification_permission("room")
+            }
+            KnownCondition::RoomVersionSupports { feature } => {
+                if !self.msc3931_enabled {
+                    false
+                } else {
+                    let flag = feature.to_string();
+                    KNOWN_RVER_FLAGS.contains(&flag)
+                        && self.room_version_feature_flags.contains(&flag)
+                }
+            }
+            KnownCondition::IsVoiceMessage => self.match_is_voice_message(),
+            KnownCondition::IsUnstableEventType { prefixes } => {
+                self.match_is_unstable_event_type(prefixes.as_deref())
+            }
+            KnownCondition::HasAnyKey { keys } => keys.iter().any(|key| self.has_key(key)),
+            KnownCondition::HasAllKeys { keys } => keys.iter().all(|key| self.has_key(key)),
+            KnownCondition::EventKeyExists { key, exists } => self.has_key(key) == *exists,
+            KnownCondition::KeysEqual { key_a, key_b } => {
+                match (
+                    self.flattened_keys.get(&**key_a),
+                    self.flattened_keys.get(&**key_b),
+                ) {
+                    (Some(value_a), Some(value_b)) => value_a == value_b,
+                    _ => false,
+                }
+            }
+            KnownCondition::QuotesMe => self.match_quotes_me(user_id),
+            KnownCondition::BodySentiment { polarity } => {
+                if self.body_sentiment_enabled {
+                    charge_match_budget(&self.body).map_err(ConditionMatchError::MatchBudget)?;
+                    body_sentiment(&self.body) == *polarity
+                } else {
+                    false
+                }
+            }
+            KnownCondition::DeviceTypeMatch { device_type } => {
+                self.device_type.as_deref() == Some(&**device_type)
+            }
+            KnownCondition::MentionsRoomName => match &self.room_name {
+                // As with display names, we ignore empty room names as otherwise
+                // they would always match.
+                Some(room_name) if !room_name.is_empty() => {
+                    ThreadLocalGlobMatcherCache::is_match_folded(
+                        &escape_glob(room_name),
+                        GlobMatchType::Word,
+                        self.folded_body.as_deref().unwrap_or_default(),
+                    )
+                    .map_err(ConditionMatchError::bad_glob(room_name))?
+                }
+                _ => false,
+            },
+            KnownCondition::SenderVerified => self.sender_verified,
+            KnownCondition::IsThreadStart => self.is_thread_start,
+            KnownCondition::ThreadDepth { is } => match (is, self.thread_depth) {
+                (Some(is), Some(depth)) => match_inequality(is, depth)?,
+                _ => false,
+            },
+            KnownCondition::IsReply => self.is_reply(),
+            KnownCondition::IsEdit => matches!(
+                self.flattened_keys.get(REL_TYPE_KEY),
+                Some(JsonValue::Value(SimpleJsonValue::Str(rel_type))) if rel_type == "m.replace"
+            ),
+            KnownCondition::RelatesToEvent { event_id } => {
+                [RELATES_TO_EVENT_ID_KEY, IN_REPLY_TO_EVENT_ID_KEY]
+                    .iter()
+                    .any(|key| {
+                        matches!(
+                            self.flattened_keys.get(*key),
+                            Some(JsonValue::Value(SimpleJsonValue::Str(related)))
+                                if related == event_id
+                        )
+                    })
+            }
+            KnownCondition::RoomHasTag { tag } => user_id
+                .and_then(|user_id| self.room_tags.get(user_id))
+                .map_or(false, |tags| tags.contains(&**tag)),
+            KnownCondition::SenderIsSelf => match (self.flattened_keys.get("sender"), user_id) {
+                (Some(JsonValue::Value(SimpleJsonValue::Str(sender))), Some(recipient)) => {
+                    sender == recipient
+                }
+                _ => false,
+            },
+            KnownCondition::IsEncrypted => matches!(
+                self.flattened_keys.get("type"),
+                Some(JsonValue::Value(SimpleJsonValue::Str(event_type))) if event_type == "m.room.encrypted"
+            ),
+            KnownCondition::EventSelector { path, op, value } => SelectorPath::parse(path)?
+                .select(&self.flattened_keys)
+                .into_iter()
+                .any(|selected| op.compare_values(selected, value)),
+            KnownCondition::EventValueCompare { key, op, value } => {
+                match get_flattened_value(&self.flattened_keys, key) {
+                    Some(SimpleJsonValue::Int(event_value)) => op.compare(*event_value, *value),
+                    _ => false,
+                }
+            }
+            KnownCondition::RoomIdEquals { room_id } => matches!(
+                self.flattened_keys.get("room_id"),
+                Some(JsonValue::Value(SimpleJsonValue::Str(event_room_id))) if event_room_id == room_id
+            ),
+            KnownCondition::RoomIdMatch { pattern } => match self.flattened_keys.get("room_id") {
+                Some(JsonValue::Value(SimpleJsonValue::Str(room_id))) => {
+                    PrecompiledGlobs::is_match(globs, pattern, GlobMatchType::Whole, room_id)
+                        .map_err(ConditionMatchError::bad_glob(pattern))?
+                }
+                _ => false,
+            },
+            KnownCondition::IsDirectMessage => {
+                user_id.map_or(false, |user_id| self.dm_user_ids.contains(user_id))
+            }
+            KnownCondition::UnreadCount { kind, is } => {
+                let counts = match kind {
+                    UnreadCountKind::Notifications => &self.unread_notification_counts,
+                    UnreadCountKind::Highlights => &self.unread_highlight_counts,
+                };
+                let count = user_id.and_then(|user_id| counts.get(user_id).copied());
+                match (is, count) {
+                    (Some(is), Some(count)) => match_inequality(is, count)?,
+                    _ => false,
+                }
+            }
+            KnownCondition::SenderIsServerNotices => {
+                match (
+                    &self.server_notices_user_id,
+                    self.flattened_keys.get("sender"),
+                ) {
+                    (
+                        Some(server_notices_user_id),
+                        Some(JsonValue::Value(SimpleJsonValue::Str(sender))),
+                    ) => sender == server_notices_user_id,
+                    _ => false,
+                }
+            }
+            KnownCondition::SenderDisplayNameMatch { pattern } => match &self.sender_display_name {
+                Some(sender_display_name) => PrecompiledGlobs::is_match(
+                    globs,
+                    pattern,
+                    GlobMatchType::Whole,
+                    sender_display_name,
+                )
+                .map_err(ConditionMatchError::bad_glob(pattern))?,
+                None => false,
+            },
+            KnownCondition::SenderServerMatch { pattern } => {
+                match self.flattened_keys.get("sender") {
+                    Some(JsonValue::Value(SimpleJsonValue::Str(sender))) => {
+                        match get_server_name_from_id(sender) {
+                            Ok(server_name) => PrecompiledGlobs::is_match(
+                                globs,
+                                pattern,
+                                GlobMatchType::Whole,
+                                server_name,
+                            )
+                            .map_err(ConditionMatchError::bad_glob(pattern))?,
+                            Err(_) => false,
+                        }
+                    }
+                    _ => false,
+                }
+            }
+            KnownCondition::WithinTimeWindow {
+                start_minute,
+                end_minute,
+                tz_offset_minutes,
+            } => match self.now_ts {
+                Some(now_ts) => within_time_window(
+                    local_minute_of_day(now_ts, *tz_offset_minutes),
+                    *start_minute,
+                    *end_minute,
+                ),
+                None => false,
+            },
+            KnownCondition::EventAgeLessThan { ms } => match (self.event_origin_ts, self.now_ts) {
+                // Events from the future (e.g. due to clock skew) have an age of
+                // zero.
+                (Some(origin_ts), Some(now_ts)) => now_ts.saturating_sub(origin_ts) < *ms,
+                _ => false,
+            },
+            KnownCondition::UserMembership { membership } => user_id
+                .and_then(|user_id| self.user_memberships.get(user_id))
+                .map_or(false, |user_membership| user_membership == membership),
+            KnownCondition::StateKeyMatch {
+                pattern,
+                pattern_type,
+            } => {
+                let (pattern, globs) = match (pattern, pattern_type) {
+                    (_, Some(pattern_type)) => {
+                        match self.resolve_pattern_type(pattern_type, user_id)? {
+                            Some(pattern) => (pattern, None),
+                            None => return Ok(false),
+                        }
+                    }
+                    (Some(pattern), None) => (&**pattern, globs),
+                    (None, None) => return Ok(false),
+                };
+
+                self.match_event_match(
+                    &self.flattened_keys,
+                    "state_key",
+                    pattern,
+                    GlobMatchType::Whole,
+                    globs,
+                )?
+            }
+            KnownCondition::Not { condition } => {
+                self.match_negated_condition(condition, user_id, display_name, globs)
+            }
+            KnownCondition::AnyOf { conditions } => {
+                self.match_any_of(conditions, user_id, display_name, globs)
+            }
+            KnownCondition::MembershipTransition { from, to } => {
+                self.match_membership_transition(from, to)
+            }
+            KnownCondition::IsDuplicateMessage => {
+                !self.body.trim().is_empty()
+                    && self
+                        .recent_message_hashes
+                        .contains(&message_body_hash(&self.body))
+            }
+            KnownCondition::BodyContainsCode => self.match_body_contains_code()?,
+            KnownCondition::UserPowerLevel { user, is } => {
+                match (is, self.user_power_level(user, user_id)) {
+                    (Some(is), Some(power_level)) => match_power_level_inequality(is, power_level)?,
+                    _ => false,
+                }
+            }
+            KnownCondition::HasFormattedBody => matches!(
+                self.flattened_keys.get("content.formatted_body"),
+                Some(JsonValue::Value(SimpleJsonValue::Str(formatted_body))) if !formatted_body.is_empty()
+            ),
+            KnownCondition::RoomNotificationsToday { is } => {
+                match (is, self.room_notifications_today) {
+                    (Some(is), Some(count)) => match_inequality(is, count)?,
+                    _ => false,
+                }
+            }
+            KnownCondition::RelationCount { rel_type, is } => match is {
+                Some(is) if self.relation_count_enabled => {
+                    let count = self.relation_counts.get(&**rel_type).copied().unwrap_or(0);
+                    match_inequality(is, count)?
+                }
+                _ => false,
+            },
+            KnownCondition::HasAggregation { rel_type, key } => {
+                self.aggregation_match_enabled
+                    && self
+                        .aggregations
+                        .get(&**rel_type)
+                        .map_or(false, |aggregations| match key {
+                            Some(key) => aggregations.get(&**key).map_or(false, |count| *count > 0),
+                            None => aggregations.values().any(|count| *count > 0),
+                        })
+            }
+            KnownCondition::SenderEventRate { is } => match (is, self.sender_event_rate) {
+                (Some(is), Some(rate)) => match_inequality(is, rate)?,
+                _ => false,
+            },
+            KnownCondition::BodyLength { is } => match is {
+                Some(is)
+                    if matches!(
+                        self.flattened_keys.get("content.body"),
+                        Some(JsonValue::Value(SimpleJsonValue::Str(_)))
+                    ) =>
+                {
+                    match_inequality(is, self.body.chars().count() as u64)?
+                }
+                _ => false,
+            },
+            KnownCondition::AtHandleCount { is } => {
+                if let Some(is) = is {
+                    charge_match_budget(&self.body).map_err(ConditionMatchError::MatchBudget)?;
+                    let count = AT_HANDLE.find_iter(&self.body).count() as u64;
+                    match_inequality(is, count)?
+                } else {
+                    false
+                }
+            }
+            KnownCondition::MentionCount { is } => {
+                if let Some(is) = is {
+                    match_inequality(is, self.mention_count())?
+                } else {
+                    false
+                }
+            }
+        };
+
+        Ok(result)
+    }
+
+    /// Resolves a `pattern_type` to the pattern it stands for: the user's ID
+    /// or localpart, or the event sender's ID or localpart. Returns None if
+    /// there is no such ID (or its localpart is needed but it is malformed), in
+    /// which case the condition can't match.
+    fn resolve_pattern_type<'a>(
+        &'a self,
+        pattern_type: &EventMatchPatternType,
+        user_id: Option<&'a str>,
+    ) -> Result<Option<&'a str>, ConditionMatchError> {
+        let id = match pattern_type {
+            EventMatchPatternType::UserId | EventMatchPatternType::UserLocalpart => user_id,
+            EventMatchPatternType::Sender | EventMatchPatternType::SenderLocalpart => {
+                match self.flattened_keys.get("sender") {
+                    Some(JsonValue::Value(SimpleJsonValue::Str(sender))) => Some(&**sender),
+                    _ => None,
+                }
+            }
+        };
+
+        let id = if let Some(id) = id {
+            id
+        } else {
+            return Ok(None);
+        };
+
+        match pattern_type {
+            EventMatchPatternType::UserId | EventMatchPatternType::Sender => Ok(Some(id)),
+            EventMatchPatternType::UserLocalpart | EventMatchPatternType::SenderLocalpart => {
+                // Malformed IDs (e.g. of senders on other servers) can't be
+                // matched, but aren't an error in the rule.
+                Ok(get_localpart_from_id(id).ok())
+            }
+        }
+    }
+
+    /// Evaluates a `event_match` condition, matching the pattern in the given
+    /// way.
+    fn match_event_match(
+        &self,
+        flattened_event: &BTreeMap<FlattenedKey, JsonValue>,
+        key: &str,
+        pattern: &str,
+        match_type: GlobMatchType,
+        globs: Option<&PrecompiledGlobs>,
+    ) -> Result<bool, ConditionMatchError> {
+        event_match(flattened_event, key, pattern, match_type, globs)
+    }
+
+    /// Evaluates a `event_property_is` condition.
+    fn match_event_property_is(
+        &self,
+        event_property_is: &EventPropertyIsCondition,
+    ) -> Result<bool, ConditionMatchError> {
+        let value = &event_property_is.value;
+
+        let haystack = if let Some(JsonValue::Value(haystack)) =
+            self.flattened_keys.get(&*event_property_is.key)
+        {
+            haystack
+        } else {
+            return Ok(false);
+        };
+
+        Ok(haystack == &**value)
+    }
+
+    /// Evaluates a `related_event_match` condition. (MSC3664)
+    ///
+    /// Matches if any of the related events with the given relation type
+    /// match.
+    fn match_related_event_match(
+        &self,
+        rel_type: &str,
+        include_fallbacks: Option<bool>,
+        key: Option<Cow<str>>,
+        pattern: Option<Cow<str>>,
+        globs: Option<&PrecompiledGlobs>,
+    ) -> Result<bool, ConditionMatchError> {
+        // First check if related event matching is enabled...
+        if !self.related_event_match_enabled {
+            return Ok(false);
+        }
+
+        let include_fallbacks = include_fallbacks.unwrap_or(false);
+        let related_event_matches = |event: &BTreeMap<FlattenedKey, JsonValue>| {
+            match (&key, &pattern) {
+                // if we have no key, accept the event as matching.
+                (None, _) => Ok(true),
+                // There was a key, so we *must* have a pattern to go with it.
+                (Some(_), None) => Ok(false),
+                // If there is a key & pattern, check if they're in the flattened event.
+                (Some(key), Some(pattern)) => {
+                    self.match_event_match(event, key, pattern, event_match_type(key), globs)
+                }
+            }
+        };
+
+        // Events from a source are matched as they're yielded. Only whether
+        // any of them match matters, so unlike `related_events` they don't
+        // need to be ordered or deduplicated.
+        if let Some(source) = &self.related_events_source {
+            for event in source.related_events(rel_type) {
+                if !include_fallbacks && event.contains_key(RELATED_EVENT_FALLBACK_KEY) {
+                    continue;
+                }
+                if related_event_matches(&event)? {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+
+        for event in self.related_events(rel_type, include_fallbacks) {
+            if related_event_matches(event)? {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// The related events of the given relation type to match against, in a
+    /// deterministic order: the real relations first (in the order given), then
+    /// the fallback ones if `include_fallbacks` is set (see
+    /// `RELATED_EVENT_FALLBACK_KEY`).
+    ///
+    /// Events are deduplicated by their ID, so an event which is both really
+    /// related and related via a fallback is only included once, as a real
+    /// relation. Events without an ID are always included.
+    fn related_events(
+        &self,
+        rel_type: &str,
+        include_fallbacks: bool,
+    ) -> Vec<&BTreeMap<FlattenedKey, JsonValue>> {
+        let events = match self.related_events_flattened.get(rel_type) {
+            Some(events) => events,
+            None => return Vec::new(),
+        };
+
+        let (real, fallbacks): (Vec<_>, Vec<_>) = events
+            .iter()
+            .partition(|event| !event.contains_key(RELATED_EVENT_FALLBACK_KEY));
+        let fallbacks = if include_fallbacks {
+            fallbacks
+        } else {
+            Vec::new()
+        };
+
+        let mut seen_event_ids = BTreeSet::new();
+        real.into_iter()
+            .chain(fallbacks)
+            .filter(|event| match event.get("event_id") {
+                Some(JsonValue::Value(SimpleJsonValue::Str(event_id))) => {
+                    seen_event_ids.insert(&**event_id)
+                }
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Evaluates a `event_property_contains` condition.
+    fn match_event_property_contains(
+        &self,
+        key: Cow<str>,
+        value: Cow<SimpleJsonValue>,
+    ) -> Result<bool, ConditionMatchError> {
+        let haystack = if let Some(JsonValue::Array(haystack)) = self.flattened_keys.get(&*key) {
+            haystack
+        } else {
+            return Ok(false);
+        };
+
+        Ok(haystack.contains(&value))
+    }
+
+    /// Whether the sender has the power level required to send the given kind
+    /// of notification, e.g. "room" for `@room` notifications.
+    ///
+    /// If the sender's power level isn't known then `unknown_sender_power_level`
+    /// decides.
+    fn sender_has_notification_permission(&self, key: &str) -> bool {
+        let sender_power_level = match (self.sender_power_level, self.unknown_sender_power_level) {
+            (Some(sender_power_level), _) => sender_power_level,
+            (None, UnknownSenderPowerLevel::Level(level)) => level,
+            (None, UnknownSenderPowerLevel::Deny) => return false,
+            (None, UnknownSenderPowerLevel::Skip) => return true,
+        };
+
+        sender_power_level >= self.required_notification_power_level(key)
+    }
+
+    /// Whether the (case-folded) body contains a lookalike of the display name,
+    /// i.e. whether it does once confusable characters in both are folded, see
+    /// `confusable_display_names`.
+    fn contains_confusable_display_name(
+        &self,
+        display_name: &str,
+        folded_body: &str,
+    ) -> Result<bool, ConditionMatchError> {
+        let display_name = fold_case(display_name);
+        let display_name = fold_confusables(&display_name);
+        let display_name = trim_display_name(&display_name);
+        if display_name.is_empty() {
+            return Ok(false);
+        }
+
+        charge_match_budget(folded_body).map_err(ConditionMatchError::MatchBudget)?;
+        ThreadLocalGlobMatcherCache::is_match_folded(
+            &escape_glob(display_name),
+            GlobMatchType::Word,
+            &fold_confusables(folded_body),
+        )
+        .map_err(ConditionMatchError::bad_glob(display_name))
+    }
+
+    /// The power level required to send the given kind of notification.
+    fn required_notification_power_level(&self, key: &str) -> i64 {
+        self.notification_power_levels
+            .get(key)
+            .copied()
+            .unwrap_or(self.default_notification_power_level)
+    }
+
+    /// Evaluates a `is_user_mention` condition, i.e. whether the user is one
+    /// of the users intentionally mentioned by the event. (MSC3952)
+    fn match_is_user_mention(&self, user_id: Option<&str>) -> bool {
+        let user_id = match user_id {
+            Some(user_id) => user_id,
+            None => return false,
+        };
+
+        match self.flattened_keys.get(MENTIONS_USER_IDS_KEY) {
+            Some(JsonValue::Array(user_ids)) => user_ids
+                .iter()
+                .any(|mentioned| matches!(mentioned, SimpleJsonValue::Str(mentioned) if mentioned == user_id)),
+            _ => false,
+        }
+    }
+
+    /// The number of distinct users mentioned by the event, i.e. in its
+    /// `m.mentions`. (MSC3952) Entries which aren't strings don't count, and
+    /// events without mentions mention no one.
+    fn mention_count(&self) -> u64 {
+        match self.flattened_keys.get(MENTIONS_USER_IDS_KEY) {
+            Some(JsonValue::Array(user_ids)) => user_ids
+                .iter()
+                .filter_map(|user_id| match user_id {
+                    SimpleJsonValue::Str(user_id) => Some(&**user_id),
+                    _ => None,
+                })
+                .collect::<BTreeSet<_>>()
+                .len() as u64,
+            _ => 0,
+        }
+    }
+
+    /// Evaluates a `is_voice_message` condition. (MSC3245)
+    ///
+    /// Voice messages are `m.audio` messages with an additional marker object.
+    /// The marker is considered present if the key itself (which the flattener
+    /// emits for it), or any key nested beneath it, exists.
+    fn match_is_voice_message(&self) -> bool {
+        match self.flattened_keys.get(MSGTYPE_KEY) {
+            Some(JsonValue::Value(SimpleJsonValue::Str(msgtype))) if msgtype == "m.audio" => {}
+            _ => return false,
+        }
+
+        self.has_key(VOICE_MESSAGE_MARKER_KEY)
+    }
+
+    /// Whether the flattened key, or any key nested beneath it, is present in
+    /// the event, whatever its value. Objects aren't flattened themselves, so
+    /// this is how to check for one.
+    fn has_key(&self, key: &str) -> bool {
+        if self.flattened_keys.contains_key(key) {
+            return true;
+        }
+
+        let nested_prefix = format!("{key}.");
+        self.flattened_keys
+            .range::<str, _>((Bound::Included(nested_prefix.as_str()), Bound::Unbounded))
+            .next()
+            .map_or(false, |(key, _)| key.starts_with(&nested_prefix))
+    }
+
+    /// Evaluates a `quotes_me` condition, i.e. whether any quoted line of the
+    /// body (starting with `>`) is part of one of the user's recent messages.
+    fn match_quotes_me(&self, user_id: Option<&str>) -> bool {
+        let recent_messages = match user_id.and_then(|id| self.recent_user_messages.get(id)) {
+            Some(messages) if !messages.is_empty() => messages,
+            _ => return false,
+        };
+
+        self.body
+            .lines()
+            .filter_map(|line| line.strip_prefix('>'))
+            .map(|quoted| {
+                let quoted = quoted.trim();
+                // Reply fallbacks prefix the first quoted line with the sender,
+                // e.g. `> <@alice:example.org> hello`.
+                match quoted.strip_prefix('<').and_then(|q| q.split_once("> ")) {
+                    Some((_, text)) => text.trim(),
+                    None => quoted,
+                }
+            })
+            .filter(|quoted| !quoted.is_empty())
+            .any(|quoted| {
+                recent_messages
+                    .iter()
+                    .any(|message| message.contains(quoted))
+            })
+    }
+
+    /// Evaluates a `membership_transition` condition. It never matches if the
+    /// previous membership isn't known, so callers should give "leave" for
+    /// users who were never in the room.
+    fn match_membership_transition(&self, from: &str, to: &str) -> bool {
+        let membership = |flattened: &BTreeMap<FlattenedKey, JsonValue>, key: &str| match flattened
+            .get(key)
+        {
+            Some(JsonValue::Value(SimpleJsonValue::Str(membership))) => Some(membership.clone()),
+            _ => None,
+        };
+
+        let is_member_event = matches!(
+            self.flattened_keys.get("type"),
+            Some(JsonValue::Value(SimpleJsonValue::Str(event_type))) if event_type == "m.room.member"
+        );
+        if !is_member_event {
+            return false;
+        }
+
+        let current = membership(&self.flattened_keys, "content.membership");
+        let previous = membership(&self.prev_content_flattened, "membership");
+
+        current.as_deref() == Some(to) && previous.as_deref() == Some(from)
+    }
+
+    /// The power level of the user referred to by a `user_power_level`
+    /// condition, if known, where `recipient` is the user whose push rules are
+    /// being evaluated. The sender's power level may also be known from
+    /// `sender_power_level`.
+    fn user_power_level(&self, user: &str, recipient: Option<&str>) -> Option<i64> {
+        let user_id = match user {
+            "sender" => match self.flattened_keys.get("sender") {
+                Some(JsonValue::Value(SimpleJsonValue::Str(sender))) => &**sender,
+                _ => return self.sender_power_level,
+            },
+            "recipient" => recipient?,
+            user_id => user_id,
+        };
+
+        match self.user_power_levels.get(user_id) {
+            Some(power_level) => Some(*power_level),
+            None if user == "sender" => self.sender_power_level,
+            None => None,
+        }
+    }
+
+    /// Evaluates a `body_contains_code` condition, checking both the plain body
+    /// and the HTML `formatted_body` (if any).
+    fn match_body_contains_code(&self) -> Result<bool, ConditionMatchError> {
+        charge_match_budget(&self.body).map_err(ConditionMatchError::MatchBudget)?;
+        if BODY_CODE.is_match(&self.body) {
+            return Ok(true);
+        }
+
+        match self.flattened_keys.get("content.formatted_body") {
+            Some(JsonValue::Value(SimpleJsonValue::Str(formatted_body))) => {
+                charge_match_budget(formatted_body).map_err(ConditionMatchError::MatchBudget)?;
+                Ok(FORMATTED_BODY_CODE.is_match(formatted_body))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Evaluates a `is_unstable_event_type` condition.
+    fn match_is_unstable_event_type(&self, prefixes: Option<&[Cow<str>]>) -> bool {
+        let event_type = match self.flattened_keys.get("type") {
+            Some(JsonValue::Value(SimpleJsonValue::Str(event_type))) => event_type,
+            _ => return false,
+        };
+
+        match prefixes {
+            Some(prefixes) => prefixes
+                .iter()
+                .any(|prefix| event_type.starts_with(&**prefix)),
+            None => DEFAULT_UNSTABLE_EVENT_TYPE_PREFIXES
+                .iter()
+                .any(|prefix| event_type.starts_with(prefix)),
+        }
+    }
+
+    /// Match the member count against an 'is' condition
+    /// The `is` condition can be things like '>2', '==3' or even just '4'.
+    fn match_member_count(&self, is: &str) -> Result<bool, ConditionMatchError> {
+        member_count_matches(is, self.room_member_count)
+    }
+}
+
+/// The `kind` of the condition, e.g. for describing it.
+fn condition_kind(condition: &Condition) -> &str {
+    match condition {
+        Condition::Known(known_condition) => known_condition.kind(),
+        Condition::Unknown(value) => value
+            .get("kind")
+            .and_then(Value::as_str)
+            .unwrap_or("unknown"),
+    }
+}
+
+/// Whether the unknown condition is of a kind we recognize, i.e. it failed to
+/// deserialize because it's malformed rather than because it's unrecognized.
+fn is_malformed_known_condition(unknown: &Value) -> bool {
+    unknown
+        .get("kind")
+        .and_then(Value::as_str)
+        .map_or(false, KnownCondition::is_known_kind)
+}
+
+/// Formats the value as JSON, e.g. for describing it.
+fn json_display(value: &SimpleJsonValue) -> String {
+    serde_json::to_string(value).unwrap_or_else(|_| format!("{value:?}"))
+}
+
+/// Returns the canonical form of an 'is' condition, e.g. both '4' and '==04'
+/// become '==4', and both '2..10' and '>=2 <10' become '>=2 <10'. Returns None
+/// if the condition is invalid.
+pub(crate) fn normalize_inequality(is: &str) -> Option<String> {
+    let bounds = parse_inequality(is).ok()?;
+
+    Some(
+        bounds
+            .iter()
+            .map(|(ineq, rhs)| format!("{ineq}{rhs}"))
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+/// Parses an 'is' condition into the bounds which a count must satisfy.
+///
+/// This is either one or two (whitespace separated) inequalities, e.g. '>2',
+/// '==3', '4' or '>=2 <10', or a range: '2..10' includes 2 but excludes 10,
+/// while '2..=10' includes both. Ranges which can never match (e.g. '10..2')
+/// are rejected. Numbers too large for a u64 are treated as u64::MAX (see
+/// `parse_bound`).
+pub(crate) fn parse_inequality(is: &str) -> Result<Vec<(&'static str, u64)>, ConditionMatchError> {
+    let is = is.trim();
+
+    let bounds = if let Some((start, end)) = is.split_once("..") {
+        let (end_ineq, end) = match end.strip_prefix('=') {
+            Some(end) => ("<=", end),
+            None => ("<", end),
+        };
+        let start = parse_bound(start)
+            .ok_or_else(|| ConditionMatchError::bad_clause(is, "bad range start"))?;
+        let end =
+            parse_bound(end).ok_or_else(|| ConditionMatchError::bad_clause(is, "bad range end"))?;
+
+        vec![(">=", start), (end_ineq, end)]
+    } else {
+        let mut bounds = Vec::new();
+        let mut last_end = 0;
+        for captures in INEQUALITY_EXPR.captures_iter(is) {
+            let clause = captures
+                .get(0)
+                .ok_or_else(|| ConditionMatchError::bad_clause(is, "missing clause"))?;
+            if !is[last_end..clause.start()].trim().is_empty() {
+                return Err(ConditionMatchError::bad_clause(is, "unexpected text"));
+            }
+            last_end = clause.end();
+
+            let ineq = match captures.get(1).map_or("", |m| m.as_str()) {
+                "" | "==" => "==",
+                "<" => "<",
+                ">" => ">",
+                ">=" => ">=",
+                "<=" => "<=",
+                ineq => {
+                    return Err(ConditionMatchError::bad_clause(
+                        is,
+                        format!("bad inequality {ineq}"),
+                    ))
+                }
+            };
+            let rhs = captures
+                .get(2)
+                .ok_or_else(|| ConditionMatchError::bad_clause(is, "missing number"))?
+                .as_str();
+            let rhs = parse_bound(rhs)
+                .ok_or_else(|| ConditionMatchError::bad_clause(is, "bad number"))?;
+            bounds.push((ineq, rhs));
+        }
+
+        if bounds.is_empty() || bounds.len() > 2 || !is[last_end..].trim().is_empty() {
+            return Err(ConditionMatchError::bad_clause(
+                is,
+                "expected one or two inequalities",
+            ));
+        }
+
+        bounds
+    };
+
+    // A single bound which can never match (e.g. '<0') is allowed, but
+    // combining bounds that way is likely a mistake.
+    if bounds.len() > 1 {
+        let (mut min, mut max) = (0, u64::MAX);
+        for (ineq, rhs) in &bounds {
+            let (lower, upper) = match *ineq {
+                "==" => (Some(*rhs), Some(*rhs)),
+                "<" => (Some(0), rhs.checked_sub(1)),
+                ">" => (rhs.checked_add(1), Some(u64::MAX)),
+                ">=" => (Some(*rhs), Some(u64::MAX)),
+                _ => (Some(0), Some(*rhs)),
+            };
+            match (lower, upper) {
+                (Some(lower), Some(upper)) => {
+                    min = min.max(lower);
+                    max = max.min(upper);
+                }
+                _ => return Err(ConditionMatchError::bad_clause(is, "empty range")),
+            }
+        }
+        if min > max {
+            return Err(ConditionMatchError::bad_clause(is, "inverted range"));
+        }
+    }
+
+    Ok(bounds)
+}
+
+/// Describes the unknown conditions of an `UnknownConditionsError`.
+fn describe_unknown_conditions(conditions: &[(String, Value)]) -> String {
+    conditions
+        .iter()
+        .map(|(rule_id, condition)| format!("{condition} in {rule_id}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Whether the given condition depends on the related events of the event.
+fn depends_on_related_events(condition: &Condition) -> bool {
+    match condition {
+        Condition::Known(KnownCondition::RelatedEventMatch(_))
+        | Condition::Known(KnownCondition::RelatedEventMatchType(_)) => true,
+        Condition::Known(KnownCondition::Not { condition }) => depends_on_related_events(condition),
+        Condition::Known(KnownCondition::AnyOf { conditions }) => {
+            conditions.iter().any(depends_on_related_events)
+        }
+        _ => false,
+    }
+}
+
+/// The actions of a matching rule which should be stored, i.e. without the
+/// `dont_notify` and `coalesce` actions (since they result in no action by the
+/// pushers).
+fn stored_actions(push_rule: &PushRule) -> Vec<Action> {
+    filter_stored_actions(&push_rule.actions)
+}
+
+/// Filters out the `dont_notify` and `coalesce` actions, see `stored_actions`.
+fn filter_stored_actions(actions: &[Action]) -> Vec<Action> {
+    actions
+        .iter()
+        .filter(|a| is_stored_action(a))
+        .cloned()
+        .collect()
+}
+
+/// Whether the action should be stored, i.e. isn't `dont_notify` or
+/// `coalesce`, see `stored_actions`.
+fn is_stored_action(action: &Action) -> bool {
+    !matches!(action, Action::DontNotify | Action::Coalesce)
+}
+
+/// Matches a message type against a pattern without going via a glob matcher,
+/// if the pattern is literal (e.g. `m.notice`) and both are ASCII, so that the
+/// result is the same as a case-insensitive glob match. Returns None if the
+/// pattern has to be matched as a glob instead.
+fn match_literal_msgtype(pattern: &str, msgtype: &str) -> Option<bool> {
+    let is_literal = !pattern.contains(['*', '?', '[', '\\']);
+    (is_literal && pattern.is_ascii() && msgtype.is_ascii())
+        .then(|| pattern.eq_ignore_ascii_case(msgtype))
+}
+
+/// Parses a number of an 'is' condition, saturating to u64::MAX if too large.
+fn parse_bound(bound: &str) -> Option<u64> {
+    let bound = bound.trim();
+    match bound.parse() {
+        Ok(bound) => Some(bound),
+        Err(_) if !bound.is_empty() && bound.bytes().all(|b| b.is_ascii_digit()) => Some(u64::MAX),
+        Err(_) => None,
+    }
+}
+
+/// Checks if the power level satisfies an 'is' condition, as per
+/// `match_inequality`. Power levels can be negative, in which case they're
+/// below every bound.
+fn match_power_level_inequality(is: &str, power_level: i64) -> Result<bool, ConditionMatchError> {
+    match u64::try_from(power_level) {
+        Ok(power_level) => match_inequality(is, power_level),
+        Err(_) => Ok(parse_inequality(is)?
+            .into_iter()
+            .all(|(ineq, _)| ineq.starts_with('<'))),
+    }
+}
+
+/// Match a count against an 'is' condition, as used by `room_member_count`.
+/// The `is` condition can be things like '>2', '==3' or even just '4', or a
+/// range like '2..10' or '>=2 <10', see `parse_inequality`.
+fn match_inequality(is: &str, value: u64) -> Result<bool, ConditionMatchError> {
+    let matches = parse_inequality(is)?
+        .into_iter()
+        .all(|(ineq, rhs)| match ineq {
+            "==" => value == rhs,
+            "<" => value < rhs,
+            ">" => value > rhs,
+            ">=" => value >= rhs,
+            _ => value <= rhs,
+        });
+
+    Ok(matches)
+}
+
+// The predicates below take everything they depend on as arguments, rather
+// than depending on a `PushRuleEvaluator`, so that other parts of the server
+// (e.g. search and filtering) can match events in the same way as push rules.
+
+/// Checks if the glob matches the haystack case-insensitively, in the given
+/// way (see `GlobMatchType`). Compiled globs are cached via
+/// `ThreadLocalGlobMatcherCache`.
+pub fn glob_matches(
+    pattern: &str,
+    match_type: GlobMatchType,
+    haystack: &str,
+) -> Result<bool, ConditionMatchError> {
+    ThreadLocalGlobMatcherCache::is_match(pattern, match_type, haystack)
+        .map_err(ConditionMatchError::bad_glob(pattern))
+}
+
+/// Checks if the value of the key in the flattened event matches the pattern,
+/// as per an `event_match` condition. The key may have wildcard segments (see
+/// `get_wildcard_flattened_values`), in which case any of the values can match,
+/// and `event_match_type` gives the usual way of matching the key.
+///
+/// The pattern is taken from `globs` if it has been precompiled, and from
+/// `ThreadLocalGlobMatcherCache` otherwise. Values which aren't strings never
+/// match.
+pub fn event_match(
+    flattened_event: &BTreeMap<FlattenedKey, JsonValue>,
+    key: &str,
+    pattern: &str,
+    match_type: GlobMatchType,
+    globs: Option<&PrecompiledGlobs>,
+) -> Result<bool, ConditionMatchError> {
+    if is_wildcard_key(key) {
+        for value in get_wildcard_flattened_values(flattened_event, key) {
+            if let SimpleJsonValue::Str(haystack) = value {
+                if PrecompiledGlobs::is_match(globs, pattern, match_type, haystack)
+                    .map_err(ConditionMatchError::bad_glob(pattern))?
+                {
+                    return Ok(true);
+                }
+            }
+        }
+        return Ok(false);
+    }
+
+    let haystack =
+        if let Some(SimpleJsonValue::Str(haystack)) = get_flattened_value(flattened_event, key) {
+            haystack
+        } else {
+            return Ok(false);
+        };
+
+    if key == MSGTYPE_KEY && match_type == GlobMatchType::Whole {
+        if let Some(matches) = match_literal_msgtype(pattern, haystack) {
+            return Ok(matches);
+        }
+    }
+
+    PrecompiledGlobs::is_match(globs, pattern, match_type, haystack)
+        .map_err(ConditionMatchError::bad_glob(pattern))
+}
+
+/// Checks if the member count satisfies the 'is' clause of a
+/// `room_member_count` condition, e.g. `>=10` or `2` (i.e. `==2`).
+pub fn member_count_matches(is: &str, member_count: u64) -> Result<bool, ConditionMatchError> {
+    match_inequality(is, member_count)
+}
+
+/// How the pattern of an `event_match` condition on the given key is matched:
+/// for the content.body we match against "words", but for everything else we
+/// match against the entire value.
+/// The results of the conditions matched so far during a single run of the
+/// evaluator, so that identical conditions in different rules are only matched
+/// once.
+///
+/// This must not outlive the run, as the results are only valid for the event
+/// being evaluated.
+#[derive(Default)]
+struct ConditionResults<'a> {
+    results: HashMap<ConditionKey<'a>, bool>,
+    /// The number of conditions actually evaluated, i.e. not remembered.
+    evaluated: usize,
+    /// Which of the rules' combined word globs matched the body, once any
+    /// condition has needed them, see `match_combined_body_word`.
+    body_words: Option<CombinedWordMatches<'a>>,
+}
+
+impl<'a> ConditionResults<'a> {
+    /// Returns the result of matching the condition, calling `matches` only if
+    /// an identical condition hasn't been matched already. Errors aren't
+    /// remembered.
+    fn get_or_insert_with(
+        &mut self,
+        condition: &'a Condition,
+        matches: impl FnOnce(&mut Option<CombinedWordMatches<'a>>) -> Result<bool, ConditionMatchError>,
+    ) -> Result<bool, ConditionMatchError> {
+        let key = ConditionKey(condition);
+        if let Some(result) = self.results.get(&key) {
+            return Ok(*result);
+        }
+
+        self.evaluated += 1;
+        let result = matches(&mut self.body_words)?;
+        self.results.insert(key, result);
+        Ok(result)
+    }
+}
+
+/// Compares conditions structurally, which is equivalent to comparing their
+/// serialized forms but doesn't need them to be serialized for every event.
+#[derive(PartialEq, Eq)]
+struct ConditionKey<'a>(&'a Condition);
+
+impl Hash for ConditionKey<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Only hash enough to tell the common conditions apart cheaply, the
+        // rest is left to `Eq`.
+        match self.0 {
+            Condition::Known(KnownCondition::EventMatch(event_match)) => {
+                event_match.key.hash(state);
+                event_match.pattern.hash(state);
+            }
+            Condition::Known(known_condition) => known_condition.kind().hash(state),
+            Condition::Unknown(_) => {}
+        }
+    }
+}
+
+/// Trims whitespace and zero-width characters from both ends of the display
+/// name, so that names made up of only those are treated as empty.
+fn trim_display_name(display_name: &str) -> &str {
+    display_name.trim_matches(|c: char| {
+        c.is_whitespace()
+            || matches!(
+                c,
+                // Zero width space, non-joiner and joiner, word joiner and
+                // zero width no-break space.
+                '\u{200B}' | '\u{200C}' | '\u{200D}' | '\u{2060}' | '\u{FEFF}'
+            )
+    })
+}
+
+/// Whether the body contains a run of words within `max_distance` edits of the
+/// display name (ignoring case and punctuation between words). Display names
+/// shorter than `MIN_FUZZY_DISPLAY_NAME_LENGTH` never match.
+fn fuzzy_contains_display_name(display_name: &str, body: &str, max_distance: usize) -> bool {
+    let words = |text: &str| -> Vec<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    };
+
+    let name_words = words(display_name);
+    let name = name_words.join(" ");
+    if name_words.is_empty() || name.chars().count() < MIN_FUZZY_DISPLAY_NAME_LENGTH {
+        return false;
+    }
+    let name_length = name.chars().count();
+
+    words(body).windows(name_words.len()).any(|window| {
+        let candidate = window.join(" ");
+        // The edit distance is at least the difference in length, so skip the
+        // (more expensive) comparison if that is already too large.
+        candidate.chars().count().abs_diff(name_length) <= max_distance
+            && edit_distance(&candidate, &name) <= max_distance
+    })
+}
+
+/// A compact description of the condition for logs, i.e. its kind and, if it
+/// has them, its key and pattern.
+fn describe_condition(condition: &Condition) -> String {
+    let value = serde_json::to_value(condition).unwrap_or_default();
+    let field = |name| match value.get(name) {
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(other) => Some(other.to_string()),
+        None => None,
+    };
+
+    let mut description = field("kind").unwrap_or_else(|| "unknown".to_string());
+    for name in ["key", "pattern"] {
+        if let Some(value) = field(name) {
+            description.push_str(&format!(" {name}={value:?}"));
+        }
+    }
+    description
+}
+
+/// Logs that a condition of the rule failed to match, unless the same failure
+/// was logged in the last `CONDITION_FAILURE_LOG_INTERVAL`, so that a single
+/// malformed rule doesn't flood the logs. The number of failures which
+/// weren't logged is included in the next log.
+fn log_condition_failure(rule_id: &str, condition: &Condition, err: &ConditionMatchError) {
+    let message = format!("{rule_id}: {}: {err}", describe_condition(condition));
+    match record_condition_failure(&message, Instant::now()) {
+        Some(0) => warn!("Condition match failed for rule {message}"),
+        Some(suppressed) => warn!(
+            "Condition match failed for rule {message} ({suppressed} similar failures not logged)"
+        ),
+        None => {}
+    }
+}
+
+/// Records a condition failure with the given message at the given time,
+/// returning the number of failures not logged since it was last logged, if
+/// it should be logged now.
+fn record_condition_failure(message: &str, now: Instant) -> Option<u64> {
+    let mut log = match CONDITION_FAILURE_LOG.lock() {
+        Ok(log) => log,
+        // Logging is better than suppressing failures forever.
+        Err(_) => return Some(0),
+    };
+
+    match log.get_mut(message) {
+        Some((last_logged, suppressed))
+            if now.duration_since(*last_logged) < CONDITION_FAILURE_LOG_INTERVAL =>
+        {
+            *suppressed += 1;
+            None
+        }
+        Some((last_logged, suppressed)) => {
+            *last_logged = now;
+            Some(std::mem::take(suppressed))
+        }
+        None => {
+            log.put(message.to_string(), (now, 0));
+            Some(0)
+        }
+    }
+}
+
+/// How `event_match` conditions on the given key are matched, unless the
+/// condition says otherwise (see `EventMatchCondition::match_type`).
+///
+/// Only the body is matched by word. Everything else, e.g. the event `type`, is
+/// matched as a whole, so a pattern like `m.room.*` matches any event type in
+/// the `m.room.` namespace.
+pub fn event_match_type(key: &str) -> GlobMatchType {
+    if key == "content.body" {
+        GlobMatchType::Word
+    } else {
+        GlobMatchType::Whole
+    }
+}
+
+/// Extracts the "content.body" from a flattened event (or an empty string if
+/// there isn't one), along with the body case-folded for glob matching if there
+/// is one.
+///
+/// Only the first `max_length` characters of the body are kept, see
+/// `PushRuleEvaluator::max_body_match_length`.
+fn body_from_flattened_keys(
+    flattened_keys: &BTreeMap<FlattenedKey, JsonValue>,
+    max_length: usize,
+) -> (String, Option<String>) {
+    match flattened_keys.get("content.body") {
+        Some(JsonValue::Value(SimpleJsonValue::Str(s))) => {
+            let body = match s.char_indices().nth(max_length) {
+                Some((end, _)) => {
+                    warn!(
+                        "Truncated content.body of {} bytes to {max_length} characters for matching",
+                        s.len()
+                    );
+                    &s[..end]
+                }
+                None => s,
+            };
+            (body.to_owned(), Some(fold_case(body)))
+        }
+        _ => (String::new(), None),
+    }
+}
+
+/// The number of minutes in a day, i.e. the bound of the minutes of a
+/// `within_time_window` condition.
+pub(crate) const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// The number of minutes past midnight of the timestamp (in milliseconds since
+/// the Unix epoch) in the time zone with the given offset from UTC.
+fn local_minute_of_day(ts: u64, tz_offset_minutes: i32) -> u32 {
+    let utc_minutes = (ts / 60_000) as i64;
+    (utc_minutes + i64::from(tz_offset_minutes)).rem_euclid(i64::from(MINUTES_PER_DAY)) as u32
+}
+
+/// Whether the minute of the day is within the window from `start` (inclusive)
+/// to `end` (exclusive), which wraps past midnight if `end` is before `start`.
+/// A window which starts and ends at the same time is empty, and windows with
+/// minutes out of range never match.
+fn within_time_window(minute: u32, start: u32, end: u32) -> bool {
+    if start >= MINUTES_PER_DAY || end >= MINUTES_PER_DAY {
+        return false;
+    }
+
+    if start <= end {
+        start <= minute && minute < end
+    } else {
+        minute >= start || minute < end
+    }
+}
+
+/// Removes the quoted lines (i.e. those starting with `>`) from a body, such
+/// as the fallback of a reply.
+fn strip_quoted_lines(body: &str) -> String {
+    body.lines()
+        .filter(|line| !line.starts_with('>'))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// An event which two rule sets give different actions, see
+/// `diff_rule_sets`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleSetDiff {
+    /// The index of the event in the corpus.
+    pub index: usize,
+    /// The actions given by the old rule set.
+    pub old_actions: Vec<Action>,
+    /// The actions given by the new rule set.
+    pub new_actions: Vec<Action>,
+}
+
+/// Runs two rule sets against each event of a corpus (along with its room
+/// context, i.e. the inputs of an evaluator), returning the events for which
+/// they give different actions. This is meant for detecting regressions when
+/// changing rule sets, e.g. when migrating to a new version of the base rules.
+///
+/// The actions are those of `run`, with conflicting tweaks resolved (see
+/// `resolve_tweaks`) and in their canonical order, so that actions which have
+/// the same effect aren't reported. They're compared in their serialized form,
+/// i.e. as pushers would see them.
+pub fn diff_rule_sets(
+    old_rules: &FilteredPushRules,
+    new_rules: &FilteredPushRules,
+    corpus: &[EvaluatorInputs],
+    user_id: Option<&str>,
+    display_name: Option<&str>,
+) -> Vec<RuleSetDiff> {
+    let resolved_actions = |evaluator: &PushRuleEvaluator, push_rules| {
+        let mut actions = resolve_tweaks(&evaluator.run(push_rules, user_id, display_name));
+        sort_actions_canonically(&mut actions);
+        actions
+    };
+
+    corpus
+        .iter()
+        .enumerate()
+        .filter_map(|(index, inputs)| {
+            let evaluator = PushRuleEvaluator::new(inputs.clone());
+            let old_actions = resolved_actions(&evaluator, old_rules);
+            let new_actions = resolved_actions(&evaluator, new_rules);
+            let serialize =
+                |actions: &[Action]| serde_json::to_value(actions).expect("valid actions");
+            (serialize(&old_actions) != serialize(&new_actions)).then(|| RuleSetDiff {
+                index,
+                old_actions,
+                new_actions,
+            })
+        })
+        .collect()
+}
+
+/// The hash of a message body used by `is_duplicate_message` conditions.
+///
+/// The body is trimmed of surrounding whitespace and lowercased before being
+/// hashed with BLAKE2s-256, and the digest is returned hex encoded. This is
+/// equivalent to `hashlib.blake2s(body.strip().lower().encode()).hexdigest()`
+/// in Python.
+pub fn message_body_hash(body: &str) -> String {
+    hex::encode(Blake2s256::digest(body.trim().to_lowercase().as_bytes()))
+}
+
+#[test]
+fn push_rule_evaluator() {
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        "content.body".into(),
+        JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("foo bar bob hello"))),
+    );
+    let evaluator = PushRuleEvaluator::new(EvaluatorInputs {
+        flattened_keys,
+        room_member_count: 10,
+        sender_power_level: Some(0),
+        related_event_match_enabled: true,
+        msc3931_enabled: true,
+        ..Default::default()
+    });
+
+    let result = evaluator.run(&FilteredPushRules::default(), None, Some("bob"));
+    assert_eq!(result.len(), 3);
+}
+
+#[test]
+fn test_requires_room_version_supports_condition() {
+    use std::borrow::Cow;
+
+    use crate::push::FilteredPushRulesBuilder;
+
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        "content.body".into(),
+        JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("foo bar bob hello"))),
+    );
+    let flags = vec![RoomVersionFeatures::ExtensibleEvents.as_str().to_string()];
+    let evaluator = PushRuleEvaluator::new(EvaluatorInputs {
+        flattened_keys,
+        room_member_count: 10,
+        sender_power_level: Some(0),
+        room_version_feature_flags: flags,
+        msc3931_enabled: true,
+        ..Default::default()
+    });
+
+    // first test: are the master and contains_user_name rules excluded from the "requires room
+    // version condition" check?
+    let mut result = evaluator.run(
+        &FilteredPushRules::default(),
+        Some("@bob:example.org"),
+        None,
+    );
+    assert_eq!(result.len(), 3);
+
+    // second test: if an appropriate push rule is in play, does it get handled?
+    let custom_rule = PushRule {
+        rule_id: Cow::from("global/underride/.org.example.extensible"),
+        priority_class: 1, // underride
+        conditions: Cow::from(vec![Condition::Known(
+            KnownCondition::RoomVersionSupports {
+                feature: Cow::from(RoomVersionFeatures::ExtensibleEvents.as_str().to_string()),
+            },
+        )]),
+        actions: Cow::from(vec![Action::Notify]),
+        default: false,
+        default_enabled: true,
+    };
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(custom_rule)
+        .msc1767_enabled(true)
+        .msc3664_enabled(true)
+        .build()
+        .unwrap();
+    result = evaluator.run(&rules, None, None);
+    assert_eq!(result.len(), 1);
+}
+
+/// Parses a rule with the conditions and actions as stored in the database, for
+/// building rule sets with `FilteredPushRulesBuilder`.
+#[cfg(test)]
+fn db_rule(
+    rule_id: impl Into<String>,
+    priority_class: i32,
+    conditions: &str,
+    actions: &str,
+) -> PushRule {
+    PushRule::from_db(rule_id.into(), priority_class, conditions, actions).unwrap()
+}
+
+/// Starts building a rule set with all the base rules disabled, so that only
+/// the rules added to it are evaluated.
+#[cfg(test)]
+fn without_base_rules() -> crate::push::FilteredPushRulesBuilder {
+    FilteredPushRules::default().iter().fold(
+        crate::push::FilteredPushRulesBuilder::new(),
+        |builder, (push_rule, _)| builder.enabled(push_rule.rule_id.to_string(), false),
+    )
+}
+
+/// Builds an evaluator for the given flattened event, using defaults for the
+/// rest of the room context.
+#[cfg(test)]
+fn evaluator_for_event(flattened_keys: BTreeMap<FlattenedKey, JsonValue>) -> PushRuleEvaluator {
+    PushRuleEvaluator::new(EvaluatorInputs {
+        flattened_keys,
+        room_member_count: 10,
+        sender_power_level: Some(0),
+        related_event_match_enabled: true,
+        msc3931_enabled: true,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+fn flattened_event(keys: &[(&str, &'static str)]) -> BTreeMap<FlattenedKey, JsonValue> {
+    keys.iter()
+        .map(|(key, value)| {
+            (
+                FlattenedKey::from(*key),
+                JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed(*value))),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_match_inequality_ranges() {
+    // Single bounds work as before.
+    assert!(match_inequality("4", 4).unwrap());
+    assert!(match_inequality("==4", 4).unwrap());
+    assert!(match_inequality(">2", 3).unwrap());
+    assert!(!match_inequality("<=2", 3).unwrap());
+    assert!(!match_inequality("<0", 0).unwrap());
+
+    // Ranges exclude their end, unless written with `..=`.
+    for (is, value, expected) in [
+        ("2..10", 2, true),
+        ("2..10", 9, true),
+        ("2..10", 10, false),
+        ("2..10", 1, false),
+        ("2..=10", 10, true),
+        ("2..=10", 11, false),
+        ("5..=5", 5, true),
+    ] {
+        assert_eq!(
+            match_inequality(is, value).unwrap(),
+            expected,
+            "{is} {value}"
+        );
+    }
+
+    // As do pairs of bounds, which may be separated by any whitespace.
+    for is in [">=2 <10", ">= 2   < 10", " >=2\t<10 "] {
+        assert!(!match_inequality(is, 1).unwrap());
+        assert!(match_inequality(is, 2).unwrap());
+        assert!(!match_inequality(is, 10).unwrap());
+    }
+    assert!(match_inequality(">2 <=10", 10).unwrap());
+    assert!(!match_inequality(">2 <=10", 2).unwrap());
+
+    // Ranges which can never match are rejected.
+    for is in ["10..2", "5..5", ">10 <2", "==3 ==4", "1..0"] {
+        let err = match_inequality(is, 3).unwrap_err().to_string();
+        assert!(err.contains("range"), "{is}: {err}");
+    }
+
+    // As are malformed clauses.
+    for is in ["", ">", "a..10", "1 2 3", "=>2", ">2 foo"] {
+        assert!(match_inequality(is, 3).is_err(), "{is}");
+    }
+
+    // Equivalent ranges normalize to the same form.
+    assert_eq!(normalize_inequality("2..10").unwrap(), ">=2 <10");
+    assert_eq!(normalize_inequality(" >=2  <10").unwrap(), ">=2 <10");
+    assert_eq!(normalize_inequality("004").unwrap(), "==4");
+}
+
+#[test]
+fn test_match_inequality_overflow() {
+    let huge = "99999999999999999999";
+    assert!(huge.parse::<u64>().is_err());
+
+    // Bounds too large for a u64 are greater than any real count.
+    for (is, value, expected) in [
+        (format!("<{huge}"), 0, true),
+        (format!("<{huge}"), u64::MAX - 1, true),
+        (format!("<={huge}"), u64::MAX, true),
+        (format!(">{huge}"), u64::MAX, false),
+        (format!("=={huge}"), 10, false),
+        (format!("1..{huge}"), 10, true),
+        (format!("1..{huge}"), 0, false),
+        (format!(">=2 <{huge}"), 10, true),
+    ] {
+        assert_eq!(
+            match_inequality(&is, value).unwrap(),
+            expected,
+            "{is} {value}"
+        );
+    }
+    assert!(match_inequality(&format!("{huge}..1"), 0).is_err());
+
+    // Zero is a valid bound.
+    assert!(match_inequality("0", 0).unwrap());
+    assert!(match_inequality(">0", 1).unwrap());
+    assert!(!match_inequality(">0", 0).unwrap());
+    assert!(match_inequality("0..1", 0).unwrap());
+    assert!(!match_inequality("<0", 0).unwrap());
+
+    // Member count conditions with a huge bound still match, rather than
+    // failing.
+    let evaluator = evaluator_for_event(BTreeMap::new());
+    let member_count = |is: &str| {
+        let condition = Condition::Known(KnownCondition::RoomMemberCount {
+            is: Some(Cow::Owned(is.to_string())),
+        });
+        evaluator.match_condition(&condition, None, None).unwrap()
+    };
+    assert!(member_count(&format!("<{huge}")));
+    assert!(!member_count(&format!(">{huge}")));
+    assert!(!member_count("0"));
+    assert!(member_count(">0"));
+}
+
+#[test]
+fn test_condition_match_errors() {
+    use crate::push::{EventMatchPatternType, EventMatchTypeCondition};
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "hi bob")]));
+
+    let member_count: Condition =
+        serde_json::from_str(r#"{"kind": "room_member_count", "is": "<<2"}"#).unwrap();
+    match evaluator.match_condition(&member_count, None, None) {
+        Err(ConditionMatchError::BadMemberCountClause { clause, .. }) => assert_eq!(clause, "<<2"),
+        other => panic!("unexpected result: {other:?}"),
+    }
+
+    let localpart = Condition::Known(KnownCondition::EventMatchType(EventMatchTypeCondition {
+        key: Cow::Borrowed("content.body"),
+        pattern_type: Cow::Owned(EventMatchPatternType::UserLocalpart),
+    }));
+    // Malformed user IDs are a clean non-match.
+    for user_id in ["@bob", "bob:test", ""] {
+        assert!(!evaluator
+            .match_condition(&localpart, Some(user_id), None)
+            .unwrap());
+    }
+
+    // The typed errors still convert into `anyhow::Error`.
+    let err: Error = ConditionMatchError::bad_clause("<<2", "unexpected text").into();
+    assert_eq!(err.to_string(), r#"bad 'is' clause "<<2": unexpected text"#);
+}
+
+#[test]
+fn test_standalone_predicates() {
+    let event = flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.notice"),
+        ("content.body", "Coffee is ready"),
+    ]);
+    let matches = |key: &str, pattern: &str| {
+        event_match(&event, key, pattern, event_match_type(key), None).unwrap()
+    };
+
+    assert!(matches("type", "m.room.*"));
+    assert!(!matches("type", "m.room"));
+    assert!(matches("content.msgtype", "M.NOTICE"));
+    assert!(matches("content.body", "coffee"));
+    assert!(!matches("content.body", "coff"));
+    assert!(!matches("content.missing", "*"));
+    assert!(event_match(&event, "content.body", "[", GlobMatchType::Whole, None).is_err());
+
+    assert!(glob_matches("caf?", GlobMatchType::Whole, "CAFE").unwrap());
+    assert!(glob_matches("cafe", GlobMatchType::Word, "a cafe nearby").unwrap());
+    assert!(!glob_matches("cafe", GlobMatchType::Whole, "a cafe nearby").unwrap());
+
+    assert!(member_count_matches("2", 2).unwrap());
+    assert!(!member_count_matches("2", 3).unwrap());
+    assert!(member_count_matches(">=10", 10).unwrap());
+    assert!(!member_count_matches("<10", 10).unwrap());
+    assert!(member_count_matches(">x", 10).is_err());
+}
+
+#[test]
+fn test_evaluation_uses_thread_local_glob_cache() {
+    // Use a fresh thread, so that other tests running on this thread don't
+    // affect its cache.
+    std::thread::spawn(|| {
+        ThreadLocalGlobMatcherCache::clear();
+
+        let evaluator = evaluator_for_event(flattened_event(&[
+            ("type", "m.room.message"),
+            ("content.body", "thread local coffee"),
+        ]));
+        let condition: Condition = serde_json::from_str(
+            r#"{"kind": "event_match", "key": "content.body", "pattern": "thread*coffee"}"#,
+        )
+        .unwrap();
+        assert!(evaluator.match_condition(&condition, None, None).unwrap());
+        assert_eq!(ThreadLocalGlobMatcherCache::len(), 1);
+
+        // Display names are cached in the same way.
+        assert!(evaluator
+            .match_condition(
+                &Condition::Known(KnownCondition::ContainsDisplayName),
+                None,
+                Some("Coffee"),
+            )
+            .unwrap());
+        assert_eq!(ThreadLocalGlobMatcherCache::len(), 2);
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_event_match_array_index() {
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        "content.m\\.mentions.user_ids".into(),
+        JsonValue::Array(vec![
+            SimpleJsonValue::Str(Cow::Borrowed("@alice:test")),
+            SimpleJsonValue::Str(Cow::Borrowed("@bob:test")),
+        ]),
+    );
+    let evaluator = evaluator_for_event(flattened_keys);
+
+    let first_mention = |pattern: &str| {
+        let condition: Condition = serde_json::from_value(serde_json::json!({
+            "kind": "event_match",
+            "key": "content.m\\.mentions.user_ids.0",
+            "pattern": pattern,
+        }))
+        .unwrap();
+        evaluator.match_condition(&condition, None, None).unwrap()
+    };
+
+    assert!(first_mention("@alice:test"));
+    assert!(!first_mention("@bob:test"));
+}
+
+#[test]
+fn test_intentional_mentions() {
+    let is_user_mention: Condition =
+        serde_json::from_str(r#"{"kind": "org.matrix.msc3952.is_user_mention"}"#).unwrap();
+    let is_room_mention: Condition =
+        serde_json::from_str(r#"{"kind": "org.matrix.msc3952.is_room_mention"}"#).unwrap();
+
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        r"content.m\.mentions.user_ids".into(),
+        JsonValue::Array(vec![SimpleJsonValue::Str(Cow::Borrowed("@bob:test"))]),
+    );
+    flattened_keys.insert(
+        r"content.m\.mentions.room".into(),
+        JsonValue::Value(SimpleJsonValue::Bool(true)),
+    );
+    let mut evaluator = evaluator_for_event(flattened_keys);
+
+    // The conditions never match unless enabled.
+    assert!(!evaluator
+        .match_condition(&is_user_mention, Some("@bob:test"), None)
+        .unwrap());
+    evaluator.msc3952_intentional_mentions = true;
+
+    assert!(evaluator
+        .match_condition(&is_user_mention, Some("@bob:test"), None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&is_user_mention, Some("@alice:test"), None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&is_user_mention, None, None)
+        .unwrap());
+
+    // Room mentions require the sender to have permission to notify the room.
+    evaluator.sender_power_level = Some(0);
+    assert!(!evaluator
+        .match_condition(&is_room_mention, None, None)
+        .unwrap());
+    evaluator.sender_power_level = Some(100);
+    assert!(evaluator
+        .match_condition(&is_room_mention, None, None)
+        .unwrap());
+
+    // Malformed or missing mentions never match.
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        r"content.m\.mentions.user_ids".into(),
+        JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("@bob:test"))),
+    );
+    flattened_keys.insert(
+        r"content.m\.mentions.room".into(),
+        JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("true"))),
+    );
+    for flattened_keys in [flattened_keys, BTreeMap::new()] {
+        let mut evaluator = evaluator_for_event(flattened_keys);
+        evaluator.msc3952_intentional_mentions = true;
+        evaluator.sender_power_level = Some(100);
+
+        assert!(!evaluator
+            .match_condition(&is_user_mention, Some("@bob:test"), None)
+            .unwrap());
+        assert!(!evaluator
+            .match_condition(&is_room_mention, None, None)
+            .unwrap());
+    }
+}
+
+#[test]
+fn test_relation_count() {
+    let mut evaluator = evaluator_for_event(BTreeMap::new());
+    let thread_replies = |is: &str| {
+        let condition: Condition = serde_json::from_value(serde_json::json!({
+            "kind": "io.element.relation_count",
+            "rel_type": "m.thread",
+            "is": is,
+        }))
+        .unwrap();
+        condition
+    };
+
+    // The condition never matches unless enabled.
+    assert!(!evaluator
+        .match_condition(&thread_replies("0"), None, None)
+        .unwrap());
+    evaluator.relation_count_enabled = true;
+
+    // Relation types without a count have no related events.
+    assert!(evaluator
+        .match_condition(&thread_replies("0"), None, None)
+        .unwrap());
+
+    evaluator.relation_counts.insert("m.thread".to_string(), 10);
+    assert!(evaluator
+        .match_condition(&thread_replies(">=10"), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&thread_replies("<10"), None, None)
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&thread_replies("<<10"), None, None)
+        .is_err());
+}
+
+#[test]
+fn test_has_aggregation_condition() {
+    let mut evaluator = evaluator_for_event(BTreeMap::new());
+    let has_aggregation = |key: Option<&'static str>| {
+        Condition::Known(KnownCondition::HasAggregation {
+            rel_type: Cow::Borrowed("m.annotation"),
+            key: key.map(Cow::Borrowed),
+        })
+    };
+    let matches = |evaluator: &PushRuleEvaluator, key| {
+        evaluator
+            .match_condition(&has_aggregation(key), None, None)
+            .unwrap()
+    };
+
+    // Without aggregation data the condition never matches.
+    evaluator.aggregation_match_enabled = true;
+    assert!(!matches(&evaluator, None));
+    assert!(!matches(&evaluator, Some("👍")));
+
+    evaluator.aggregations.insert(
+        "m.annotation".to_string(),
+        BTreeMap::from([("👍".to_string(), 1), ("👎".to_string(), 0)]),
+    );
+    assert!(matches(&evaluator, None));
+    assert!(matches(&evaluator, Some("👍")));
+    assert!(!matches(&evaluator, Some("👎")));
+    assert!(!matches(&evaluator, Some("🎉")));
+
+    // Or unless enabled.
+    evaluator.aggregation_match_enabled = false;
+    assert!(!matches(&evaluator, None));
+    assert!(!matches(&evaluator, Some("👍")));
+}
+
+#[test]
+fn test_room_id_conditions() {
+    let room_id_equals: Condition =
+        serde_json::from_str(r#"{"kind": "io.element.room_id_equals", "room_id": "!abc:test"}"#)
+            .unwrap();
+    let room_id_match: Condition =
+        serde_json::from_str(r#"{"kind": "io.element.room_id_match", "pattern": "!*:test"}"#)
+            .unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[("room_id", "!abc:test")]));
+    assert!(evaluator
+        .match_condition(&room_id_equals, None, None)
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&room_id_match, None, None)
+        .unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[("room_id", "!abc:other")]));
+    assert!(!evaluator
+        .match_condition(&room_id_equals, None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&room_id_match, None, None)
+        .unwrap());
+
+    // Room IDs are case-sensitive.
+    let evaluator = evaluator_for_event(flattened_event(&[("room_id", "!ABC:test")]));
+    assert!(!evaluator
+        .match_condition(&room_id_equals, None, None)
+        .unwrap());
+
+    let evaluator = evaluator_for_event(BTreeMap::new());
+    assert!(!evaluator
+        .match_condition(&room_id_equals, None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&room_id_match, None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_sender_server_match() {
+    let condition = |pattern: &'static str| {
+        Condition::Known(KnownCondition::SenderServerMatch {
+            pattern: Cow::Borrowed(pattern),
+        })
+    };
+    let matches = |sender: &'static str, pattern| {
+        evaluator_for_event(flattened_event(&[("sender", sender)]))
+            .match_condition(&condition(pattern), None, None)
+            .unwrap()
+    };
+
+    // Exact matches.
+    assert!(matches("@alice:example.org", "example.org"));
+    assert!(matches("@alice:EXAMPLE.org", "example.org"));
+    assert!(!matches("@alice:example.org", "example.com"));
+    assert!(!matches("@alice:sub.example.org", "example.org"));
+    assert!(matches("@alice:example.org:8448", "example.org:8448"));
+
+    // Wildcard matches.
+    assert!(matches("@alice:sub.example.org", "*.example.org"));
+    assert!(!matches("@alice:example.org", "*.example.org"));
+    assert!(matches("@alice:example.org:8448", "example.org*"));
+
+    // Malformed senders don't match.
+    assert!(!matches("alice:example.org", "*"));
+    assert!(!matches("@alice", "*"));
+    assert!(!matches("@alice:", "*"));
+    assert!(!evaluator_for_event(BTreeMap::new())
+        .match_condition(&condition("*"), None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_sender_display_name_match_condition() {
+    let mut evaluator = evaluator_for_event(flattened_event(&[("sender", "@github:example.org")]));
+    let matches = |evaluator: &PushRuleEvaluator, pattern: &'static str| {
+        let condition = Condition::Known(KnownCondition::SenderDisplayNameMatch {
+            pattern: Cow::Borrowed(pattern),
+        });
+        evaluator.match_condition(&condition, None, None).unwrap()
+    };
+
+    // Without a display name the condition never matches.
+    assert!(!matches(&evaluator, "*"));
+
+    evaluator.sender_display_name = Some("GitHub Bot".to_string());
+    assert!(matches(&evaluator, "*Bot"));
+    assert!(matches(&evaluator, "github *"));
+    assert!(matches(&evaluator, "GitHub Bot"));
+    // The whole display name has to match.
+    assert!(!matches(&evaluator, "Bot"));
+    assert!(!matches(&evaluator, "*Bots"));
+}
+
+#[test]
+fn test_is_direct_message_condition() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/override/dms",
+            5,
+            r#"[{"kind": "io.element.is_direct_message"}]"#,
+            r#"["notify", {"set_tweak": "sound", "value": "default"}]"#,
+        ))
+        .build()
+        .unwrap();
+    let mut evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.notice"),
+        ("content.body", "hello"),
+    ]));
+
+    // Rooms aren't DMs unless the caller says so.
+    assert!(!evaluator
+        .match_condition(
+            &Condition::Known(KnownCondition::IsDirectMessage),
+            Some("@alice:test"),
+            None
+        )
+        .unwrap());
+    assert_eq!(
+        evaluator
+            .run_with_matched_rule(&rules, Some("@alice:test"), None)
+            .unwrap()
+            .0,
+        "global/override/.m.rule.suppress_notices"
+    );
+
+    // The room is only a DM for the given users.
+    evaluator.dm_user_ids = BTreeSet::from(["@alice:test".to_string()]);
+    assert!(!evaluator
+        .match_condition(
+            &Condition::Known(KnownCondition::IsDirectMessage),
+            Some("@bob:test"),
+            None
+        )
+        .unwrap());
+    assert!(evaluator
+        .match_condition(
+            &Condition::Known(KnownCondition::IsDirectMessage),
+            Some("@alice:test"),
+            None
+        )
+        .unwrap());
+    assert_eq!(
+        evaluator
+            .run_with_matched_rule(&rules, Some("@alice:test"), None)
+            .unwrap()
+            .0,
+        "global/override/dms"
+    );
+}
+
+#[test]
+fn test_sender_is_server_notices_condition() {
+    let condition = Condition::Known(KnownCondition::SenderIsServerNotices);
+    let matches = |sender: &'static str, server_notices_user_id: Option<&str>| {
+        let mut evaluator = evaluator_for_event(flattened_event(&[("sender", sender)]));
+        evaluator.server_notices_user_id = server_notices_user_id.map(str::to_string);
+        evaluator.match_condition(&condition, None, None).unwrap()
+    };
+
+    assert!(matches(
+        "@notices:example.org",
+        Some("@notices:example.org")
+    ));
+    assert!(!matches("@alice:example.org", Some("@notices:example.org")));
+    // User IDs are compared exactly.
+    assert!(!matches(
+        "@Notices:example.org",
+        Some("@notices:example.org")
+    ));
+    // Nothing matches without a server notices user.
+    assert!(!matches("@notices:example.org", None));
+}
+
+#[test]
+fn test_event_value_compare() {
+    let compare = |op: &str, value: i64| {
+        let condition: Condition = serde_json::from_value(serde_json::json!({
+            "kind": "io.element.event_value_compare",
+            "key": "content.value",
+            "op": op,
+            "value": value,
+        }))
+        .unwrap();
+        condition
+    };
+
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        "content.value".into(),
+        JsonValue::Value(SimpleJsonValue::Int(7)),
+    );
+    let evaluator = evaluator_for_event(flattened_keys);
+
+    for (op, value, expected) in [
+        (">", 5, true),
+        (">", 7, false),
+        (">=", 7, true),
+        ("<", 10, true),
+        ("<=", 6, false),
+        ("==", 7, true),
+        ("!=", 7, false),
+    ] {
+        assert_eq!(
+            evaluator
+                .match_condition(&compare(op, value), None, None)
+                .unwrap(),
+            expected,
+            "{op} {value}"
+        );
+    }
+
+    // Strings (even of digits) and booleans aren't compared as integers.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.value", "7")]));
+    assert!(!evaluator
+        .match_condition(&compare("==", 7), None, None)
+        .unwrap());
+
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        "content.value".into(),
+        JsonValue::Value(SimpleJsonValue::Bool(true)),
+    );
+    let evaluator = evaluator_for_event(flattened_keys);
+    assert!(!evaluator
+        .match_condition(&compare("==", 1), None, None)
+        .unwrap());
+
+    // Unknown operators are rejected when parsing the condition.
+    let unknown_op: Condition = serde_json::from_str(
+        r#"{"kind": "io.element.event_value_compare", "key": "content.value", "op": "=~", "value": 7}"#,
+    )
+    .unwrap();
+    assert!(matches!(unknown_op, Condition::Unknown(_)));
+}
+
+#[test]
+fn test_dry_run() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rule = db_rule(
+        "global/override/coffee",
+        5,
+        r#"[
+            {"kind": "event_match", "key": "content.msgtype", "pattern": "m.text"},
+            {"kind": "event_match", "key": "content.body", "pattern": "coffee"}
+        ]"#,
+        r#"["notify", "dont_notify"]"#,
+    );
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule)
+        .enabled("global/override/.m.rule.suppress_notices", false)
+        .build()
+        .unwrap();
+    let trace = |evaluator: &PushRuleEvaluator, rule_id: &str| {
+        evaluator
+            .dry_run(&rules, Some("@bob:test"), None)
+            .into_iter()
+            .find(|trace| trace.rule_id == rule_id)
+            .unwrap()
+    };
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.msgtype", "m.text"),
+        ("content.body", "coffee?"),
+    ]));
+    assert_eq!(
+        trace(&evaluator, "global/override/coffee"),
+        RuleEvaluationTrace {
+            rule_id: "global/override/coffee".to_string(),
+            matched: true,
+            failed_condition: None,
+            reason: None,
+            unconditional: false,
+            // Unlike `run`, `dont_notify` isn't filtered out.
+            actions: vec![Action::Notify, Action::DontNotify],
+        }
+    );
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.msgtype", "m.text"),
+        ("content.body", "tea?"),
+    ]));
+    let coffee = trace(&evaluator, "global/override/coffee");
+    assert!(!coffee.matched);
+    assert_eq!(coffee.failed_condition, Some(1));
+    assert_eq!(
+        coffee.reason.as_deref(),
+        Some("condition 1 (event_match) did not match")
+    );
+
+    // Disabled rules are included, but never match.
+    let suppress_notices = trace(&evaluator, "global/override/.m.rule.suppress_notices");
+    assert!(!suppress_notices.matched);
+    assert_eq!(suppress_notices.reason.as_deref(), Some("rule is disabled"));
+}
+
+#[test]
+fn test_explain_non_match() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let explain = |evaluator: &PushRuleEvaluator, conditions: &str| {
+        let rule = db_rule("global/override/test", 5, conditions, r#"["notify"]"#);
+        // Rules which can't be evaluated are explained too.
+        let rules = FilteredPushRulesBuilder::new()
+            .push_rule(rule)
+            .build_unchecked();
+        evaluator
+            .explain_non_match("global/override/test", &rules, Some("@bob:test"), None)
+            .unwrap()
+    };
+
+    let mut flattened_keys = flattened_event(&[
+        ("type", "m.room.message"),
+        ("sender", "@alice:test"),
+        ("content.msgtype", "m.text"),
+        ("content.body", "hello world"),
+    ]);
+    flattened_keys.insert(
+        "content.tags".into(),
+        JsonValue::Array(vec![SimpleJsonValue::Str("a".into())]),
+    );
+    let evaluator = evaluator_for_event(flattened_keys);
+
+    for (condition, reason) in [
+        (
+            r#"{"kind": "event_match", "key": "content.body", "pattern": "foo"}"#,
+            "content.body did not contain 'foo'",
+        ),
+        (
+            r#"{"kind": "event_match", "key": "type", "pattern": "m.room.member"}"#,
+            "type did not match 'm.room.member'",
+        ),
+        (
+            r#"{"kind": "event_match", "key": "content.topic", "pattern": "foo"}"#,
+            "event has no content.topic",
+        ),
+        (
+            r#"{"kind": "event_match", "key": ["content.body", "content.topic"], "pattern": "foo"}"#,
+            "none of content.body, content.topic matched 'foo'",
+        ),
+        (
+            r#"{"kind": "contains_display_name"}"#,
+            "the user's display name is not known",
+        ),
+        (
+            r#"{"kind": "room_member_count", "is": ">20"}"#,
+            "room member count 10 is not > 20",
+        ),
+        (
+            r#"{"kind": "room_member_count", "is": "2..5"}"#,
+            "room member count 10 is not >= 2 and < 5",
+        ),
+        (
+            r#"{"kind": "sender_notification_permission", "key": "room"}"#,
+            "sender's power level 0 is below the 50 required for 'room' notifications",
+        ),
+        (
+            r#"{"kind": "event_property_is", "key": "content.msgtype", "value": "m.notice"}"#,
+            r#"content.msgtype was "m.text", not "m.notice""#,
+        ),
+        (
+            r#"{"kind": "event_property_is", "key": "content.tags", "value": "a"}"#,
+            "content.tags is an array",
+        ),
+        (
+            r#"{"kind": "event_property_contains", "key": "content.tags", "value": "b"}"#,
+            r#"content.tags did not contain "b""#,
+        ),
+        (
+            r#"{"kind": "event_property_contains", "key": "content.body", "value": "b"}"#,
+            "content.body is not an array",
+        ),
+        (
+            r#"{"kind": "io.element.not", "condition": {"kind": "event_match", "key": "type", "pattern": "m.room.message"}}"#,
+            "negated condition matched",
+        ),
+        (
+            r#"{"kind": "io.element.is_encrypted"}"#,
+            "io.element.is_encrypted condition did not match",
+        ),
+        (
+            r#"{"kind": "org.example.unknown"}"#,
+            "unrecognized condition kind 'org.example.unknown'",
+        ),
+        (
+            r#"{"kind": "room_member_count", "is": 2}"#,
+            "malformed room_member_count condition",
+        ),
+        (
+            r#"{"kind": "room_member_count", "is": "<<2"}"#,
+            r#"room_member_count condition failed: bad 'is' clause "<<2": bad inequality <<"#,
+        ),
+    ] {
+        assert_eq!(
+            explain(&evaluator, &format!("[{condition}]")),
+            Some(NonMatchExplanation {
+                failed_condition: Some(0),
+                reason: reason.to_string(),
+            }),
+            "{condition}"
+        );
+    }
+
+    // The first condition which doesn't match is explained.
+    assert_eq!(
+        explain(
+            &evaluator,
+            r#"[
+                {"kind": "event_match", "key": "type", "pattern": "m.room.message"},
+                {"kind": "event_match", "key": "content.body", "pattern": "foo"},
+                {"kind": "event_match", "key": "content.body", "pattern": "bar"}
+            ]"#
+        ),
+        Some(NonMatchExplanation {
+            failed_condition: Some(1),
+            reason: "content.body did not contain 'foo'".to_string(),
+        })
+    );
+    assert_eq!(
+        explain(
+            &evaluator,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "hello"}]"#
+        ),
+        None
+    );
+
+    // Patterns from the user's ID are only used by the base rules.
+    assert_eq!(
+        evaluator
+            .explain_non_match(
+                "global/content/.m.rule.contains_user_name",
+                &FilteredPushRules::default(),
+                Some("@bob:test"),
+                None
+            )
+            .unwrap(),
+        Some(NonMatchExplanation {
+            failed_condition: Some(0),
+            reason: "content.body did not contain the user's localpart".to_string(),
+        })
+    );
+
+    // Rules which can't match whatever their conditions are explained too.
+    let rules = FilteredPushRulesBuilder::new()
+        .enabled("global/override/.m.rule.master", false)
+        .build()
+        .unwrap();
+    assert_eq!(
+        evaluator
+            .explain_non_match("global/override/.m.rule.master", &rules, None, None)
+            .unwrap(),
+        Some(NonMatchExplanation {
+            failed_condition: None,
+            reason: "rule is disabled".to_string(),
+        })
+    );
+    assert!(evaluator
+        .explain_non_match("global/override/missing", &rules, None, None)
+        .is_err());
+}
+
+#[test]
+fn test_unconditional_rule() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = |enabled: bool| {
+        FilteredPushRulesBuilder::new()
+            .push_rule(db_rule(
+                "global/override/everything",
+                5,
+                "[]",
+                r#"["notify"]"#,
+            ))
+            .enabled("global/override/everything", enabled)
+            .build()
+            .unwrap()
+    };
+
+    for event in [
+        flattened_event(&[]),
+        flattened_event(&[("type", "m.room.message"), ("content.body", "hi")]),
+        flattened_event(&[("type", "org.example.event")]),
+    ] {
+        let evaluator = evaluator_for_event(event);
+
+        assert_eq!(
+            evaluator.run_with_matched_rule(&rules(true), None, None),
+            Some((
+                "global/override/everything".to_string(),
+                vec![Action::Notify]
+            ))
+        );
+        assert_ne!(
+            evaluator
+                .run_with_matched_rule(&rules(false), None, None)
+                .map(|(rule_id, _)| rule_id)
+                .as_deref(),
+            Some("global/override/everything")
+        );
+    }
+
+    // The rule is marked as unconditional in the diagnostics, whether or not
+    // it is enabled.
+    let evaluator = evaluator_for_event(flattened_event(&[]));
+    for enabled in [true, false] {
+        let trace = evaluator
+            .dry_run(&rules(enabled), None, None)
+            .into_iter()
+            .find(|trace| trace.rule_id == "global/override/everything")
+            .unwrap();
+        assert!(trace.unconditional);
+        assert_eq!(trace.matched, enabled);
+    }
+    assert!(evaluator
+        .explain(&rules(true), None, None)
+        .rules
+        .iter()
+        .filter(|rule| rule.rule_id != "global/override/everything")
+        .all(|rule| rule.unconditional == rule.conditions.is_empty()));
+}
+
+#[test]
+fn test_sender_pattern_types() {
+    use crate::push::EventMatchTypeCondition;
+
+    let condition = |key: &'static str, pattern_type| {
+        Condition::Known(KnownCondition::EventMatchType(EventMatchTypeCondition {
+            key: Cow::Borrowed(key),
+            pattern_type: Cow::Owned(pattern_type),
+        }))
+    };
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("sender", "@alice:example.org"),
+        ("content.body", "alice here, ping @bob:test"),
+        ("state_key", "@alice:example.org"),
+    ]));
+    let matches = |condition: &Condition| {
+        evaluator
+            .match_condition(condition, Some("@bob:test"), None)
+            .unwrap()
+    };
+
+    assert!(matches(&condition(
+        "state_key",
+        EventMatchPatternType::Sender
+    )));
+    assert!(matches(&condition(
+        "content.body",
+        EventMatchPatternType::SenderLocalpart
+    )));
+    assert!(!matches(&condition(
+        "state_key",
+        EventMatchPatternType::SenderLocalpart
+    )));
+
+    // The existing types still match against the user.
+    assert!(matches(&condition(
+        "content.body",
+        EventMatchPatternType::UserId
+    )));
+    assert!(!matches(&condition(
+        "state_key",
+        EventMatchPatternType::UserId
+    )));
+
+    // Without a sender the sender types never match.
+    let evaluator = evaluator_for_event(flattened_event(&[("state_key", "@alice:example.org")]));
+    assert!(!evaluator
+        .match_condition(
+            &condition("state_key", EventMatchPatternType::Sender),
+            Some("@bob:test"),
+            None
+        )
+        .unwrap());
+}
+
+#[test]
+fn test_precompiled_rules() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "cof*"}]"#,
+            r#"["notify"]"#,
+        ))
+        .push_rule(db_rule(
+            "global/override/tea",
+            5,
+            r#"[{"kind": "event_match", "key": "content.msgtype", "pattern": "org.example.tea"}]"#,
+            r#"["notify", {"set_tweak": "highlight"}]"#,
+        ))
+        .build()
+        .unwrap();
+    let precompiled = rules.precompiled();
+    assert!(rules.precompiled_globs().is_none());
+    assert!(precompiled
+        .precompiled_globs()
+        .unwrap()
+        .get("cof*", GlobMatchType::Word)
+        .is_some());
+    assert!(precompiled
+        .precompiled_globs()
+        .unwrap()
+        .get("org.example.tea", GlobMatchType::Whole)
+        .is_some());
+
+    for event in [
+        flattened_event(&[("content.body", "Coffee?")]),
+        flattened_event(&[
+            ("content.body", "tea?"),
+            ("content.msgtype", "org.example.tea"),
+        ]),
+        flattened_event(&[("content.body", "decaf")]),
+    ] {
+        let evaluator = evaluator_for_event(event);
+        assert_eq!(
+            evaluator.run_with_matched_rule(&precompiled, Some("@bob:test"), None),
+            evaluator.run_with_matched_rule(&rules, Some("@bob:test"), None),
+        );
+    }
+
+    // Derived rule sets stay precompiled.
+    assert!(precompiled.normalize().precompiled_globs().is_some());
+}
+
+#[test]
+fn test_combined_content_rules() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let keywords = [
+        "coffee", "tea", "café", "r?st", "release*", "[bc]at", "c++", "猫",
+    ];
+    let rules = keywords
+        .iter()
+        .enumerate()
+        .fold(FilteredPushRulesBuilder::new(), |builder, (i, keyword)| {
+            builder.push_rule(db_rule(
+                format!("global/content/keyword{i}"),
+                4,
+                &format!(
+                    r#"[{{"kind": "event_match", "key": "content.body", "pattern": "{keyword}"}}]"#
+                ),
+                r#"["notify", {"set_tweak": "highlight"}]"#,
+            ))
+        })
+        // Conditions on other keys, or which ignore quotes, aren't combined.
+        .push_rule(db_rule(
+            "global/content/quoted",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "quoted", "ignore_quotes": true}]"#,
+            r#"["notify"]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/topic",
+            4,
+            r#"[{"kind": "event_match", "key": "content.topic", "pattern": "coffee"}]"#,
+            r#"["notify"]"#,
+        ))
+        .build()
+        .unwrap();
+    let precompiled = rules.precompiled();
+    assert!(precompiled
+        .precompiled_globs()
+        .unwrap()
+        .combined_words()
+        .is_some());
+
+    // Combined rules match exactly as the rules do when evaluated separately.
+    for body in [
+        "Coffee or TEA?",
+        "coffees",
+        "café au lait",
+        "a rust release-candidate",
+        "cat bat rat",
+        "c++ rocks",
+        "我的猫",
+        "> quoted\n\nnot quoted",
+        "nothing to see here",
+    ] {
+        let evaluator = evaluator_for_event(flattened_event(&[
+            ("type", "m.room.message"),
+            ("content.body", body),
+            ("content.topic", "coffee"),
+        ]));
+        assert_eq!(
+            evaluator.run_all_matches(&precompiled, Some("@bob:test"), None),
+            evaluator.run_all_matches(&rules, Some("@bob:test"), None),
+            "{body}"
+        );
+    }
+}
+
+#[test]
+fn test_run_with_matched_rule() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rule = db_rule(
+        "global/content/coffee",
+        4,
+        r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+        r#"["notify", "dont_notify"]"#,
+    );
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule)
+        .build()
+        .unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+    assert_eq!(
+        evaluator.run_with_matched_rule(&rules, Some("@bob:test"), None),
+        Some(("global/content/coffee".to_string(), vec![Action::Notify]))
+    );
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "tea?")]));
+    assert_eq!(
+        evaluator.run_with_matched_rule(&rules, Some("@bob:test"), None),
+        None
+    );
+    assert!(evaluator.run(&rules, Some("@bob:test"), None).is_empty());
+}
+
+#[test]
+fn test_has_notify_action() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/content/tea",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "tea"}]"#,
+            r#"["dont_notify"]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["notify"]"#,
+        ))
+        .build()
+        .unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+    assert!(evaluator.has_notify_action(&rules, Some("@bob:test"), None));
+
+    // A match of a `dont_notify` rule stops evaluation, even though a later
+    // rule would notify.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "tea or coffee?")]));
+    assert!(!evaluator.has_notify_action(&rules, Some("@bob:test"), None));
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "water?")]));
+    assert!(!evaluator.has_notify_action(&rules, Some("@bob:test"), None));
+}
+
+#[test]
+fn test_should_highlight() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rule = |body: &str, actions: &str| {
+        db_rule(
+            format!("global/content/{body}"),
+            4,
+            &format!(r#"[{{"kind": "event_match", "key": "content.body", "pattern": "{body}"}}]"#),
+            actions,
+        )
+    };
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule(
+            "implicit",
+            r#"["notify", {"set_tweak": "highlight"}]"#,
+        ))
+        .push_rule(rule(
+            "explicit_true",
+            r#"["notify", {"set_tweak": "highlight", "value": true}]"#,
+        ))
+        .push_rule(rule(
+            "explicit_false",
+            r#"["notify", {"set_tweak": "highlight", "value": false}]"#,
+        ))
+        .push_rule(rule(
+            "overridden",
+            r#"["notify", {"set_tweak": "highlight"}, {"set_tweak": "highlight", "value": false}]"#,
+        ))
+        .push_rule(rule(
+            "none",
+            r#"["notify", {"set_tweak": "sound", "value": "default"}]"#,
+        ))
+        .build()
+        .unwrap();
+    let should_highlight = |body: &'static str| {
+        evaluator_for_event(flattened_event(&[("content.body", body)])).should_highlight(
+            &rules,
+            Some("@bob:test"),
+            None,
+        )
+    };
+
+    assert!(should_highlight("implicit"));
+    assert!(should_highlight("explicit_true"));
+    assert!(!should_highlight("explicit_false"));
+    // The last highlight tweak wins.
+    assert!(!should_highlight("overridden"));
+    assert!(!should_highlight("none"));
+    assert!(!should_highlight("water?"));
+
+    // It agrees with the summary of the actions.
+    for body in [
+        "implicit",
+        "explicit_true",
+        "explicit_false",
+        "overridden",
+        "none",
+    ] {
+        let evaluator = evaluator_for_event(flattened_event(&[("content.body", body)]));
+        assert_eq!(
+            evaluator.should_highlight(&rules, Some("@bob:test"), None),
+            evaluator
+                .run_summary(&rules, Some("@bob:test"), None)
+                .highlight,
+            "{body}"
+        );
+    }
+}
+
+#[test]
+fn test_msgtype_match() {
+    use crate::push::EventMatchCondition;
+
+    let matches = |msgtype: &'static str, pattern: &str| {
+        let condition = Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
+            key: EventMatchKey::Single(Cow::Borrowed(MSGTYPE_KEY)),
+            pattern: Cow::Owned(pattern.to_string()),
+            ignore_quotes: false,
+            match_words: None,
+            contains: false,
+        }));
+        let fast_path = match_literal_msgtype(pattern, msgtype);
+        let matched = evaluator_for_event(flattened_event(&[(MSGTYPE_KEY, msgtype)]))
+            .match_condition(&condition, None, None)
+            .unwrap();
+        // The fast path agrees with glob matching whenever it applies.
+        if let Some(fast_path) = fast_path {
+            assert_eq!(
+                fast_path,
+                PrecompiledGlobs::is_match(None, pattern, GlobMatchType::Whole, msgtype).unwrap(),
+                "{pattern:?} against {msgtype:?}"
+            );
+        }
+        (matched, fast_path.is_some())
+    };
+
+    // Literal patterns are compared directly, case-insensitively.
+    assert_eq!(matches("m.notice", "m.notice"), (true, true));
+    assert_eq!(matches("m.notice", "M.Notice"), (true, true));
+    assert_eq!(matches("m.notice", "m.text"), (false, true));
+    assert_eq!(matches("m.notice", "m.notic"), (false, true));
+
+    // Wildcards and escapes fall back to glob matching.
+    assert_eq!(matches("m.notice", "m.*"), (true, false));
+    assert_eq!(matches("m.notice", "m.n?tice"), (true, false));
+    assert_eq!(matches("m.text", "m.[!t]ext"), (false, false));
+    assert_eq!(matches("m.*", r"m.\*"), (true, false));
+    assert_eq!(matches("m.text", r"m.\*"), (false, false));
+
+    // As does non-ASCII text, which is case-folded differently.
+    assert_eq!(matches("m.te\u{212A}t", "m.tekt"), (true, false));
+
+    // A missing or non-string message type never matches.
+    let condition = Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
+        key: EventMatchKey::Single(Cow::Borrowed(MSGTYPE_KEY)),
+        pattern: Cow::Borrowed("m.text"),
+        ignore_quotes: false,
+        match_words: None,
+        contains: false,
+    }));
+    assert!(!evaluator_for_event(BTreeMap::new())
+        .match_condition(&condition, None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_run_resolved() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    // Both rules match, with conflicting highlight tweaks.
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["coalesce", {"set_tweak": "highlight"}, "notify", {"set_tweak": "highlight", "value": false}]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/tea",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "tea"}]"#,
+            r#"["notify", {"set_tweak": "highlight", "value": true}]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/cake",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "cake"}]"#,
+            r#"["coalesce", {"set_tweak": "sound", "value": "default"}]"#,
+        ))
+        .build()
+        .unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee or tea?")]));
+
+    let raw: Vec<Action> = serde_json::from_str(
+        r#"[{"set_tweak": "highlight"}, "notify", {"set_tweak": "highlight", "value": false}]"#,
+    )
+    .unwrap();
+    assert_eq!(evaluator.run(&rules, Some("@bob:test"), None), raw);
+
+    // Only the first matching rule applies, with its last highlight tweak
+    // winning and `coalesce` dropped.
+    let resolved: Vec<Action> =
+        serde_json::from_str(r#"["notify", {"set_tweak": "highlight", "value": false}]"#).unwrap();
+    assert_eq!(
+        evaluator.run_resolved(&rules, Some("@bob:test"), None),
+        resolved
+    );
+
+    // As with `run`, `coalesce` doesn't notify.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "cake?")]));
+    let sound: Vec<Action> =
+        serde_json::from_str(r#"[{"set_tweak": "sound", "value": "default"}]"#).unwrap();
+    assert_eq!(evaluator.run(&rules, Some("@bob:test"), None), sound);
+    assert_eq!(
+        evaluator.run_resolved(&rules, Some("@bob:test"), None),
+        sound
+    );
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "water?")]));
+    assert!(evaluator
+        .run_resolved(&rules, Some("@bob:test"), None)
+        .is_empty());
+}
+
+#[test]
+fn test_run_summary() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["notify", {"set_tweak": "sound", "value": "default"}, {"set_tweak": "sound", "value": "ring"}, {"set_tweak": "highlight"}]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/tea",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "tea"}]"#,
+            r#"["coalesce", {"set_tweak": "highlight", "value": true}, {"set_tweak": "highlight", "value": false}, {"set_tweak": "rate_limit", "value": 20}]"#,
+        ))
+        .build()
+        .unwrap();
+    let summary = |body: &'static str| {
+        evaluator_for_event(flattened_event(&[("content.body", body)])).run_summary(
+            &rules,
+            Some("@bob:test"),
+            None,
+        )
+    };
+
+    // The last sound tweak wins, and a highlight tweak without a value
+    // highlights.
+    assert_eq!(
+        summary("coffee?"),
+        NotificationSummary {
+            notify: true,
+            highlight: true,
+            sound: Some("ring".to_string()),
+            rate_limit: None,
+        }
+    );
+
+    // The last highlight tweak wins, the rate limit is passed through, and
+    // `coalesce` doesn't notify.
+    assert_eq!(
+        summary("tea?"),
+        NotificationSummary {
+            notify: false,
+            highlight: false,
+            sound: None,
+            rate_limit: Some(20),
+        }
+    );
+
+    assert_eq!(summary("water?"), NotificationSummary::default());
+
+    // Summarizing the raw actions agrees.
+    assert!(!NotificationSummary::from_actions(&[Action::Coalesce]).notify);
+    assert!(NotificationSummary::from_actions(&[Action::Coalesce, Action::Notify]).notify);
+}
+
+#[test]
+fn test_run_tweaks() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["notify", {"set_tweak": "sound", "value": "default"}, {"set_tweak": "sound", "value": "ring"}, {"set_tweak": "highlight"}]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/tea",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "tea"}]"#,
+            r#"["notify", {"set_tweak": "highlight", "value": true}, {"set_tweak": "highlight", "value": false}, {"set_tweak": "org.example.colour", "value": "green"}, {"set_tweak": "org.example.led", "value": {"blink": true}}]"#,
+        ))
+        .build()
+        .unwrap();
+    let tweaks = |body: &'static str| {
+        evaluator_for_event(flattened_event(&[("content.body", body)])).run_tweaks(
+            &rules,
+            Some("@bob:test"),
+            None,
+        )
+    };
+
+    // The last sound tweak wins, and the highlight tweak has no value.
+    assert_eq!(
+        tweaks("coffee?"),
+        BTreeMap::from([
+            ("highlight".to_string(), None),
+            (
+                "sound".to_string(),
+                Some(SimpleJsonValue::Str(Cow::Borrowed("ring")))
+            ),
+        ])
+    );
+
+    // The last highlight tweak wins, custom tweaks are included, and those with
+    // values which aren't simple are skipped.
+    assert_eq!(
+        tweaks("tea?"),
+        BTreeMap::from([
+            ("highlight".to_string(), Some(SimpleJsonValue::Bool(false))),
+            (
+                "org.example.colour".to_string(),
+                Some(SimpleJsonValue::Str(Cow::Borrowed("green")))
+            ),
+        ])
+    );
+
+    // Only the tweaks of the first matching rule apply.
+    assert_eq!(tweaks("coffee or tea?"), tweaks("coffee?"));
+
+    assert!(tweaks("water?").is_empty());
+}
+
+#[test]
+fn test_run_raw() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["dont_notify", "coalesce", {"set_tweak": "sound", "value": "default"}]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/tea",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "tea"}]"#,
+            r#"["notify"]"#,
+        ))
+        .build()
+        .unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee or tea?")]));
+
+    // The actions of the first matching rule are returned as is.
+    let raw: Vec<Action> = serde_json::from_str(
+        r#"["dont_notify", "coalesce", {"set_tweak": "sound", "value": "default"}]"#,
+    )
+    .unwrap();
+    assert_eq!(evaluator.run_raw(&rules, None, None), raw);
+
+    // Whereas `run` filters them.
+    let stored: Vec<Action> =
+        serde_json::from_str(r#"[{"set_tweak": "sound", "value": "default"}]"#).unwrap();
+    assert_eq!(evaluator.run(&rules, None, None), stored);
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "water?")]));
+    assert!(evaluator.run_raw(&rules, None, None).is_empty());
+}
+
+#[test]
+fn test_run_borrowed() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["dont_notify", "coalesce", {"set_tweak": "sound", "value": "default"}]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/tea",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "tea"}]"#,
+            r#"["notify", {"set_tweak": "highlight"}, "dont_notify"]"#,
+        ))
+        .build()
+        .unwrap();
+
+    // The borrowed actions are those `run` returns, whichever rule matches
+    // (including the base rules) and whether or not any does.
+    for body in [
+        "coffee?",
+        "tea?",
+        "coffee or tea?",
+        "hello bob",
+        "water?",
+        "",
+    ] {
+        let evaluator = evaluator_for_event(flattened_event(&[
+            ("type", "m.room.message"),
+            ("content.msgtype", "m.text"),
+            ("content.body", body),
+        ]));
+        let borrowed: Vec<&Action> = evaluator
+            .run_borrowed(&rules, Some("@bob:test"), Some("bob"))
+            .collect();
+        let owned = evaluator.run(&rules, Some("@bob:test"), Some("bob"));
+        assert_eq!(borrowed, owned.iter().collect::<Vec<_>>(), "{body:?}");
+    }
+
+    // The actions outlive the evaluator.
+    let actions: Vec<&Action> = {
+        let evaluator = evaluator_for_event(flattened_event(&[("content.body", "tea?")]));
+        evaluator.run_borrowed(&rules, None, None).collect()
+    };
+    assert_eq!(actions.len(), 2);
+}
+
+#[test]
+fn test_run_outcome() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["dont_notify"]"#,
+        ))
+        .build()
+        .unwrap();
+
+    // A rule which explicitly doesn't notify is distinguished from no rule
+    // matching, even though `run` returns no actions for either.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+    assert!(evaluator.run(&rules, None, None).is_empty());
+    let outcome = evaluator.run_outcome(&rules, None, None);
+    assert_eq!(
+        outcome,
+        RunOutcome::Matched {
+            rule_id: "global/content/coffee".to_string(),
+            actions: vec![],
+        }
+    );
+    assert_eq!(outcome.rule_id(), Some("global/content/coffee"));
+    assert_eq!(
+        serde_json::to_value(&outcome).unwrap(),
+        serde_json::json!({
+            "outcome": "matched",
+            "rule_id": "global/content/coffee",
+            "actions": [],
+        })
+    );
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "tea?")]));
+    assert!(evaluator.run(&rules, None, None).is_empty());
+    let outcome = evaluator.run_outcome(&rules, None, None);
+    assert_eq!(outcome, RunOutcome::Default);
+    assert!(outcome.actions().is_empty());
+    assert_eq!(outcome.rule_id(), None);
+    assert_eq!(
+        serde_json::to_value(&outcome).unwrap(),
+        serde_json::json!({"outcome": "default"})
+    );
+}
+
+#[test]
+fn test_run_timed() {
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.text"),
+        ("content.body", "hello bob"),
+    ]));
+    let rules = FilteredPushRules::default();
+
+    let timed_run = evaluator.run_timed(&rules, Some("@bob:example.org"), Some("bob"));
+    assert_eq!(
+        timed_run.actions,
+        evaluator.run(&rules, Some("@bob:example.org"), Some("bob"))
+    );
+    let (_, slowest_elapsed) = timed_run.slowest_rule.unwrap();
+    assert!(slowest_elapsed <= timed_run.elapsed);
+
+    // Disabling every rule means none are evaluated.
+    let rules = without_base_rules().build().unwrap();
+    let timed_run = evaluator.run_timed(&rules, Some("@bob:example.org"), Some("bob"));
+    assert!(timed_run.actions.is_empty());
+    assert_eq!(timed_run.slowest_rule, None);
+}
+
+#[test]
+fn test_master_rule_suppresses_other_rules() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = |master_enabled: bool| {
+        FilteredPushRulesBuilder::new()
+            .push_rule(db_rule(
+                "global/content/coffee",
+                4,
+                r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+                r#"["notify", {"set_tweak": "highlight"}]"#,
+            ))
+            .enabled("global/override/.m.rule.master", master_enabled)
+            .build()
+            .unwrap()
+    };
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+
+    assert!(!evaluator.run(&rules(false), None, None).is_empty());
+
+    // The master rule comes before every other rule and matches every event,
+    // so when enabled it stops any other rule from applying.
+    assert!(evaluator.run(&rules(true), None, None).is_empty());
+    assert!(evaluator.run_raw(&rules(true), None, None).is_empty());
+    assert_eq!(
+        evaluator.run_with_matched_rule(&rules(true), None, None),
+        Some(("global/override/.m.rule.master".to_string(), vec![]))
+    );
+}
+
+#[test]
+fn test_rule_priority_order() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    // A rule of each priority class matching the same event, given lowest
+    // priority first.
+    let rule_ids = [
+        "global/underride/coffee",
+        "global/sender/@alice:example.org",
+        "global/room/!room:example.org",
+        "global/content/coffee",
+        "global/override/coffee",
+    ];
+    let rules: Vec<_> = rule_ids
+        .iter()
+        .enumerate()
+        .map(|(priority_class, rule_id)| {
+            db_rule(
+                *rule_id,
+                priority_class as i32 + 1,
+                r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+                r#"["notify"]"#,
+            )
+        })
+        .collect();
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+
+    // Disabling the highest priority rule in turn gives the next one.
+    for (disabled, expected) in rule_ids.iter().rev().enumerate() {
+        let push_rules = rule_ids
+            .iter()
+            .rev()
+            .take(disabled)
+            .fold(
+                rules.iter().cloned().fold(
+                    FilteredPushRulesBuilder::new(),
+                    FilteredPushRulesBuilder::push_rule,
+                ),
+                |builder, rule_id| builder.enabled(*rule_id, false),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(
+            evaluator
+                .run_with_matched_rule(&push_rules, None, None)
+                .map(|(rule_id, _)| rule_id)
+                .as_deref(),
+            Some(*expected)
+        );
+    }
+}
+
+#[test]
+fn test_run_with_disabled_rules() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rule = |rule_id: &str, pattern: &str, actions: &str| {
+        db_rule(
+            rule_id,
+            4,
+            &format!(
+                r#"[{{"kind": "event_match", "key": "content.body", "pattern": "{pattern}"}}]"#
+            ),
+            actions,
+        )
+    };
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule("global/content/coffee", "coffee", r#"["notify"]"#))
+        .push_rule(rule(
+            "global/content/tea",
+            "tea",
+            r#"["notify", {"set_tweak": "highlight"}]"#,
+        ))
+        .push_rule(rule("global/content/water", "water", r#"["notify"]"#))
+        .enabled("global/content/water", false)
+        .build()
+        .unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "coffee, tea or water?",
+    )]));
+    let disabled = |rule_ids: &[&str]| rule_ids.iter().map(|id| id.to_string()).collect();
+
+    assert_eq!(
+        evaluator.run_with_disabled_rules(&rules, None, None, disabled(&[])),
+        vec![Action::Notify]
+    );
+    // Disabling the first matching rule falls through to the next one.
+    let tea_actions: Vec<Action> =
+        serde_json::from_str(r#"["notify", {"set_tweak": "highlight"}]"#).unwrap();
+    assert_eq!(
+        evaluator.run_with_disabled_rules(
+            &rules,
+            None,
+            None,
+            disabled(&["global/content/coffee", "global/content/unknown"])
+        ),
+        tea_actions
+    );
+
+    // Rules disabled in the push rules stay disabled.
+    assert!(evaluator
+        .run_with_disabled_rules(
+            &rules,
+            None,
+            None,
+            disabled(&["global/content/coffee", "global/content/tea"])
+        )
+        .is_empty());
+}
+
+#[test]
+fn test_run_matches_repeated_conditions_once() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::push::FilteredPushRulesBuilder;
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    let counter = calls.clone();
+    evaluator.register_custom_condition(
+        "org.example.counted".to_string(),
+        Box::new(move |_: &Value, _: Option<&str>, _: Option<&str>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(true)
+        }),
+    );
+
+    let rule = |rule_id: &str, conditions: &str| db_rule(rule_id, 5, conditions, r#"["notify"]"#);
+    let counted = r#"{"kind": "org.example.counted"}"#;
+    let not_a_member = r#"{"kind": "event_match", "key": "type", "pattern": "m.room.member"}"#;
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule(
+            "global/override/first",
+            &format!("[{counted}, {not_a_member}]"),
+        ))
+        .push_rule(rule(
+            "global/override/second",
+            &format!("[{not_a_member}, {counted}]"),
+        ))
+        .push_rule(rule("global/override/third", &format!("[{counted}]")))
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        evaluator
+            .run_with_matched_rule(&rules, None, None)
+            .map(|(rule_id, _)| rule_id),
+        Some("global/override/third".to_string())
+    );
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // Results aren't remembered between runs.
+    evaluator.run(&rules, None, None);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_run_batch() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rule = db_rule(
+        "global/content/coffee",
+        4,
+        r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+        r#"["notify"]"#,
+    );
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule)
+        .build()
+        .unwrap();
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee time")]));
+
+    let events = vec![
+        (flattened_event(&[("content.body", "tea?")]), false),
+        (flattened_event(&[("content.body", "coffee?")]), false),
+        (flattened_event(&[]), false),
+    ];
+    let results = evaluator.run_batch(events, &rules, Some("@bob:test"), None);
+
+    // Results are in the same order as the events.
+    assert_eq!(results.len(), 3);
+    assert!(!results[0].contains(&Action::Notify));
+    assert!(results[1].contains(&Action::Notify));
+    assert!(!results[2].contains(&Action::Notify));
+
+    // The evaluator's own event is unaffected.
+    assert!(evaluator
+        .run(&rules, Some("@bob:test"), None)
+        .contains(&Action::Notify));
+
+    // Whether each event has mentions is taken from the batch, so the legacy
+    // mention rules only apply to the events without them.
+    let events = vec![
+        (flattened_event(&[("content.body", "hi bob")]), false),
+        (flattened_event(&[("content.body", "hi bob")]), true),
+    ];
+    let results = evaluator.run_batch(
+        events,
+        &FilteredPushRules::default(),
+        Some("@bob:test"),
+        None,
+    );
+    assert!(results[0].contains(&Action::Notify));
+    assert!(!results[1].contains(&Action::Notify));
+}
+
+#[test]
+fn test_event_property_is_condition() {
+    let property_is = |key: &'static str, value: SimpleJsonValue| {
+        Condition::Known(KnownCondition::EventPropertyIs(EventPropertyIsCondition {
+            key: Cow::Borrowed(key),
+            value: Cow::Owned(value),
+        }))
+    };
+
+    let evaluator = evaluator_for_event(BTreeMap::from([
+        (
+            "content.msgtype".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("m.text"))),
+        ),
+        (
+            "content.int".into(),
+            JsonValue::Value(SimpleJsonValue::Int(5)),
+        ),
+        (
+            "content.string".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("5"))),
+        ),
+        (
+            "content.bool".into(),
+            JsonValue::Value(SimpleJsonValue::Bool(true)),
+        ),
+    ]));
+    let matches = |key, value| {
+        evaluator
+            .match_condition(&property_is(key, value), None, None)
+            .unwrap()
+    };
+
+    // Values are compared exactly, without globbing or case folding.
+    assert!(matches(
+        "content.msgtype",
+        SimpleJsonValue::Str(Cow::Borrowed("m.text"))
+    ));
+    assert!(!matches(
+        "content.msgtype",
+        SimpleJsonValue::Str(Cow::Borrowed("m.*"))
+    ));
+    assert!(!matches(
+        "content.msgtype",
+        SimpleJsonValue::Str(Cow::Borrowed("M.TEXT"))
+    ));
+
+    // Values are never coerced between types.
+    assert!(matches("content.int", SimpleJsonValue::Int(5)));
+    assert!(!matches(
+        "content.int",
+        SimpleJsonValue::Str(Cow::Borrowed("5"))
+    ));
+    assert!(!matches("content.string", SimpleJsonValue::Int(5)));
+    assert!(matches("content.bool", SimpleJsonValue::Bool(true)));
+    assert!(!matches("content.bool", SimpleJsonValue::Bool(false)));
+    assert!(!matches("content.bool", SimpleJsonValue::Int(1)));
+    assert!(!matches(
+        "content.bool",
+        SimpleJsonValue::Str(Cow::Borrowed("true"))
+    ));
+    assert!(!matches("content.missing", SimpleJsonValue::Null));
+}
+
+#[test]
+fn test_event_property_in_condition() {
+    let condition: Condition = serde_json::from_str(
+        r#"{"kind": "io.element.event_property_in", "key": "content.msgtype", "values": ["m.text", "m.notice", 1]}"#,
+    )
+    .unwrap();
+    let matches = |value: SimpleJsonValue| {
+        evaluator_for_event(BTreeMap::from([(
+            "content.msgtype".into(),
+            JsonValue::Value(value),
+        )]))
+        .match_condition(&condition, None, None)
+        .unwrap()
+    };
+
+    // Membership.
+    assert!(matches(SimpleJsonValue::Str(Cow::Borrowed("m.text"))));
+    assert!(matches(SimpleJsonValue::Str(Cow::Borrowed("m.notice"))));
+    assert!(matches(SimpleJsonValue::Int(1)));
+
+    // Non-membership, compared exactly.
+    assert!(!matches(SimpleJsonValue::Str(Cow::Borrowed("m.emote"))));
+    assert!(!matches(SimpleJsonValue::Str(Cow::Borrowed("M.TEXT"))));
+    assert!(!matches(SimpleJsonValue::Str(Cow::Borrowed("m.*"))));
+
+    // Type mismatches.
+    assert!(!matches(SimpleJsonValue::Str(Cow::Borrowed("1"))));
+    assert!(!matches(SimpleJsonValue::Int(2)));
+    assert!(!matches(SimpleJsonValue::Bool(true)));
+    assert!(!matches(SimpleJsonValue::Null));
+
+    // Missing keys and arrays aren't in the set.
+    assert!(!evaluator_for_event(BTreeMap::new())
+        .match_condition(&condition, None, None)
+        .unwrap());
+    assert!(!evaluator_for_event(BTreeMap::from([(
+        "content.msgtype".into(),
+        JsonValue::Array(vec![SimpleJsonValue::Str(Cow::Borrowed("m.text"))]),
+    )]))
+    .match_condition(&condition, None, None)
+    .unwrap());
+}
+
+#[test]
+fn test_event_property_contains_condition() {
+    let contains = |value: SimpleJsonValue| {
+        Condition::Known(KnownCondition::EventPropertyContains(
+            EventPropertyIsCondition {
+                key: Cow::Borrowed("content.tags"),
+                value: Cow::Owned(value),
+            },
+        ))
+    };
+
+    let evaluator = evaluator_for_event(BTreeMap::from([
+        (
+            "content.tags".into(),
+            JsonValue::Array(vec![
+                SimpleJsonValue::Str(Cow::Borrowed("urgent")),
+                SimpleJsonValue::Int(5),
+                SimpleJsonValue::Bool(true),
+            ]),
+        ),
+        (
+            "content.single".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("urgent"))),
+        ),
+    ]));
+
+    for value in [
+        SimpleJsonValue::Str(Cow::Borrowed("urgent")),
+        SimpleJsonValue::Int(5),
+        SimpleJsonValue::Bool(true),
+    ] {
+        assert!(evaluator
+            .match_condition(&contains(value), None, None)
+            .unwrap());
+    }
+
+    // Values of a different type never match.
+    for value in [
+        SimpleJsonValue::Str(Cow::Borrowed("5")),
+        SimpleJsonValue::Int(1),
+        SimpleJsonValue::Str(Cow::Borrowed("true")),
+        SimpleJsonValue::Null,
+    ] {
+        assert!(!evaluator
+            .match_condition(&contains(value), None, None)
+            .unwrap());
+    }
+
+    // Only arrays are searched.
+    let condition = Condition::Known(KnownCondition::EventPropertyContains(
+        EventPropertyIsCondition {
+            key: Cow::Borrowed("content.single"),
+            value: Cow::Owned(SimpleJsonValue::Str(Cow::Borrowed("urgent"))),
+        },
+    ));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_is_voice_message_condition() {
+    let condition = Condition::Known(KnownCondition::IsVoiceMessage);
+
+    // A voice message as flattened by `_flatten_dict`, i.e. with the empty
+    // marker object recorded as an empty string next to the nested audio info.
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.audio"),
+        ("content.body", "Voice message"),
+        ("content.url", "mxc://test/voice"),
+        (r"content.org\.matrix\.msc1767\.audio.duration", "1000"),
+        (r"content.org\.matrix\.msc3245\.voice", ""),
+    ]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Without the marker it's regular audio.
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.audio"),
+        ("content.body", "Some audio"),
+        (r"content.org\.matrix\.msc1767\.audio.duration", "1000"),
+    ]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.msgtype", "m.audio"),
+        (r"content.org\.matrix\.msc3245\.voice.duration", "1000"),
+    ]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Regular audio doesn't match, even if a similarly named key is present.
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.msgtype", "m.audio"),
+        (r"content.org\.matrix\.msc3245\.voiceover", "true"),
+    ]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Neither does a text message which carries the marker.
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.msgtype", "m.text"),
+        (r"content.org\.matrix\.msc3245\.voice", ""),
+    ]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_custom_condition_evaluator() {
+    let evaluator_with_result = |result: bool| {
+        let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+        evaluator.register_custom_condition(
+            "org.example.custom".to_string(),
+            Box::new(move |_: &Value, _: Option<&str>, _: Option<&str>| Ok(result)),
+        );
+        evaluator
+    };
+
+    let custom: Condition = serde_json::from_str(r#"{"kind":"org.example.custom"}"#).unwrap();
+    let unregistered: Condition = serde_json::from_str(r#"{"kind":"org.example.other"}"#).unwrap();
+
+    let evaluator = evaluator_with_result(true);
+    assert!(evaluator.match_condition(&custom, None, None).unwrap());
+    assert!(!evaluator
+        .match_condition(&unregistered, None, None)
+        .unwrap());
+
+    let evaluator = evaluator_with_result(false);
+    assert!(!evaluator.match_condition(&custom, None, None).unwrap());
+
+    // The raw condition and the user are passed through to the evaluator.
+    let mut evaluator = evaluator_for_event(BTreeMap::new());
+    evaluator.register_custom_condition(
+        "org.example.custom".to_string(),
+        Box::new(
+            |condition: &Value, user_id: Option<&str>, _: Option<&str>| {
+                Ok(condition["value"] == "bob" && user_id == Some("@bob:example.org"))
+            },
+        ),
+    );
+    let custom: Condition =
+        serde_json::from_str(r#"{"kind":"org.example.custom","value":"bob"}"#).unwrap();
+    assert!(evaluator
+        .match_condition(&custom, Some("@bob:example.org"), None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&custom, Some("@alice:example.org"), None)
+        .unwrap());
+}
+
+#[test]
+fn test_unknown_condition_matches() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/override/future",
+            5,
+            r#"[{"kind": "org.example.future"}, {"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["notify"]"#,
+        ))
+        .build()
+        .unwrap();
+    let matched_rule = |evaluator: &PushRuleEvaluator| {
+        evaluator
+            .run_with_matched_rule(&rules, None, None)
+            .map(|(rule_id, _)| rule_id)
+    };
+
+    // By default unknown conditions fail closed.
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+    assert_eq!(matched_rule(&evaluator), None);
+
+    // Otherwise they fail open, and the rule matches if its other conditions do.
+    evaluator.unknown_condition_matches = true;
+    assert_eq!(
+        matched_rule(&evaluator),
+        Some("global/override/future".to_string())
+    );
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "tea?")]));
+    evaluator.unknown_condition_matches = true;
+    assert_eq!(matched_rule(&evaluator), None);
+
+    // Custom evaluators still take precedence.
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+    evaluator.unknown_condition_matches = true;
+    evaluator.register_custom_condition(
+        "org.example.future".to_string(),
+        Box::new(|_: &Value, _: Option<&str>, _: Option<&str>| Ok(false)),
+    );
+    assert_eq!(matched_rule(&evaluator), None);
+
+    // Malformed conditions of a kind we recognize don't fail open.
+    let condition: Condition =
+        serde_json::from_str(r#"{"kind": "room_member_count", "is": 2}"#).unwrap();
+    assert!(matches!(condition, Condition::Unknown(_)));
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+    evaluator.unknown_condition_matches = true;
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_at_handle_count_condition() {
+    let at_least_three = Condition::Known(KnownCondition::AtHandleCount {
+        is: Some(Cow::Borrowed(">=3")),
+    });
+    let none = Condition::Known(KnownCondition::AtHandleCount {
+        is: Some(Cow::Borrowed("0")),
+    });
+
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "no handles here, mail me at bob@example.org",
+    )]));
+    assert!(evaluator.match_condition(&none, None, None).unwrap());
+    assert!(!evaluator
+        .match_condition(&at_least_three, None, None)
+        .unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "hi @alice:example.org",
+    )]));
+    assert!(!evaluator.match_condition(&none, None, None).unwrap());
+    assert!(!evaluator
+        .match_condition(&at_least_three, None, None)
+        .unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "@alice @bob, (@carol) and @@dave",
+    )]));
+    assert!(evaluator
+        .match_condition(&at_least_three, None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_mention_count_condition() {
+    let mention_count = |user_ids: Option<Vec<String>>, is: &'static str| {
+        let mut flattened_keys = flattened_event(&[("content.body", "hello")]);
+        if let Some(user_ids) = user_ids {
+            flattened_keys.insert(
+                MENTIONS_USER_IDS_KEY.into(),
+                JsonValue::Array(
+                    user_ids
+                        .into_iter()
+                        .map(|user_id| SimpleJsonValue::Str(Cow::Owned(user_id)))
+                        .collect(),
+                ),
+            );
+        }
+        evaluator_for_event(flattened_keys)
+            .match_condition(
+                &Condition::Known(KnownCondition::MentionCount {
+                    is: Some(Cow::Borrowed(is)),
+                }),
+                None,
+                None,
+            )
+            .unwrap()
+    };
+    let users = |n: usize| Some((0..n).map(|i| format!("@user{i}:test")).collect());
+
+    // No mentions, either because there are none or because they're absent.
+    assert!(mention_count(users(0), "0"));
+    assert!(mention_count(None, "0"));
+    assert!(!mention_count(None, ">0"));
+
+    // A few mentions, where repeated users only count once.
+    let few = Some(vec![
+        "@alice:test".to_string(),
+        "@bob:test".to_string(),
+        "@alice:test".to_string(),
+    ]);
+    assert!(mention_count(few.clone(), "2"));
+    assert!(!mention_count(few, ">10"));
+
+    // Mass mentions.
+    assert!(mention_count(users(11), ">10"));
+    assert!(mention_count(users(150), ">10"));
+    assert!(!mention_count(users(10), ">10"));
+}
+
+#[test]
+fn test_body_length_condition() {
+    let condition = |is: &'static str| {
+        Condition::Known(KnownCondition::BodyLength {
+            is: Some(Cow::Borrowed(is)),
+        })
+    };
+
+    // Five characters, but more bytes.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "héllö")]));
+    assert!(evaluator
+        .match_condition(&condition("<=5"), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition("<5"), None, None)
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&condition("5"), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition(">5"), None, None)
+        .unwrap());
+
+    // An empty body has a length, a missing one doesn't.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "")]));
+    assert!(evaluator
+        .match_condition(&condition("0"), None, None)
+        .unwrap());
+    let evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.member")]));
+    assert!(!evaluator
+        .match_condition(&condition("0"), None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_run_canonical() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let actions: Vec<Action> = serde_json::from_str(
+        r#"[{"set_tweak": "sound", "value": "default"}, {"set_tweak": "highlight"}, "notify"]"#,
+    )
+    .unwrap();
+    let rule = PushRule {
+        rule_id: Cow::Borrowed("global/override/.org.example.tweaks_first"),
+        priority_class: 5,
+        conditions: Cow::Borrowed(&[]),
+        actions: Cow::Owned(actions.clone()),
+        default: false,
+        default_enabled: true,
+    };
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule)
+        .build()
+        .unwrap();
+    let evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+
+    // `run` keeps the rule's order, `run_canonical` puts `notify` first and
+    // sorts the tweaks by name.
+    assert_eq!(evaluator.run(&rules, None, None), actions);
+    assert_eq!(
+        evaluator.run_canonical(&rules, None, None),
+        vec![actions[2].clone(), actions[1].clone(), actions[0].clone()]
+    );
+}
+
+#[test]
+fn test_room_notifications_today_condition() {
+    let quiet = Condition::Known(KnownCondition::RoomNotificationsToday {
+        is: Some(Cow::Borrowed("<5")),
+    });
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    // The condition never matches if the count isn't known.
+    assert!(!evaluator.match_condition(&quiet, None, None).unwrap());
+
+    evaluator.room_notifications_today = Some(2);
+    assert!(evaluator.match_condition(&quiet, None, None).unwrap());
+
+    evaluator.room_notifications_today = Some(40);
+    assert!(!evaluator.match_condition(&quiet, None, None).unwrap());
+}
+
+#[test]
+fn test_is_unstable_event_type_condition() {
+    let default_prefixes: Condition =
+        serde_json::from_str(r#"{"kind":"io.element.is_unstable_event_type"}"#).unwrap();
+    let custom_prefixes: Condition = serde_json::from_str(
+        r#"{"kind":"io.element.is_unstable_event_type","prefixes":["io.element."]}"#,
+    )
+    .unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    assert!(!evaluator
+        .match_condition(&default_prefixes, None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&custom_prefixes, None, None)
+        .unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[("type", "org.matrix.msc3401.call")]));
+    assert!(evaluator
+        .match_condition(&default_prefixes, None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&custom_prefixes, None, None)
+        .unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[("type", "io.element.widgets.layout")]));
+    assert!(!evaluator
+        .match_condition(&default_prefixes, None, None)
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&custom_prefixes, None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_sender_event_rate_condition() {
+    let flooding = Condition::Known(KnownCondition::SenderEventRate {
+        is: Some(Cow::Borrowed(">10")),
+    });
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    assert!(!evaluator.match_condition(&flooding, None, None).unwrap());
+
+    evaluator.sender_event_rate = Some(3);
+    assert!(!evaluator.match_condition(&flooding, None, None).unwrap());
+
+    evaluator.sender_event_rate = Some(25);
+    assert!(evaluator.match_condition(&flooding, None, None).unwrap());
+}
+
+#[test]
+fn test_run_top_n() {
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.text"),
+        ("content.body", "hello bob"),
+    ]));
+    let rules = FilteredPushRules::default();
+
+    // The display name rule comes before the catch-all message rule.
+    let matches = evaluator.run_top_n(5, &rules, Some("@bob:example.org"), Some("bob"));
+    let rule_ids: Vec<_> = matches
+        .iter()
+        .map(|(rule_id, _)| rule_id.as_str())
+        .collect();
+    assert_eq!(
+        rule_ids,
+        [
+            "global/override/.m.rule.contains_display_name",
+            "global/content/.m.rule.contains_user_name",
+            "global/underride/.m.rule.message",
+        ]
+    );
+
+    // Results are bounded by `n`, and the first is what `run` returns.
+    let matches = evaluator.run_top_n(1, &rules, Some("@bob:example.org"), Some("bob"));
+    assert_eq!(matches.len(), 1);
+    assert_eq!(
+        matches[0].1,
+        evaluator.run(&rules, Some("@bob:example.org"), Some("bob"))
+    );
+    assert!(evaluator
+        .run_top_n(0, &rules, Some("@bob:example.org"), Some("bob"))
+        .is_empty());
+}
+
+#[test]
+fn test_run_all_matches() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.text"),
+        ("content.body", "hello bob"),
+    ]));
+    let rules = FilteredPushRules::default();
+
+    let matches = evaluator.run_all_matches(&rules, Some("@bob:example.org"), Some("bob"));
+    assert_eq!(
+        matches,
+        evaluator.run_top_n(usize::MAX, &rules, Some("@bob:example.org"), Some("bob"))
+    );
+    assert_eq!(matches.len(), 3);
+
+    // `run` still only returns the actions of the first match.
+    assert_eq!(
+        matches[0].1,
+        evaluator.run(&rules, Some("@bob:example.org"), Some("bob"))
+    );
+
+    // Disabled rules aren't included.
+    let rules = FilteredPushRulesBuilder::new()
+        .enabled("global/content/.m.rule.contains_user_name", false)
+        .build()
+        .unwrap();
+    let rule_ids: Vec<_> = evaluator
+        .run_all_matches(&rules, Some("@bob:example.org"), Some("bob"))
+        .into_iter()
+        .map(|(rule_id, _)| rule_id)
+        .collect();
+    assert_eq!(
+        rule_ids,
+        [
+            "global/override/.m.rule.contains_display_name",
+            "global/underride/.m.rule.message",
+        ]
+    );
+}
+
+#[test]
+fn test_user_localpart_case_insensitive() {
+    let rules = FilteredPushRules::default();
+    let highlights = |body: &'static str, user_id: &str| {
+        let evaluator = evaluator_for_event(flattened_event(&[
+            ("type", "m.room.message"),
+            ("content.msgtype", "m.text"),
+            ("content.body", body),
+        ]));
+        evaluator
+            .run_all_matches(&rules, Some(user_id), None)
+            .iter()
+            .any(|(rule_id, _)| rule_id == "global/content/.m.rule.contains_user_name")
+    };
+
+    assert!(highlights("hey ALICE, are you there?", "@alice:test"));
+    assert!(highlights("hey alice", "@Alice:test"));
+    assert!(highlights("ÉMILE!", "@émile:test"));
+    assert!(!highlights("hey ALICEANDBOB", "@alice:test"));
+}
+
+#[test]
+fn test_body_contains_code_condition() {
+    let condition = Condition::Known(KnownCondition::BodyContainsCode);
+
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "try this:\n```\ncargo test\n```",
+    )]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "run `cargo test`")]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.body", "run cargo test"),
+        ("content.formatted_body", "run <code>cargo test</code>"),
+    ]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Plain text, including a lone backtick, doesn't match.
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.body", "it's a plain `message"),
+        ("content.formatted_body", "it's a <b>plain</b> message"),
+    ]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_has_formatted_body_condition() {
+    let condition = Condition::Known(KnownCondition::HasFormattedBody);
+    let matches = |event: &[(&str, &'static str)]| {
+        evaluator_for_event(flattened_event(event))
+            .match_condition(&condition, None, None)
+            .unwrap()
+    };
+
+    assert!(matches(&[
+        ("content.body", "hello *world*"),
+        ("content.formatted_body", "hello <em>world</em>"),
+    ]));
+    assert!(!matches(&[("content.body", "hello world")]));
+    // An empty formatted body is the same as none.
+    assert!(!matches(&[
+        ("content.body", "hello world"),
+        ("content.formatted_body", ""),
+    ]));
+}
+
+#[test]
+fn test_condition_match_budget() {
+    use crate::push::EventMatchCondition;
+
+    let body = format!("{}needle", "hay ".repeat(10_000));
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        "content.body".into(),
+        JsonValue::Value(SimpleJsonValue::Str(Cow::Owned(body))),
+    );
+    let condition = Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
+        key: EventMatchKey::Single(Cow::Borrowed("content.body")),
+        pattern: Cow::Borrowed("ne*le"),
+        ignore_quotes: false,
+        match_words: None,
+        contains: false,
+    }));
+
+    let mut evaluator = evaluator_for_event(flattened_keys);
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.condition_match_budget = Some(100_000);
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    // The large body can't be matched within the budget, so the condition is
+    // treated as not matching, without scanning the body.
+    evaluator.condition_match_budget = Some(1_000);
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Nested conditions share the budget, so it isn't reset by `not`.
+    let not = Condition::Known(KnownCondition::Not {
+        condition: Box::new(condition),
+    });
+    assert!(!evaluator.match_condition(&not, None, None).unwrap());
+
+    // Scans of the body other than globs are charged to the budget too.
+    let body = format!("`code` @bob {}", "hay ".repeat(10_000));
+    let mut flattened_keys = BTreeMap::new();
+    flattened_keys.insert(
+        "content.body".into(),
+        JsonValue::Value(SimpleJsonValue::Str(Cow::Owned(body))),
+    );
+    let mut evaluator = evaluator_for_event(flattened_keys);
+    for condition in [
+        KnownCondition::BodyContainsCode,
+        KnownCondition::AtHandleCount {
+            is: Some(Cow::Borrowed(">=1")),
+        },
+    ] {
+        let condition = Condition::Known(condition);
+        evaluator.condition_match_budget = None;
+        assert!(evaluator.match_condition(&condition, None, None).unwrap());
+        evaluator.condition_match_budget = Some(1_000);
+        assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+    }
+}
+
+#[test]
+fn test_sender_verified_condition() {
+    let condition = Condition::Known(KnownCondition::SenderVerified);
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.sender_verified = true;
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_mentions_room_name_condition() {
+    let condition = Condition::Known(KnownCondition::MentionsRoomName);
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "has anyone seen the Rust Lounge today?",
+    )]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.room_name = Some(String::new());
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.room_name = Some("Rust Lounge".to_string());
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.room_name = Some("Python Lounge".to_string());
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Room names are matched literally, rather than as globs.
+    let mut evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "welcome to [dev] chat, or d chat",
+    )]));
+    evaluator.room_name = Some("[dev] chat".to_string());
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+    evaluator.room_name = Some("[d] chat".to_string());
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+    evaluator.room_name = Some("w* chat".to_string());
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_device_type_match_condition() {
+    let condition = Condition::Known(KnownCondition::DeviceTypeMatch {
+        device_type: Cow::Borrowed("mobile"),
+    });
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.device_type = Some("mobile".to_string());
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.device_type = Some("desktop".to_string());
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_body_sentiment_condition() {
+    let negative: Condition =
+        serde_json::from_str(r#"{"kind":"io.element.body_sentiment","polarity":"negative"}"#)
+            .unwrap();
+    let positive: Condition =
+        serde_json::from_str(r#"{"kind":"io.element.body_sentiment","polarity":"positive"}"#)
+            .unwrap();
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "this is awful, you are pathetic",
+    )]));
+    // The condition is off by default.
+    assert!(!evaluator.match_condition(&negative, None, None).unwrap());
+
+    evaluator.body_sentiment_enabled = true;
+    assert!(evaluator.match_condition(&negative, None, None).unwrap());
+    assert!(!evaluator.match_condition(&positive, None, None).unwrap());
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "thanks, that was wonderful",
+    )]));
+    evaluator.body_sentiment_enabled = true;
+    assert!(!evaluator.match_condition(&negative, None, None).unwrap());
+    assert!(evaluator.match_condition(&positive, None, None).unwrap());
+}
+
+#[test]
+fn test_quotes_me_condition() {
+    let condition = Condition::Known(KnownCondition::QuotesMe);
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "> <@bob:example.org> shall we get lunch at noon?\n\nsounds good!",
+    )]));
+    assert!(!evaluator
+        .match_condition(&condition, Some("@alice:example.org"), None)
+        .unwrap());
+
+    evaluator.recent_user_messages = BTreeMap::from([(
+        "@alice:example.org".to_string(),
+        vec![
+            "morning all".to_string(),
+            "shall we get lunch at noon? I'm starving".to_string(),
+        ],
+    )]);
+    assert!(evaluator
+        .match_condition(&condition, Some("@alice:example.org"), None)
+        .unwrap());
+
+    // Only the recipient's own messages count.
+    assert!(!evaluator
+        .match_condition(&condition, Some("@carol:example.org"), None)
+        .unwrap());
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Text from the user's messages which isn't quoted doesn't match.
+    let mut evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "> someone else said this\nmorning all",
+    )]));
+    evaluator.recent_user_messages = BTreeMap::from([(
+        "@alice:example.org".to_string(),
+        vec!["morning all".to_string()],
+    )]);
+    assert!(!evaluator
+        .match_condition(&condition, Some("@alice:example.org"), None)
+        .unwrap());
+}
+
+#[test]
+fn test_is_duplicate_message_condition() {
+    let condition = Condition::Known(KnownCondition::IsDuplicateMessage);
+
+    let mut evaluator =
+        evaluator_for_event(flattened_event(&[("content.body", "  Buy cheap stuff! ")]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Bodies are normalized before hashing, so differences in case and
+    // surrounding whitespace are ignored.
+    evaluator.recent_message_hashes = BTreeSet::from([
+        message_body_hash("hello"),
+        message_body_hash("buy CHEAP stuff!"),
+    ]);
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "hello there")]));
+    evaluator.recent_message_hashes = BTreeSet::from([message_body_hash("hello")]);
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    // The hash is stable and matches Python's hashlib.
+    assert_eq!(
+        message_body_hash(" Hello "),
+        "19213bacc58dee6dbde3ceb9a47cbb330b3d86f8cca8997eb00be456f140ca25"
+    );
+}
+
+#[test]
+fn test_is_thread_start_condition() {
+    let condition = Condition::Known(KnownCondition::IsThreadStart);
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[(
+        r"content.m\.relates_to.rel_type",
+        "m.thread",
+    )]));
+    evaluator.is_thread_start = true;
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Subsequent replies in the thread don't match.
+    evaluator.is_thread_start = false;
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_thread_depth_condition() {
+    let condition = |is: &'static str| {
+        Condition::Known(KnownCondition::ThreadDepth {
+            is: Some(Cow::Borrowed(is)),
+        })
+    };
+    let first_reply = condition("1");
+    let deep_reply = condition(">1");
+
+    let mut evaluator = evaluator_for_event(BTreeMap::new());
+    assert!(!evaluator
+        .match_condition(&condition(">=0"), None, None)
+        .unwrap());
+
+    for (depth, is_first_reply, is_deep_reply) in [
+        (0, false, false),
+        (1, true, false),
+        (2, false, true),
+        (50, false, true),
+    ] {
+        evaluator.thread_depth = Some(depth);
+        assert_eq!(
+            evaluator.match_condition(&first_reply, None, None).unwrap(),
+            is_first_reply,
+            "depth: {depth}"
+        );
+        assert_eq!(
+            evaluator.match_condition(&deep_reply, None, None).unwrap(),
+            is_deep_reply,
+            "depth: {depth}"
+        );
+    }
+
+    evaluator.thread_depth = Some(0);
+    assert!(evaluator
+        .match_condition(&condition("0"), None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_unread_count_condition() {
+    let condition = |kind, is: &'static str| {
+        Condition::Known(KnownCondition::UnreadCount {
+            kind,
+            is: Some(Cow::Borrowed(is)),
+        })
+    };
+    let no_notifications = condition(UnreadCountKind::Notifications, "0");
+    let many_notifications = condition(UnreadCountKind::Notifications, ">=10");
+    let no_highlights = condition(UnreadCountKind::Highlights, "<1");
+
+    // Nothing matches until the recipient's counts are known.
+    let mut evaluator = evaluator_for_event(BTreeMap::new());
+    evaluator.unread_notification_counts = BTreeMap::from([("@bob:test".to_string(), 0)]);
+    evaluator.unread_highlight_counts = BTreeMap::from([("@bob:test".to_string(), 0)]);
+    for condition in [&no_notifications, &many_notifications, &no_highlights] {
+        assert!(!evaluator
+            .match_condition(condition, Some("@alice:test"), None)
+            .unwrap());
+        // Nor without the recipient's user ID.
+        assert!(!evaluator.match_condition(condition, None, None).unwrap());
+    }
+
+    for (count, is_none, is_many) in [
+        (0, true, false),
+        (1, false, false),
+        (9, false, false),
+        (10, false, true),
+    ] {
+        evaluator
+            .unread_notification_counts
+            .insert("@alice:test".to_string(), count);
+        assert_eq!(
+            evaluator
+                .match_condition(&no_notifications, Some("@alice:test"), None)
+                .unwrap(),
+            is_none,
+            "notifications: {count}"
+        );
+        assert_eq!(
+            evaluator
+                .match_condition(&many_notifications, Some("@alice:test"), None)
+                .unwrap(),
+            is_many,
+            "notifications: {count}"
+        );
+    }
+    // The highlight count is separate from the notification count.
+    assert!(!evaluator
+        .match_condition(&no_highlights, Some("@alice:test"), None)
+        .unwrap());
+
+    for (count, is_none) in [(0, true), (1, false), (2, false)] {
+        evaluator
+            .unread_highlight_counts
+            .insert("@alice:test".to_string(), count);
+        assert_eq!(
+            evaluator
+                .match_condition(&no_highlights, Some("@alice:test"), None)
+                .unwrap(),
+            is_none,
+            "highlights: {count}"
+        );
+    }
+}
+
+#[test]
+fn test_sender_is_self_condition() {
+    let condition = Condition::Known(KnownCondition::SenderIsSelf);
+
+    let evaluator = evaluator_for_event(flattened_event(&[("sender", "@alice:test")]));
+    // Without the recipient's user ID we can't tell.
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    assert!(evaluator
+        .match_condition(&condition, Some("@alice:test"), None)
+        .unwrap());
+
+    // User IDs are compared exactly.
+    for recipient in ["@bob:test", "@Alice:test", "@alice:other"] {
+        assert!(
+            !evaluator
+                .match_condition(&condition, Some(recipient), None)
+                .unwrap(),
+            "recipient: {recipient}"
+        );
+    }
+
+    let evaluator = evaluator_for_event(BTreeMap::new());
+    assert!(!evaluator
+        .match_condition(&condition, Some("@alice:test"), None)
+        .unwrap());
+}
+
+#[test]
+fn test_event_selector_condition() {
+    let mut flattened_keys = flattened_event(&[("content.level", "high")]);
+    flattened_keys.insert(
+        "content.scores".into(),
+        JsonValue::Array(vec![
+            SimpleJsonValue::Int(3),
+            SimpleJsonValue::Int(7),
+            SimpleJsonValue::Str("9".into()),
+            SimpleJsonValue::Bool(true),
+        ]),
+    );
+    let evaluator = evaluator_for_event(flattened_keys);
+
+    let matches = |condition: serde_json::Value| {
+        let condition: Condition = serde_json::from_value(condition).unwrap();
+        evaluator.match_condition(&condition, None, None)
+    };
+    let selector = |path: &str, op: &str, value: serde_json::Value| {
+        matches(serde_json::json!({
+            "kind": "io.element.event_selector",
+            "path": path,
+            "op": op,
+            "value": value,
+        }))
+        .unwrap()
+    };
+
+    // Any element of the array can match.
+    assert!(selector("$.content.scores[*]", ">", 5.into()));
+    assert!(selector("$.content.scores[*]", "<", 5.into()));
+    assert!(!selector("$.content.scores[*]", ">", 7.into()));
+    assert!(selector("$.content.scores[*]", "==", true.into()));
+    assert!(selector("$.content.scores[*]", "==", "9".into()));
+
+    // Or a specific element.
+    assert!(!selector("$.content.scores[0]", ">", 5.into()));
+    assert!(selector("$.content.scores[1]", ">", 5.into()));
+    assert!(!selector("$.content.scores[9]", "<", 100.into()));
+
+    // Values of other types never match, so the string "9" isn't > 5.
+    assert!(!selector("$.content.scores[2]", ">", 5.into()));
+    assert!(!selector("$.content.scores[2]", "!=", 5.into()));
+    assert!(!selector("$.content.scores[3]", ">", false.into()));
+
+    // Strings are ordered by code point.
+    assert!(selector("$.content.level", "==", "high".into()));
+    assert!(selector("$.content.level", ">", "abc".into()));
+    assert!(!selector("$.content.missing", "!=", "high".into()));
+
+    // Malformed paths are errors.
+    assert!(matches!(
+        matches(serde_json::json!({
+            "kind": "io.element.event_selector",
+            "path": "$..scores",
+            "op": ">",
+            "value": 5,
+        })),
+        Err(ConditionMatchError::BadSelector(
+            InvalidSelectorError::EmptySegment(_)
+        ))
+    ));
+}
+
+#[test]
+fn test_event_key_exists_condition() {
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.body", ""),
+        ("content.file.url", "mxc://example.org/abc"),
+        ("content.filename", "cat.png"),
+    ]));
+    let key_exists = |key: &'static str, exists: bool| {
+        let condition = Condition::Known(KnownCondition::EventKeyExists {
+            key: Cow::Borrowed(key),
+            exists,
+        });
+        evaluator.match_condition(&condition, None, None).unwrap()
+    };
+
+    // An empty string value is still present.
+    assert!(key_exists("content.body", true));
+    assert!(!key_exists("content.body", false));
+
+    assert!(!key_exists("content.msgtype", true));
+    assert!(key_exists("content.msgtype", false));
+
+    // Objects are present if anything nested beneath them is.
+    assert!(key_exists("content.file", true));
+    assert!(key_exists("content.file.url", true));
+    assert!(!key_exists("content.file.mimetype", true));
+    assert!(!key_exists("content.fil", true));
+    assert!(key_exists("content", true));
+}
+
+#[test]
+fn test_keys_equal_condition() {
+    let mut event = flattened_event(&[
+        ("sender", "@alice:example.org"),
+        ("content.recipient", "@alice:example.org"),
+        ("content.other", "@bob:example.org"),
+        ("content.count", "1"),
+    ]);
+    event.insert(
+        FlattenedKey::from("content.number"),
+        JsonValue::Value(SimpleJsonValue::Int(1)),
+    );
+    event.insert(
+        FlattenedKey::from("content.other_number"),
+        JsonValue::Value(SimpleJsonValue::Int(1)),
+    );
+    let evaluator = evaluator_for_event(event);
+    let keys_equal = |key_a: &'static str, key_b: &'static str| {
+        let condition = Condition::Known(KnownCondition::KeysEqual {
+            key_a: Cow::Borrowed(key_a),
+            key_b: Cow::Borrowed(key_b),
+        });
+        evaluator.match_condition(&condition, None, None).unwrap()
+    };
+
+    // Equal values.
+    assert!(keys_equal("content.recipient", "sender"));
+    assert!(keys_equal("sender", "content.recipient"));
+    assert!(keys_equal("content.number", "content.other_number"));
+
+    // Unequal values, including those which only differ in type.
+    assert!(!keys_equal("content.other", "sender"));
+    assert!(!keys_equal("content.count", "content.number"));
+
+    // A missing key never matches, even against another missing key.
+    assert!(!keys_equal("content.missing", "sender"));
+    assert!(!keys_equal("sender", "content.missing"));
+    assert!(!keys_equal("content.missing", "content.missing"));
+}
+
+#[test]
+fn test_membership_transition_condition() {
+    let transition = |from: &'static str, to: &'static str| {
+        Condition::Known(KnownCondition::MembershipTransition {
+            from: Cow::Borrowed(from),
+            to: Cow::Borrowed(to),
+        })
+    };
+    let membership_event = |membership: &'static str, prev_membership: Option<&'static str>| {
+        let mut evaluator = evaluator_for_event(flattened_event(&[
+            ("type", "m.room.member"),
+            ("content.membership", membership),
+        ]));
+        if let Some(prev_membership) = prev_membership {
+            evaluator.prev_content_flattened = flattened_event(&[("membership", prev_membership)]);
+        }
+        evaluator
+    };
+
+    // join -> leave, e.g. a kick.
+    let evaluator = membership_event("leave", Some("join"));
+    assert!(evaluator
+        .match_condition(&transition("join", "leave"), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&transition("invite", "leave"), None, None)
+        .unwrap());
+
+    // invite -> join.
+    let evaluator = membership_event("join", Some("invite"));
+    assert!(evaluator
+        .match_condition(&transition("invite", "join"), None, None)
+        .unwrap());
+
+    // An unknown previous membership never matches.
+    let evaluator = membership_event("join", None);
+    assert!(!evaluator
+        .match_condition(&transition("leave", "join"), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&transition("join", "join"), None, None)
+        .unwrap());
+    let evaluator = membership_event("invite", Some("leave"));
+    assert!(evaluator
+        .match_condition(&transition("leave", "invite"), None, None)
+        .unwrap());
+
+    // A display name change doesn't change the membership.
+    let evaluator = membership_event("join", Some("join"));
+    assert!(!evaluator
+        .match_condition(&transition("join", "leave"), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&transition("invite", "join"), None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_not_condition() {
+    let evaluator = evaluator_for_event(flattened_event(&[("sender", "@alice:test")]));
+    let not: Condition = serde_json::from_str(
+        r#"{"kind": "io.element.not", "condition": {"kind": "event_match", "key": "sender", "pattern": "@bob:test"}}"#,
+    )
+    .unwrap();
+    assert!(evaluator.match_condition(&not, None, None).unwrap());
+
+    let not_not = Condition::Known(KnownCondition::Not {
+        condition: Box::new(not),
+    });
+    assert!(!evaluator.match_condition(&not_not, None, None).unwrap());
+
+    // Inner conditions which fail to evaluate don't cause the negation to match.
+    let not_invalid: Condition = serde_json::from_str(
+        r#"{"kind": "io.element.not", "condition": {"kind": "room_member_count", "is": "<<"}}"#,
+    )
+    .unwrap();
+    assert!(!evaluator.match_condition(&not_invalid, None, None).unwrap());
+
+    // By default the negation of an unrecognized condition doesn't match.
+    let mut evaluator = evaluator;
+    let not_unknown: Condition = serde_json::from_str(
+        r#"{"kind": "io.element.not", "condition": {"kind": "org.example.other"}}"#,
+    )
+    .unwrap();
+    assert!(!evaluator.match_condition(&not_unknown, None, None).unwrap());
+
+    evaluator.negate_unknown_conditions = true;
+    assert!(evaluator.match_condition(&not_unknown, None, None).unwrap());
+
+    // Malformed conditions of a recognized kind are never negated.
+    let not_malformed: Condition = serde_json::from_str(
+        r#"{"kind": "io.element.not", "condition": {"kind": "room_member_count", "is": 2}}"#,
+    )
+    .unwrap();
+    assert!(!evaluator
+        .match_condition(&not_malformed, None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_any_of_condition() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("sender", "@alice:test"),
+        ("content.body", "coffee?"),
+    ]));
+    let condition = |json: &str| serde_json::from_str::<Condition>(json).unwrap();
+    let matches = |evaluator: &PushRuleEvaluator, json| {
+        evaluator
+            .match_condition(&condition(json), None, None)
+            .unwrap()
+    };
+
+    // (body contains "tea") OR (sender is Alice)
+    assert!(matches(
+        &evaluator,
+        r#"{"kind": "io.element.any_of", "conditions": [
+            {"kind": "event_match", "key": "content.body", "pattern": "tea"},
+            {"kind": "event_match", "key": "sender", "pattern": "@alice:test"}
+        ]}"#
+    ));
+    assert!(!matches(
+        &evaluator,
+        r#"{"kind": "io.element.any_of", "conditions": [
+            {"kind": "event_match", "key": "content.body", "pattern": "tea"},
+            {"kind": "event_match", "key": "sender", "pattern": "@bob:test"}
+        ]}"#
+    ));
+    assert!(!matches(
+        &evaluator,
+        r#"{"kind": "io.element.any_of", "conditions": []}"#
+    ));
+
+    // Groups nest, and compose with `not`.
+    assert!(matches(
+        &evaluator,
+        r#"{"kind": "io.element.any_of", "conditions": [
+            {"kind": "io.element.any_of", "conditions": [
+                {"kind": "event_match", "key": "sender", "pattern": "@bob:test"}
+            ]},
+            {"kind": "io.element.not", "condition": {"kind": "io.element.any_of", "conditions": [
+                {"kind": "event_match", "key": "content.body", "pattern": "tea"}
+            ]}}
+        ]}"#
+    ));
+    assert!(!matches(
+        &evaluator,
+        r#"{"kind": "io.element.not", "condition": {"kind": "io.element.any_of", "conditions": [
+            {"kind": "event_match", "key": "content.body", "pattern": "tea"},
+            {"kind": "event_match", "key": "content.body", "pattern": "coffee"}
+        ]}}"#
+    ));
+
+    // A condition which fails to evaluate doesn't stop the others matching.
+    assert!(matches(
+        &evaluator,
+        r#"{"kind": "io.element.any_of", "conditions": [
+            {"kind": "room_member_count", "is": "<<"},
+            {"kind": "event_match", "key": "content.body", "pattern": "coffee"}
+        ]}"#
+    ));
+
+    // Evaluation stops at the first matching condition.
+    let mut evaluator = evaluator;
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    evaluator.register_custom_condition(
+        "org.example.count".to_string(),
+        Box::new(move |_: &Value, _: Option<&str>, _: Option<&str>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(false)
+        }),
+    );
+    assert!(matches(
+        &evaluator,
+        r#"{"kind": "io.element.any_of", "conditions": [
+            {"kind": "org.example.count"},
+            {"kind": "event_match", "key": "content.body", "pattern": "coffee"},
+            {"kind": "org.example.count"}
+        ]}"#
+    ));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_event_age_less_than() {
+    let mut evaluator = evaluator_for_event(BTreeMap::new());
+    let condition: Condition =
+        serde_json::from_str(r#"{"kind": "io.element.event_age_less_than", "ms": 1000}"#).unwrap();
+
+    // The condition doesn't match if the timestamps aren't known.
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+    evaluator.event_origin_ts = Some(10_000);
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    for (now_ts, matches) in [
+        (10_000, true),
+        (10_999, true),
+        (11_000, false),
+        (20_000, false),
+    ] {
+        evaluator.now_ts = Some(now_ts);
+        assert_eq!(
+            evaluator.match_condition(&condition, None, None).unwrap(),
+            matches,
+            "now_ts: {now_ts}"
+        );
+    }
+
+    // Events from the future are treated as brand new.
+    evaluator.now_ts = Some(5_000);
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.event_origin_ts = None;
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_within_time_window() {
+    let condition = |start_minute, end_minute, tz_offset_minutes| {
+        Condition::Known(KnownCondition::WithinTimeWindow {
+            start_minute,
+            end_minute,
+            tz_offset_minutes,
+        })
+    };
+    // Quiet hours from 22:00 to 07:00.
+    let quiet_hours = |tz_offset_minutes| condition(22 * 60, 7 * 60, tz_offset_minutes);
+
+    let mut evaluator = evaluator_for_event(BTreeMap::new());
+    assert!(!evaluator
+        .match_condition(&quiet_hours(0), None, None)
+        .unwrap());
+
+    // Midnight UTC on 2023-01-02.
+    let day_ts = 1_672_617_600_000;
+    let at = |hours: u64, minutes: u64| day_ts + (hours * 60 + minutes) * 60_000;
+
+    for (now_ts, tz_offset_minutes, matches) in [
+        // In UTC, wrapping past midnight.
+        (at(21, 59), 0, false),
+        (at(22, 0), 0, true),
+        (at(23, 59), 0, true),
+        (at(0, 0), 0, true),
+        (at(6, 59), 0, true),
+        (at(7, 0), 0, false),
+        (at(12, 0), 0, false),
+        // 03:00 UTC is 22:00 the day before in UTC-5.
+        (at(3, 0), -300, true),
+        (at(2, 59), -300, false),
+        // 20:30 UTC is 02:00 the next day in UTC+5:30.
+        (at(20, 30), 330, true),
+        (at(1, 30), 330, false),
+        (at(16, 30), 330, true),
+    ] {
+        evaluator.now_ts = Some(now_ts);
+        assert_eq!(
+            evaluator
+                .match_condition(&quiet_hours(tz_offset_minutes), None, None)
+                .unwrap(),
+            matches,
+            "now_ts: {now_ts}, tz_offset_minutes: {tz_offset_minutes}"
+        );
+    }
+
+    // Windows within a day, empty windows and invalid windows.
+    evaluator.now_ts = Some(at(12, 0));
+    assert!(evaluator
+        .match_condition(&condition(9 * 60, 17 * 60, 0), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition(13 * 60, 17 * 60, 0), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition(12 * 60, 12 * 60, 0), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition(22 * 60, MINUTES_PER_DAY, 0), None, None)
+        .unwrap());
+
+    // Negative offsets can move the time to the previous day.
+    assert_eq!(local_minute_of_day(0, -60), 23 * 60);
+}
+
+#[test]
+fn test_user_membership() {
+    let condition = |membership: &'static str| {
+        Condition::Known(KnownCondition::UserMembership {
+            membership: Cow::Borrowed(membership),
+        })
+    };
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+
+    // The membership must be known to match.
+    for membership in ["join", "invite", "leave"] {
+        assert!(!evaluator
+            .match_condition(&condition(membership), Some("@alice:test"), None)
+            .unwrap());
+    }
+
+    for membership in ["join", "invite", "leave"] {
+        evaluator.user_memberships =
+            BTreeMap::from([("@alice:test".to_string(), membership.to_string())]);
+        for expected in ["join", "invite", "leave"] {
+            assert_eq!(
+                evaluator
+                    .match_condition(&condition(expected), Some("@alice:test"), None)
+                    .unwrap(),
+                membership == expected,
+                "{membership} vs {expected}"
+            );
+            // Only the recipient's membership counts.
+            assert!(!evaluator
+                .match_condition(&condition(expected), Some("@bob:test"), None)
+                .unwrap());
+            assert!(!evaluator
+                .match_condition(&condition(expected), None, None)
+                .unwrap());
+        }
+    }
+}
+
+#[test]
+fn test_event_match_ignore_quotes() {
+    use crate::push::{EventMatchCondition, EventMatchKey};
+
+    let condition = |ignore_quotes| {
+        Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
+            key: EventMatchKey::Single(Cow::Borrowed("content.body")),
+            pattern: Cow::Borrowed("coffee"),
+            ignore_quotes,
+            match_words: None,
+            contains: false,
+        }))
+    };
+
+    // The keyword only appears in the quoted reply.
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "> <@alice:example.org> anyone for coffee?\n\nNo thanks",
+    )]));
+    assert!(evaluator
+        .match_condition(&condition(false), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition(true), None, None)
+        .unwrap());
+
+    // The keyword appears in the user's own text.
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "> <@alice:example.org> anyone for tea?\n\nI'd rather have coffee",
+    )]));
+    assert!(evaluator
+        .match_condition(&condition(true), None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_event_match_match_words() {
+    let condition = |match_words: Option<bool>| {
+        let mut condition = serde_json::json!({
+            "kind": "event_match",
+            "key": "content.topic",
+            "pattern": "coffee",
+        });
+        if let Some(match_words) = match_words {
+            condition["match_words"] = match_words.into();
+        }
+        let condition: Condition = serde_json::from_value(condition).unwrap();
+        condition
+    };
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.topic", "All about coffee")]));
+
+    // By default keys other than the body match the entire value.
+    assert!(!evaluator
+        .match_condition(&condition(None), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition(Some(false)), None, None)
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&condition(Some(true)), None, None)
+        .unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.topic", "coffee")]));
+    assert!(evaluator
+        .match_condition(&condition(None), None, None)
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&condition(Some(false)), None, None)
+        .unwrap());
+
+    // The override is serialized only if set.
+    assert!(!serde_json::to_string(&condition(None))
+        .unwrap()
+        .contains("match_words"));
+    assert!(serde_json::to_string(&condition(Some(true)))
+        .unwrap()
+        .contains(r#""match_words":true"#));
+}
+
+#[test]
+fn test_event_match_contains() {
+    let condition = |contains: bool, match_words: bool| {
+        let condition: Condition = serde_json::from_value(serde_json::json!({
+            "kind": "event_match",
+            "key": "content.topic",
+            "pattern": "coffee",
+            "contains": contains,
+            "match_words": match_words,
+        }))
+        .unwrap();
+        condition
+    };
+
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.topic",
+        "All about coffeemakers",
+    )]));
+
+    // The pattern matches part of a word only with `contains`, which takes
+    // precedence over `match_words`.
+    assert!(!evaluator
+        .match_condition(&condition(false, false), None, None)
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition(false, true), None, None)
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&condition(true, false), None, None)
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&condition(true, true), None, None)
+        .unwrap());
+}
+
+#[test]
+fn test_event_match_whole_is_case_insensitive() {
+    let condition: Condition = serde_json::from_value(serde_json::json!({
+        "kind": "event_match",
+        "key": "content.msgtype",
+        "pattern": "M.TEXT",
+    }))
+    .unwrap();
+
+    // Whole matches are case-insensitive, like word matches on the body.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.msgtype", "m.text")]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.msgtype", "m.texts")]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_event_match_multiple_keys() {
+    let condition: Condition = serde_json::from_value(serde_json::json!({
+        "kind": "event_match",
+        "key": ["content.body", "content.formatted_body"],
+        "pattern": "coffee",
+    }))
+    .unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.body", "tea"),
+        ("content.formatted_body", "coffee"),
+    ]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Keys which aren't in the event are skipped.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "tea?")]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    let evaluator = evaluator_for_event(BTreeMap::new());
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    // Each key is matched as it would be on its own, so only the body is
+    // matched by word.
+    let evaluator = evaluator_for_event(flattened_event(&[("content.formatted_body", "coffee?")]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    let mut by_word = condition.clone();
+    if let Condition::Known(KnownCondition::EventMatch(event_match)) = &mut by_word {
+        event_match.match_words = Some(true);
+    }
+    assert!(evaluator.match_condition(&by_word, None, None).unwrap());
+
+    // Both forms round trip.
+    assert_eq!(
+        serde_json::to_value(&condition).unwrap()["key"],
+        serde_json::json!(["content.body", "content.formatted_body"])
+    );
+    let single: Condition = serde_json::from_value(serde_json::json!({
+        "kind": "event_match",
+        "key": "content.body",
+        "pattern": "coffee",
+    }))
+    .unwrap();
+    assert_eq!(
+        serde_json::to_value(&single).unwrap()["key"],
+        serde_json::json!("content.body")
+    );
+}
+
+#[test]
+fn test_event_match_event_type_glob() {
+    let rules = crate::push::FilteredPushRulesBuilder::new()
+        .push_rule(db_rule(
+            "global/override/room_events",
+            5,
+            r#"[{"kind": "event_match", "key": "type", "pattern": "m.room.*"}]"#,
+            r#"["notify"]"#,
+        ))
+        .build()
+        .unwrap();
+    let condition: Condition =
+        serde_json::from_str(r#"{"kind": "event_match", "key": "type", "pattern": "m.room.*"}"#)
+            .unwrap();
+
+    for (event_type, matches) in [
+        ("m.room.message", true),
+        ("m.room.topic", true),
+        ("M.ROOM.NAME", true),
+        ("m.room.", true),
+        ("m.room", false),
+        ("m.roomy.message", false),
+        ("m.reaction", false),
+        ("org.example.m.room.message", false),
+    ] {
+        let evaluator = evaluator_for_event(flattened_event(&[("type", event_type)]));
+        assert_eq!(
+            evaluator.match_condition(&condition, None, None).unwrap(),
+            matches,
+            "{event_type}"
+        );
+
+        // The precompiled glob behaves the same.
+        let matched = evaluator
+            .run_with_matched_rule(&rules.precompiled(), None, None)
+            .map(|(rule_id, _)| rule_id);
+        assert_eq!(
+            matched.as_deref() == Some("global/override/room_events"),
+            matches,
+            "{event_type}"
+        );
+    }
+}
+
+#[test]
+fn test_related_event_match_multiple_events() {
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    evaluator.related_event_match_enabled = true;
+    evaluator.related_events_flattened.insert(
+        "m.annotation".to_string(),
+        vec![
+            flattened_event(&[("sender", "@alice:example.org")]),
+            flattened_event(&[("sender", "@bob:example.org")]),
+        ],
+    );
+    evaluator.related_events_flattened.insert(
+        "m.in_reply_to".to_string(),
+        vec![
+            flattened_event(&[("sender", "@alice:example.org")]),
+            flattened_event(&[
+                ("sender", "@bob:example.org"),
+                ("im.vector.is_falling_back", ""),
+            ]),
+        ],
+    );
+
+    let condition = |rel_type: &str, sender: &str, include_fallbacks: Option<bool>| {
+        Condition::Known(KnownCondition::RelatedEventMatch(
+            crate::push::RelatedEventMatchCondition {
+                key: Some(Cow::Borrowed("sender")),
+                pattern: Some(Cow::Owned(sender.to_string())),
+                rel_type: Cow::Owned(rel_type.to_string()),
+                include_fallbacks,
+            },
+        ))
+    };
+
+    // Any of the related events of the type can match.
+    for sender in ["@alice:example.org", "@bob:example.org"] {
+        assert!(evaluator
+            .match_condition(&condition("m.annotation", sender, None), None, None)
+            .unwrap());
+    }
+    assert!(!evaluator
+        .match_condition(
+            &condition("m.annotation", "@carol:example.org", None),
+            None,
+            None
+        )
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(
+            &condition("m.thread", "@alice:example.org", None),
+            None,
+            None
+        )
+        .unwrap());
+
+    // Fallbacks are skipped individually.
+    assert!(evaluator
+        .match_condition(
+            &condition("m.in_reply_to", "@alice:example.org", None),
+            None,
+            None
+        )
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(
+            &condition("m.in_reply_to", "@bob:example.org", None),
+            None,
+            None
+        )
+        .unwrap());
+    assert!(evaluator
+        .match_condition(
+            &condition("m.in_reply_to", "@bob:example.org", Some(true)),
+            None,
+            None
+        )
+        .unwrap());
+}
+
+#[test]
+fn test_related_events_source() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    evaluator.related_event_match_enabled = true;
+
+    let yielded = Arc::new(AtomicUsize::new(0));
+    let source_yielded = yielded.clone();
+    evaluator.set_related_events_source(Box::new(move |rel_type: &str| {
+        let yielded = source_yielded.clone();
+        let events = match rel_type {
+            "m.annotation" => (0..1000)
+                .map(|i| match i {
+                    1 => flattened_event(&[
+                        ("sender", "@bob:example.org"),
+                        ("im.vector.is_falling_back", ""),
+                    ]),
+                    2 => flattened_event(&[("sender", "@bob:example.org")]),
+                    _ => flattened_event(&[("sender", "@alice:example.org")]),
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        events.into_iter().inspect(move |_| {
+            yielded.fetch_add(1, Ordering::SeqCst);
+        })
+    }));
+
+    let condition = |sender: &str, include_fallbacks: Option<bool>| {
+        Condition::Known(KnownCondition::RelatedEventMatch(
+            crate::push::RelatedEventMatchCondition {
+                key: Some(Cow::Borrowed("sender")),
+                pattern: Some(Cow::Owned(sender.to_string())),
+                rel_type: Cow::Borrowed("m.annotation"),
+                include_fallbacks,
+            },
+        ))
+    };
+
+    // Iteration stops at the first match, skipping the fallback unless
+    // fallbacks are included.
+    assert!(evaluator
+        .match_condition(&condition("@bob:example.org", None), None, None)
+        .unwrap());
+    assert_eq!(yielded.swap(0, Ordering::SeqCst), 3);
+    assert!(evaluator
+        .match_condition(&condition("@bob:example.org", Some(true)), None, None)
+        .unwrap());
+    assert_eq!(yielded.swap(0, Ordering::SeqCst), 2);
+
+    // Without a match every event is considered.
+    assert!(!evaluator
+        .match_condition(&condition("@carol:example.org", None), None, None)
+        .unwrap());
+    assert_eq!(yielded.swap(0, Ordering::SeqCst), 1000);
+}
+
+#[test]
+fn test_related_events_prefer_real_relations() {
+    let mut evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    evaluator.related_event_match_enabled = true;
+    evaluator.related_events_flattened.insert(
+        "m.in_reply_to".to_string(),
+        vec![
+            flattened_event(&[
+                ("event_id", "$a"),
+                ("sender", "@alice:example.org"),
+                ("im.vector.is_falling_back", ""),
+            ]),
+            flattened_event(&[
+                ("event_id", "$c"),
+                ("sender", "@carol:example.org"),
+                ("im.vector.is_falling_back", ""),
+            ]),
+            flattened_event(&[("event_id", "$b"), ("sender", "@bob:example.org")]),
+            flattened_event(&[("event_id", "$a"), ("sender", "@alice:example.org")]),
+            flattened_event(&[("event_id", "$b"), ("sender", "@bob:example.org")]),
+            flattened_event(&[("sender", "@dan:example.org")]),
+        ],
+    );
+
+    let related_senders = |include_fallbacks| {
+        evaluator
+            .related_events("m.in_reply_to", include_fallbacks)
+            .into_iter()
+            .map(|event| match event.get("sender") {
+                Some(JsonValue::Value(SimpleJsonValue::Str(sender))) => sender.to_string(),
+                _ => panic!("no sender"),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Real relations come first, and each event is only included once.
+    assert_eq!(
+        related_senders(false),
+        ["@bob:example.org", "@alice:example.org", "@dan:example.org"]
+    );
+    assert_eq!(
+        related_senders(true),
+        [
+            "@bob:example.org",
+            "@alice:example.org",
+            "@dan:example.org",
+            "@carol:example.org"
+        ]
+    );
+
+    let condition = |sender: &str, include_fallbacks: Option<bool>| {
+        Condition::Known(KnownCondition::RelatedEventMatch(
+            crate::push::RelatedEventMatchCondition {
+                key: Some(Cow::Borrowed("sender")),
+                pattern: Some(Cow::Owned(sender.to_string())),
+                rel_type: Cow::Borrowed("m.in_reply_to"),
+                include_fallbacks,
+            },
+        ))
+    };
+    let matches = |sender, include_fallbacks| {
+        evaluator
+            .match_condition(&condition(sender, include_fallbacks), None, None)
+            .unwrap()
+    };
+
+    // Alice's event is a fallback relation too, but it's also a real one.
+    assert!(matches("@alice:example.org", Some(false)));
+    assert!(matches("@alice:example.org", Some(true)));
+    // Carol's is only a fallback relation.
+    assert!(!matches("@carol:example.org", None));
+    assert!(matches("@carol:example.org", Some(true)));
+}
+
+#[cfg(feature = "unicode-normalization")]
+#[test]
+fn test_unicode_normalization() {
+    let composed = "caf\u{e9}";
+    let decomposed = "cafe\u{301}";
+
+    for (pattern, body) in [(composed, decomposed), (decomposed, composed)] {
+        let body = format!("Meet at the {body} later?");
+        let mut flattened_keys = BTreeMap::new();
+        flattened_keys.insert(
+            "content.body".into(),
+            JsonValue::Value(SimpleJsonValue::Str(Cow::Owned(body))),
+        );
+        let evaluator = evaluator_for_event(flattened_keys);
+
+        let condition: Condition = serde_json::from_value(serde_json::json!({
+            "kind": "event_match",
+            "key": "content.body",
+            "pattern": pattern,
+        }))
+        .unwrap();
+        assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+        assert!(evaluator
+            .match_condition(
+                &Condition::Known(KnownCondition::ContainsDisplayName),
+                None,
+                Some(pattern),
+            )
+            .unwrap());
+    }
+}
+
+#[test]
+fn test_has_any_and_all_keys_conditions() {
+    let keys = vec![
+        Cow::Borrowed("content.url"),
+        Cow::Borrowed("content.info.size"),
+    ];
+    let any = Condition::Known(KnownCondition::HasAnyKey { keys: keys.clone() });
+    let all = Condition::Known(KnownCondition::HasAllKeys { keys });
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "hello")]));
+    assert!(!evaluator.match_condition(&any, None, None).unwrap());
+    assert!(!evaluator.match_condition(&all, None, None).unwrap());
+
+    let evaluator =
+        evaluator_for_event(flattened_event(&[("content.url", "mxc://example.org/abc")]));
+    assert!(evaluator.match_condition(&any, None, None).unwrap());
+    assert!(!evaluator.match_condition(&all, None, None).unwrap());
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("content.url", "mxc://example.org/abc"),
+        ("content.info.size", "1024"),
+    ]));
+    assert!(evaluator.match_condition(&any, None, None).unwrap());
+    assert!(evaluator.match_condition(&all, None, None).unwrap());
+
+    // As with `event_key_exists`, objects are present if any key nested
+    // beneath them is.
+    let keys = vec![Cow::Borrowed("content.info"), Cow::Borrowed("content.url")];
+    let all = Condition::Known(KnownCondition::HasAllKeys { keys });
+    assert!(evaluator.match_condition(&all, None, None).unwrap());
+}
+
+#[test]
+fn test_display_name_unicode_case_insensitive() {
+    let condition = Condition::Known(KnownCondition::ContainsDisplayName);
+    let evaluator =
+        evaluator_for_event(flattened_event(&[("content.body", "Hallo ÄNNE, Kaffee?")]));
+
+    assert!(evaluator
+        .match_condition(&condition, None, Some("änne"))
+        .unwrap());
+    assert!(evaluator
+        .match_condition(&condition, None, Some("Änne"))
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition, None, Some("Anne"))
+        .unwrap());
+}
+
+#[test]
+fn test_confusable_display_names() {
+    let condition = Condition::Known(KnownCondition::ContainsDisplayName);
+    let contains_display_name = |body: &'static str, display_name: &str, confusable: bool| {
+        let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", body)]));
+        evaluator.confusable_display_names = confusable;
+        evaluator
+            .match_condition(&condition, None, Some(display_name))
+            .unwrap()
+    };
+
+    let lookalikes = [
+        // A dotless i.
+        "Al\u{131}ce, over here",
+        // A Cyrillic a and e.
+        "\u{410}lic\u{435}, over here",
+        // A Greek omicron.
+        "Hello \u{41f}\u{3bf}b Alic\u{435}",
+        // Fullwidth letters.
+        "\u{ff21}\u{ff4c}\u{ff49}\u{ff43}\u{ff45}, over here",
+        // A zero width space.
+        "Ali\u{200b}ce, over here",
+    ];
+    for body in lookalikes {
+        assert!(
+            contains_display_name(body, "Alice", true),
+            "{body:?} should match"
+        );
+        // Lookalikes are only matched if enabled.
+        assert!(
+            !contains_display_name(body, "Alice", false),
+            "{body:?} shouldn't match"
+        );
+    }
+
+    // The display name itself can use confusable characters.
+    assert!(contains_display_name(
+        "Alice, over here",
+        "Al\u{131}ce",
+        true
+    ));
+
+    // The usual matching still applies, e.g. to whole words.
+    assert!(contains_display_name("Alice, over here", "Alice", true));
+    assert!(!contains_display_name(
+        "Al\u{131}cey, over here",
+        "Alice",
+        true
+    ));
+    assert!(!contains_display_name("Bob, over here", "Alice", true));
+
+    // Display names are matched literally, rather than as globs.
+    assert!(contains_display_name(
+        "[bot] Alice, over here",
+        "[bot] Alice",
+        false
+    ));
+    assert!(contains_display_name(
+        "[b\u{43e}t] Alice, over here",
+        "[bot] Alice",
+        true
+    ));
+    assert!(!contains_display_name(
+        "b Alice, over here",
+        "[bot] Alice",
+        false
+    ));
+    assert!(!contains_display_name(
+        "b Alice, over here",
+        "[bot] Alice",
+        true
+    ));
+    assert!(!contains_display_name("Alice, over here", "A*e", false));
+}
+
+#[test]
+fn test_folded_body_matches_unfolded() {
+    let bodies = [
+        "Hallo ÄNNE, Kaffee?",
+        "KEYWORD42 in CAPS",
+        "straße und STRASSE",
+        "",
+    ];
+    let patterns = ["änne", "kaffee*", "keyword4?", "STRASSE", "straße", "*", ""];
+
+    for body in bodies {
+        let evaluator = evaluator_for_event(flattened_event(&[("content.body", body)]));
+        assert_eq!(evaluator.folded_body.as_deref(), Some(&*fold_case(body)));
+
+        for pattern in patterns {
+            let condition: Condition = serde_json::from_value(serde_json::json!({
+                "kind": "event_match",
+                "key": "content.body",
+                "pattern": pattern,
+            }))
+            .unwrap();
+            let unfolded = evaluator
+                .match_event_match(
+                    &evaluator.flattened_keys,
+                    "content.body",
+                    pattern,
+                    GlobMatchType::Word,
+                    None,
+                )
+                .unwrap();
+            assert_eq!(
+                evaluator.match_condition(&condition, None, None).unwrap(),
+                unfolded,
+                "{pattern:?} against {body:?}"
+            );
+        }
+    }
+
+    // Events without a body have nothing to fold.
+    let evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.message")]));
+    assert_eq!(evaluator.folded_body, None);
+}
+
+#[test]
+fn test_blank_display_name() {
+    let condition = Condition::Known(KnownCondition::ContainsDisplayName);
+    let evaluator = evaluator_for_event(flattened_event(&[(
+        "content.body",
+        "a  spaced out message",
+    )]));
+
+    for display_name in ["", " ", "\t \n", "\u{200D}", "\u{200B}\u{FEFF} \u{200D}"] {
+        assert!(
+            !evaluator
+                .match_condition(&condition, None, Some(display_name))
+                .unwrap(),
+            "{display_name:?} matched"
+        );
+    }
+
+    // Non-blank names are trimmed.
+    assert!(evaluator
+        .match_condition(&condition, None, Some(" spaced\u{200B}"))
+        .unwrap());
+}
+
+#[test]
+fn test_display_name_preprocessor() {
+    let condition = Condition::Known(KnownCondition::ContainsDisplayName);
+    let mut evaluator =
+        evaluator_for_event(flattened_event(&[("content.body", "hey alice, lunch?")]));
+
+    assert!(!evaluator
+        .match_condition(&condition, None, Some("Alice 🌸"))
+        .unwrap());
+
+    // Strip emoji (and the resulting trailing whitespace).
+    evaluator.set_display_name_preprocessor(Box::new(|text| {
+        text.chars()
+            .filter(|c| c.is_alphanumeric() || c.is_whitespace() || c.is_ascii_punctuation())
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }));
+    assert!(evaluator
+        .match_condition(&condition, None, Some("Alice 🌸"))
+        .unwrap());
+    assert!(!evaluator
+        .match_condition(&condition, None, Some("Bob 🌸"))
+        .unwrap());
+    // A name which is empty after preprocessing never matches.
+    assert!(!evaluator
+        .match_condition(&condition, None, Some("🌸"))
+        .unwrap());
+}
+
+#[test]
+fn test_explain_report() {
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.text"),
+        ("content.body", "hello bob"),
+    ]));
+
+    let report = evaluator.explain(&FilteredPushRules::default(), None, Some("bob"));
+    assert_eq!(
+        report.matched_rule_id.as_deref(),
+        Some("global/override/.m.rule.contains_display_name")
+    );
+    assert_eq!(
+        report.actions,
+        evaluator.run(&FilteredPushRules::default(), None, Some("bob"))
+    );
+
+    let json: Value = serde_json::to_value(&report).unwrap();
+    assert_eq!(
+        json["matched_rule_id"],
+        "global/override/.m.rule.contains_display_name"
+    );
+
+    let rules = json["rules"].as_array().unwrap();
+    assert_eq!(rules.len(), report.rules.len());
+
+    // The master rule is disabled by default.
+    assert_eq!(rules[0]["rule_id"], "global/override/.m.rule.master");
+    assert_eq!(rules[0]["enabled"], false);
+    assert_eq!(rules[0]["matched"], false);
+    assert_eq!(rules[0]["reason"], "rule is disabled");
+
+    // Failing conditions are recorded.
+    let suppress_notices = rules
+        .iter()
+        .find(|rule| rule["rule_id"] == "global/override/.m.rule.suppress_notices")
+        .unwrap();
+    assert_eq!(suppress_notices["matched"], false);
+    assert_eq!(suppress_notices["conditions"][0]["matched"], false);
+    assert_eq!(
+        suppress_notices["conditions"][0]["condition"]["pattern"],
+        "m.notice"
+    );
+    assert!(suppress_notices.get("reason").is_none());
+}
+
+#[test]
+fn test_default_notification_power_level() {
+    let condition = Condition::Known(KnownCondition::SenderNotificationPermission {
+        key: Cow::Borrowed("room"),
+    });
+
+    let mut evaluator = evaluator_for_event(BTreeMap::new());
+    evaluator.sender_power_level = Some(75);
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    // The default only applies if the key isn't listed.
+    evaluator.default_notification_power_level = 100;
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator
+        .notification_power_levels
+        .insert("room".to_string(), 75);
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_set_power_levels() {
+    let condition = Condition::Known(KnownCondition::SenderNotificationPermission {
+        key: Cow::Borrowed("room"),
+    });
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "@room hi")]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.set_sender_power_level(Some(50));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.set_notification_power_levels(BTreeMap::from([("room".to_string(), 100)]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+
+    evaluator.set_sender_power_level(Some(100));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    // The rest of the evaluator is unchanged.
+    assert_eq!(evaluator.body, "@room hi");
+}
+
+#[test]
+fn test_unknown_sender_power_level() {
+    let condition = Condition::Known(KnownCondition::SenderNotificationPermission {
+        key: Cow::Borrowed("room"),
+    });
+    let has_permission = |fallback: UnknownSenderPowerLevel| {
+        let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "@room hi")]));
+        evaluator.set_sender_power_level(None);
+        evaluator.set_notification_power_levels(BTreeMap::from([("room".to_string(), 50)]));
+        evaluator.unknown_sender_power_level = fallback;
+        evaluator.match_condition(&condition, None, None).unwrap()
+    };
+
+    // By default the sender doesn't have permission.
+    assert_eq!(
+        UnknownSenderPowerLevel::default(),
+        UnknownSenderPowerLevel::Deny
+    );
+    assert!(!has_permission(UnknownSenderPowerLevel::Deny));
+
+    assert!(!has_permission(UnknownSenderPowerLevel::Level(0)));
+    assert!(!has_permission(UnknownSenderPowerLevel::Level(49)));
+    assert!(has_permission(UnknownSenderPowerLevel::Level(50)));
+
+    assert!(has_permission(UnknownSenderPowerLevel::Skip));
+
+    // The fallback only applies if the sender's power level isn't known.
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "@room hi")]));
+    evaluator.set_sender_power_level(Some(0));
+    evaluator.set_notification_power_levels(BTreeMap::from([("room".to_string(), 50)]));
+    evaluator.unknown_sender_power_level = UnknownSenderPowerLevel::Skip;
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_user_power_level_condition() {
+    let condition = |user: &'static str, is: &'static str| {
+        Condition::Known(KnownCondition::UserPowerLevel {
+            user: Cow::Borrowed(user),
+            is: Some(Cow::Borrowed(is)),
+        })
+    };
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[("sender", "@alice:example.org")]));
+    evaluator.user_power_levels = BTreeMap::from([
+        ("@alice:example.org".to_string(), 100),
+        ("@bob:example.org".to_string(), 50),
+        ("@eve:example.org".to_string(), -10),
+    ]);
+    let matches_for = |evaluator: &PushRuleEvaluator, recipient, user, is| {
+        evaluator
+            .match_condition(&condition(user, is), recipient, None)
+            .unwrap()
+    };
+    let matches = |evaluator: &PushRuleEvaluator, user, is| matches_for(evaluator, None, user, is);
+
+    // The sender is resolved from the event.
+    assert!(matches(&evaluator, "sender", ">=100"));
+    assert!(!matches(&evaluator, "sender", "<100"));
+
+    // The recipient is the user whose rules are being evaluated, if known.
+    assert!(!matches(&evaluator, "recipient", ">=0"));
+    let bob = Some("@bob:example.org");
+    assert!(matches_for(&evaluator, bob, "recipient", "50"));
+    assert!(!matches_for(&evaluator, bob, "recipient", ">50"));
+
+    // Other users are given by their ID.
+    assert!(matches(&evaluator, "@bob:example.org", "0..=50"));
+    assert!(matches(&evaluator, "@eve:example.org", "<0"));
+    assert!(!matches(&evaluator, "@eve:example.org", ">=0"));
+    assert!(!matches(&evaluator, "@carol:example.org", ">=0"));
+
+    // The sender's power level falls back to the one the evaluator was given.
+    let mut evaluator = evaluator_for_event(flattened_event(&[("sender", "@carol:example.org")]));
+    evaluator.set_sender_power_level(None);
+    assert!(!matches(&evaluator, "sender", ">=0"));
+    evaluator.set_sender_power_level(Some(25));
+    assert!(matches(&evaluator, "sender", "25"));
+}
+
+#[test]
+fn test_event_match_wildcard_key() {
+    let condition: Condition = serde_json::from_str(
+        r#"{"kind":"event_match","key":"content.m\\.relates_to.*","pattern":"m.thread"}"#,
+    )
+    .unwrap();
+
+    let matches = |event: &[(&str, &'static str)]| {
+        evaluator_for_event(flattened_event(event))
+            .match_condition(&condition, None, None)
+            .unwrap()
+    };
+
+    assert!(matches(&[
+        ("content.m\\.relates_to.event_id", "$root"),
+        ("content.m\\.relates_to.rel_type", "m.thread"),
+    ]));
+    assert!(!matches(&[
+        ("content.m\\.relates_to.event_id", "$root"),
+        ("content.m\\.relates_to.rel_type", "m.reference"),
+    ]));
+
+    // Only keys with a single segment in place of the wildcard are matched.
+    assert!(!matches(&[(
+        "content.m\\.relates_to.m\\.in_reply_to.rel_type",
+        "m.thread"
+    )]));
+    assert!(!matches(&[("content.m\\.relates_to", "m.thread")]));
+    assert!(!matches(&[("content.rel_type", "m.thread")]));
+}
+
+#[test]
+fn test_match_single_condition() {
+    use crate::push::utils::GlobComplexityLimits;
+
+    let condition = |json: &str| serde_json::from_str::<Condition>(json).unwrap();
+
+    let evaluator = evaluator_for_event(flattened_event(&[("content.body", "coffee?")]));
+    assert!(evaluator.match_single_condition(
+        condition(r#"{"kind": "event_match", "key": "content.body", "pattern": "coffee"}"#),
+        None,
+        None,
+    ));
+    assert!(!evaluator.match_single_condition(
+        condition(r#"{"kind": "event_match", "key": "content.body", "pattern": "tea"}"#),
+        None,
+        None,
+    ));
+
+    // Errors, e.g. from a glob which is too complex, don't match.
+    let pattern = "*?".repeat(GlobComplexityLimits::default().max_wildcards);
+    let complex = condition(&format!(
+        r#"{{"kind": "event_match", "key": "content.body", "pattern": "{pattern}"}}"#
+    ));
+    assert!(evaluator.match_condition(&complex, None, None).is_err());
+    assert!(!evaluator.match_single_condition(complex, None, None));
+}
+
+#[test]
+fn test_state_key_match() {
+    let condition = |json: &str| serde_json::from_str::<Condition>(json).unwrap();
+    let user_id = condition(r#"{"kind": "io.element.state_key_match", "pattern_type": "user_id"}"#);
+    let localpart =
+        condition(r#"{"kind": "io.element.state_key_match", "pattern_type": "user_localpart"}"#);
+    let pattern =
+        condition(r#"{"kind": "io.element.state_key_match", "pattern": "@*:example.org"}"#);
+
+    // A membership event of the user.
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.member"),
+        ("state_key", "@bob:example.org"),
+        ("content.membership", "invite"),
+    ]));
+    let matches = |evaluator: &PushRuleEvaluator, condition, user_id| {
+        evaluator
+            .match_condition(condition, Some(user_id), None)
+            .unwrap()
+    };
+    assert!(matches(&evaluator, &user_id, "@bob:example.org"));
+    assert!(!matches(&evaluator, &user_id, "@alice:example.org"));
+    assert!(matches(&evaluator, &pattern, "@alice:example.org"));
+
+    // As with `event_match` on keys other than the body, the whole state key
+    // must match, so a localpart only matches a state key which is just that.
+    assert!(!matches(&evaluator, &localpart, "@bob:example.org"));
+    let custom_state = evaluator_for_event(flattened_event(&[
+        ("type", "org.example.nickname"),
+        ("state_key", "bob"),
+    ]));
+    assert!(matches(&custom_state, &localpart, "@bob:other.org"));
+
+    // Non-state events never match.
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("sender", "@bob:example.org"),
+    ]));
+    assert!(!matches(&evaluator, &user_id, "@bob:example.org"));
+    assert!(!matches(&evaluator, &pattern, "@bob:example.org"));
+
+    // Without a pattern or pattern type the condition never matches.
+    let empty = condition(r#"{"kind": "io.element.state_key_match"}"#);
+    assert!(matches!(
+        empty,
+        Condition::Known(KnownCondition::StateKeyMatch { .. })
+    ));
+    let evaluator = evaluator_for_event(flattened_event(&[("state_key", "")]));
+    assert!(!matches(&evaluator, &empty, "@bob:example.org"));
+}
+
+#[test]
+fn test_condition_failure_logging() {
+    let condition: Condition =
+        serde_json::from_str(r#"{"kind": "event_match", "key": "content.body", "pattern": "*?"}"#)
+            .unwrap();
+    assert_eq!(
+        describe_condition(&condition),
+        r#"event_match key="content.body" pattern="*?""#
+    );
+    let condition: Condition =
+        serde_json::from_str(r#"{"kind": "room_member_count", "is": "<>2"}"#).unwrap();
+    assert_eq!(describe_condition(&condition), "room_member_count");
+    let condition: Condition =
+        serde_json::from_str(r#"{"kind": "event_match", "key": ["a", "b"], "pattern": "x"}"#)
+            .unwrap();
+    assert_eq!(
+        describe_condition(&condition),
+        r#"event_match key="[\"a\",\"b\"]" pattern="x""#
+    );
+
+    // Repeated failures are only logged once per interval, with the number of
+    // failures in between.
+    let message = "global/override/test_condition_failure_logging: event_match: failed";
+    let start = Instant::now();
+    assert_eq!(record_condition_failure(message, start), Some(0));
+    assert_eq!(record_condition_failure(message, start), None);
+    assert_eq!(
+        record_condition_failure(message, start + Duration::from_secs(1)),
+        None
+    );
+    assert_eq!(
+        record_condition_failure(message, start + CONDITION_FAILURE_LOG_INTERVAL),
+        Some(2)
+    );
+    assert_eq!(
+        record_condition_failure(message, start + CONDITION_FAILURE_LOG_INTERVAL),
+        None
+    );
+
+    // Other failures are logged independently.
+    assert_eq!(record_condition_failure("other", start), Some(0));
+}
+
+#[test]
+fn test_is_encrypted() {
+    let condition = Condition::Known(KnownCondition::IsEncrypted);
+
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.encrypted"),
+        ("content.algorithm", "m.megolm.v1.aes-sha2"),
+    ]));
+    assert!(evaluator.match_condition(&condition, None, None).unwrap());
+
+    for event_type in [
+        "m.room.message",
+        "m.room.encrypted.other",
+        "M.ROOM.ENCRYPTED",
+    ] {
+        let evaluator = evaluator_for_event(flattened_event(&[("type", event_type)]));
+        assert!(
+            !evaluator.match_condition(&condition, None, None).unwrap(),
+            "{event_type}"
+        );
+    }
+
+    let evaluator = evaluator_for_event(flattened_event(&[]));
+    assert!(!evaluator.match_condition(&condition, None, None).unwrap());
+}
+
+#[test]
+fn test_fuzzy_contains_display_name() {
+    let condition = Condition::Known(KnownCondition::ContainsDisplayName);
+    let matches = |body: &'static str, display_name, max_distance| {
+        let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", body)]));
+        evaluator.display_name_max_edit_distance = max_distance;
+        evaluator
+            .match_condition(&condition, None, Some(display_name))
+            .unwrap()
+    };
+
+    // Exact matching is the default.
+    assert!(matches("hi Margaret!", "Margaret", None));
+    assert!(!matches("hi Margeret!", "Margaret", None));
+
+    // A one character typo is within a distance of one, but two aren't.
+    assert!(matches("hi Margeret!", "Margaret", Some(1)));
+    assert!(matches("hi margaret1", "Margaret", Some(1)));
+    assert!(!matches("hi Margerett!", "Margaret", Some(1)));
+    assert!(matches("hi Margerett!", "Margaret", Some(2)));
+
+    // Names of several words are compared to runs of as many words.
+    assert!(matches("is Ann Smyth here?", "Ann Smith", Some(1)));
+    assert!(!matches("is Ann here? Smyth is", "Ann Smith", Some(1)));
+
+    // Short names are only matched exactly.
+    assert!(!matches("hi Bib", "Bob", Some(1)));
+    assert!(matches("hi Bob", "Bob", Some(1)));
+}
+
+#[test]
+fn test_is_reply() {
+    let condition = Condition::Known(KnownCondition::IsReply);
+    let is_reply = |event: BTreeMap<FlattenedKey, JsonValue>| {
+        evaluator_for_event(event)
+            .match_condition(&condition, None, None)
+            .unwrap()
+    };
+
+    // A reply.
+    assert!(is_reply(flattened_event(&[
+        ("content.body", "> quoted\n\nreply"),
+        (IN_REPLY_TO_EVENT_ID_KEY, "$parent"),
+    ])));
+
+    // A thread message, whose reply is only a fallback, unless it is an
+    // actual reply within the thread.
+    let mut thread_message = flattened_event(&[
+        (r"content.m\.relates_to.rel_type", "m.thread"),
+        (r"content.m\.relates_to.event_id", "$root"),
+        (IN_REPLY_TO_EVENT_ID_KEY, "$latest"),
+    ]);
+    thread_message.insert(
+        IS_FALLING_BACK_KEY.into(),
+        JsonValue::Value(SimpleJsonValue::Bool(true)),
+    );
+    assert!(!is_reply(thread_message.clone()));
+    thread_message.insert(
+        IS_FALLING_BACK_KEY.into(),
+        JsonValue::Value(SimpleJsonValue::Bool(false)),
+    );
+    assert!(is_reply(thread_message));
+
+    // A plain message.
+    assert!(!is_reply(flattened_event(&[("content.body", "hi")])));
+
+    // Malformed relations.
+    assert!(!is_reply(flattened_event(&[(
+        IN_REPLY_TO_EVENT_ID_KEY,
+        ""
+    )])));
+    let mut malformed = BTreeMap::new();
+    malformed.insert(
+        IN_REPLY_TO_EVENT_ID_KEY.into(),
+        JsonValue::Value(SimpleJsonValue::Int(1)),
+    );
+    assert!(!is_reply(malformed));
+}
+
+#[test]
+fn test_is_edit() {
+    let condition = Condition::Known(KnownCondition::IsEdit);
+    let is_edit = |event: BTreeMap<FlattenedKey, JsonValue>| {
+        evaluator_for_event(event)
+            .match_condition(&condition, None, None)
+            .unwrap()
+    };
+
+    assert!(is_edit(flattened_event(&[
+        ("content.body", "* hello world"),
+        (REL_TYPE_KEY, "m.replace"),
+        (r"content.m\.relates_to.event_id", "$original"),
+    ])));
+    assert!(!is_edit(flattened_event(&[
+        ("content.body", "> quoted\n\nreply"),
+        (IN_REPLY_TO_EVENT_ID_KEY, "$parent"),
+    ])));
+    assert!(!is_edit(flattened_event(&[(REL_TYPE_KEY, "m.thread")])));
+    assert!(!is_edit(flattened_event(&[(
+        "content.body",
+        "hello world"
+    )])));
+}
+
+#[test]
+fn test_room_has_tag_condition() {
+    let has_tag = |tags: Option<&[&str]>, tag: &'static str| {
+        let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "hello")]));
+        // Another user's tags are never used.
+        evaluator.room_tags = BTreeMap::from([(
+            "@bob:test".to_string(),
+            BTreeSet::from(["m.lowpriority".to_string(), "m.favourite".to_string()]),
+        )]);
+        if let Some(tags) = tags {
+            evaluator.room_tags.insert(
+                "@alice:test".to_string(),
+                tags.iter().map(|tag| tag.to_string()).collect(),
+            );
+        }
+        evaluator
+            .match_condition(
+                &Condition::Known(KnownCondition::RoomHasTag {
+                    tag: Cow::Borrowed(tag),
+                }),
+                Some("@alice:test"),
+                None,
+            )
+            .unwrap()
+    };
+
+    let tags: &[&str] = &["m.lowpriority", "u.work"];
+    assert!(has_tag(Some(tags), "m.lowpriority"));
+    assert!(!has_tag(Some(tags), "m.favourite"));
+
+    let tags: &[&str] = &["m.favourite"];
+    assert!(has_tag(Some(tags), "m.favourite"));
+    assert!(!has_tag(Some(tags), "m.lowpriority"));
+
+    assert!(!has_tag(Some(&[]), "m.lowpriority"));
+
+    // Without the room's tags the condition never matches.
+    assert!(!has_tag(None, "m.lowpriority"));
+    assert!(!has_tag(None, "m.favourite"));
 }
 
-impl PushRuleEvaluator {
-    /// Match a given `Condition` for a push rule.
-    pub fn match_condition(
-        &self,
-        condition: &Condition,
-        user_id: Option<&str>,
-        display_name: Option<&str>,
-    ) -> Result<bool, Error> {
-        let known_condition = match condition {
-            Condition::Known(known) => known,
-            Condition::Unknown(_) => {
-                return Ok(false);
-            }
-        };
+#[test]
+fn test_relates_to_event_condition() {
+    let condition = Condition::Known(KnownCondition::RelatesToEvent {
+        event_id: Cow::Borrowed("$root"),
+    });
+    let relates_to_root = |event: BTreeMap<FlattenedKey, JsonValue>| {
+        evaluator_for_event(event)
+            .match_condition(&condition, None, None)
+            .unwrap()
+    };
 
-        let result = match known_condition {
-            KnownCondition::EventMatch(event_match) => self.match_event_match(
-                &self.flattened_keys,
-                &event_match.key,
-                &event_match.pattern,
-            )?,
-            KnownCondition::EventMatchType(event_match) => {
-                // The `pattern_type` can either be "user_id" or "user_localpart",
-                // either way if we don't have a `user_id` then the condition can't
-                // match.
-                let user_id = if let Some(user_id) = user_id {
-                    user_id
-                } else {
-                    return Ok(false);
-                };
+    assert!(relates_to_root(flattened_event(&[
+        ("content.body", "in the thread"),
+        (REL_TYPE_KEY, "m.thread"),
+        (RELATES_TO_EVENT_ID_KEY, "$root"),
+    ])));
+    assert!(relates_to_root(flattened_event(&[
+        ("content.body", "a reply"),
+        (IN_REPLY_TO_EVENT_ID_KEY, "$root"),
+    ])));
 
-                let pattern = match &*event_match.pattern_type {
-                    EventMatchPatternType::UserId => user_id,
-                    EventMatchPatternType::UserLocalpart => get_localpart_from_id(user_id)?,
-                };
+    assert!(!relates_to_root(flattened_event(&[
+        ("content.body", "in another thread"),
+        (REL_TYPE_KEY, "m.thread"),
+        (RELATES_TO_EVENT_ID_KEY, "$other"),
+        (IN_REPLY_TO_EVENT_ID_KEY, "$other"),
+    ])));
+    assert!(!relates_to_root(flattened_event(&[(
+        "content.body",
+        "no relation"
+    )])));
+}
 
-                self.match_event_match(&self.flattened_keys, &event_match.key, pattern)?
-            }
-            KnownCondition::EventPropertyIs(event_property_is) => {
-                self.match_event_property_is(event_property_is)?
-            }
-            KnownCondition::RelatedEventMatch(event_match) => self.match_related_event_match(
-                &event_match.rel_type.clone(),
-                event_match.include_fallbacks,
-                event_match.key.clone(),
-                event_match.pattern.clone(),
-            )?,
-            KnownCondition::RelatedEventMatchType(event_match) => {
-                // The `pattern_type` can either be "user_id" or "user_localpart",
-                // either way if we don't have a `user_id` then the condition can't
-                // match.
-                let user_id = if let Some(user_id) = user_id {
-                    user_id
-                } else {
-                    return Ok(false);
-                };
+#[test]
+fn test_run_with_stats() {
+    let rules = without_base_rules()
+        .push_rule(db_rule(
+            "global/content/coffee",
+            4,
+            r#"[{"kind": "event_match", "key": "type", "pattern": "m.room.message"}, {"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["notify"]"#,
+        ))
+        .push_rule(db_rule(
+            "global/content/tea",
+            4,
+            r#"[{"kind": "event_match", "key": "type", "pattern": "m.room.message"}, {"kind": "event_match", "key": "content.body", "pattern": "tea"}]"#,
+            r#"["notify"]"#,
+        ))
+        .build()
+        .unwrap();
 
-                let pattern = match &*event_match.pattern_type {
-                    EventMatchPatternType::UserId => user_id,
-                    EventMatchPatternType::UserLocalpart => get_localpart_from_id(user_id)?,
-                };
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.body", "coffee"),
+    ]));
+    let stats = evaluator.run_with_stats(&rules, None, None);
+    assert_eq!(stats.actions, evaluator.run(&rules, None, None));
+    assert_eq!(stats.rules_evaluated, 1);
+    assert_eq!(stats.conditions_evaluated, 2);
 
-                self.match_related_event_match(
-                    &event_match.rel_type.clone(),
-                    event_match.include_fallbacks,
-                    Some(event_match.key.clone()),
-                    Some(Cow::Borrowed(pattern)),
-                )?
-            }
-            KnownCondition::EventPropertyContains(event_property_is) => self
-                .match_event_property_contains(
-                    event_property_is.key.clone(),
-                    event_property_is.value.clone(),
-                )?,
-            KnownCondition::ExactEventPropertyContainsType(exact_event_match) => {
-                // The `pattern_type` can either be "user_id" or "user_localpart",
-                // either way if we don't have a `user_id` then the condition can't
-                // match.
-                let user_id = if let Some(user_id) = user_id {
-                    user_id
-                } else {
-                    return Ok(false);
-                };
+    // The type condition shared by both rules is only evaluated once.
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.body", "tea"),
+    ]));
+    let stats = evaluator.run_with_stats(&rules, None, None);
+    assert_eq!(stats.actions, evaluator.run(&rules, None, None));
+    assert_eq!(stats.rules_evaluated, 2);
+    assert_eq!(stats.conditions_evaluated, 3);
 
-                let pattern = match &*exact_event_match.value_type {
-                    EventMatchPatternType::UserId => user_id.to_owned(),
-                    EventMatchPatternType::UserLocalpart => {
-                        get_localpart_from_id(user_id)?.to_owned()
-                    }
-                };
+    // Nothing matches, so every rule is evaluated.
+    let evaluator = evaluator_for_event(flattened_event(&[("type", "m.room.member")]));
+    let stats = evaluator.run_with_stats(&rules, None, None);
+    assert!(stats.actions.is_empty());
+    assert_eq!(stats.rules_evaluated, 2);
+    assert_eq!(stats.conditions_evaluated, 1);
+}
 
-                self.match_event_property_contains(
-                    exact_event_match.key.clone(),
-                    Cow::Borrowed(&SimpleJsonValue::Str(Cow::Owned(pattern))),
-                )?
-            }
-            KnownCondition::ContainsDisplayName => {
-                if let Some(dn) = display_name {
-                    if !dn.is_empty() {
-                        get_glob_matcher(dn, GlobMatchType::Word)?.is_match(&self.body)?
-                    } else {
-                        // We specifically ignore empty display names, as otherwise
-                        // they would always match.
-                        false
-                    }
-                } else {
-                    false
-                }
-            }
-            KnownCondition::RoomMemberCount { is } => {
-                if let Some(is) = is {
-                    self.match_member_count(is)?
-                } else {
-                    false
-                }
-            }
-            KnownCondition::SenderNotificationPermission { key } => {
-                if let Some(sender_power_level) = &self.sender_power_level {
-                    let required_level = self
-                        .notification_power_levels
-                        .get(key.as_ref())
-                        .copied()
-                        .unwrap_or(50);
-
-                    *sender_power_level >= required_level
-                } else {
-                    false
-                }
-            }
-            KnownCondition::RoomVersionSupports { feature } => {
-                if !self.msc3931_enabled {
-                    false
-                } else {
-                    let flag = feature.to_string();
-                    KNOWN_RVER_FLAGS.contains(&flag)
-                        && self.room_version_feature_flags.contains(&flag)
-                }
-            }
-        };
+#[test]
+fn test_max_rules_evaluated() {
+    let rule = |rule_id: &str, body: &str| {
+        db_rule(
+            format!("global/content/{rule_id}"),
+            4,
+            &format!(r#"[{{"kind": "event_match", "key": "content.body", "pattern": "{body}"}}]"#),
+            r#"["notify"]"#,
+        )
+    };
+    let rules = without_base_rules()
+        .push_rule(rule("coffee", "coffee"))
+        .push_rule(rule("tea", "tea"))
+        .push_rule(rule("cake", "cake"))
+        // Disabled rules don't count towards the limit.
+        .push_rule(rule("disabled", "nothing"))
+        .enabled("global/content/disabled", false)
+        .push_rule(rule("biscuits", "biscuits"))
+        .build()
+        .unwrap();
 
-        Ok(result)
-    }
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "biscuits")]));
+    assert_eq!(evaluator.run(&rules, None, None), vec![Action::Notify]);
 
-    /// Evaluates a `event_match` condition.
-    fn match_event_match(
-        &self,
-        flattened_event: &BTreeMap<String, JsonValue>,
-        key: &str,
-        pattern: &str,
-    ) -> Result<bool, Error> {
-        let haystack = if let Some(JsonValue::Value(SimpleJsonValue::Str(haystack))) =
-            flattened_event.get(key)
-        {
-            haystack
-        } else {
-            return Ok(false);
-        };
+    // Only the first three rules are evaluated, so the last doesn't match.
+    evaluator.max_rules_evaluated = 3;
+    assert!(evaluator.run(&rules, None, None).is_empty());
+    assert!(evaluator.run_all_matches(&rules, None, None).is_empty());
+    assert!(evaluator.run_timed(&rules, None, None).actions.is_empty());
+    let stats = evaluator.run_with_stats(&rules, None, None);
+    assert!(stats.actions.is_empty());
+    assert_eq!(stats.rules_evaluated, 3);
+    assert!(evaluator.run_strict(&rules, None, None).actions.is_empty());
 
-        // For the content.body we match against "words", but for everything
-        // else we match against the entire value.
-        let match_type = if key == "content.body" {
-            GlobMatchType::Word
-        } else {
-            GlobMatchType::Whole
-        };
+    // Rules within the limit still match.
+    let mut evaluator = evaluator_for_event(flattened_event(&[("content.body", "cake")]));
+    evaluator.max_rules_evaluated = 3;
+    assert_eq!(evaluator.run(&rules, None, None), vec![Action::Notify]);
+}
 
-        let mut compiled_pattern = get_glob_matcher(pattern, match_type)?;
-        compiled_pattern.is_match(haystack)
-    }
+#[test]
+fn test_max_body_match_length() {
+    use crate::push::EventMatchCondition;
 
-    /// Evaluates a `event_property_is` condition.
-    fn match_event_property_is(
-        &self,
-        event_property_is: &EventPropertyIsCondition,
-    ) -> Result<bool, Error> {
-        let value = &event_property_is.value;
+    let body_match = |pattern: &'static str| {
+        Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
+            key: EventMatchKey::Single(Cow::Borrowed("content.body")),
+            pattern: Cow::Borrowed(pattern),
+            ignore_quotes: false,
+            match_words: None,
+            contains: false,
+        }))
+    };
+    let evaluator = |body: &str, max_body_match_length| {
+        PushRuleEvaluator::new(EvaluatorInputs {
+            flattened_keys: BTreeMap::from([(
+                "content.body".into(),
+                JsonValue::Value(SimpleJsonValue::Str(Cow::Owned(body.to_string()))),
+            )]),
+            max_body_match_length,
+            ..Default::default()
+        })
+    };
 
-        let haystack = if let Some(JsonValue::Value(haystack)) =
-            self.flattened_keys.get(&*event_property_is.key)
-        {
-            haystack
-        } else {
-            return Ok(false);
-        };
+    // A keyword near the start and one far past the default limit.
+    let body = format!(
+        "coffee {} tea bob",
+        "la ".repeat(DEFAULT_MAX_BODY_MATCH_LENGTH)
+    );
+    let evaluator_default = evaluator(&body, DEFAULT_MAX_BODY_MATCH_LENGTH);
+    assert!(evaluator_default
+        .match_condition(&body_match("coffee"), None, None)
+        .unwrap());
+    assert!(!evaluator_default
+        .match_condition(&body_match("tea"), None, None)
+        .unwrap());
+    assert!(!evaluator_default
+        .match_condition(
+            &Condition::Known(KnownCondition::ContainsDisplayName),
+            None,
+            Some("bob")
+        )
+        .unwrap());
 
-        Ok(haystack == &**value)
-    }
+    // The limit is in characters, and bodies within it are matched in full.
+    let evaluator_short = evaluator("☕☕ coffee", 9);
+    assert!(evaluator_short
+        .match_condition(&body_match("coffee"), None, None)
+        .unwrap());
+    let evaluator_short = evaluator("☕☕ coffee", 8);
+    assert!(!evaluator_short
+        .match_condition(&body_match("coffee"), None, None)
+        .unwrap());
+}
 
-    /// Evaluates a `related_event_match` condition. (MSC3664)
-    fn match_related_event_match(
-        &self,
-        rel_type: &str,
-        include_fallbacks: Option<bool>,
-        key: Option<Cow<str>>,
-        pattern: Option<Cow<str>>,
-    ) -> Result<bool, Error> {
-        // First check if related event matching is enabled...
-        if !self.related_event_match_enabled {
-            return Ok(false);
-        }
+#[test]
+fn test_run_strict() {
+    use crate::push::FilteredPushRulesBuilder;
 
-        // get the related event, fail if there is none.
-        let event = if let Some(event) = self.related_events_flattened.get(rel_type) {
-            event
-        } else {
-            return Ok(false);
-        };
+    let rule = |rule_id: &str, body: &str| {
+        db_rule(
+            format!("global/content/{rule_id}"),
+            4,
+            &format!(r#"[{{"kind": "event_match", "key": "content.body", "pattern": "{body}"}}]"#),
+            r#"["notify"]"#,
+        )
+    };
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule("coffee", "coffee"))
+        .push_rule(rule("tea", "tea"))
+        .push_rule(rule("drinks", "*"))
+        .enabled("global/content/coffee", false)
+        .enabled("global/content/tea", false)
+        .build()
+        .unwrap();
 
-        // If we are not matching fallbacks, don't match if our special key indicating this is a
-        // fallback relation is not present.
-        if !include_fallbacks.unwrap_or(false) && event.contains_key("im.vector.is_falling_back") {
-            return Ok(false);
-        }
+    let evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.text"),
+        ("content.body", "coffee"),
+    ]));
+    let strict_run = evaluator.run_strict(&rules, None, None);
 
-        match (key, pattern) {
-            // if we have no key, accept the event as matching.
-            (None, _) => Ok(true),
-            // There was a key, so we *must* have a pattern to go with it.
-            (Some(_), None) => Ok(false),
-            // If there is a key & pattern, check if they're in the flattened event (given by rel_type).
-            (Some(key), Some(pattern)) => self.match_event_match(event, &key, &pattern),
-        }
-    }
+    // The actions are unaffected by the disabled rules.
+    assert_eq!(strict_run.actions, evaluator.run(&rules, None, None));
+    assert_eq!(
+        evaluator
+            .run_with_matched_rule(&rules, None, None)
+            .unwrap()
+            .0,
+        "global/content/drinks"
+    );
+    // The master rule is disabled by default, and has no conditions.
+    assert_eq!(
+        strict_run.disabled_matches,
+        ["global/override/.m.rule.master", "global/content/coffee"]
+    );
+}
 
-    /// Evaluates a `event_property_contains` condition.
-    fn match_event_property_contains(
-        &self,
-        key: Cow<str>,
-        value: Cow<SimpleJsonValue>,
-    ) -> Result<bool, Error> {
-        let haystack = if let Some(JsonValue::Array(haystack)) = self.flattened_keys.get(&*key) {
-            haystack
-        } else {
-            return Ok(false);
-        };
+#[test]
+fn test_run_strict_conditions() {
+    use crate::push::FilteredPushRulesBuilder;
 
-        Ok(haystack.contains(&value))
-    }
+    let rule = |rule_id: &str, conditions: &str| {
+        db_rule(
+            format!("global/override/{rule_id}"),
+            5,
+            conditions,
+            r#"["notify"]"#,
+        )
+    };
+    // The rules aren't checked, as unknown conditions are the point.
+    let rules = |push_rules: Vec<PushRule>, disabled: &[&str]| {
+        let builder = push_rules.into_iter().fold(
+            FilteredPushRulesBuilder::new(),
+            FilteredPushRulesBuilder::push_rule,
+        );
+        disabled
+            .iter()
+            .fold(builder, |builder, rule_id| builder.enabled(*rule_id, false))
+            .build_unchecked()
+    };
+    let mut evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.text"),
+        ("content.body", "coffee"),
+    ]));
 
-    /// Match the member count against an 'is' condition
-    /// The `is` condition can be things like '>2', '==3' or even just '4'.
-    fn match_member_count(&self, is: &str) -> Result<bool, Error> {
-        let captures = INEQUALITY_EXPR.captures(is).context("bad 'is' clause")?;
-        let ineq = captures.get(1).map_or("==", |m| m.as_str());
-        let rhs: u64 = captures
-            .get(2)
-            .context("missing number")?
-            .as_str()
-            .parse()?;
-
-        let matches = match ineq {
-            "" | "==" => self.room_member_count == rhs,
-            "<" => self.room_member_count < rhs,
-            ">" => self.room_member_count > rhs,
-            ">=" => self.room_member_count >= rhs,
-            "<=" => self.room_member_count <= rhs,
-            _ => false,
-        };
+    // Rule sets without unknown conditions run as usual.
+    let known = rules(
+        vec![rule(
+            "coffee",
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+        )],
+        &[],
+    );
+    assert_eq!(
+        evaluator.run_strict_conditions(&known, None, None).unwrap(),
+        evaluator.run(&known, None, None)
+    );
+
+    // Misspelled kinds are reported, including those nested in other
+    // conditions and those in rules the event doesn't reach.
+    let misspelled = rules(
+        vec![
+            rule(
+                "coffee",
+                r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            ),
+            rule(
+                "typo",
+                r#"[{"kind": "event_matc", "key": "content.body", "pattern": "tea"}]"#,
+            ),
+            rule(
+                "nested",
+                r#"[{"kind": "io.element.not", "condition": {"kind": "room_member_cuont", "is": "2"}}]"#,
+            ),
+            rule("disabled", r#"[{"kind": "org.example.disabled"}]"#),
+        ],
+        &["global/override/disabled"],
+    );
+    let err = evaluator
+        .run_strict_conditions(&misspelled, None, None)
+        .unwrap_err();
+    assert_eq!(
+        err.conditions,
+        [
+            (
+                "global/override/typo".to_string(),
+                serde_json::json!({"kind": "event_matc", "key": "content.body", "pattern": "tea"})
+            ),
+            (
+                "global/override/nested".to_string(),
+                serde_json::json!({"kind": "room_member_cuont", "is": "2"})
+            ),
+        ]
+    );
+    assert!(err.to_string().contains("event_matc"));
+
+    // The lenient run treats them as not matching.
+    assert_eq!(
+        evaluator
+            .run_with_matched_rule(&misspelled, None, None)
+            .unwrap()
+            .0,
+        "global/override/coffee"
+    );
+
+    // Conditions with a custom evaluator aren't unknown.
+    let custom = rules(
+        vec![rule("custom", r#"[{"kind": "org.example.custom"}]"#)],
+        &[],
+    );
+    assert!(evaluator
+        .run_strict_conditions(&custom, None, None)
+        .is_err());
+    evaluator.register_custom_condition(
+        "org.example.custom".to_string(),
+        Box::new(|_: &Value, _: Option<&str>, _: Option<&str>| Ok(true)),
+    );
+    assert_eq!(
+        evaluator
+            .run_strict_conditions(&custom, None, None)
+            .unwrap(),
+        [Action::Notify]
+    );
+}
+
+#[test]
+fn test_diff_rule_sets() {
+    use crate::push::FilteredPushRulesBuilder;
+
+    let rule = |rule_id: &str| {
+        db_rule(
+            format!("global/content/{rule_id}"),
+            4,
+            &format!(
+                r#"[{{"kind": "event_match", "key": "content.body", "pattern": "{rule_id}"}}]"#
+            ),
+            r#"["notify", {"set_tweak": "highlight"}]"#,
+        )
+    };
+    let rules = FilteredPushRulesBuilder::new()
+        .push_rule(rule("coffee"))
+        .push_rule(rule("tea"));
+    let old_rules = rules.clone().build().unwrap();
+    let new_rules = rules.enabled("global/content/tea", false).build().unwrap();
+
+    let corpus: Vec<_> = ["coffee?", "tea?", "water?", "coffee or tea?", "green tea"]
+        .into_iter()
+        .map(|body| EvaluatorInputs {
+            flattened_keys: flattened_event(&[
+                ("type", "m.room.message"),
+                ("content.msgtype", "m.text"),
+                ("content.body", body),
+            ]),
+            room_member_count: 5,
+            ..Default::default()
+        })
+        .collect();
 
-        Ok(matches)
+    // Only the events which matched the disabled rule (and no earlier one)
+    // change, and fall through to the base rules.
+    let highlight: Vec<Action> =
+        serde_json::from_str(r#"["notify", {"set_tweak": "highlight"}]"#).unwrap();
+    let no_highlight: Vec<Action> =
+        serde_json::from_str(r#"["notify", {"set_tweak": "highlight", "value": false}]"#).unwrap();
+    let diff = diff_rule_sets(&old_rules, &new_rules, &corpus, Some("@bob:test"), None);
+    assert_eq!(
+        diff.iter().map(|diff| diff.index).collect::<Vec<_>>(),
+        [1, 4]
+    );
+    for diff in &diff {
+        assert_eq!(
+            serde_json::to_value(&diff.old_actions).unwrap(),
+            serde_json::to_value(&highlight).unwrap()
+        );
+        assert_eq!(
+            serde_json::to_value(&diff.new_actions).unwrap(),
+            serde_json::to_value(&no_highlight).unwrap()
+        );
     }
+
+    assert!(diff_rule_sets(&old_rules, &old_rules, &corpus, Some("@bob:test"), None).is_empty());
 }
 
 #[test]
-fn push_rule_evaluator() {
-    let mut flattened_keys = BTreeMap::new();
-    flattened_keys.insert(
-        "content.body".to_string(),
-        JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("foo bar bob hello"))),
+fn test_reevaluate_related_events() {
+    let rule = |rule_id: &str, conditions: &str, actions: &str| {
+        db_rule(format!("global/override/{rule_id}"), 5, conditions, actions)
+    };
+    let rules = without_base_rules()
+        .push_rule(rule(
+            "coffee",
+            r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#,
+            r#"["notify"]"#,
+        ))
+        .push_rule(rule(
+            "reply_to_bob",
+            r#"[{"kind": "im.nheko.msc3664.related_event_match", "rel_type": "m.in_reply_to", "key": "sender", "pattern": "@bob:example.org"}]"#,
+            r#"["notify", {"set_tweak": "highlight"}]"#,
+        ))
+        .push_rule(rule(
+            "messages",
+            r#"[{"kind": "event_match", "key": "type", "pattern": "m.room.message"}]"#,
+            r#"["notify"]"#,
+        ))
+        .msc3664_enabled(true)
+        .build()
+        .unwrap();
+
+    let mut evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("content.msgtype", "m.text"),
+        ("content.body", "tea"),
+    ]));
+    evaluator.related_event_match_enabled = true;
+    let previous = evaluator.run_outcome(&rules, None, None);
+    assert_eq!(previous.rule_id(), Some("global/override/messages"));
+
+    // Only the rule on the related events is evaluated again.
+    let reply = BTreeMap::from([(
+        "m.in_reply_to".to_string(),
+        vec![flattened_event(&[("sender", "@bob:example.org")])],
+    )]);
+    let update = evaluator.reevaluate_related_events(&rules, &previous, reply, None, None);
+    assert_eq!(update.rules_evaluated, 1);
+    assert!(update.changed);
+    assert_eq!(update.outcome, evaluator.run_outcome(&rules, None, None));
+    assert_eq!(
+        update.outcome.rule_id(),
+        Some("global/override/reply_to_bob")
     );
-    let evaluator = PushRuleEvaluator::py_new(
-        flattened_keys,
-        false,
-        10,
-        Some(0),
-        BTreeMap::new(),
-        BTreeMap::new(),
-        true,
-        vec![],
-        true,
-    )
-    .unwrap();
 
-    let result = evaluator.run(&FilteredPushRules::default(), None, Some("bob"));
-    assert_eq!(result.len(), 3);
+    // Once the reply no longer matches, the later rules are evaluated again.
+    let previous = update.outcome;
+    let update =
+        evaluator.reevaluate_related_events(&rules, &previous, BTreeMap::new(), None, None);
+    assert_eq!(update.rules_evaluated, 2);
+    assert!(update.changed);
+    assert_eq!(update.outcome.rule_id(), Some("global/override/messages"));
+
+    // Unchanged related events leave the outcome alone.
+    let update =
+        evaluator.reevaluate_related_events(&rules, &update.outcome, BTreeMap::new(), None, None);
+    assert_eq!(update.rules_evaluated, 1);
+    assert!(!update.changed);
+
+    // Rules past the limit aren't evaluated again either.
+    evaluator.max_rules_evaluated = 1;
+    let previous = evaluator.run_outcome(&rules, None, None);
+    assert_eq!(previous, RunOutcome::Default);
+    let reply = BTreeMap::from([(
+        "m.in_reply_to".to_string(),
+        vec![flattened_event(&[("sender", "@bob:example.org")])],
+    )]);
+    let update = evaluator.reevaluate_related_events(&rules, &previous, reply, None, None);
+    assert_eq!(update.rules_evaluated, 0);
+    assert!(!update.changed);
+    assert_eq!(update.outcome, RunOutcome::Default);
 }
 
 #[test]
-fn test_requires_room_version_supports_condition() {
-    use std::borrow::Cow;
+fn test_inputs_json_round_trip() {
+    let mut evaluator = evaluator_for_event(flattened_event(&[
+        ("type", "m.room.message"),
+        ("sender", "@alice:example.org"),
+        ("content.body", "hello bob"),
+    ]));
+    evaluator.set_sender_power_level(Some(100));
+    evaluator.set_notification_power_levels(BTreeMap::from([("room".to_string(), 20)]));
+    evaluator.condition_match_budget = Some(5_000);
+    evaluator.room_name = Some("Coffee".to_string());
 
-    use crate::push::{PushRule, PushRules};
+    let json = evaluator.inputs_to_json().unwrap();
+    let rebuilt = PushRuleEvaluator::from_inputs_json(&json).unwrap();
+    assert_eq!(rebuilt.inputs(), evaluator.inputs());
+    assert_eq!(rebuilt.inputs_to_json().unwrap(), json);
 
-    let mut flattened_keys = BTreeMap::new();
-    flattened_keys.insert(
-        "content.body".to_string(),
-        JsonValue::Value(SimpleJsonValue::Str(Cow::Borrowed("foo bar bob hello"))),
+    let rules = FilteredPushRules::default();
+    assert_eq!(
+        rebuilt.run(&rules, Some("@bob:example.org"), Some("bob")),
+        evaluator.run(&rules, Some("@bob:example.org"), Some("bob"))
     );
-    let flags = vec![RoomVersionFeatures::ExtensibleEvents.as_str().to_string()];
-    let evaluator = PushRuleEvaluator::py_new(
-        flattened_keys,
-        false,
-        10,
-        Some(0),
-        BTreeMap::new(),
-        BTreeMap::new(),
-        false,
-        flags,
-        true,
+
+    // Inputs with defaults can be left out.
+    let rebuilt = PushRuleEvaluator::from_inputs_json(
+        r#"{
+            "flattened_keys": {"type": "m.room.message"},
+            "has_mentions": false,
+            "room_member_count": 2,
+            "sender_power_level": null,
+            "notification_power_levels": {},
+            "related_events_flattened": {},
+            "related_event_match_enabled": false,
+            "room_version_feature_flags": [],
+            "msc3931_enabled": false
+        }"#,
     )
     .unwrap();
+    assert_eq!(rebuilt.room_member_count, 2);
+    assert_eq!(rebuilt.default_notification_power_level, 50);
 
-    // first test: are the master and contains_user_name rules excluded from the "requires room
-    // version condition" check?
-    let mut result = evaluator.run(
-        &FilteredPushRules::default(),
-        Some("@bob:example.org"),
-        None,
-    );
-    assert_eq!(result.len(), 3);
+    // If a flattened key is given more than once the last value wins.
+    let rebuilt = PushRuleEvaluator::from_inputs_json(
+        r#"{
+            "flattened_keys": {"content.body": "hello bob", "content.body": "hello"},
+            "has_mentions": false,
+            "room_member_count": 2,
+            "sender_power_level": null,
+            "notification_power_levels": {},
+            "related_events_flattened": {},
+            "related_event_match_enabled": false,
+            "room_version_feature_flags": [],
+            "msc3931_enabled": false
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(rebuilt.body, "hello");
+    assert!(!rebuilt
+        .run(&FilteredPushRules::default(), None, Some("bob"))
+        .contains(&Action::Notify));
 
-    // second test: if an appropriate push rule is in play, does it get handled?
-    let custom_rule = PushRule {
-        rule_id: Cow::from("global/underride/.org.example.extensible"),
-        priority_class: 1, // underride
-        conditions: Cow::from(vec![Condition::Known(
-            KnownCondition::RoomVersionSupports {
-                feature: Cow::from(RoomVersionFeatures::ExtensibleEvents.as_str().to_string()),
-            },
-        )]),
-        actions: Cow::from(vec![Action::Notify]),
-        default: false,
-        default_enabled: true,
-    };
-    let rules = PushRules::new(vec![custom_rule]);
-    result = evaluator.run(
-        &FilteredPushRules::py_new(rules, BTreeMap::new(), true, false, true, false),
-        None,
-        None,
+    assert!(PushRuleEvaluator::from_inputs_json("{}").is_err());
+}
+
+#[test]
+fn test_new_from_rust() {
+    let evaluator = PushRuleEvaluator::new(EvaluatorInputs {
+        flattened_keys: flattened_event(&[
+            ("type", "m.room.message"),
+            ("content.msgtype", "m.text"),
+            ("content.body", "hello bob"),
+        ]),
+        room_member_count: 2,
+        ..Default::default()
+    });
+
+    assert_eq!(
+        evaluator.run_with_matched_rule(
+            &FilteredPushRules::default(),
+            Some("@bob:example.org"),
+            Some("bob")
+        ),
+        Some((
+            "global/override/.m.rule.contains_display_name".to_string(),
+            evaluator.run(
+                &FilteredPushRules::default(),
+                Some("@bob:example.org"),
+                Some("bob")
+            )
+        ))
     );
-    assert_eq!(result.len(), 1);
+
+    // The defaults are the same as those of the Python constructor.
+    assert_eq!(evaluator.max_rules_evaluated, DEFAULT_MAX_RULES_EVALUATED);
+    assert_eq!(evaluator.default_notification_power_level, 50);
+    assert_eq!(evaluator.inputs().room_member_count, 2);
 }
@@ -0,0 +1,187 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Golden tests of evaluating realistic rule sets against events.
+//!
+//! Each JSON file in the `golden` directory is a rule set and the events to
+//! run it against, along with the actions `PushRuleEvaluator::run` must
+//! return for each in their serialized form:
+//!
+//! ```json
+//! {
+//!     "rules": [
+//!         {
+//!             "rule_id": "global/content/coffee",
+//!             "priority_class": 4,
+//!             "conditions": [{"kind": "event_match", "key": "content.body", "pattern": "coffee"}],
+//!             "actions": ["notify", {"set_tweak": "highlight"}]
+//!         }
+//!     ],
+//!     "enabled": {"global/override/.m.rule.master": false},
+//!     "cases": [
+//!         {
+//!             "name": "keyword",
+//!             "inputs": {"flattened_keys": {"type": "m.room.message", "content.body": "coffee?"}},
+//!             "user_id": "@alice:example.org",
+//!             "display_name": "Alice",
+//!             "actions": ["notify", {"set_tweak": "highlight"}]
+//!         }
+//!     ]
+//! }
+//! ```
+//!
+//! The user's `rules` are evaluated along with the base rules, and `enabled`
+//! is optional. The `inputs` of a case are those of the evaluator (see
+//! `EvaluatorInputs`), and only need to include what differs from its
+//! `Default`. The `user_id` and `display_name` are optional.
+//!
+//! New fixtures are picked up automatically, and every failing case is
+//! reported rather than just the first.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::evaluator::{EvaluatorInputs, PushRuleEvaluator};
+use super::{Action, Condition, FilteredPushRules, PushRule, PushRules};
+
+/// The directory of the fixtures, relative to the crate.
+const FIXTURES_DIR: &str = "src/push/golden";
+
+#[derive(Deserialize)]
+struct Fixture {
+    rules: Vec<FixtureRule>,
+    #[serde(default)]
+    enabled: BTreeMap<String, bool>,
+    cases: Vec<FixtureCase>,
+}
+
+#[derive(Deserialize)]
+struct FixtureRule {
+    rule_id: String,
+    priority_class: i32,
+    conditions: Vec<Condition>,
+    actions: Vec<Action>,
+}
+
+#[derive(Deserialize)]
+struct FixtureCase {
+    name: String,
+    inputs: serde_json::Map<String, Value>,
+    user_id: Option<String>,
+    display_name: Option<String>,
+    /// The expected actions, as they'd be sent to clients.
+    actions: Value,
+}
+
+impl Fixture {
+    fn push_rules(&self) -> FilteredPushRules {
+        let rules = self
+            .rules
+            .iter()
+            .map(|rule| PushRule {
+                rule_id: rule.rule_id.clone().into(),
+                priority_class: rule.priority_class,
+                conditions: rule.conditions.clone().into(),
+                actions: rule.actions.clone().into(),
+                default: false,
+                default_enabled: true,
+            })
+            .collect();
+
+        FilteredPushRules::py_new(
+            PushRules::new(rules),
+            self.enabled.clone(),
+            false,
+            false,
+            false,
+            false,
+        )
+    }
+}
+
+impl FixtureCase {
+    /// Builds the evaluator for the case, with the default inputs overridden by
+    /// those of the case.
+    fn evaluator(&self) -> Result<PushRuleEvaluator, serde_json::Error> {
+        let mut inputs = match serde_json::to_value(EvaluatorInputs::default())? {
+            Value::Object(inputs) => inputs,
+            _ => unreachable!("inputs serialize to an object"),
+        };
+        inputs.extend(self.inputs.clone());
+
+        Ok(PushRuleEvaluator::new(serde_json::from_value(
+            Value::Object(inputs),
+        )?))
+    }
+}
+
+/// Runs the cases of the fixture, returning a description of each failure.
+fn run_fixture(path: &Path) -> Vec<String> {
+    let name = path.display();
+    let fixture: Fixture = match fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|err| err.to_string()))
+    {
+        Ok(fixture) => fixture,
+        Err(err) => return vec![format!("{name}: invalid fixture: {err}")],
+    };
+    let push_rules = fixture.push_rules();
+
+    let mut failures = Vec::new();
+    for case in &fixture.cases {
+        let evaluator = match case.evaluator() {
+            Ok(evaluator) => evaluator,
+            Err(err) => {
+                failures.push(format!("{name}: {}: invalid inputs: {err}", case.name));
+                continue;
+            }
+        };
+
+        let actions = evaluator.run(
+            &push_rules,
+            case.user_id.as_deref(),
+            case.display_name.as_deref(),
+        );
+        // Compare the serialized actions, as the same action may be
+        // represented in more than one way.
+        let actions = serde_json::to_value(&actions).unwrap_or_default();
+        if actions != case.actions {
+            failures.push(format!(
+                "{name}: {}: expected {}, got {actions}",
+                case.name, case.actions,
+            ));
+        }
+    }
+
+    failures
+}
+
+#[test]
+fn test_golden_fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(FIXTURES_DIR);
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "No fixtures in {}", dir.display());
+
+    let failures: Vec<_> = paths.iter().flat_map(|path| run_fixture(path)).collect();
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
@@ -0,0 +1,199 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Counters of the work done evaluating push rules: how often, and for how
+//! long, each kind of condition is evaluated and how often globs are compiled.
+//!
+//! The counters are only recorded if the `metrics` feature is enabled,
+//! otherwise recording compiles down to nothing.
+
+use std::collections::BTreeMap;
+
+use pyo3::prelude::*;
+
+use super::KnownCondition;
+
+/// A snapshot of the push rule evaluation counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[pyclass(frozen, get_all)]
+pub struct PushMetrics {
+    /// Whether the counters are recorded, i.e. the `metrics` feature is
+    /// enabled.
+    pub enabled: bool,
+    /// The number of times each kind of condition has been evaluated.
+    pub condition_counts: BTreeMap<String, u64>,
+    /// The total time spent evaluating each kind of condition, in nanoseconds.
+    pub condition_time_ns: BTreeMap<String, u64>,
+    /// The number of globs compiled to regexes.
+    pub glob_compilations: u64,
+}
+
+/// Returns a snapshot of the push rule evaluation counters.
+#[pyfunction]
+pub fn get_push_metrics() -> PushMetrics {
+    imp::snapshot()
+}
+
+/// Evaluates the condition with `evaluate`, recording how long it took.
+#[inline]
+pub fn time_condition<T>(condition: &KnownCondition, evaluate: impl FnOnce() -> T) -> T {
+    imp::time_condition(condition, evaluate)
+}
+
+/// Records that a glob was compiled to a regex.
+#[inline]
+pub fn record_glob_compilation() {
+    imp::record_glob_compilation()
+}
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use std::collections::HashMap;
+    use std::mem::{discriminant, Discriminant};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::RwLock;
+    use std::time::Instant;
+
+    use lazy_static::lazy_static;
+
+    use super::{KnownCondition, PushMetrics};
+
+    /// The counters for a single `KnownCondition` variant.
+    struct ConditionCounters {
+        /// The `kind` of the condition. Note that some variants share a kind.
+        kind: String,
+        count: AtomicU64,
+        time_ns: AtomicU64,
+    }
+
+    lazy_static! {
+        static ref CONDITION_COUNTERS: RwLock<HashMap<Discriminant<KnownCondition>, ConditionCounters>> =
+            RwLock::new(HashMap::new());
+    }
+
+    static GLOB_COMPILATIONS: AtomicU64 = AtomicU64::new(0);
+
+    pub fn time_condition<T>(condition: &KnownCondition, evaluate: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = evaluate();
+        let elapsed = start.elapsed().as_nanos().try_into().unwrap_or(u64::MAX);
+
+        let key = discriminant(condition);
+        let record = |counters: &ConditionCounters| {
+            counters.count.fetch_add(1, Ordering::Relaxed);
+            counters.time_ns.fetch_add(elapsed, Ordering::Relaxed);
+        };
+
+        if let Some(counters) = CONDITION_COUNTERS
+            .read()
+            .ok()
+            .as_ref()
+            .and_then(|counters| counters.get(&key))
+        {
+            record(counters);
+            return result;
+        }
+
+        // This is the first time we've seen this kind of condition.
+        if let Ok(mut all_counters) = CONDITION_COUNTERS.write() {
+            let counters = all_counters
+                .entry(key)
+                .or_insert_with(|| ConditionCounters {
+                    kind: condition.kind().to_string(),
+                    count: AtomicU64::new(0),
+                    time_ns: AtomicU64::new(0),
+                });
+            record(counters);
+        }
+
+        result
+    }
+
+    pub fn record_glob_compilation() {
+        GLOB_COMPILATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot() -> PushMetrics {
+        let mut metrics = PushMetrics {
+            enabled: true,
+            glob_compilations: GLOB_COMPILATIONS.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+
+        if let Ok(all_counters) = CONDITION_COUNTERS.read() {
+            for counters in all_counters.values() {
+                *metrics
+                    .condition_counts
+                    .entry(counters.kind.clone())
+                    .or_default() += counters.count.load(Ordering::Relaxed);
+                *metrics
+                    .condition_time_ns
+                    .entry(counters.kind.clone())
+                    .or_default() += counters.time_ns.load(Ordering::Relaxed);
+            }
+        }
+
+        metrics
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    use super::{KnownCondition, PushMetrics};
+
+    #[inline(always)]
+    pub fn time_condition<T>(_condition: &KnownCondition, evaluate: impl FnOnce() -> T) -> T {
+        evaluate()
+    }
+
+    #[inline(always)]
+    pub fn record_glob_compilation() {}
+
+    pub fn snapshot() -> PushMetrics {
+        PushMetrics::default()
+    }
+}
+
+#[test]
+#[cfg(feature = "metrics")]
+fn test_metrics() {
+    let condition = KnownCondition::IsThreadStart;
+
+    let before = get_push_metrics();
+    assert!(time_condition(&condition, || true));
+    assert!(!time_condition(&condition, || false));
+    record_glob_compilation();
+    let after = get_push_metrics();
+
+    assert!(after.enabled);
+    // Other tests may evaluate conditions concurrently.
+    let count = |metrics: &PushMetrics| {
+        metrics
+            .condition_counts
+            .get("io.element.is_thread_start")
+            .copied()
+            .unwrap_or(0)
+    };
+    assert!(count(&after) >= count(&before) + 2);
+    assert!(after.glob_compilations > before.glob_compilations);
+}
+
+#[test]
+#[cfg(not(feature = "metrics"))]
+fn test_metrics_disabled() {
+    assert!(time_condition(&KnownCondition::IsThreadStart, || true));
+    record_glob_compilation();
+
+    assert_eq!(get_push_metrics(), PushMetrics::default());
+}
@@ -52,9 +52,11 @@
 //! kind, etc, etc.
 
 use std::borrow::Cow;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Error};
+use anyhow::{bail, Context, Error};
 use log::warn;
 use pyo3::exceptions::PyTypeError;
 use pyo3::prelude::*;
@@ -64,11 +66,22 @@ use serde::de::Error as _;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use self::evaluator::PushRuleEvaluator;
+pub use self::base_rules::BASE_RULES_VERSION;
+use self::evaluator::{event_match_type, normalize_inequality, PushRuleEvaluator};
+pub use self::sentiment::SentimentPolarity;
+use self::utils::{GlobMatchType, PrecompiledGlobs, GLOB_MATCHER_CACHE};
 
 mod base_rules;
+mod confusables;
 pub mod evaluator;
+#[cfg(test)]
+mod golden;
+pub mod metrics;
+mod sentiment;
 pub mod utils;
+pub mod validation;
+#[cfg(test)]
+mod wire_format;
 
 /// Called when registering modules with python.
 pub fn register_module(py: Python<'_>, m: &PyModule) -> PyResult<()> {
@@ -77,7 +90,11 @@ pub fn register_module(py: Python<'_>, m: &PyModule) -> PyResult<()> {
     child_module.add_class::<PushRules>()?;
     child_module.add_class::<FilteredPushRules>()?;
     child_module.add_class::<PushRuleEvaluator>()?;
+    child_module.add_class::<metrics::PushMetrics>()?;
     child_module.add_function(wrap_pyfunction!(get_base_rule_ids, m)?)?;
+    child_module.add_function(wrap_pyfunction!(get_base_rules, m)?)?;
+    child_module.add_function(wrap_pyfunction!(metrics::get_push_metrics, m)?)?;
+    child_module.add_function(wrap_pyfunction!(validation::py_validate_rule, m)?)?;
 
     m.add_submodule(child_module)?;
 
@@ -95,6 +112,26 @@ fn get_base_rule_ids() -> HashSet<&'static str> {
     base_rules::BASE_RULES_BY_ID.keys().copied().collect()
 }
 
+/// Returns the base rules of the given version, without any user rules, see
+/// `base_rules`.
+#[pyfunction]
+fn get_base_rules(version: &str) -> Result<FilteredPushRules, Error> {
+    base_rules(version)
+}
+
+/// Returns the base rules of the given version (see `BASE_RULES_VERSION`),
+/// without any user rules and with the experimental rules disabled, so that
+/// Python and other tools can share the definitions of the base rules.
+///
+/// Fails if the version is unknown.
+pub fn base_rules(version: &str) -> Result<FilteredPushRules, Error> {
+    if !base_rules::BASE_RULES_VERSIONS.contains(&version) {
+        bail!("Unknown base rules version {}", version);
+    }
+
+    Ok(FilteredPushRules::default())
+}
+
 /// A single push rule for a user.
 #[derive(Debug, Clone)]
 #[pyclass(frozen)]
@@ -138,6 +175,15 @@ impl PushRule {
         })
     }
 
+    /// Returns a copy of this rule with its conditions in canonical form, see
+    /// [`Condition::normalize`].
+    pub fn normalize(&self) -> PushRule {
+        PushRule {
+            conditions: self.conditions.iter().map(Condition::normalize).collect(),
+            ..self.clone()
+        }
+    }
+
     #[getter]
     fn rule_id(&self) -> &str {
         &self.rule_id
@@ -175,6 +221,56 @@ pub enum Action {
     Unknown(Value),
 }
 
+impl Action {
+    /// The position of this action in the canonical action order, see
+    /// [`sort_actions_canonically`].
+    fn canonical_rank(&self) -> (u8, &str) {
+        match self {
+            Action::Notify => (0, ""),
+            Action::SetTweak(tweak) => (1, &tweak.set_tweak),
+            Action::DontNotify => (2, ""),
+            Action::Coalesce => (3, ""),
+            Action::Unknown(_) => (4, ""),
+        }
+    }
+}
+
+/// Resolves conflicting tweaks in a list of actions.
+///
+/// If the same tweak is set more than once then the last action setting it
+/// wins, and the earlier ones are dropped. For example, a rule with the actions
+/// `[{"set_tweak": "highlight"}, {"set_tweak": "highlight", "value": false}]`
+/// does not highlight. All other actions are kept in their original order.
+pub fn resolve_tweaks(actions: &[Action]) -> Vec<Action> {
+    // The index of the last action setting each tweak.
+    let mut last_set: HashMap<&str, usize> = HashMap::new();
+    for (index, action) in actions.iter().enumerate() {
+        if let Action::SetTweak(tweak) = action {
+            last_set.insert(&tweak.set_tweak, index);
+        }
+    }
+
+    actions
+        .iter()
+        .enumerate()
+        .filter(|(index, action)| match action {
+            Action::SetTweak(tweak) => last_set[&*tweak.set_tweak] == *index,
+            _ => true,
+        })
+        .map(|(_, action)| action.clone())
+        .collect()
+}
+
+/// Sorts actions into a canonical order: `notify` first, then tweaks sorted by
+/// name, then the legacy `dont_notify` and `coalesce` actions, and finally any
+/// unrecognized actions.
+///
+/// The sort is stable, so multiple tweaks with the same name (and multiple
+/// unrecognized actions) keep their relative order.
+pub fn sort_actions_canonically(actions: &mut [Action]) {
+    actions.sort_by(|a, b| a.canonical_rank().cmp(&b.canonical_rank()));
+}
+
 impl IntoPy<PyObject> for Action {
     fn into_py(self, py: Python<'_>) -> PyObject {
         // When we pass the `Action` struct to Python we want it to be converted
@@ -198,6 +294,43 @@ pub struct SetTweak {
     other_keys: Value,
 }
 
+/// The tweak asking for a notification at most once per so many matching
+/// events, e.g. `{"set_tweak": "rate_limit", "value": 10}`. The evaluator holds
+/// no state across events, so throttling is left to the caller.
+pub const RATE_LIMIT_TWEAK: &str = "rate_limit";
+
+impl SetTweak {
+    /// The number of matching events per notification, if this is a
+    /// `rate_limit` tweak with a valid value (a positive integer).
+    pub fn rate_limit(&self) -> Option<u64> {
+        if self.set_tweak != RATE_LIMIT_TWEAK {
+            return None;
+        }
+
+        match &self.value {
+            Some(TweakValue::Other(Value::Number(value))) => value.as_u64().filter(|n| *n > 0),
+            _ => None,
+        }
+    }
+}
+
+impl TweakValue {
+    /// The value as a simple JSON value, or None if it isn't one (e.g. it's an
+    /// object or a float).
+    pub fn to_simple(&self) -> Option<SimpleJsonValue> {
+        match self {
+            TweakValue::String(value) => Some(SimpleJsonValue::Str(value.clone())),
+            TweakValue::Other(Value::String(value)) => {
+                Some(SimpleJsonValue::Str(Cow::Owned(value.clone())))
+            }
+            TweakValue::Other(Value::Number(value)) => value.as_i64().map(SimpleJsonValue::Int),
+            TweakValue::Other(Value::Bool(value)) => Some(SimpleJsonValue::Bool(*value)),
+            TweakValue::Other(Value::Null) => Some(SimpleJsonValue::Null),
+            TweakValue::Other(_) => None,
+        }
+    }
+}
+
 /// The value of a `set_tweak`.
 ///
 /// We need this (rather than using `TweakValue` directly) so that we can use
@@ -310,11 +443,134 @@ impl<'source> FromPyObject<'source> for JsonValue {
     }
 }
 
+/// A key of a flattened event, e.g. "content.body" (see
+/// `utils::get_flattened_value`).
+///
+/// The same few keys appear in almost every event, so rather than allocating
+/// them for every event the well-known keys (see `FlattenedKey::well_known`)
+/// are stored as static strings. Other keys are allocated as usual. Flattened events can be
+/// looked up by `str`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FlattenedKey(Cow<'static, str>);
+
+impl FlattenedKey {
+    /// The static copy of the key, if it is one of the keys which appear in
+    /// most events.
+    fn well_known(key: &str) -> Option<&'static str> {
+        // A match is compiled to a comparison of the lengths and then of the
+        // contents, which is cheaper than e.g. hashing the key.
+        let key = match key {
+            "auth_events" => "auth_events",
+            "content.body" => "content.body",
+            "content.format" => "content.format",
+            "content.formatted_body" => "content.formatted_body",
+            r"content.m\.mentions.room" => r"content.m\.mentions.room",
+            r"content.m\.mentions.user_ids" => r"content.m\.mentions.user_ids",
+            r"content.m\.new_content.body" => r"content.m\.new_content.body",
+            r"content.m\.new_content.msgtype" => r"content.m\.new_content.msgtype",
+            r"content.m\.relates_to.event_id" => r"content.m\.relates_to.event_id",
+            r"content.m\.relates_to.is_falling_back" => r"content.m\.relates_to.is_falling_back",
+            r"content.m\.relates_to.key" => r"content.m\.relates_to.key",
+            r"content.m\.relates_to.m\.in_reply_to.event_id" => {
+                r"content.m\.relates_to.m\.in_reply_to.event_id"
+            }
+            r"content.m\.relates_to.rel_type" => r"content.m\.relates_to.rel_type",
+            "content.membership" => "content.membership",
+            "content.msgtype" => "content.msgtype",
+            "content.name" => "content.name",
+            "content.topic" => "content.topic",
+            "content.type" => "content.type",
+            "content.url" => "content.url",
+            "depth" => "depth",
+            "event_id" => "event_id",
+            "hashes.sha256" => "hashes.sha256",
+            "origin" => "origin",
+            "origin_server_ts" => "origin_server_ts",
+            "prev_events" => "prev_events",
+            "room_id" => "room_id",
+            "sender" => "sender",
+            "state_key" => "state_key",
+            "type" => "type",
+            "unsigned.age" => "unsigned.age",
+            _ => return None,
+        };
+        Some(key)
+    }
+
+    /// Whether the key is stored without an allocation of its own.
+    pub fn is_static(&self) -> bool {
+        matches!(self.0, Cow::Borrowed(_))
+    }
+}
+
+impl From<&str> for FlattenedKey {
+    fn from(key: &str) -> Self {
+        match FlattenedKey::well_known(key) {
+            Some(key) => FlattenedKey(Cow::Borrowed(key)),
+            None => FlattenedKey(Cow::Owned(key.to_string())),
+        }
+    }
+}
+
+impl From<String> for FlattenedKey {
+    fn from(key: String) -> Self {
+        match FlattenedKey::well_known(&key) {
+            Some(key) => FlattenedKey(Cow::Borrowed(key)),
+            None => FlattenedKey(Cow::Owned(key)),
+        }
+    }
+}
+
+impl std::ops::Deref for FlattenedKey {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::borrow::Borrow<str> for FlattenedKey {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for FlattenedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for FlattenedKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for FlattenedKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Ok(FlattenedKey::from(String::deserialize(deserializer)?))
+    }
+}
+
+impl<'source> FromPyObject<'source> for FlattenedKey {
+    fn extract(ob: &'source PyAny) -> PyResult<Self> {
+        let key = <PyString as pyo3::PyTryFrom>::try_from(ob)?;
+        Ok(FlattenedKey::from(key.to_str()?))
+    }
+}
+
 /// A condition used in push rules to match against an event.
 ///
 /// We need this split as `serde` doesn't give us the ability to have a
 /// "catchall" variant in tagged enums.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum Condition {
     /// A recognized condition that we can match against
@@ -324,7 +580,7 @@ pub enum Condition {
 }
 
 /// The set of "known" conditions that we can handle.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "kind")]
 pub enum KnownCondition {
@@ -350,10 +606,593 @@ pub enum KnownCondition {
     SenderNotificationPermission {
         key: Cow<'static, str>,
     },
+    // Compares the power level of a user in the room: `user` is either
+    // "sender", "recipient" (the user the rules belong to) or a user ID. Never
+    // matches if the user's power level isn't known.
+    #[serde(rename = "io.element.user_power_level")]
+    UserPowerLevel {
+        user: Cow<'static, str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Matches if the user is one of the users intentionally mentioned by the
+    // event. Only evaluated if enabled on the evaluator.
+    #[serde(rename = "org.matrix.msc3952.is_user_mention")]
+    IsUserMention,
+    // Matches if the event intentionally mentions the room, and the sender is
+    // allowed to notify the room. Only evaluated if enabled on the evaluator.
+    #[serde(rename = "org.matrix.msc3952.is_room_mention")]
+    IsRoomMention,
     #[serde(rename = "org.matrix.msc3931.room_version_supports")]
     RoomVersionSupports {
         feature: Cow<'static, str>,
     },
+    // Matches `m.audio` messages which carry the MSC3245 voice message marker.
+    #[serde(rename = "org.matrix.msc3245.is_voice_message")]
+    IsVoiceMessage,
+    // Compares the number of `@`-prefixed handles in the body, regardless of
+    // whether the event uses intentional mentions.
+    #[serde(rename = "io.element.at_handle_count")]
+    AtHandleCount {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Matches events whose type is in an unstable namespace. Defaults to the
+    // `org.` and `im.` prefixes if no prefixes are given.
+    #[serde(rename = "io.element.is_unstable_event_type")]
+    IsUnstableEventType {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        prefixes: Option<Vec<Cow<'static, str>>>,
+    },
+    // Matches if any of the given flattened keys are present in the event, in
+    // the same sense as `event_key_exists`.
+    #[serde(rename = "io.element.has_any_key")]
+    HasAnyKey {
+        keys: Vec<Cow<'static, str>>,
+    },
+    // Matches if all of the given flattened keys are present in the event, in
+    // the same sense as `event_key_exists`.
+    #[serde(rename = "io.element.has_all_keys")]
+    HasAllKeys {
+        keys: Vec<Cow<'static, str>>,
+    },
+    // Matches if the flattened key is present (or absent, if `exists` is
+    // false) in the event, whatever its value. As only simple values are
+    // flattened, an object counts as present if any key nested beneath it is,
+    // e.g. `content.file` is present if `content.file.url` is.
+    #[serde(rename = "io.element.event_key_exists")]
+    EventKeyExists {
+        key: Cow<'static, str>,
+        exists: bool,
+    },
+    // Matches if the values of the two flattened keys are equal, e.g.
+    // `content.recipient` and `sender`. Values are compared with their types,
+    // so `1` doesn't equal `"1"`. Never matches if either key is missing.
+    #[serde(rename = "io.element.keys_equal")]
+    KeysEqual {
+        key_a: Cow<'static, str>,
+        key_b: Cow<'static, str>,
+    },
+    // Matches messages which quote one of the user's recent messages.
+    #[serde(rename = "io.element.quotes_me")]
+    QuotesMe,
+    // Matches messages whose body has the given (estimated) sentiment. Only
+    // evaluated if enabled on the evaluator.
+    #[serde(rename = "io.element.body_sentiment")]
+    BodySentiment {
+        polarity: SentimentPolarity,
+    },
+    // Matches if the notification is for the given type of device, e.g.
+    // "mobile" or "desktop".
+    #[serde(rename = "io.element.device_type_match")]
+    DeviceTypeMatch {
+        device_type: Cow<'static, str>,
+    },
+    // Matches messages which mention the room's name.
+    #[serde(rename = "io.element.mentions_room_name")]
+    MentionsRoomName,
+    // Matches events sent from a verified device.
+    #[serde(rename = "io.element.sender_verified")]
+    SenderVerified,
+    // Matches messages containing fenced or inline code.
+    #[serde(rename = "io.element.body_contains_code")]
+    BodyContainsCode,
+    // Matches messages with a (non-empty) HTML `formatted_body`.
+    #[serde(rename = "io.element.has_formatted_body")]
+    HasFormattedBody,
+    // Compares the number of notifications in the room today, if known.
+    #[serde(rename = "io.element.room_notifications_today")]
+    RoomNotificationsToday {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Compares the number of events the sender has recently sent to the user,
+    // if known.
+    #[serde(rename = "io.element.sender_event_rate")]
+    SenderEventRate {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Matches messages whose body is a duplicate of a recent message in the
+    // room, see `PushRuleEvaluator::recent_message_hashes`.
+    #[serde(rename = "io.element.is_duplicate_message")]
+    IsDuplicateMessage,
+    // Matches events which are the first reply in a new thread.
+    #[serde(rename = "io.element.is_thread_start")]
+    IsThreadStart,
+    // Matches encrypted events, i.e. `m.room.encrypted` events, whose content
+    // can't be matched.
+    #[serde(rename = "io.element.is_encrypted")]
+    IsEncrypted,
+    // Matches replies, i.e. events with an `m.in_reply_to` relation. The
+    // fallback replies of thread messages don't count, see
+    // `PushRuleEvaluator::is_reply`.
+    #[serde(rename = "io.element.is_reply")]
+    IsReply,
+    // Matches edits, i.e. events with an `m.replace` relation.
+    #[serde(rename = "io.element.is_edit")]
+    IsEdit,
+    // Matches if the user has given the room the tag, e.g. `m.lowpriority`.
+    // Never matches if the room's tags aren't known.
+    #[serde(rename = "io.element.room_has_tag")]
+    RoomHasTag {
+        tag: Cow<'static, str>,
+    },
+    // Matches events related to the given event, either by a relation (e.g. a
+    // thread root) or by replying to it.
+    #[serde(rename = "io.element.relates_to_event")]
+    RelatesToEvent {
+        event_id: Cow<'static, str>,
+    },
+    // Compares the number of distinct users mentioned in `m.mentions`, e.g. to
+    // flag mass mentions. Events without mentions mention no one.
+    #[serde(rename = "io.element.mention_count")]
+    MentionCount {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Compares the length of the body in characters, i.e. Unicode code
+    // points rather than bytes or grapheme clusters (so e.g. an emoji made of
+    // several code points counts as several characters). Events without a
+    // body never match.
+    #[serde(rename = "io.element.body_length")]
+    BodyLength {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Matches events whose sender's server name (the part of the user ID after
+    // the first colon) matches the given glob. Events with a malformed sender
+    // never match.
+    #[serde(rename = "io.element.sender_server_match")]
+    SenderServerMatch {
+        pattern: Cow<'static, str>,
+    },
+    // Matches events whose sender's display name matches the given glob, e.g.
+    // to mute a bot by its name. Never matches if the display name isn't
+    // known.
+    #[serde(rename = "io.element.sender_display_name_match")]
+    SenderDisplayNameMatch {
+        pattern: Cow<'static, str>,
+    },
+    // Matches events in rooms which the user considers a direct message (DM),
+    // as determined by the caller.
+    #[serde(rename = "io.element.is_direct_message")]
+    IsDirectMessage,
+    // Matches events sent by the server notices user, as configured by the
+    // caller. Never matches if there is no server notices user.
+    #[serde(rename = "io.element.sender_is_server_notices")]
+    SenderIsServerNotices,
+    // Compares the recipient's number of unread notifications or highlights in
+    // the room, e.g. to only notify if there are no unread notifications yet.
+    // Never matches if the evaluator wasn't given the count.
+    #[serde(rename = "io.element.unread_count")]
+    UnreadCount {
+        // `kind` is taken by the condition's own tag.
+        #[serde(rename = "count_kind")]
+        kind: UnreadCountKind,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Matches if the value of the key is one of the given values. As with
+    // `event_property_is` the values are compared exactly, including their
+    // type, so e.g. the integer `1` isn't in `["1"]`.
+    #[serde(rename = "io.element.event_property_in")]
+    EventPropertyIn {
+        key: Cow<'static, str>,
+        values: Vec<SimpleJsonValue>,
+    },
+    // Matches if the current time (`now_ts`, not the event's timestamp) is
+    // within the window, e.g. quiet hours. The start and end are minutes past
+    // midnight in the recipient's time zone, given as an offset from UTC. The
+    // start is inclusive and the end exclusive, and windows wrap past midnight
+    // if the end is before the start. Never matches if the current time isn't
+    // known.
+    #[serde(rename = "io.element.within_time_window")]
+    WithinTimeWindow {
+        start_minute: u32,
+        end_minute: u32,
+        #[serde(default)]
+        tz_offset_minutes: i32,
+    },
+    // Compares how deep the event is in its thread: 0 for the thread root, 1
+    // for replies to it, and so on. Never matches if the depth isn't known.
+    #[serde(rename = "io.element.thread_depth")]
+    ThreadDepth {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Matches events sent by the user the rules belong to, so e.g. a rule can
+    // be excluded from their own events with `not`. Never matches if the rules
+    // are evaluated without a user ID.
+    #[serde(rename = "io.element.sender_is_self")]
+    SenderIsSelf,
+    // Matches if any of the values selected by the path (see
+    // `SelectorPath::parse` for the supported syntax) compares with the value,
+    // e.g. `$.content.scores[*] > 5`. See `CompareOp::compare_values` for how
+    // values of different types compare.
+    #[serde(rename = "io.element.event_selector")]
+    EventSelector {
+        path: Cow<'static, str>,
+        op: CompareOp,
+        value: SimpleJsonValue,
+    },
+    // Matches membership events which change the target's membership from
+    // `from` to `to`, e.g. "join" to "leave" for leaves and kicks.
+    #[serde(rename = "io.element.membership_transition")]
+    MembershipTransition {
+        from: Cow<'static, str>,
+        to: Cow<'static, str>,
+    },
+    // Compares the number of events related to the event with the given
+    // relation type, e.g. the number of replies in a thread. Only evaluated if
+    // enabled on the evaluator.
+    #[serde(rename = "io.element.relation_count")]
+    RelationCount {
+        rel_type: Cow<'static, str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is: Option<Cow<'static, str>>,
+    },
+    // Matches if the event has an aggregation (e.g. from bundled aggregations)
+    // of the given relation type, and with the given key if set, e.g. a 👍
+    // reaction for `m.annotation`. Only evaluated if enabled on the evaluator.
+    #[serde(rename = "io.element.has_aggregation")]
+    HasAggregation {
+        rel_type: Cow<'static, str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<Cow<'static, str>>,
+    },
+    // Compares an integer value of the event, e.g. `content.value > 5`. Values
+    // of other types (including strings of digits) never match.
+    #[serde(rename = "io.element.event_value_compare")]
+    EventValueCompare {
+        key: Cow<'static, str>,
+        op: CompareOp,
+        value: i64,
+    },
+    // Matches events in the given room.
+    #[serde(rename = "io.element.room_id_equals")]
+    RoomIdEquals {
+        room_id: Cow<'static, str>,
+    },
+    // Matches events in rooms whose ID matches the given glob. Note that, as
+    // with `event_match`, the glob is matched case-insensitively.
+    #[serde(rename = "io.element.room_id_match")]
+    RoomIdMatch {
+        pattern: Cow<'static, str>,
+    },
+    // Matches events younger than the given number of milliseconds, if the
+    // age of the event is known.
+    #[serde(rename = "io.element.event_age_less_than")]
+    EventAgeLessThan {
+        ms: u64,
+    },
+    // Matches if the recipient's membership of the room is the given one,
+    // e.g. "join". Never matches if the membership isn't known.
+    #[serde(rename = "io.element.user_membership")]
+    UserMembership {
+        membership: Cow<'static, str>,
+    },
+    // Matches state events whose state key matches the given glob or pattern
+    // type, e.g. membership events of the user with a `user_id` pattern type.
+    // The pattern type takes precedence, and non-state events never match.
+    #[serde(rename = "io.element.state_key_match")]
+    StateKeyMatch {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<Cow<'static, str>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern_type: Option<EventMatchPatternType>,
+    },
+    // Matches if the wrapped condition doesn't match, see
+    // `PushRuleEvaluator::match_negated_condition`.
+    #[serde(rename = "io.element.not")]
+    Not {
+        condition: Box<Condition>,
+    },
+    // Matches if any of the given conditions match, see
+    // `PushRuleEvaluator::match_any_of`.
+    #[serde(rename = "io.element.any_of")]
+    AnyOf {
+        conditions: Vec<Condition>,
+    },
+}
+
+impl KnownCondition {
+    /// Every `kind` returned by `kind`, i.e. the kinds we recognize.
+    pub const KINDS: &'static [&'static str] = &[
+        "event_match",
+        "event_property_is",
+        "im.nheko.msc3664.related_event_match",
+        "event_property_contains",
+        "contains_display_name",
+        "room_member_count",
+        "sender_notification_permission",
+        "io.element.user_power_level",
+        "org.matrix.msc3952.is_user_mention",
+        "org.matrix.msc3952.is_room_mention",
+        "org.matrix.msc3931.room_version_supports",
+        "org.matrix.msc3245.is_voice_message",
+        "io.element.at_handle_count",
+        "io.element.is_unstable_event_type",
+        "io.element.has_any_key",
+        "io.element.has_all_keys",
+        "io.element.event_key_exists",
+        "io.element.keys_equal",
+        "io.element.quotes_me",
+        "io.element.body_sentiment",
+        "io.element.device_type_match",
+        "io.element.mentions_room_name",
+        "io.element.sender_verified",
+        "io.element.body_contains_code",
+        "io.element.has_formatted_body",
+        "io.element.room_notifications_today",
+        "io.element.sender_event_rate",
+        "io.element.is_duplicate_message",
+        "io.element.is_thread_start",
+        "io.element.is_encrypted",
+        "io.element.is_reply",
+        "io.element.is_edit",
+        "io.element.room_has_tag",
+        "io.element.relates_to_event",
+        "io.element.mention_count",
+        "io.element.body_length",
+        "io.element.sender_server_match",
+        "io.element.sender_display_name_match",
+        "io.element.is_direct_message",
+        "io.element.sender_is_server_notices",
+        "io.element.unread_count",
+        "io.element.event_property_in",
+        "io.element.within_time_window",
+        "io.element.thread_depth",
+        "io.element.sender_is_self",
+        "io.element.event_selector",
+        "io.element.membership_transition",
+        "io.element.relation_count",
+        "io.element.has_aggregation",
+        "io.element.event_value_compare",
+        "io.element.room_id_equals",
+        "io.element.room_id_match",
+        "io.element.event_age_less_than",
+        "io.element.user_membership",
+        "io.element.state_key_match",
+        "io.element.not",
+        "io.element.any_of",
+    ];
+
+    /// Whether the kind is one we recognize. Conditions of a recognized kind
+    /// which fail to deserialize are malformed, rather than unknown.
+    pub fn is_known_kind(kind: &str) -> bool {
+        Self::KINDS.contains(&kind)
+    }
+
+    /// The `kind` of the condition, as it appears in the serialized push rule.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KnownCondition::EventMatch(_) => "event_match",
+            KnownCondition::EventMatchType(_) => "event_match",
+            KnownCondition::EventPropertyIs(_) => "event_property_is",
+            KnownCondition::RelatedEventMatch(_) => "im.nheko.msc3664.related_event_match",
+            KnownCondition::RelatedEventMatchType(_) => "im.nheko.msc3664.related_event_match",
+            KnownCondition::EventPropertyContains(_) => "event_property_contains",
+            KnownCondition::ExactEventPropertyContainsType(_) => "event_property_contains",
+            KnownCondition::ContainsDisplayName => "contains_display_name",
+            KnownCondition::RoomMemberCount { .. } => "room_member_count",
+            KnownCondition::SenderNotificationPermission { .. } => "sender_notification_permission",
+            KnownCondition::UserPowerLevel { .. } => "io.element.user_power_level",
+            KnownCondition::IsUserMention => "org.matrix.msc3952.is_user_mention",
+            KnownCondition::IsRoomMention => "org.matrix.msc3952.is_room_mention",
+            KnownCondition::RoomVersionSupports { .. } => {
+                "org.matrix.msc3931.room_version_supports"
+            }
+            KnownCondition::IsVoiceMessage => "org.matrix.msc3245.is_voice_message",
+            KnownCondition::AtHandleCount { .. } => "io.element.at_handle_count",
+            KnownCondition::IsUnstableEventType { .. } => "io.element.is_unstable_event_type",
+            KnownCondition::HasAnyKey { .. } => "io.element.has_any_key",
+            KnownCondition::HasAllKeys { .. } => "io.element.has_all_keys",
+            KnownCondition::EventKeyExists { .. } => "io.element.event_key_exists",
+            KnownCondition::KeysEqual { .. } => "io.element.keys_equal",
+            KnownCondition::QuotesMe => "io.element.quotes_me",
+            KnownCondition::BodySentiment { .. } => "io.element.body_sentiment",
+            KnownCondition::DeviceTypeMatch { .. } => "io.element.device_type_match",
+            KnownCondition::MentionsRoomName => "io.element.mentions_room_name",
+            KnownCondition::SenderVerified => "io.element.sender_verified",
+            KnownCondition::BodyContainsCode => "io.element.body_contains_code",
+            KnownCondition::HasFormattedBody => "io.element.has_formatted_body",
+            KnownCondition::RoomNotificationsToday { .. } => "io.element.room_notifications_today",
+            KnownCondition::SenderEventRate { .. } => "io.element.sender_event_rate",
+            KnownCondition::IsDuplicateMessage => "io.element.is_duplicate_message",
+            KnownCondition::IsThreadStart => "io.element.is_thread_start",
+            KnownCondition::IsEncrypted => "io.element.is_encrypted",
+            KnownCondition::IsReply => "io.element.is_reply",
+            KnownCondition::IsEdit => "io.element.is_edit",
+            KnownCondition::RoomHasTag { .. } => "io.element.room_has_tag",
+            KnownCondition::RelatesToEvent { .. } => "io.element.relates_to_event",
+            KnownCondition::MentionCount { .. } => "io.element.mention_count",
+            KnownCondition::BodyLength { .. } => "io.element.body_length",
+            KnownCondition::SenderServerMatch { .. } => "io.element.sender_server_match",
+            KnownCondition::SenderDisplayNameMatch { .. } => "io.element.sender_display_name_match",
+            KnownCondition::IsDirectMessage => "io.element.is_direct_message",
+            KnownCondition::SenderIsServerNotices => "io.element.sender_is_server_notices",
+            KnownCondition::UnreadCount { .. } => "io.element.unread_count",
+            KnownCondition::EventPropertyIn { .. } => "io.element.event_property_in",
+            KnownCondition::WithinTimeWindow { .. } => "io.element.within_time_window",
+            KnownCondition::ThreadDepth { .. } => "io.element.thread_depth",
+            KnownCondition::SenderIsSelf => "io.element.sender_is_self",
+            KnownCondition::EventSelector { .. } => "io.element.event_selector",
+            KnownCondition::MembershipTransition { .. } => "io.element.membership_transition",
+            KnownCondition::RelationCount { .. } => "io.element.relation_count",
+            KnownCondition::HasAggregation { .. } => "io.element.has_aggregation",
+            KnownCondition::EventValueCompare { .. } => "io.element.event_value_compare",
+            KnownCondition::RoomIdEquals { .. } => "io.element.room_id_equals",
+            KnownCondition::RoomIdMatch { .. } => "io.element.room_id_match",
+            KnownCondition::EventAgeLessThan { .. } => "io.element.event_age_less_than",
+            KnownCondition::UserMembership { .. } => "io.element.user_membership",
+            KnownCondition::StateKeyMatch { .. } => "io.element.state_key_match",
+            KnownCondition::Not { .. } => "io.element.not",
+            KnownCondition::AnyOf { .. } => "io.element.any_of",
+        }
+    }
+}
+
+impl Condition {
+    /// Returns a semantically equivalent condition in a canonical form, so that
+    /// equivalent conditions compare as equal.
+    ///
+    /// This normalizes `is` clauses (e.g. `5` and `==05` both become `==5`) and
+    /// sorts lists whose order doesn't matter. Invalid `is` clauses are left as
+    /// is. Patterns are never rewritten, as whitespace in them is significant.
+    pub fn normalize(&self) -> Condition {
+        let known_condition = match self {
+            Condition::Known(known_condition) => known_condition,
+            Condition::Unknown(_) => return self.clone(),
+        };
+
+        let normalize_is = |is: &Option<Cow<'static, str>>| {
+            is.as_ref().map(|is| match normalize_inequality(is) {
+                Some(normalized) => Cow::Owned(normalized),
+                None => is.clone(),
+            })
+        };
+        let normalized = match known_condition {
+            KnownCondition::RoomMemberCount { is } => KnownCondition::RoomMemberCount {
+                is: normalize_is(is),
+            },
+            KnownCondition::AtHandleCount { is } => KnownCondition::AtHandleCount {
+                is: normalize_is(is),
+            },
+            KnownCondition::MentionCount { is } => KnownCondition::MentionCount {
+                is: normalize_is(is),
+            },
+            KnownCondition::BodyLength { is } => KnownCondition::BodyLength {
+                is: normalize_is(is),
+            },
+            KnownCondition::ThreadDepth { is } => KnownCondition::ThreadDepth {
+                is: normalize_is(is),
+            },
+            KnownCondition::RoomNotificationsToday { is } => {
+                KnownCondition::RoomNotificationsToday {
+                    is: normalize_is(is),
+                }
+            }
+            KnownCondition::SenderEventRate { is } => KnownCondition::SenderEventRate {
+                is: normalize_is(is),
+            },
+            KnownCondition::RelationCount { rel_type, is } => KnownCondition::RelationCount {
+                rel_type: rel_type.clone(),
+                is: normalize_is(is),
+            },
+            KnownCondition::UnreadCount { kind, is } => KnownCondition::UnreadCount {
+                kind: *kind,
+                is: normalize_is(is),
+            },
+            KnownCondition::UserPowerLevel { user, is } => KnownCondition::UserPowerLevel {
+                user: user.clone(),
+                is: normalize_is(is),
+            },
+            KnownCondition::IsUnstableEventType {
+                prefixes: Some(prefixes),
+            } => KnownCondition::IsUnstableEventType {
+                prefixes: Some(sorted_keys(prefixes)),
+            },
+            KnownCondition::HasAnyKey { keys } => KnownCondition::HasAnyKey {
+                keys: sorted_keys(keys),
+            },
+            KnownCondition::HasAllKeys { keys } => KnownCondition::HasAllKeys {
+                keys: sorted_keys(keys),
+            },
+            KnownCondition::Not { condition } => KnownCondition::Not {
+                condition: Box::new(condition.normalize()),
+            },
+            // The order is kept, as it is the order the conditions are
+            // evaluated in.
+            KnownCondition::AnyOf { conditions } => KnownCondition::AnyOf {
+                conditions: conditions.iter().map(Condition::normalize).collect(),
+            },
+            other => other.clone(),
+        };
+
+        Condition::Known(normalized)
+    }
+}
+
+/// Collects the globs (and how they're matched) used by the given condition,
+/// see `FilteredPushRules::precompiled`.
+fn collect_globs<'a>(condition: &'a Condition, globs: &mut Vec<(&'a str, GlobMatchType)>) {
+    match condition {
+        Condition::Known(KnownCondition::EventMatch(event_match)) => {
+            for key in event_match.key.iter() {
+                globs.push((&event_match.pattern, event_match.match_type(key)));
+            }
+        }
+        Condition::Known(KnownCondition::RelatedEventMatch(event_match)) => {
+            if let (Some(key), Some(pattern)) = (&event_match.key, &event_match.pattern) {
+                globs.push((pattern, event_match_type(key)));
+            }
+        }
+        Condition::Known(KnownCondition::RoomIdMatch { pattern })
+        | Condition::Known(KnownCondition::SenderServerMatch { pattern })
+        | Condition::Known(KnownCondition::SenderDisplayNameMatch { pattern })
+        | Condition::Known(KnownCondition::StateKeyMatch {
+            pattern: Some(pattern),
+            pattern_type: None,
+        }) => {
+            globs.push((pattern, GlobMatchType::Whole));
+        }
+        Condition::Known(KnownCondition::Not { condition }) => collect_globs(condition, globs),
+        Condition::Known(KnownCondition::AnyOf { conditions }) => {
+            for condition in conditions {
+                collect_globs(condition, globs);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the kinds of the given condition and of any conditions nested in
+/// it, see `FilteredPushRules::condition_kinds`.
+fn collect_condition_kinds(condition: &Condition, kinds: &mut BTreeSet<&'static str>) {
+    let known_condition = match condition {
+        Condition::Known(known_condition) => known_condition,
+        Condition::Unknown(_) => return,
+    };
+    kinds.insert(known_condition.kind());
+
+    match known_condition {
+        KnownCondition::Not { condition } => collect_condition_kinds(condition, kinds),
+        KnownCondition::AnyOf { conditions } => {
+            for condition in conditions {
+                collect_condition_kinds(condition, kinds);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns a sorted copy of the given keys, without duplicates.
+fn sorted_keys(keys: &[Cow<'static, str>]) -> Vec<Cow<'static, str>> {
+    let mut keys = keys.to_vec();
+    keys.sort();
+    keys.dedup();
+    keys
 }
 
 impl IntoPy<PyObject> for Condition {
@@ -369,21 +1208,81 @@ impl<'source> FromPyObject<'source> for Condition {
 }
 
 /// The body of a [`Condition::EventMatch`] with a pattern.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct EventMatchCondition {
-    pub key: Cow<'static, str>,
+    pub key: EventMatchKey,
     pub pattern: Cow<'static, str>,
+    /// If set, lines of the `content.body` which are quoted (i.e. start with
+    /// `>`) are ignored when matching against it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ignore_quotes: bool,
+    /// Whether the pattern is matched against words of the value (if true) or
+    /// the entire value (if false). If unset, this depends on the key, see
+    /// `event_match_type`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub match_words: Option<bool>,
+    /// If set, the pattern may match any part of the value, as if it started
+    /// and ended with `*`. This takes precedence over `match_words`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub contains: bool,
+}
+
+impl EventMatchCondition {
+    /// How the pattern is matched against the value of the given key.
+    pub fn match_type(&self, key: &str) -> GlobMatchType {
+        if self.contains {
+            return GlobMatchType::Contains;
+        }
+
+        match self.match_words {
+            Some(true) => GlobMatchType::Word,
+            Some(false) => GlobMatchType::Whole,
+            None => event_match_type(key),
+        }
+    }
+}
+
+/// The key (or keys) of an `event_match` condition.
+///
+/// With several keys the condition matches if the pattern matches the value
+/// of any of them, e.g. either `content.body` or `content.formatted_body`.
+/// Keys which aren't in the event are skipped.
+///
+/// A key may have wildcard segments, e.g. `content.foo.*.bar`, in which case
+/// the condition matches if the pattern matches the value of any key in the
+/// event with any single segment in place of each wildcard (see
+/// `get_wildcard_flattened_values`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum EventMatchKey {
+    Single(Cow<'static, str>),
+    Any(Vec<Cow<'static, str>>),
 }
 
-#[derive(Serialize, Debug, Clone)]
+impl EventMatchKey {
+    /// Iterates over the keys.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        let keys = match self {
+            EventMatchKey::Single(key) => std::slice::from_ref(key),
+            EventMatchKey::Any(keys) => keys.as_slice(),
+        };
+        keys.iter().map(|key| &**key)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum EventMatchPatternType {
     UserId,
     UserLocalpart,
+    /// The ID of the event's sender.
+    Sender,
+    /// The localpart of the ID of the event's sender.
+    SenderLocalpart,
 }
 
 /// The body of a [`Condition::EventMatch`] that uses user_id or user_localpart as a pattern.
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct EventMatchTypeCondition {
     pub key: Cow<'static, str>,
     // During serialization, the pattern_type property gets replaced with a
@@ -392,14 +1291,69 @@ pub struct EventMatchTypeCondition {
 }
 
 /// The body of a [`Condition::EventPropertyIs`]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct EventPropertyIsCondition {
     pub key: Cow<'static, str>,
     pub value: Cow<'static, SimpleJsonValue>,
 }
 
+/// Which of the recipient's unread counts an `unread_count` condition compares.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnreadCountKind {
+    Notifications,
+    Highlights,
+}
+
+/// The comparison of an `event_value_compare` or `event_selector` condition.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    #[serde(rename = "==")]
+    Eq,
+    #[serde(rename = "!=")]
+    Ne,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+}
+
+impl CompareOp {
+    /// Compares the left hand side against the right hand side.
+    pub fn compare<T: Ord>(self, lhs: T, rhs: T) -> bool {
+        match self {
+            CompareOp::Eq => lhs == rhs,
+            CompareOp::Ne => lhs != rhs,
+            CompareOp::Lt => lhs < rhs,
+            CompareOp::Le => lhs <= rhs,
+            CompareOp::Gt => lhs > rhs,
+            CompareOp::Ge => lhs >= rhs,
+        }
+    }
+
+    /// Compares two simple JSON values. Integers and strings (by code point)
+    /// are ordered, but booleans and nulls can only be compared with `==` and
+    /// `!=`. Values of different types never match, even with `!=`.
+    pub fn compare_values(self, lhs: &SimpleJsonValue, rhs: &SimpleJsonValue) -> bool {
+        let is_equality = matches!(self, CompareOp::Eq | CompareOp::Ne);
+        match (lhs, rhs) {
+            (SimpleJsonValue::Int(lhs), SimpleJsonValue::Int(rhs)) => self.compare(lhs, rhs),
+            (SimpleJsonValue::Str(lhs), SimpleJsonValue::Str(rhs)) => self.compare(lhs, rhs),
+            (SimpleJsonValue::Bool(lhs), SimpleJsonValue::Bool(rhs)) => {
+                is_equality && self.compare(lhs, rhs)
+            }
+            (SimpleJsonValue::Null, SimpleJsonValue::Null) => is_equality && self.compare((), ()),
+            _ => false,
+        }
+    }
+}
+
 /// The body of a [`Condition::EventPropertyIs`] that uses user_id or user_localpart as a pattern.
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct EventPropertyIsTypeCondition {
     pub key: Cow<'static, str>,
     // During serialization, the pattern_type property gets replaced with a
@@ -408,7 +1362,7 @@ pub struct EventPropertyIsTypeCondition {
 }
 
 /// The body of a [`Condition::RelatedEventMatch`]
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct RelatedEventMatchCondition {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub key: Option<Cow<'static, str>>,
@@ -420,7 +1374,7 @@ pub struct RelatedEventMatchCondition {
 }
 
 /// The body of a [`Condition::RelatedEventMatch`] that uses user_id or user_localpart as a pattern.
-#[derive(Serialize, Debug, Clone)]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct RelatedEventMatchTypeCondition {
     // This is only used if pattern_type exists (and thus key must exist), so is
     // a bit simpler than RelatedEventMatchCondition.
@@ -492,11 +1446,36 @@ impl PushRules {
     fn rules(&self) -> Vec<PushRule> {
         self.iter().cloned().collect()
     }
+
+    /// Returns a copy of these rules with all conditions in canonical form, see
+    /// [`Condition::normalize`].
+    pub fn normalize(&self) -> PushRules {
+        let normalize_all = |rules: &[PushRule]| rules.iter().map(PushRule::normalize).collect();
+
+        PushRules {
+            overridden_base_rules: self
+                .overridden_base_rules
+                .iter()
+                .map(|(rule_id, rule)| (rule_id.clone(), rule.normalize()))
+                .collect(),
+            override_rules: normalize_all(&self.override_rules),
+            content: normalize_all(&self.content),
+            room: normalize_all(&self.room),
+            sender: normalize_all(&self.sender),
+            underride: normalize_all(&self.underride),
+        }
+    }
 }
 
 impl PushRules {
     /// Iterates over all the rules, including base rules, in the order they
     /// should be executed in.
+    ///
+    /// This order implements rule priority, so evaluation relies on it: rules
+    /// are ordered by priority class (override, content, room, sender then
+    /// underride), as per the spec. Within a class the custom rules come in
+    /// the order they were given, with the base rules of the class after them
+    /// (except for the master rule, which comes first).
     pub fn iter(&self) -> impl Iterator<Item = &PushRule> {
         base_rules::BASE_PREPEND_OVERRIDE_RULES
             .iter()
@@ -514,6 +1493,17 @@ impl PushRules {
                     .unwrap_or(rule)
             })
     }
+
+    /// Iterates over the custom (i.e. non-base) rules, in the order they
+    /// should be executed in.
+    fn custom_rules(&self) -> impl Iterator<Item = &PushRule> {
+        self.override_rules
+            .iter()
+            .chain(self.content.iter())
+            .chain(self.room.iter())
+            .chain(self.sender.iter())
+            .chain(self.underride.iter())
+    }
 }
 
 /// A wrapper around `PushRules` that checks the enabled state of rules and
@@ -527,6 +1517,37 @@ pub struct FilteredPushRules {
     msc3381_polls_enabled: bool,
     msc3664_enabled: bool,
     msc4028_push_encrypted_events: bool,
+    /// The IDs of the server-provided rules merged into `push_rules`, see
+    /// [`FilteredPushRules::with_server_rules`].
+    server_rule_ids: BTreeSet<String>,
+    /// The IDs of server-provided rules which users can neither disable nor
+    /// replace.
+    non_disableable_rule_ids: BTreeSet<String>,
+    /// The patterns of the rules' conditions, compiled ahead of time. See
+    /// [`FilteredPushRules::precompiled`].
+    precompiled_globs: Option<Arc<PrecompiledGlobs>>,
+}
+
+/// The result of compiling the patterns of a set of rules, see
+/// [`FilteredPushRules::warm_glob_cache`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobCacheWarming {
+    /// The number of distinct patterns compiled. Invalid patterns aren't
+    /// counted.
+    pub patterns_compiled: usize,
+    /// How long compiling the patterns took in total.
+    pub elapsed: Duration,
+}
+
+/// Where a push rule came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleProvenance {
+    /// One of the base rules defined by the spec.
+    Base,
+    /// A rule provided by the server's configuration.
+    Server,
+    /// A rule created by the user.
+    User,
 }
 
 #[pymethods]
@@ -547,7 +1568,173 @@ impl FilteredPushRules {
             msc3381_polls_enabled,
             msc3664_enabled,
             msc4028_push_encrypted_events,
+            server_rule_ids: BTreeSet::new(),
+            non_disableable_rule_ids: BTreeSet::new(),
+            precompiled_globs: None,
+        }
+    }
+
+    /// Returns a copy of these rules with the patterns of their `event_match`
+    /// and `related_event_match` conditions compiled ahead of time, rather than
+    /// on first use. This makes construction more expensive, but is worthwhile
+    /// if the rules are used to evaluate many events.
+    ///
+    /// Patterns which depend on the user (i.e. `pattern_type` conditions) can't
+    /// be precompiled.
+    pub fn precompiled(&self) -> FilteredPushRules {
+        let mut globs = Vec::new();
+        for condition in self
+            .push_rules
+            .iter()
+            .flat_map(|rule| rule.conditions.iter())
+        {
+            collect_globs(condition, &mut globs);
+        }
+
+        FilteredPushRules {
+            precompiled_globs: Some(Arc::new(PrecompiledGlobs::compile(globs))),
+            ..self.clone()
+        }
+    }
+
+    /// Compiles the patterns of the rules, see `warm_glob_cache`. Returns the
+    /// number of patterns compiled and how long that took in seconds.
+    #[pyo3(name = "warm_glob_cache")]
+    fn py_warm_glob_cache(&self) -> (usize, f64) {
+        let warming = self.warm_glob_cache();
+        (warming.patterns_compiled, warming.elapsed.as_secs_f64())
+    }
+
+    /// Returns the kinds of the known conditions of the enabled rules,
+    /// including conditions nested in e.g. `not`. This lets callers skip
+    /// working out evaluator inputs which none of the rules use.
+    pub fn condition_kinds(&self) -> BTreeSet<&'static str> {
+        let mut kinds = BTreeSet::new();
+        for (push_rule, enabled) in self.iter() {
+            if enabled {
+                for condition in push_rule.conditions.iter() {
+                    collect_condition_kinds(condition, &mut kinds);
+                }
+            }
+        }
+        kinds
+    }
+
+    /// Returns a copy of these rules with the given server-provided rules
+    /// merged in.
+    ///
+    /// Server rules come before the user's rules of the same priority class.
+    /// A user rule with the same ID as a server rule replaces it, unless the
+    /// server rule is listed in `non_disableable_rule_ids`: such rules are
+    /// always enabled and any user rule with the same ID is ignored.
+    pub fn with_server_rules(
+        &self,
+        server_rules: Vec<PushRule>,
+        non_disableable_rule_ids: BTreeSet<String>,
+    ) -> Result<FilteredPushRules, Error> {
+        let mut seen_rule_ids = BTreeSet::new();
+        for rule in &server_rules {
+            if base_rules::BASE_RULES_BY_ID.contains_key(&*rule.rule_id) {
+                bail!("Server rule {} conflicts with a base rule", rule.rule_id);
+            }
+            if !(1..=5).contains(&rule.priority_class) {
+                bail!(
+                    "Unrecognized priority class for server rule {}: {}",
+                    rule.rule_id,
+                    rule.priority_class
+                );
+            }
+            if !seen_rule_ids.insert(rule.rule_id.to_string()) {
+                bail!("Duplicate server rule {}", rule.rule_id);
+            }
+        }
+        if let Some(rule_id) = non_disableable_rule_ids.difference(&seen_rule_ids).next() {
+            bail!("Non-disableable rule {} is not a server rule", rule_id);
+        }
+
+        let mut push_rules = self.push_rules.clone();
+        let mut server_rule_ids = self.server_rule_ids.clone();
+
+        for rule in server_rules.into_iter().rev() {
+            let rules = match rule.priority_class {
+                5 => &mut push_rules.override_rules,
+                4 => &mut push_rules.content,
+                3 => &mut push_rules.room,
+                2 => &mut push_rules.sender,
+                _ => &mut push_rules.underride,
+            };
+
+            if non_disableable_rule_ids.contains(&*rule.rule_id) {
+                rules.retain(|user_rule| user_rule.rule_id != rule.rule_id);
+            } else if rules
+                .iter()
+                .any(|user_rule| user_rule.rule_id == rule.rule_id)
+            {
+                // The user has replaced this server rule.
+                continue;
+            }
+
+            server_rule_ids.insert(rule.rule_id.to_string());
+            rules.insert(0, rule);
+        }
+
+        Ok(FilteredPushRules {
+            push_rules,
+            server_rule_ids,
+            non_disableable_rule_ids: self
+                .non_disableable_rule_ids
+                .union(&non_disableable_rule_ids)
+                .cloned()
+                .collect(),
+            ..self.clone()
+        }
+        .with_precompilation_of(self))
+    }
+
+    /// Merges a set of server-installed rules with a user's rules, e.g. so that
+    /// `run` can be called with a single set.
+    ///
+    /// The custom rules of `server` are merged in as per `with_server_rules`,
+    /// so rules are ordered by priority class across both sets, with the
+    /// server's rules first within each class. If both sets have a rule with
+    /// the same ID:
+    ///
+    /// - the user's rule is used (in its position among the user's rules), and
+    ///   the server's is dropped, unless
+    /// - the server's rule is non-disableable, in which case it is used and the
+    ///   user's is dropped.
+    ///
+    /// Overridden base rules and enabled states in `user` take precedence over
+    /// those in `server`. The experimental feature flags are those of `user`.
+    #[staticmethod]
+    pub fn merge(
+        server: &FilteredPushRules,
+        user: &FilteredPushRules,
+    ) -> Result<FilteredPushRules, Error> {
+        let mut push_rules = user.push_rules.clone();
+        for (rule_id, rule) in &server.push_rules.overridden_base_rules {
+            push_rules
+                .overridden_base_rules
+                .entry(rule_id.clone())
+                .or_insert_with(|| rule.clone());
+        }
+
+        let mut enabled_map = server.enabled_map.clone();
+        enabled_map.extend(
+            user.enabled_map
+                .iter()
+                .map(|(rule_id, enabled)| (rule_id.clone(), *enabled)),
+        );
+
+        FilteredPushRules {
+            push_rules,
+            enabled_map,
+            ..user.clone()
         }
+        .with_server_rules(
+            server.push_rules.custom_rules().cloned().collect(),
+            server.non_disableable_rule_ids.clone(),
+        )
     }
 
     /// Returns the list of all rules and their enabled state, including base
@@ -555,11 +1742,21 @@ impl FilteredPushRules {
     fn rules(&self) -> Vec<(PushRule, bool)> {
         self.iter().map(|(r, e)| (r.clone(), e)).collect()
     }
+
+    /// Returns a copy of these rules with all conditions in canonical form, see
+    /// [`Condition::normalize`].
+    pub fn normalize(&self) -> FilteredPushRules {
+        FilteredPushRules {
+            push_rules: self.push_rules.normalize(),
+            ..self.clone()
+        }
+        .with_precompilation_of(self)
+    }
 }
 
 impl FilteredPushRules {
     /// Iterates over all the rules and their enabled state, including base
-    /// rules, in the order they should be executed in.
+    /// rules, in the order they should be executed in, see `PushRules::iter`.
     fn iter(&self) -> impl Iterator<Item = (&PushRule, bool)> {
         self.push_rules
             .iter()
@@ -592,20 +1789,216 @@ impl FilteredPushRules {
                 true
             })
             .map(|r| {
-                let enabled = *self
-                    .enabled_map
-                    .get(&*r.rule_id)
-                    .unwrap_or(&r.default_enabled);
+                let enabled = self.non_disableable_rule_ids.contains(&*r.rule_id)
+                    || *self
+                        .enabled_map
+                        .get(&*r.rule_id)
+                        .unwrap_or(&r.default_enabled);
                 (r, enabled)
             })
     }
+
+    /// Compiles the patterns of the rules' conditions into the shared glob
+    /// cache (`GLOB_MATCHER_CACHE`), e.g. to warm it when the user logs in,
+    /// and times how long that takes to spot rule sets which are expensive to
+    /// compile. As with `precompiled`, patterns which depend on the user can't
+    /// be compiled ahead of time.
+    ///
+    /// Patterns are compiled even if they're already cached, so the time
+    /// reflects the cost of the rule set.
+    pub fn warm_glob_cache(&self) -> GlobCacheWarming {
+        let mut globs = Vec::new();
+        for condition in self
+            .push_rules
+            .iter()
+            .flat_map(|rule| rule.conditions.iter())
+        {
+            collect_globs(condition, &mut globs);
+        }
+        globs.sort_unstable_by_key(|(glob, match_type)| (*glob, *match_type as u8));
+        globs.dedup();
+
+        let start = Instant::now();
+        let patterns_compiled = globs
+            .into_iter()
+            .filter(|(glob, match_type)| GLOB_MATCHER_CACHE.insert(glob, *match_type).is_ok())
+            .count();
+
+        GlobCacheWarming {
+            patterns_compiled,
+            elapsed: start.elapsed(),
+        }
+    }
+
+    /// The precompiled patterns of the rules, if any.
+    pub(crate) fn precompiled_globs(&self) -> Option<&PrecompiledGlobs> {
+        self.precompiled_globs.as_deref()
+    }
+
+    /// Recompiles the patterns of these (derived) rules if the rules they were
+    /// derived from were precompiled.
+    fn with_precompilation_of(self, original: &FilteredPushRules) -> FilteredPushRules {
+        if original.precompiled_globs.is_some() {
+            self.precompiled()
+        } else {
+            self
+        }
+    }
+
+    /// Returns where the rule with the given ID came from, or None if there is
+    /// no such rule.
+    pub fn provenance(&self, rule_id: &str) -> Option<RuleProvenance> {
+        if base_rules::BASE_RULES_BY_ID.contains_key(rule_id) {
+            Some(RuleProvenance::Base)
+        } else if self.server_rule_ids.contains(rule_id) {
+            Some(RuleProvenance::Server)
+        } else if self.push_rules.iter().any(|rule| rule.rule_id == rule_id) {
+            Some(RuleProvenance::User)
+        } else {
+            None
+        }
+    }
+}
+
+/// Builds a [`FilteredPushRules`] from rules constructed in Rust, e.g. by tests
+/// and tooling, rather than loaded from the database.
+///
+/// The rules are checked when built: unlike `PushRules::new`, which skips
+/// rules it can't use, the builder fails on them.
+#[derive(Debug, Clone, Default)]
+pub struct FilteredPushRulesBuilder {
+    rules: Vec<PushRule>,
+    enabled_map: BTreeMap<String, bool>,
+    msc1767_enabled: bool,
+    msc3381_polls_enabled: bool,
+    msc3664_enabled: bool,
+    msc4028_push_encrypted_events: bool,
+}
+
+impl FilteredPushRulesBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a custom rule. Rules of the same priority class are executed in
+    /// the order they're added.
+    ///
+    /// If `rule_id` is that of a base rule then the rule's actions replace
+    /// those of the base rule, and its conditions are ignored.
+    pub fn rule(
+        self,
+        rule_id: impl Into<String>,
+        priority_class: i32,
+        conditions: Vec<Condition>,
+        actions: Vec<Action>,
+    ) -> Self {
+        self.push_rule(PushRule {
+            rule_id: Cow::Owned(rule_id.into()),
+            priority_class,
+            conditions: Cow::Owned(conditions),
+            actions: Cow::Owned(actions),
+            default: false,
+            default_enabled: true,
+        })
+    }
+
+    /// Adds an existing rule, see `rule`.
+    pub fn push_rule(mut self, rule: PushRule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Sets whether the rule with the given ID is enabled. Rules are otherwise
+    /// enabled unless they're disabled by default (e.g. `.m.rule.master`).
+    pub fn enabled(mut self, rule_id: impl Into<String>, enabled: bool) -> Self {
+        self.enabled_map.insert(rule_id.into(), enabled);
+        self
+    }
+
+    pub fn msc1767_enabled(mut self, enabled: bool) -> Self {
+        self.msc1767_enabled = enabled;
+        self
+    }
+
+    pub fn msc3381_polls_enabled(mut self, enabled: bool) -> Self {
+        self.msc3381_polls_enabled = enabled;
+        self
+    }
+
+    pub fn msc3664_enabled(mut self, enabled: bool) -> Self {
+        self.msc3664_enabled = enabled;
+        self
+    }
+
+    pub fn msc4028_push_encrypted_events(mut self, enabled: bool) -> Self {
+        self.msc4028_push_encrypted_events = enabled;
+        self
+    }
+
+    /// Checks the rules and builds the rule set.
+    ///
+    /// Fails if a custom rule has an unrecognized priority class or a
+    /// condition which can't be evaluated (see `validate_rule`), if two rules
+    /// have the same ID, or if an enabled state is set for an unknown rule.
+    pub fn build(self) -> Result<FilteredPushRules, Error> {
+        let mut seen_rule_ids = BTreeSet::new();
+        for rule in &self.rules {
+            if !seen_rule_ids.insert(&*rule.rule_id) {
+                bail!("Duplicate rule {}", rule.rule_id);
+            }
+            if base_rules::BASE_RULES_BY_ID.contains_key(&*rule.rule_id) {
+                continue;
+            }
+
+            if !(1..=5).contains(&rule.priority_class) {
+                bail!(
+                    "Unrecognized priority class for rule {}: {}",
+                    rule.rule_id,
+                    rule.priority_class
+                );
+            }
+            if let Err(errors) = validation::validate_rule(rule) {
+                let errors: Vec<_> = errors.iter().map(ToString::to_string).collect();
+                bail!("Invalid rule {}: {}", rule.rule_id, errors.join("; "));
+            }
+            if let Err(errors) = validation::validate_actions(&rule.actions) {
+                bail!("Invalid rule {}: {}", rule.rule_id, errors.join("; "));
+            }
+        }
+        for rule_id in self.enabled_map.keys() {
+            if !seen_rule_ids.contains(&**rule_id)
+                && !base_rules::BASE_RULES_BY_ID.contains_key(&**rule_id)
+            {
+                bail!("Enabled state set for unknown rule {}", rule_id);
+            }
+        }
+
+        Ok(self.build_unchecked())
+    }
+
+    /// Builds the rule set without checking the rules, e.g. to test how rules
+    /// which can't be evaluated are handled. As with `PushRules::new`, rules
+    /// with an unrecognized priority class are skipped.
+    pub fn build_unchecked(self) -> FilteredPushRules {
+        FilteredPushRules::py_new(
+            PushRules::new(self.rules),
+            self.enabled_map,
+            self.msc1767_enabled,
+            self.msc3381_polls_enabled,
+            self.msc3664_enabled,
+            self.msc4028_push_encrypted_events,
+        )
+    }
 }
 
 #[test]
 fn test_serialize_condition() {
     let condition = Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
-        key: "content.body".into(),
+        key: EventMatchKey::Single("content.body".into()),
         pattern: "coffee".into(),
+        ignore_quotes: false,
+        match_words: None,
+        contains: false,
     }));
 
     let json = serde_json::to_string(&condition).unwrap();
@@ -752,6 +2145,69 @@ fn test_deserialize_custom_condition() {
     assert_eq!(json, new_json);
 }
 
+#[test]
+fn test_normalize_rules() {
+    let rule = |conditions: &str| {
+        PushRule::from_db(
+            "global/override/.org.example.rule".to_string(),
+            5,
+            conditions,
+            r#"["notify"]"#,
+        )
+        .unwrap()
+    };
+
+    let normalized = |rule: PushRule| {
+        FilteredPushRules::py_new(
+            PushRules::new(vec![rule]),
+            BTreeMap::new(),
+            false,
+            false,
+            false,
+            false,
+        )
+        .normalize()
+        .iter()
+        .find(|(rule, _)| rule.rule_id == "global/override/.org.example.rule")
+        .map(|(rule, _)| rule.conditions.to_vec())
+        .unwrap()
+    };
+
+    let a = rule(
+        r#"[
+            {"kind": "room_member_count", "is": "5"},
+            {"kind": "event_match", "key": "content.body", "pattern": "coffee"},
+            {"kind": "io.element.is_unstable_event_type", "prefixes": ["org.", "im."]}
+        ]"#,
+    );
+    let b = rule(
+        r#"[
+            {"kind": "room_member_count", "is": "==005"},
+            {"kind": "event_match", "key": "content.body", "pattern": "coffee"},
+            {"kind": "io.element.is_unstable_event_type", "prefixes": ["im.", "org.", "im."]}
+        ]"#,
+    );
+
+    assert_ne!(a.conditions, b.conditions);
+    assert_eq!(normalized(a), normalized(b));
+
+    // Different rules stay different.
+    let c = rule(r#"[{"kind": "room_member_count", "is": ">=5"}]"#);
+    let d = rule(r#"[{"kind": "room_member_count", "is": "==5"}]"#);
+    assert_ne!(normalized(c), normalized(d));
+
+    let e = rule(r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#);
+    let f = rule(
+        r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee", "contains": true}]"#,
+    );
+    assert_ne!(normalized(e), normalized(f));
+
+    // Whitespace in patterns is significant.
+    let g = rule(r#"[{"kind": "event_match", "key": "content.body", "pattern": "coffee"}]"#);
+    let h = rule(r#"[{"kind": "event_match", "key": "content.body", "pattern": " coffee "}]"#);
+    assert_ne!(normalized(g), normalized(h));
+}
+
 #[test]
 fn test_deserialize_action() {
     let _: Action = serde_json::from_str(r#""notify""#).unwrap();
@@ -760,6 +2216,45 @@ fn test_deserialize_action() {
     let _: Action = serde_json::from_str(r#"{"set_tweak": "highlight"}"#).unwrap();
 }
 
+#[test]
+fn test_resolve_conflicting_tweaks() {
+    let actions: Vec<Action> = serde_json::from_str(
+        r#"[
+            "notify",
+            {"set_tweak": "highlight"},
+            {"set_tweak": "sound", "value": "default"},
+            {"set_tweak": "highlight", "value": false}
+        ]"#,
+    )
+    .unwrap();
+
+    // The last highlight tweak wins.
+    assert_eq!(
+        serde_json::to_string(&resolve_tweaks(&actions)).unwrap(),
+        r#"["notify",{"set_tweak":"sound","value":"default"},{"set_tweak":"highlight","value":false}]"#
+    );
+}
+
+#[test]
+fn test_sort_actions_canonically() {
+    let mut actions: Vec<Action> = serde_json::from_str(
+        r#"[
+            {"set_tweak": "sound", "value": "default"},
+            {"some_custom": "action_fields"},
+            {"set_tweak": "highlight"},
+            "notify"
+        ]"#,
+    )
+    .unwrap();
+
+    sort_actions_canonically(&mut actions);
+
+    assert_eq!(
+        serde_json::to_string(&actions).unwrap(),
+        r#"["notify",{"set_tweak":"highlight"},{"set_tweak":"sound","value":"default"},{"some_custom":"action_fields"}]"#
+    );
+}
+
 #[test]
 fn test_custom_action() {
     let json = r#"{"some_custom":"action_fields"}"#;
@@ -770,3 +2265,442 @@ fn test_custom_action() {
     let new_json = serde_json::to_string(&action).unwrap();
     assert_eq!(json, new_json);
 }
+
+#[test]
+fn test_rate_limit_tweak() {
+    let json = r#"{"set_tweak":"rate_limit","value":10}"#;
+
+    let action: Action = serde_json::from_str(json).unwrap();
+    let tweak = match &action {
+        Action::SetTweak(tweak) => tweak,
+        _ => panic!("not a tweak: {action:?}"),
+    };
+    assert_eq!(tweak.rate_limit(), Some(10));
+    assert_eq!(serde_json::to_string(&action).unwrap(), json);
+
+    // Invalid values and other tweaks aren't rate limits.
+    for json in [
+        r#"{"set_tweak":"rate_limit","value":0}"#,
+        r#"{"set_tweak":"rate_limit","value":-1}"#,
+        r#"{"set_tweak":"rate_limit","value":1.5}"#,
+        r#"{"set_tweak":"rate_limit","value":"10"}"#,
+        r#"{"set_tweak":"rate_limit"}"#,
+        r#"{"set_tweak":"sound","value":10}"#,
+    ] {
+        let tweak: SetTweak = serde_json::from_str(json).unwrap();
+        assert_eq!(tweak.rate_limit(), None, "{json}");
+        assert_eq!(serde_json::to_string(&tweak).unwrap(), json);
+    }
+}
+
+#[test]
+fn test_server_rules() {
+    let rule = |rule_id: &str, actions: &str| {
+        PushRule::from_db(rule_id.to_string(), 5, "[]", actions).unwrap()
+    };
+    let server_rule_id = "global/override/.org.example.server";
+
+    let user_rules = FilteredPushRules::py_new(
+        PushRules::new(vec![
+            rule(server_rule_id, r#"["dont_notify"]"#),
+            rule("global/override/.org.example.user", r#"["notify"]"#),
+        ]),
+        BTreeMap::from([(server_rule_id.to_string(), false)]),
+        false,
+        false,
+        false,
+        false,
+    );
+    let find = |rules: &FilteredPushRules| {
+        rules
+            .iter()
+            .filter(|(rule, _)| rule.rule_id == server_rule_id)
+            .map(|(rule, enabled)| (rule.actions.to_vec(), enabled))
+            .collect::<Vec<_>>()
+    };
+
+    // A user rule with the same ID replaces a disableable server rule.
+    let merged = user_rules
+        .with_server_rules(vec![rule(server_rule_id, r#"["notify"]"#)], BTreeSet::new())
+        .unwrap();
+    assert_eq!(find(&merged), vec![(vec![Action::DontNotify], false)]);
+    assert_eq!(
+        merged.provenance(server_rule_id),
+        Some(RuleProvenance::User)
+    );
+
+    // But can neither replace nor disable a non-disableable one.
+    let merged = user_rules
+        .with_server_rules(
+            vec![rule(server_rule_id, r#"["notify"]"#)],
+            BTreeSet::from([server_rule_id.to_string()]),
+        )
+        .unwrap();
+    assert_eq!(find(&merged), vec![(vec![Action::Notify], true)]);
+    assert_eq!(
+        merged.provenance(server_rule_id),
+        Some(RuleProvenance::Server)
+    );
+    assert_eq!(
+        merged.provenance("global/override/.org.example.user"),
+        Some(RuleProvenance::User)
+    );
+    assert_eq!(
+        merged.provenance("global/override/.m.rule.master"),
+        Some(RuleProvenance::Base)
+    );
+
+    // Server rules come before the user's rules of the same priority class.
+    let rule_ids: Vec<_> = merged
+        .iter()
+        .map(|(rule, _)| rule.rule_id.clone())
+        .collect();
+    let position = |rule_id: &str| rule_ids.iter().position(|r| r == rule_id).unwrap();
+    assert!(position(server_rule_id) < position("global/override/.org.example.user"));
+
+    // Server rules must not conflict with base rules or each other.
+    assert!(user_rules
+        .with_server_rules(
+            vec![rule("global/override/.m.rule.master", "[]")],
+            BTreeSet::new()
+        )
+        .is_err());
+    assert!(user_rules
+        .with_server_rules(
+            vec![rule(server_rule_id, "[]"), rule(server_rule_id, "[]")],
+            BTreeSet::new()
+        )
+        .is_err());
+    assert!(user_rules
+        .with_server_rules(vec![], BTreeSet::from([server_rule_id.to_string()]))
+        .is_err());
+}
+
+#[test]
+fn test_merge_rules() {
+    let rule = |rule_id: &str, priority_class: i32, actions: &str| {
+        PushRule::from_db(rule_id.to_string(), priority_class, "[]", actions).unwrap()
+    };
+    let filtered = |rules: Vec<PushRule>, enabled_map: BTreeMap<String, bool>| {
+        FilteredPushRules::py_new(
+            PushRules::new(rules),
+            enabled_map,
+            false,
+            false,
+            false,
+            false,
+        )
+    };
+
+    let server = filtered(
+        vec![
+            rule("global/override/.org.example.shared", 5, r#"["notify"]"#),
+            rule("global/override/.org.example.server", 5, r#"["notify"]"#),
+            rule("global/underride/.org.example.server", 1, r#"["notify"]"#),
+            rule("global/override/.m.rule.master", 5, r#"["notify"]"#),
+            rule("global/underride/.m.rule.message", 1, r#"["notify"]"#),
+        ],
+        BTreeMap::from([
+            ("global/override/.org.example.server".to_string(), false),
+            ("global/underride/.org.example.server".to_string(), false),
+        ]),
+    );
+    let user = filtered(
+        vec![
+            rule(
+                "global/override/.org.example.shared",
+                5,
+                r#"["dont_notify"]"#,
+            ),
+            rule("global/content/.org.example.user", 4, r#"["notify"]"#),
+            rule("global/underride/.m.rule.message", 1, r#"["dont_notify"]"#),
+        ],
+        BTreeMap::from([("global/underride/.org.example.server".to_string(), true)]),
+    );
+
+    let merged = FilteredPushRules::merge(&server, &user).unwrap();
+    let rules: Vec<_> = merged
+        .iter()
+        .filter(|(rule, _)| {
+            !base_rules::BASE_RULES_BY_ID.contains_key(&*rule.rule_id)
+                || [
+                    "global/override/.m.rule.master",
+                    "global/underride/.m.rule.message",
+                ]
+                .contains(&&*rule.rule_id)
+        })
+        .map(|(rule, enabled)| (rule.rule_id.to_string(), rule.actions.to_vec(), enabled))
+        .collect();
+
+    assert_eq!(
+        rules,
+        vec![
+            // The base rule overridden by the server (only).
+            (
+                "global/override/.m.rule.master".to_string(),
+                vec![Action::Notify],
+                false
+            ),
+            (
+                "global/override/.org.example.server".to_string(),
+                vec![Action::Notify],
+                false
+            ),
+            // The user's override beats the server rule with the same ID.
+            (
+                "global/override/.org.example.shared".to_string(),
+                vec![Action::DontNotify],
+                true
+            ),
+            (
+                "global/content/.org.example.user".to_string(),
+                vec![Action::Notify],
+                true
+            ),
+            // The user's enabled state takes precedence.
+            (
+                "global/underride/.org.example.server".to_string(),
+                vec![Action::Notify],
+                true
+            ),
+            // As does the user's override of a base rule.
+            (
+                "global/underride/.m.rule.message".to_string(),
+                vec![Action::DontNotify],
+                true
+            ),
+        ]
+    );
+    assert_eq!(
+        merged.provenance("global/override/.org.example.shared"),
+        Some(RuleProvenance::User)
+    );
+    assert_eq!(
+        merged.provenance("global/override/.org.example.server"),
+        Some(RuleProvenance::Server)
+    );
+}
+
+#[test]
+fn test_filtered_push_rules_builder() {
+    use self::evaluator::EvaluatorInputs;
+
+    let body_match = |pattern: &str| {
+        Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
+            key: EventMatchKey::Single("content.body".into()),
+            pattern: pattern.to_string().into(),
+            ignore_quotes: false,
+            match_words: None,
+            contains: false,
+        }))
+    };
+    let rules = FilteredPushRulesBuilder::new()
+        .rule(
+            "global/content/coffee",
+            4,
+            vec![body_match("coffee")],
+            vec![Action::Notify],
+        )
+        .rule(
+            "global/content/tea",
+            4,
+            vec![body_match("tea")],
+            vec![Action::Notify],
+        )
+        .rule(
+            "global/underride/.m.rule.message",
+            1,
+            vec![],
+            vec![Action::DontNotify],
+        )
+        .enabled("global/content/tea", false)
+        .build()
+        .unwrap();
+
+    let evaluator = |body: &'static str| {
+        PushRuleEvaluator::new(EvaluatorInputs {
+            flattened_keys: BTreeMap::from([
+                (
+                    "type".into(),
+                    JsonValue::Value(SimpleJsonValue::Str("m.room.message".into())),
+                ),
+                (
+                    "content.body".into(),
+                    JsonValue::Value(SimpleJsonValue::Str(body.into())),
+                ),
+            ]),
+            room_member_count: 10,
+            ..Default::default()
+        })
+    };
+    assert_eq!(
+        evaluator("coffee").run_with_matched_rule(&rules, None, None),
+        Some(("global/content/coffee".to_string(), vec![Action::Notify]))
+    );
+    // The disabled rule is skipped, and the overridden base rule matches
+    // (with no actions, as `dont_notify` is a no-op).
+    assert_eq!(
+        evaluator("tea").run_with_matched_rule(&rules, None, None),
+        Some(("global/underride/.m.rule.message".to_string(), vec![]))
+    );
+
+    // Invalid rule sets are rejected.
+    let duplicate = FilteredPushRulesBuilder::new()
+        .rule("global/content/coffee", 4, vec![], vec![])
+        .rule("global/content/coffee", 4, vec![], vec![]);
+    assert!(duplicate.build().is_err());
+    let bad_priority_class =
+        FilteredPushRulesBuilder::new().rule("global/content/coffee", 7, vec![], vec![]);
+    assert!(bad_priority_class.build().is_err());
+    let bad_condition = FilteredPushRulesBuilder::new().rule(
+        "global/content/coffee",
+        4,
+        vec![Condition::Known(KnownCondition::RoomMemberCount {
+            is: Some("<<2".into()),
+        })],
+        vec![],
+    );
+    assert!(bad_condition.build().is_err());
+    let unknown_rule = FilteredPushRulesBuilder::new().enabled("global/content/coffee", false);
+    assert!(unknown_rule.build().is_err());
+}
+
+#[test]
+fn test_base_rules() {
+    let rules = base_rules(BASE_RULES_VERSION).unwrap();
+    let rules: Vec<_> = rules
+        .iter()
+        .map(|(rule, enabled)| (rule.rule_id.to_string(), enabled))
+        .collect();
+
+    // The master rule comes first, and is disabled.
+    assert_eq!(
+        rules[0],
+        ("global/override/.m.rule.master".to_string(), false)
+    );
+    for rule_id in [
+        "global/override/.m.rule.is_user_mention",
+        "global/content/.m.rule.contains_user_name",
+        "global/underride/.m.rule.room_one_to_one",
+        "global/underride/.m.rule.message",
+    ] {
+        assert!(rules.contains(&(rule_id.to_string(), true)), "{rule_id}");
+    }
+    // There are no user rules, and the experimental rules are disabled.
+    assert!(rules
+        .iter()
+        .all(|(rule_id, _)| base_rules::BASE_RULES_BY_ID.contains_key(&**rule_id)));
+    assert!(!rules
+        .iter()
+        .any(|(rule_id, _)| rule_id.contains("org.matrix.msc1767")));
+
+    assert!(base_rules("0").is_err());
+    assert!(base_rules("").is_err());
+}
+
+#[test]
+fn test_warm_glob_cache() {
+    let body_match = |pattern: &str| {
+        Condition::Known(KnownCondition::EventMatch(EventMatchCondition {
+            key: EventMatchKey::Single("content.body".into()),
+            pattern: pattern.to_string().into(),
+            ignore_quotes: false,
+            match_words: None,
+            contains: false,
+        }))
+    };
+    let rule_set = |patterns: &[&str]| {
+        patterns
+            .iter()
+            .enumerate()
+            .fold(FilteredPushRulesBuilder::new(), |builder, (i, pattern)| {
+                builder.rule(
+                    format!("global/content/rule{i}"),
+                    4,
+                    vec![body_match(pattern)],
+                    vec![Action::Notify],
+                )
+            })
+            .build()
+            .unwrap()
+    };
+
+    // The base rules' patterns are compiled too, so only compare the counts.
+    let base_patterns = rule_set(&[]).warm_glob_cache().patterns_compiled;
+    let warming = rule_set(&["warm-coffee", "warm-tea", "warm-water"]).warm_glob_cache();
+    assert_eq!(warming.patterns_compiled, base_patterns + 3);
+
+    // Repeated patterns are only compiled once.
+    let warming = rule_set(&["warm-coffee", "warm-coffee"]).warm_glob_cache();
+    assert_eq!(warming.patterns_compiled, base_patterns + 1);
+    assert!(GLOB_MATCHER_CACHE
+        .is_match("warm-coffee", GlobMatchType::Word, "warm-coffee please")
+        .unwrap());
+}
+
+#[test]
+fn test_condition_kinds() {
+    let rules = FilteredPushRulesBuilder::new()
+        .rule(
+            "global/override/quiet_rooms",
+            5,
+            vec![Condition::Known(KnownCondition::Not {
+                condition: Box::new(Condition::Known(KnownCondition::RoomNotificationsToday {
+                    is: Some(Cow::Borrowed(">10")),
+                })),
+            })],
+            vec![Action::Notify],
+        )
+        .rule(
+            "global/override/not_me",
+            5,
+            vec![Condition::Known(KnownCondition::SenderIsSelf)],
+            vec![Action::DontNotify],
+        )
+        .enabled("global/override/not_me", false)
+        .build()
+        .unwrap();
+
+    let kinds = rules.condition_kinds();
+    // Nested conditions are included, as are those of the base rules.
+    assert!(kinds.contains("io.element.not"));
+    assert!(kinds.contains("io.element.room_notifications_today"));
+    assert!(kinds.contains("event_match"));
+    // Disabled rules aren't.
+    assert!(!kinds.contains("io.element.sender_is_self"));
+}
+
+#[test]
+fn test_flattened_key() {
+    // Well-known keys aren't allocated, whether or not they were already.
+    for key in ["type", "content.body", r"content.m\.relates_to.rel_type"] {
+        assert!(FlattenedKey::from(key).is_static(), "{key}");
+        assert!(FlattenedKey::from(key.to_string()).is_static(), "{key}");
+    }
+    assert!(!FlattenedKey::from("content.other").is_static());
+
+    // Either way they compare, and can be looked up, as strings.
+    let flattened: BTreeMap<FlattenedKey, JsonValue> = BTreeMap::from([
+        ("type".into(), JsonValue::Value(SimpleJsonValue::Null)),
+        (
+            "content.other".into(),
+            JsonValue::Value(SimpleJsonValue::Null),
+        ),
+    ]);
+    assert!(flattened.contains_key("type"));
+    assert!(flattened.contains_key("content.other"));
+    assert!(!flattened.contains_key("content.body"));
+    assert_eq!(
+        flattened.keys().map(|key| &**key).collect::<Vec<_>>(),
+        vec!["content.other", "type"]
+    );
+
+    assert_eq!(
+        serde_json::to_string(&flattened).unwrap(),
+        r#"{"content.other":null,"type":null}"#
+    );
+    let deserialized: BTreeMap<FlattenedKey, JsonValue> =
+        serde_json::from_str(r#"{"content.other":null,"type":null}"#).unwrap();
+    assert_eq!(deserialized, flattened);
+    assert!(deserialized.keys().any(FlattenedKey::is_static));
+}
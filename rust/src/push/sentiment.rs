@@ -0,0 +1,135 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A simple lexicon based estimate of the sentiment of a message body.
+//!
+//! This is a heuristic: each word found in the lexicon contributes its score,
+//! with the score of a word flipped if it directly follows a negation (e.g.
+//! "not good"). Bodies whose total is at least [`STRONG_SENTIMENT_SCORE`] away
+//! from zero are considered positive or negative, everything else is neutral.
+
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+/// The absolute score a body must reach to be considered positive or negative.
+const STRONG_SENTIMENT_SCORE: i32 = 2;
+
+/// Words which flip the score of the word following them.
+const NEGATIONS: &[&str] = &["not", "no", "never", "dont", "don't", "isnt", "isn't"];
+
+/// The sentiment score of words, from -3 (very negative) to 3 (very positive).
+const LEXICON: &[(&str, i32)] = &[
+    ("awesome", 3),
+    ("brilliant", 3),
+    ("excellent", 3),
+    ("fantastic", 3),
+    ("love", 3),
+    ("wonderful", 3),
+    ("amazing", 3),
+    ("great", 2),
+    ("happy", 2),
+    ("thanks", 2),
+    ("thank", 2),
+    ("glad", 2),
+    ("good", 2),
+    ("nice", 2),
+    ("like", 1),
+    ("fine", 1),
+    ("ok", 1),
+    ("cool", 1),
+    ("bad", -2),
+    ("sad", -2),
+    ("annoying", -2),
+    ("angry", -2),
+    ("ugly", -2),
+    ("useless", -2),
+    ("shut", -2),
+    ("terrible", -3),
+    ("awful", -3),
+    ("horrible", -3),
+    ("hate", -3),
+    ("stupid", -3),
+    ("idiot", -3),
+    ("disgusting", -3),
+    ("pathetic", -3),
+    ("worst", -3),
+];
+
+lazy_static! {
+    static ref LEXICON_MAP: HashMap<&'static str, i32> = LEXICON.iter().copied().collect();
+}
+
+/// The overall sentiment of a message.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SentimentPolarity {
+    Positive,
+    Neutral,
+    Negative,
+}
+
+/// Estimate the sentiment of the given body.
+pub fn body_sentiment(body: &str) -> SentimentPolarity {
+    let mut score = 0;
+    let mut negated = false;
+
+    for word in body
+        .split(|c: char| !(c.is_alphanumeric() || c == '\''))
+        .filter(|word| !word.is_empty())
+    {
+        let word = word.to_lowercase();
+
+        if NEGATIONS.contains(&word.as_str()) {
+            negated = true;
+            continue;
+        }
+
+        if let Some(word_score) = LEXICON_MAP.get(word.as_str()) {
+            score += if negated { -word_score } else { *word_score };
+        }
+        negated = false;
+    }
+
+    if score >= STRONG_SENTIMENT_SCORE {
+        SentimentPolarity::Positive
+    } else if score <= -STRONG_SENTIMENT_SCORE {
+        SentimentPolarity::Negative
+    } else {
+        SentimentPolarity::Neutral
+    }
+}
+
+#[test]
+fn test_body_sentiment() {
+    assert_eq!(
+        body_sentiment("Thanks, this is a great idea!"),
+        SentimentPolarity::Positive
+    );
+    assert_eq!(
+        body_sentiment("You are a stupid idiot, I hate this."),
+        SentimentPolarity::Negative
+    );
+    assert_eq!(
+        body_sentiment("The meeting is at 3pm."),
+        SentimentPolarity::Neutral
+    );
+
+    // Negations flip the sentiment of the following word.
+    assert_eq!(
+        body_sentiment("This is not good"),
+        SentimentPolarity::Negative
+    );
+}
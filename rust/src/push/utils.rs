@@ -12,204 +12,2616 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use anyhow::bail;
-use anyhow::Context;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
 use anyhow::Error;
 use lazy_static::lazy_static;
+use log::warn;
+use lru::LruCache;
+use once_cell::sync::OnceCell;
 use regex;
 use regex::Regex;
 use regex::RegexBuilder;
+use regex::{RegexSet, RegexSetBuilder};
+use serde::de::{MapAccess, Visitor};
+use serde::Deserializer;
+
+use super::{FlattenedKey, JsonValue, SimpleJsonValue};
 
 lazy_static! {
-    /// Matches runs of non-wildcard characters followed by wildcard characters.
-    static ref WILDCARD_RUN: Regex = Regex::new(r"([^\?\*]*)([\?\*]*)").expect("valid regex");
+    /// Matches a single character of `UNSPACED_SCRIPTS`.
+    static ref UNSPACED_SCRIPT_CHAR: Regex =
+        Regex::new(&format!("^{UNSPACED_SCRIPTS}$")).expect("valid regex");
+
+    /// Matches a single word character, i.e. `\w` except `EMOJI_MARKS`.
+    static ref WORD_CHAR: Regex =
+        Regex::new(&format!(r"^[\w--{EMOJI_MARKS}]$")).expect("valid regex");
+
+    /// The process wide cache of compiled globs, shared by all push rule
+    /// evaluators.
+    pub static ref GLOB_MATCHER_CACHE: GlobMatcherCache = GlobMatcherCache::new(
+        NonZeroUsize::new(GLOB_MATCHER_CACHE_SIZE).expect("non-zero cache size")
+    );
 }
 
-/// Extract the localpart from a Matrix style ID
-pub(crate) fn get_localpart_from_id(id: &str) -> Result<&str, Error> {
-    let (localpart, _) = id
+/// A regex character class of the scripts which don't separate words with
+/// spaces (e.g. Chinese and Japanese). Every character of these scripts is
+/// treated as being a word on its own when word matching.
+const UNSPACED_SCRIPTS: &str =
+    r"[\p{Han}\p{Hiragana}\p{Katakana}\p{Thai}\p{Lao}\p{Khmer}\p{Myanmar}]";
+
+/// Whether the character is of one of the `UNSPACED_SCRIPTS`.
+fn is_unspaced_script(c: char) -> bool {
+    UNSPACED_SCRIPT_CHAR.is_match(c.encode_utf8(&mut [0; 4]))
+}
+
+/// A regex character class of the marks which only modify the presentation of
+/// emoji: variation selectors and the combining keycap. Unicode counts them as
+/// word characters (like accents), so e.g. `1️⃣coffee` wouldn't contain the word
+/// `coffee`; instead they are treated as boundaries when word matching.
+const EMOJI_MARKS: &str = r"[\x{FE00}-\x{FE0F}\x{E0100}-\x{E01EF}\x{20E3}]";
+
+/// Whether the character is part of a word when word matching.
+fn is_word_char(c: char) -> bool {
+    WORD_CHAR.is_match(c.encode_utf8(&mut [0; 4]))
+}
+
+thread_local! {
+    /// The glob match budget of this thread, if limited, see
+    /// `with_match_budget`.
+    static MATCH_BUDGET: Cell<Option<MatchBudget>> = const { Cell::new(None) };
+}
+
+/// What remains of a budget set by `with_match_budget`.
+#[derive(Clone, Copy)]
+struct MatchBudget {
+    /// The number of haystack bytes which may still be matched against.
+    remaining: u64,
+    /// Whether a match was refused as it would have exceeded the budget.
+    exceeded: bool,
+}
+
+/// Calls `f` with glob matching on this thread limited to `budget` haystack
+/// bytes in total. Matching a compiled glob is linear in the length of the
+/// haystack, so this bounds the cost of `f` regardless of the patterns.
+///
+/// Matches which would exceed the budget fail before looking at the haystack,
+/// and `None` is returned once `f` finishes. Calls made by `f` share its
+/// budget rather than setting their own.
+pub fn with_match_budget<T>(budget: u64, f: impl FnOnce() -> T) -> Option<T> {
+    if MATCH_BUDGET.with(|current| current.get().is_some()) {
+        return Some(f());
+    }
+
+    /// Removes the budget again, even if `f` panics.
+    struct ResetBudget;
+
+    impl Drop for ResetBudget {
+        fn drop(&mut self) {
+            MATCH_BUDGET.with(|current| current.set(None));
+        }
+    }
+
+    MATCH_BUDGET.with(|current| {
+        current.set(Some(MatchBudget {
+            remaining: budget,
+            exceeded: false,
+        }))
+    });
+    let _reset = ResetBudget;
+
+    let result = f();
+    let exceeded = MATCH_BUDGET.with(|current| current.get().map_or(false, |b| b.exceeded));
+    (!exceeded).then(|| result)
+}
+
+/// Takes the length of the haystack from the budget set by
+/// `with_match_budget`, if any, failing if there isn't enough left.
+///
+/// Compiled globs charge this themselves. Other linear scans of event text
+/// (e.g. by a regex) should charge it before scanning, so they count towards
+/// the same budget.
+pub(crate) fn charge_match_budget(haystack: &str) -> Result<(), Error> {
+    MATCH_BUDGET.with(|current| {
+        let mut budget = match current.get() {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+
+        let result = match budget.remaining.checked_sub(haystack.len() as u64) {
+            Some(remaining) => {
+                budget.remaining = remaining;
+                Ok(())
+            }
+            None => {
+                budget.exceeded = true;
+                Err(anyhow::anyhow!("glob match budget exceeded"))
+            }
+        };
+        current.set(Some(budget));
+        result
+    })
+}
+
+/// The maximum number of compiled globs kept in `GLOB_MATCHER_CACHE`.
+const GLOB_MATCHER_CACHE_SIZE: usize = 10_000;
+
+/// The number of elements at the start of an array which can be addressed by
+/// index in flattened keys, see `get_flattened_value`.
+pub const MAX_INDEXED_ARRAY_ELEMENTS: usize = 100;
+
+/// Looks up a simple value in a flattened event.
+///
+/// Events are flattened into a single layer of keys (see `_flatten_dict` in
+/// Python), e.g. `{"content": {"body": "hi"}}` becomes `{"content.body": "hi"}`.
+/// Periods and backslashes in the original keys are escaped with a backslash.
+/// Only simple values (strings, integers, booleans and null) and arrays of
+/// simple values are kept.
+///
+/// Elements of arrays can be addressed with a trailing index segment, e.g.
+/// `content.m.mentions.user_ids.0` is the first user ID mentioned. Only the
+/// first `MAX_INDEXED_ARRAY_ELEMENTS` elements can be addressed, so that the
+/// elements of huge arrays aren't indexed. Indexes are looked up on demand
+/// rather than added to the flattened event, and an actual key which looks
+/// like an index takes precedence.
+pub fn get_flattened_value<'a>(
+    flattened: &'a BTreeMap<FlattenedKey, JsonValue>,
+    key: &str,
+) -> Option<&'a SimpleJsonValue> {
+    match flattened.get(key) {
+        Some(JsonValue::Value(value)) => return Some(value),
+        Some(JsonValue::Array(_)) => return None,
+        None => {}
+    }
+
+    let (array_key, index) = key.rsplit_once('.')?;
+
+    // The period is part of the last segment if it is escaped, i.e. follows an
+    // odd number of backslashes.
+    let backslashes = array_key.len() - array_key.trim_end_matches('\\').len();
+    if backslashes % 2 == 1 {
+        return None;
+    }
+
+    // Only accept canonical indexes, e.g. not `01` or `+1`.
+    let is_canonical =
+        index.bytes().all(|b| b.is_ascii_digit()) && (index == "0" || !index.starts_with('0'));
+    let index: usize = match index.parse() {
+        Ok(index) if is_canonical && index < MAX_INDEXED_ARRAY_ELEMENTS => index,
+        _ => return None,
+    };
+
+    match flattened.get(array_key) {
+        Some(JsonValue::Array(array)) => array.get(index),
+        _ => None,
+    }
+}
+
+/// Inserts a value into a flattened event, returning the value it replaced (if
+/// any).
+///
+/// If a key is inserted more than once the last value wins. Keys are compared
+/// exactly, so e.g. keys which only differ in case are distinct (as they are
+/// in events). Inserting the same key with differing values means the event
+/// was flattened incorrectly, so debug builds warn about it.
+pub fn insert_flattened_value(
+    flattened: &mut BTreeMap<FlattenedKey, JsonValue>,
+    key: FlattenedKey,
+    value: JsonValue,
+) -> Option<JsonValue> {
+    match flattened.entry(key) {
+        Entry::Occupied(mut entry) => {
+            if cfg!(debug_assertions) && *entry.get() != value {
+                warn!(
+                    "Flattened key {} inserted again with a differing value, keeping the last",
+                    entry.key()
+                );
+            }
+            Some(entry.insert(value))
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(value);
+            None
+        }
+    }
+}
+
+/// Deserializes a flattened event, with the same policy for duplicate keys as
+/// `insert_flattened_value`.
+pub fn deserialize_flattened<'de, D>(
+    deserializer: D,
+) -> Result<BTreeMap<FlattenedKey, JsonValue>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct FlattenedVisitor;
+
+    impl<'de> Visitor<'de> for FlattenedVisitor {
+        type Value = BTreeMap<FlattenedKey, JsonValue>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a flattened event")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let mut flattened = BTreeMap::new();
+            while let Some((key, value)) = map.next_entry()? {
+                insert_flattened_value(&mut flattened, key, value);
+            }
+            Ok(flattened)
+        }
+    }
+
+    deserializer.deserialize_map(FlattenedVisitor)
+}
+
+/// The maximum number of flattened keys which are checked against a key with
+/// a wildcard segment, see `get_wildcard_flattened_values`.
+pub const MAX_WILDCARD_KEY_CANDIDATES: usize = 1000;
+
+/// Splits a flattened key into its segments, i.e. at periods which aren't
+/// escaped. The segments are left escaped.
+fn key_segments(key: &str) -> Vec<&str> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in key.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '.' => {
+                segments.push(&key[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&key[start..]);
+    segments
+}
+
+/// Whether a flattened key has a wildcard segment, i.e. a segment which is
+/// just `*`, e.g. `content.foo.*.bar`.
+pub fn is_wildcard_key(key: &str) -> bool {
+    key.contains('*') && key_segments(key).contains(&"*")
+}
+
+/// Looks up the simple values of every flattened key which matches a key with
+/// wildcard segments (see `is_wildcard_key`). Each wildcard segment matches
+/// any single segment, e.g. `content.foo.*.bar` matches `content.foo.a.bar`
+/// and `content.foo.b.bar` but not `content.foo.a.b.bar`.
+///
+/// Only keys starting with the segments before the first wildcard are
+/// checked, and at most `MAX_WILDCARD_KEY_CANDIDATES` of those, so that huge
+/// events can't make matching expensive. Array elements can't be addressed.
+pub fn get_wildcard_flattened_values<'a>(
+    flattened: &'a BTreeMap<FlattenedKey, JsonValue>,
+    key: &str,
+) -> Vec<&'a SimpleJsonValue> {
+    get_wildcard_flattened_entries(flattened, key)
+        .into_iter()
+        .filter_map(|value| match value {
+            JsonValue::Value(value) => Some(value),
+            JsonValue::Array(_) => None,
+        })
+        .collect()
+}
+
+/// As `get_wildcard_flattened_values`, but includes arrays.
+fn get_wildcard_flattened_entries<'a>(
+    flattened: &'a BTreeMap<FlattenedKey, JsonValue>,
+    key: &str,
+) -> Vec<&'a JsonValue> {
+    let segments = key_segments(key);
+    let literal_segments = segments
+        .iter()
+        .position(|segment| *segment == "*")
+        .unwrap_or(segments.len());
+
+    let mut prefix = segments[..literal_segments].join(".");
+    if !prefix.is_empty() {
+        prefix.push('.');
+    }
+
+    flattened
+        .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+        .take_while(|(candidate, _)| candidate.starts_with(&prefix))
+        .take(MAX_WILDCARD_KEY_CANDIDATES)
+        .filter_map(|(candidate, value)| {
+            let candidate_segments = key_segments(candidate);
+            let matches = candidate_segments.len() == segments.len()
+                && segments
+                    .iter()
+                    .zip(&candidate_segments)
+                    .all(|(segment, candidate)| *segment == "*" || segment == candidate);
+            matches.then(|| value)
+        })
+        .collect()
+}
+
+/// Which array elements an `event_selector` path selects, see `SelectorPath`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectorElements {
+    /// The value of the key itself, rather than of array elements.
+    None,
+    /// Every element of the array (`[*]`).
+    All,
+    /// The element of the array at the index (`[N]`).
+    Index(usize),
+}
+
+/// A parsed `event_selector` path, see `SelectorPath::parse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorPath<'a> {
+    /// The flattened key, which may have wildcard segments.
+    pub key: &'a str,
+    pub elements: SelectorElements,
+}
+
+/// Why an `event_selector` path is malformed, see `SelectorPath::parse`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidSelectorError {
+    #[error("selector must start with '$.': {0}")]
+    MissingRoot(String),
+    #[error("selector has an empty key segment: {0}")]
+    EmptySegment(String),
+    #[error("selector has unsupported syntax: {0}")]
+    Unsupported(String),
+    #[error("selector array index must be less than {MAX_INDEXED_ARRAY_ELEMENTS}: {0}")]
+    BadIndex(String),
+}
+
+impl<'a> SelectorPath<'a> {
+    /// Parses a path, which is a small subset of JSONPath over flattened events
+    /// (see `get_flattened_value`):
+    ///
+    /// - `$.` followed by a flattened key, e.g. `$.content.m\.mentions.room`
+    ///   (with periods in keys escaped as usual). Segments which are just `*`
+    ///   match any single segment, see `get_wildcard_flattened_values`.
+    /// - optionally followed by `[*]` to select every element of the array at
+    ///   the key, or `[N]` to select the element at index N (which must be less
+    ///   than `MAX_INDEXED_ARRAY_ELEMENTS`).
+    ///
+    /// Nothing else is supported: no recursive descent (`..`), filters, slices,
+    /// unions or bracketed keys. As events are flattened, only simple values
+    /// and arrays of simple values can be selected, not objects in arrays.
+    pub fn parse(path: &'a str) -> Result<Self, InvalidSelectorError> {
+        let error = |make: fn(String) -> InvalidSelectorError| make(path.to_string());
+
+        let key = path
+            .strip_prefix("$.")
+            .ok_or_else(|| error(InvalidSelectorError::MissingRoot))?;
+
+        let (key, elements) = match key.strip_suffix(']') {
+            None => (key, SelectorElements::None),
+            Some(key) => {
+                let (key, index) = key
+                    .rsplit_once('[')
+                    .ok_or_else(|| error(InvalidSelectorError::Unsupported))?;
+                let elements = if index == "*" {
+                    SelectorElements::All
+                } else if !index.is_empty()
+                    && index.bytes().all(|b| b.is_ascii_digit())
+                    && (index == "0" || !index.starts_with('0'))
+                {
+                    match index.parse() {
+                        Ok(index) if index < MAX_INDEXED_ARRAY_ELEMENTS => {
+                            SelectorElements::Index(index)
+                        }
+                        _ => return Err(error(InvalidSelectorError::BadIndex)),
+                    }
+                } else {
+                    return Err(error(InvalidSelectorError::Unsupported));
+                };
+                (key, elements)
+            }
+        };
+
+        if key.contains(['[', ']']) {
+            return Err(error(InvalidSelectorError::Unsupported));
+        }
+        if key_segments(key).contains(&"") {
+            return Err(error(InvalidSelectorError::EmptySegment));
+        }
+
+        Ok(SelectorPath { key, elements })
+    }
+
+    /// The values of the flattened event selected by the path.
+    pub fn select<'b>(
+        &self,
+        flattened: &'b BTreeMap<FlattenedKey, JsonValue>,
+    ) -> Vec<&'b SimpleJsonValue> {
+        let entries = if is_wildcard_key(self.key) {
+            get_wildcard_flattened_entries(flattened, self.key)
+        } else if self.elements == SelectorElements::None {
+            return get_flattened_value(flattened, self.key)
+                .into_iter()
+                .collect();
+        } else {
+            flattened.get(self.key).into_iter().collect()
+        };
+
+        entries
+            .into_iter()
+            .flat_map(|entry| match (entry, self.elements) {
+                (JsonValue::Value(value), SelectorElements::None) => vec![value],
+                (JsonValue::Array(array), SelectorElements::All) => array.iter().collect(),
+                (JsonValue::Array(array), SelectorElements::Index(index)) => {
+                    array.get(index).into_iter().collect()
+                }
+                _ => Vec::new(),
+            })
+            .collect()
+    }
+}
+
+/// The Levenshtein distance between the two strings, i.e. the minimum number of
+/// characters which must be inserted, deleted or substituted to turn one into
+/// the other.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+
+    // The distances between the prefix of `a` processed so far and each
+    // prefix of `b`.
+    let mut distances: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut previous_diagonal = distances[0];
+        distances[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous_diagonal + usize::from(a_char != *b_char);
+            previous_diagonal = distances[j + 1];
+            distances[j + 1] = substitution
+                .min(distances[j] + 1)
+                .min(previous_diagonal + 1);
+        }
+    }
+    distances[b.len()]
+}
+
+/// The sigils which start the different types of Matrix style IDs, e.g. `@`
+/// for user IDs.
+const ID_SIGILS: &[char] = &['@', '!', '#', '$', '+'];
+
+/// Why a Matrix style ID is malformed.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidIdError {
+    #[error("ID does not contain colon: {0}")]
+    MissingColon(String),
+    #[error("ID does not start with a sigil: {0}")]
+    MissingSigil(String),
+    #[error("ID has an empty server name: {0}")]
+    EmptyServerName(String),
+}
+
+/// Splits a Matrix style ID into its localpart and server name, i.e. the parts
+/// before and after the first colon (the server name may contain a port).
+fn split_id(id: &str) -> Result<(&str, &str), InvalidIdError> {
+    let (localpart, server_name) = id
         .split_once(':')
-        .with_context(|| format!("ID does not contain colon: {id}"))?;
+        .ok_or_else(|| InvalidIdError::MissingColon(id.to_string()))?;
 
     // We need to strip off the first character, which is the ID type.
-    if localpart.is_empty() {
-        bail!("Invalid ID {id}");
+    match localpart.strip_prefix(ID_SIGILS) {
+        Some(localpart) => Ok((localpart, server_name)),
+        None => Err(InvalidIdError::MissingSigil(id.to_string())),
     }
+}
+
+/// Extract the localpart from a Matrix style ID
+pub(crate) fn get_localpart_from_id(id: &str) -> Result<&str, InvalidIdError> {
+    split_id(id).map(|(localpart, _)| localpart)
+}
 
-    Ok(&localpart[1..])
+/// Extract the server name from a Matrix style ID
+pub(crate) fn get_server_name_from_id(id: &str) -> Result<&str, InvalidIdError> {
+    match split_id(id)? {
+        (_, "") => Err(InvalidIdError::EmptyServerName(id.to_string())),
+        (_, server_name) => Ok(server_name),
+    }
 }
 
 /// Used by `glob_to_regex` to specify what to match the regex against.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum GlobMatchType {
     /// The generated regex will match against the entire input.
     Whole,
     /// The generated regex will match against words.
     Word,
+    /// The generated regex will match against any part of the input, i.e. as
+    /// if the glob started and ended with `*` (though not across lines).
+    Contains,
 }
 
 /// Convert a "glob" style expression to a regex, anchoring either to the entire
 /// input or to individual words.
 pub fn glob_to_regex(glob: &str, match_type: GlobMatchType) -> Result<Regex, Error> {
-    let mut chunks = Vec::new();
+    let regex_str = glob_to_regex_source(glob, match_type)?;
 
-    // Patterns with wildcards must be simplified to avoid performance cliffs
-    // - The glob `?**?**?` is equivalent to the glob `???*`
-    // - The glob `???*` is equivalent to the regex `.{3,}`
-    for captures in WILDCARD_RUN.captures_iter(glob) {
-        if let Some(chunk) = captures.get(1) {
-            chunks.push(regex::escape(chunk.as_str()));
-        }
+    super::metrics::record_glob_compilation();
 
-        if let Some(wildcards) = captures.get(2) {
-            if wildcards.as_str() == "" {
+    // The regex must be Unicode aware (the default) so that `?` matches a
+    // character rather than a byte.
+    Ok(RegexBuilder::new(&regex_str)
+        .case_insensitive(true)
+        .unicode(true)
+        .build()?)
+}
+
+/// Convert a "glob" style expression to the source of a regex, see
+/// `glob_to_regex`. This is useful for code which needs the same semantics as
+/// push rule globs, but builds its own regexes.
+///
+/// `*` and `?` match any number of characters (including none) and exactly one
+/// character respectively, and `[...]` matches one character of a class,
+/// unless escaped (see `glob_tokens`). Characters are Unicode code points, so
+/// e.g. `h?llo` matches `héllo` if the `é` is a single code point, and
+/// wildcards (and negated classes) don't match newlines. Everything else
+/// (including regex metacharacters) matches literally. Note that the regex
+/// must be compiled case-insensitively to match in the same way as push rules.
+///
+/// Fails if the glob has a malformed character class.
+pub fn glob_to_regex_source(
+    glob: &str,
+    match_type: GlobMatchType,
+) -> Result<String, InvalidGlobError> {
+    let tokens = glob_tokens(glob)?;
+
+    let mut joined = String::new();
+    let mut literal = String::new();
+    let mut tokens_iter = tokens.iter().peekable();
+    while let Some(token) = tokens_iter.next() {
+        let mut question_marks = 0;
+        let mut star = false;
+        match token {
+            GlobToken::Char(c) => {
+                literal.push(*c);
                 continue;
             }
+            GlobToken::Class(class) => {
+                joined.push_str(&regex::escape(&literal));
+                literal.clear();
+                joined.push_str(&class.regex_source());
+                continue;
+            }
+            GlobToken::Any => question_marks += 1,
+            GlobToken::Star => star = true,
+        }
 
-            let question_marks = wildcards.as_str().chars().filter(|c| *c == '?').count();
-
-            if wildcards.as_str().contains('*') {
-                chunks.push(format!(".{{{question_marks},}}"));
-            } else {
-                chunks.push(format!(".{{{question_marks}}}"));
+        // Patterns with wildcards must be simplified to avoid performance cliffs
+        // - The glob `?**?**?` is equivalent to the glob `???*`
+        // - The glob `???*` is equivalent to the regex `.{3,}`
+        while let Some(token) =
+            tokens_iter.next_if(|token| matches!(token, GlobToken::Any | GlobToken::Star))
+        {
+            match token {
+                GlobToken::Any => question_marks += 1,
+                _ => star = true,
             }
         }
+
+        joined.push_str(&regex::escape(&literal));
+        literal.clear();
+        if star {
+            joined.push_str(&format!(".{{{question_marks},}}"));
+        } else {
+            joined.push_str(&format!(".{{{question_marks}}}"));
+        }
     }
+    joined.push_str(&regex::escape(&literal));
 
-    let joined = chunks.join("");
+    let is_unspaced_token = |token: Option<&GlobToken>| matches!(token, Some(GlobToken::Char(c)) if is_unspaced_script(*c));
 
-    let regex_str = match match_type {
+    let source = match match_type {
         GlobMatchType::Whole => format!(r"\A{joined}\z"),
 
+        GlobMatchType::Contains => joined,
+
         // `^|\W` and `\W|$` handle the case where `pattern` starts or ends with a non-word
-        // character.
-        GlobMatchType::Word => format!(r"(?:^|\b|\W){joined}(?:\b|\W|$)"),
+        // character. `\b` and `\W` are Unicode aware, and as scripts like Chinese
+        // don't separate words with spaces, their characters are treated as
+        // boundaries too, as are the marks which follow emoji. Emoji themselves
+        // and bidirectional marks (e.g. in Hebrew or Arabic text) are already
+        // non-word characters.
+        GlobMatchType::Word => {
+            let start = if is_unspaced_token(tokens.first()) {
+                String::new()
+            } else {
+                format!(r"(?:^|\b|\W|{EMOJI_MARKS}|{UNSPACED_SCRIPTS})")
+            };
+            let end = if is_unspaced_token(tokens.last()) {
+                String::new()
+            } else {
+                format!(r"(?:\b|\W|{EMOJI_MARKS}|{UNSPACED_SCRIPTS}|$)")
+            };
+            format!("{start}{joined}{end}")
+        }
     };
 
-    Ok(RegexBuilder::new(&regex_str)
-        .case_insensitive(true)
-        .build()?)
+    Ok(source)
+}
+
+/// Splits a glob into its tokens.
+///
+/// `*` and `?` are wildcards unless escaped with a backslash: `\*` and `\?`
+/// match a literal `*` and `?`, and `\\` matches a literal backslash. A
+/// backslash before any other character (or at the end of the glob) is a
+/// literal backslash, so that globs which already contained backslashes, e.g.
+/// `C:\Users`, still match the same. Only globs containing `\*`, `\?` or `\\`
+/// changed meaning when escaping was introduced.
+///
+/// `[` starts a character class, see `glob_class`, and `\[` matches a literal
+/// `[`. A `]` outside of a class is literal. Globs containing `[` changed
+/// meaning when classes were introduced, see `docs/upgrade.md`. Text which
+/// should always match literally, e.g. display names, is escaped with
+/// `escape_glob`.
+fn glob_tokens(glob: &str) -> Result<Vec<GlobToken>, InvalidGlobError> {
+    let mut tokens = Vec::new();
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '*' => GlobToken::Star,
+            '?' => GlobToken::Any,
+            '[' => GlobToken::Class(glob_class(glob, &mut chars)?),
+            '\\' => GlobToken::Char(
+                chars
+                    .next_if(|c| matches!(c, '*' | '?' | '[' | '\\'))
+                    .unwrap_or(c),
+            ),
+            c => GlobToken::Char(c),
+        });
+    }
+    Ok(tokens)
+}
+
+/// Parses the rest of a character class, after its opening `[`.
+///
+/// As in shell globs, a class matches one of its members, e.g. `[abc]`, where
+/// members may be ranges of characters, e.g. `[a-z]`. Classes starting with `!`
+/// are negated, e.g. `[!abc]` matches any character other than `a`, `b` or `c`.
+/// A `]` at the start of the class (after any `!`) is a member rather than the
+/// end of the class, as is a `-` at the start or end. There is no escaping
+/// within classes, so backslashes are members too.
+fn glob_class(
+    glob: &str,
+    chars: &mut std::iter::Peekable<std::str::Chars<'_>>,
+) -> Result<GlobClass, InvalidGlobError> {
+    let unclosed = || InvalidGlobError::UnclosedClass(glob.to_string());
+
+    let negated = chars.next_if_eq(&'!').is_some();
+    let mut ranges = Vec::new();
+    let mut first = true;
+    loop {
+        let start = chars.next().ok_or_else(unclosed)?;
+        if start == ']' && !first {
+            return Ok(GlobClass { negated, ranges });
+        }
+        first = false;
+
+        // A `-` is a range unless it's the last member of the class.
+        let mut lookahead = chars.clone();
+        let end = match (lookahead.next(), lookahead.next()) {
+            (Some('-'), Some(end)) if end != ']' => {
+                chars.nth(1);
+                end
+            }
+            _ => start,
+        };
+        if end < start {
+            return Err(InvalidGlobError::ReversedRange { start, end });
+        }
+        ranges.push((start, end));
+    }
+}
+
+/// The text matched by the glob, if it doesn't have any wildcards (or
+/// character classes). Malformed globs don't have any text.
+fn glob_literal(glob: &str) -> Option<String> {
+    glob_tokens(glob)
+        .ok()?
+        .into_iter()
+        .map(|token| match token {
+            GlobToken::Char(c) => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Escapes the text so that it matches literally when used as a glob, see
+/// `glob_tokens`.
+pub fn escape_glob(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The default maximum length of a glob, in characters, see
+/// `GlobComplexityLimits`.
+const DEFAULT_MAX_GLOB_LENGTH: usize = 2048;
+
+/// The default maximum number of wildcards in a glob, see
+/// `GlobComplexityLimits`.
+const DEFAULT_MAX_GLOB_WILDCARDS: usize = 100;
+
+static MAX_GLOB_LENGTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_GLOB_LENGTH);
+static MAX_GLOB_WILDCARDS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_GLOB_WILDCARDS);
+
+/// Limits on the complexity of the globs `get_glob_matcher` will compile.
+///
+/// Globs come from users' push rules, so these stop a user from making the
+/// server compile (and then evaluate every event against) a pathologically
+/// large pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GlobComplexityLimits {
+    /// The maximum length of a glob, in characters.
+    pub max_length: usize,
+    /// The maximum number of wildcards (`*` and `?`) in a glob.
+    pub max_wildcards: usize,
+}
+
+impl Default for GlobComplexityLimits {
+    fn default() -> Self {
+        GlobComplexityLimits {
+            max_length: DEFAULT_MAX_GLOB_LENGTH,
+            max_wildcards: DEFAULT_MAX_GLOB_WILDCARDS,
+        }
+    }
+}
+
+impl GlobComplexityLimits {
+    /// The limits currently applied by `get_glob_matcher`.
+    pub fn current() -> Self {
+        GlobComplexityLimits {
+            max_length: MAX_GLOB_LENGTH.load(Ordering::Relaxed),
+            max_wildcards: MAX_GLOB_WILDCARDS.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Sets the limits applied by `get_glob_matcher`. Globs which have already
+    /// been compiled and cached are unaffected.
+    pub fn set(self) {
+        MAX_GLOB_LENGTH.store(self.max_length, Ordering::Relaxed);
+        MAX_GLOB_WILDCARDS.store(self.max_wildcards, Ordering::Relaxed);
+    }
+
+    /// Checks that the glob is within the limits.
+    pub fn check(&self, glob: &str) -> Result<(), GlobTooComplexError> {
+        let length = glob.chars().count();
+        if length > self.max_length {
+            return Err(GlobTooComplexError::TooLong {
+                length,
+                max_length: self.max_length,
+            });
+        }
+
+        let wildcards = glob.chars().filter(|c| matches!(c, '*' | '?')).count();
+        if wildcards > self.max_wildcards {
+            return Err(GlobTooComplexError::TooManyWildcards {
+                wildcards,
+                max_wildcards: self.max_wildcards,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Why a glob is malformed, see `glob_tokens`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidGlobError {
+    #[error("glob has a character class without a closing ']': {0}")]
+    UnclosedClass(String),
+    #[error("glob has a character class with a reversed range: {start}-{end}")]
+    ReversedRange { start: char, end: char },
+}
+
+/// Why a glob was rejected by `GlobComplexityLimits`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum GlobTooComplexError {
+    #[error("glob is {length} characters long, the maximum is {max_length}")]
+    TooLong { length: usize, max_length: usize },
+    #[error("glob contains {wildcards} wildcards, the maximum is {max_wildcards}")]
+    TooManyWildcards {
+        wildcards: usize,
+        max_wildcards: usize,
+    },
+}
+
+/// Normalizes the text to NFC if the `unicode-normalization` feature is
+/// enabled, so that e.g. a composed "é" matches an "e" followed by a combining
+/// accent. Both globs and the text they're matched against are normalized.
+#[cfg(feature = "unicode-normalization")]
+pub fn normalize_unicode(text: &str) -> Cow<'_, str> {
+    use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+    match is_nfc_quick(text.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(text),
+        _ => Cow::Owned(text.nfc().collect()),
+    }
+}
+
+/// Normalizes the text to NFC if the `unicode-normalization` feature is
+/// enabled, which it isn't.
+#[cfg(not(feature = "unicode-normalization"))]
+pub fn normalize_unicode(text: &str) -> Cow<'_, str> {
+    Cow::Borrowed(text)
+}
+
+/// Case-folds the text the way `Matcher`s do before matching: normalized (see
+/// `normalize_unicode`) and lowercased. Text which is matched repeatedly can be
+/// folded once and matched with `Matcher::is_match_folded`.
+pub fn fold_case(text: &str) -> String {
+    normalize_unicode(text).to_lowercase()
 }
 
 /// Compiles the glob into a `Matcher`.
+///
+/// Fails with a `GlobTooComplexError` if the glob exceeds the current
+/// `GlobComplexityLimits`.
 pub fn get_glob_matcher(glob: &str, match_type: GlobMatchType) -> Result<Matcher, Error> {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::trace_span!("push_rule_evaluator.get_glob_matcher", ?match_type).entered();
+
+    GlobComplexityLimits::current().check(glob)?;
+
+    let glob = &*normalize_unicode(glob);
+
     // There are a number of shortcuts we can make if the glob doesn't contain a
     // wild card.
-    let matcher = if glob.contains(['*', '?']) {
-        let regex = glob_to_regex(glob, match_type)?;
-        Matcher::Regex(regex)
-    } else if match_type == GlobMatchType::Whole {
+    let matcher = match glob_literal(glob) {
+        None => {
+            let regex = glob_to_regex(glob, match_type)?;
+            Matcher::Regex(regex)
+        }
         // If there aren't any wildcards and we're matching the whole thing,
         // then we simply can do a case-insensitive string match.
-        Matcher::Whole(glob.to_lowercase())
-    } else {
+        Some(literal) if match_type == GlobMatchType::Whole => {
+            Matcher::Whole(literal.to_lowercase().into())
+        }
+        // Similarly, without wildcards we can check for a case-insensitive
+        // substring.
+        Some(literal) if match_type == GlobMatchType::Contains => {
+            Matcher::Contains(literal.to_lowercase().into())
+        }
         // Otherwise, if we're matching against words then can first check
         // if the haystack contains the glob at all.
-        Matcher::Word {
-            word: glob.to_lowercase(),
-            regex: None,
-        }
+        Some(literal) => Matcher::Word {
+            word: literal.to_lowercase().into(),
+            regex: Arc::new(OnceCell::new()),
+        },
     };
 
     Ok(matcher)
 }
 
 /// Matches against a glob
+///
+/// Matchers are `Send + Sync`, and cloning one is cheap as clones share the
+/// compiled glob (including any part compiled lazily), so they can be stored
+/// in caches shared between threads.
+#[derive(Clone)]
 pub enum Matcher {
     /// Plain regex matching.
     Regex(Regex),
 
     /// Case-insensitive equality.
-    Whole(String),
+    Whole(Arc<str>),
 
-    /// Word matching. `regex` is a cache of calling [`glob_to_regex`] on word.
-    Word { word: String, regex: Option<Regex> },
+    /// Case-insensitive substring matching.
+    Contains(Arc<str>),
+
+    /// Word matching. `regex` is a cache of calling [`glob_to_regex`] on the
+    /// (escaped) word.
+    Word {
+        word: Arc<str>,
+        regex: Arc<OnceCell<Regex>>,
+    },
 }
 
 impl Matcher {
     /// Checks if the glob matches the given haystack.
-    pub fn is_match(&mut self, haystack: &str) -> Result<bool, Error> {
+    pub fn is_match(&self, haystack: &str) -> Result<bool, Error> {
         // We want to to do case-insensitive matching, so we convert to
         // lowercase first.
-        let haystack = haystack.to_lowercase();
+        self.is_match_folded(&fold_case(haystack))
+    }
+
+    /// Checks if the glob matches the given haystack, which has already been
+    /// case-folded with `fold_case`.
+    pub fn is_match_folded(&self, haystack: &str) -> Result<bool, Error> {
+        charge_match_budget(haystack)?;
 
         match self {
-            Matcher::Regex(regex) => Ok(regex.is_match(&haystack)),
-            Matcher::Whole(whole) => Ok(whole == &haystack),
-            Matcher::Word { word, regex } => {
+            Matcher::Regex(regex) => Ok(regex.is_match(haystack)),
+            Matcher::Whole(whole) => Ok(**whole == *haystack),
+            Matcher::Contains(needle) => Ok(haystack.contains(&**needle)),
+            Matcher::Word { word, .. } => {
                 // If we're looking for a literal word, then we first check if
                 // the haystack contains the word as a substring.
-                if !haystack.contains(&*word) {
+                if !haystack.contains(&**word) {
                     return Ok(false);
                 }
 
                 // If it does contain the word as a substring, then we need to
                 // check if it is an actual word by testing it against the regex.
-                let regex = if let Some(regex) = regex {
-                    regex
-                } else {
-                    let compiled_regex = glob_to_regex(word, GlobMatchType::Word)?;
-                    regex.insert(compiled_regex)
-                };
+                Ok(self
+                    .word_regex()?
+                    .map_or(false, |regex| regex.is_match(haystack)))
+            }
+        }
+    }
+
+    /// Compiles any part of the matcher which would otherwise be compiled
+    /// lazily by `is_match`, so that the first match isn't any slower.
+    pub fn compile_fully(&self) -> Result<(), Error> {
+        self.word_regex()?;
+        Ok(())
+    }
 
-                Ok(regex.is_match(&haystack))
+    /// Returns the regex of a word matcher, compiling it if this is its first
+    /// use (by any clone of the matcher).
+    fn word_regex(&self) -> Result<Option<&Regex>, Error> {
+        match self {
+            Matcher::Word { word, regex } => {
+                Ok(Some(regex.get_or_try_init(|| {
+                    glob_to_regex(&escape_glob(word), GlobMatchType::Word)
+                })?))
             }
+            _ => Ok(None),
         }
     }
 }
 
-#[test]
-fn test_get_domain_from_id() {
-    get_localpart_from_id("").unwrap_err();
-    get_localpart_from_id(":").unwrap_err();
-    get_localpart_from_id(":asd").unwrap_err();
-    get_localpart_from_id("::as::asad").unwrap_err();
-
-    assert_eq!(get_localpart_from_id("@test:foo").unwrap(), "test");
-    assert_eq!(get_localpart_from_id("@:").unwrap(), "");
-    assert_eq!(get_localpart_from_id("@test:foo:907").unwrap(), "test");
+/// The source of a regex which matches wherever the word glob could match, i.e.
+/// the glob without the word boundaries, see `glob_to_regex_source`.
+fn word_candidate_regex_source(glob: &str) -> Result<String, Error> {
+    let glob = &*normalize_unicode(glob);
+    let source = match glob_literal(glob) {
+        Some(literal) => regex::escape(&literal.to_lowercase()),
+        None => glob_to_regex_source(glob, GlobMatchType::Contains)?,
+    };
+    Ok(source)
 }
 
-#[test]
-fn tset_glob() -> Result<(), Error> {
-    assert_eq!(
-        glob_to_regex("simple", GlobMatchType::Whole)?.as_str(),
-        r"\Asimple\z"
-    );
-    assert_eq!(
-        glob_to_regex("simple*", GlobMatchType::Whole)?.as_str(),
-        r"\Asimple.{0,}\z"
-    );
-    assert_eq!(
-        glob_to_regex("simple?", GlobMatchType::Whole)?.as_str(),
-        r"\Asimple.{1}\z"
-    );
-    assert_eq!(
-        glob_to_regex("simple?*?*", GlobMatchType::Whole)?.as_str(),
-        r"\Asimple.{2,}\z"
-    );
-    assert_eq!(
-        glob_to_regex("simple???", GlobMatchType::Whole)?.as_str(),
-        r"\Asimple.{3}\z"
-    );
+/// The minimum number of word globs which `PrecompiledGlobs` combines into a
+/// `CombinedWordGlobs`.
+const MIN_COMBINED_WORD_GLOBS: usize = 2;
 
-    assert_eq!(
-        glob_to_regex("escape.", GlobMatchType::Whole)?.as_str(),
-        r"\Aescape\.\z"
-    );
+/// Word globs (e.g. the keywords of a user's content rules) combined so that a
+/// haystack is scanned once for all of them, rather than once per glob.
+///
+/// The scan uses a single `RegexSet` of the globs without their word
+/// boundaries, which (unlike the word regexes) benefits from literal
+/// prefilters. Only the globs it finds are then checked with their own
+/// `Matcher`, so each glob matches exactly as it would on its own.
+#[derive(Clone)]
+pub struct CombinedWordGlobs {
+    candidates: RegexSet,
+    /// The globs and their matchers, in the order of the patterns of
+    /// `candidates`.
+    globs: Vec<(String, Matcher)>,
+    indices: HashMap<String, usize>,
+}
 
-    assert!(glob_to_regex("simple", GlobMatchType::Whole)?.is_match("simple"));
-    assert!(!glob_to_regex("simple", GlobMatchType::Whole)?.is_match("simples"));
-    assert!(glob_to_regex("simple*", GlobMatchType::Whole)?.is_match("simples"));
-    assert!(glob_to_regex("simple?", GlobMatchType::Whole)?.is_match("simples"));
-    assert!(glob_to_regex("simple*", GlobMatchType::Whole)?.is_match("simple"));
+impl CombinedWordGlobs {
+    /// Combines the given word globs. As with `PrecompiledGlobs::compile`,
+    /// invalid globs are skipped.
+    pub fn compile<'a>(globs: impl IntoIterator<Item = &'a str>) -> Result<Self, Error> {
+        let matchers = globs.into_iter().filter_map(|glob| {
+            let matcher = get_glob_matcher(glob, GlobMatchType::Word).ok()?;
+            matcher.compile_fully().ok()?;
+            Some((glob, matcher))
+        });
+        CombinedWordGlobs::combine(matchers)
+    }
 
-    assert!(glob_to_regex("simple", GlobMatchType::Word)?.is_match("some simple."));
-    assert!(glob_to_regex("simple", GlobMatchType::Word)?.is_match("simple"));
-    assert!(!glob_to_regex("simple", GlobMatchType::Word)?.is_match("simples"));
+    /// Combines the given (fully compiled) word glob matchers.
+    fn combine<'a>(matchers: impl IntoIterator<Item = (&'a str, Matcher)>) -> Result<Self, Error> {
+        let mut globs = Vec::new();
+        let mut indices = HashMap::new();
+        let mut sources = Vec::new();
+        for (glob, matcher) in matchers {
+            if indices.contains_key(glob) {
+                continue;
+            }
+            if let Ok(source) = word_candidate_regex_source(glob) {
+                indices.insert(glob.to_string(), globs.len());
+                globs.push((glob.to_string(), matcher));
+                sources.push(source);
+            }
+        }
 
-    assert!(glob_to_regex("@user:foo", GlobMatchType::Word)?.is_match("Some @user:foo test"));
-    assert!(glob_to_regex("@user:foo", GlobMatchType::Word)?.is_match("@user:foo"));
+        super::metrics::record_glob_compilation();
+
+        let candidates = RegexSetBuilder::new(sources)
+            .case_insensitive(true)
+            .unicode(true)
+            .build()?;
+
+        Ok(CombinedWordGlobs {
+            candidates,
+            globs,
+            indices,
+        })
+    }
+
+    /// Matches all of the globs against the haystack, which has already been
+    /// case-folded with `fold_case`.
+    pub fn matches_folded(&self, haystack: &str) -> CombinedWordMatches<'_> {
+        let mut matched = vec![false; self.globs.len()];
+        for index in self.candidates.matches(haystack).iter() {
+            // The matchers are fully compiled, so can't fail.
+            matched[index] = self.globs[index]
+                .1
+                .is_match_folded(haystack)
+                .unwrap_or(false);
+        }
+
+        CombinedWordMatches {
+            globs: self,
+            matched,
+        }
+    }
+
+    /// The number of combined globs.
+    pub fn len(&self) -> usize {
+        self.globs.len()
+    }
+
+    /// Whether there are no combined globs.
+    pub fn is_empty(&self) -> bool {
+        self.globs.is_empty()
+    }
+}
+
+/// Which of a `CombinedWordGlobs` matched a haystack.
+pub struct CombinedWordMatches<'a> {
+    globs: &'a CombinedWordGlobs,
+    matched: Vec<bool>,
+}
+
+impl<'a> CombinedWordMatches<'a> {
+    /// Whether the glob matched, or None if it isn't one of the combined globs.
+    pub fn is_match(&self, glob: &str) -> Option<bool> {
+        let index = *self.globs.indices.get(glob)?;
+        Some(self.matched[index])
+    }
+
+    /// The globs which matched, in the order they were combined.
+    pub fn iter(&self) -> impl Iterator<Item = &'a str> + '_ {
+        self.globs
+            .globs
+            .iter()
+            .zip(&self.matched)
+            .filter(|(_, matched)| **matched)
+            .map(|((glob, _), _)| glob.as_str())
+    }
+}
+
+/// A set of globs compiled ahead of time, e.g. the patterns of a user's push
+/// rules, which can be shared between evaluators.
+///
+/// If there are enough word globs, they're also combined into a
+/// `CombinedWordGlobs`.
+#[derive(Clone, Default)]
+pub struct PrecompiledGlobs {
+    matchers: HashMap<GlobMatchType, HashMap<String, Matcher>>,
+    combined_words: Option<CombinedWordGlobs>,
+}
+
+impl PrecompiledGlobs {
+    /// Compiles the given globs. Invalid globs are skipped, as they will fail
+    /// when matched instead.
+    pub fn compile<'a>(globs: impl IntoIterator<Item = (&'a str, GlobMatchType)>) -> Self {
+        let mut matchers: HashMap<GlobMatchType, HashMap<String, Matcher>> = HashMap::new();
+
+        for (glob, match_type) in globs {
+            let by_glob = matchers.entry(match_type).or_default();
+            if by_glob.contains_key(glob) {
+                continue;
+            }
+
+            let matcher = get_glob_matcher(glob, match_type).and_then(|matcher| {
+                matcher.compile_fully()?;
+                Ok(matcher)
+            });
+            if let Ok(matcher) = matcher {
+                by_glob.insert(glob.to_string(), matcher);
+            }
+        }
+
+        let mut words: Vec<(&str, Matcher)> = matchers
+            .get(&GlobMatchType::Word)
+            .map(|by_glob| {
+                by_glob
+                    .iter()
+                    .map(|(glob, matcher)| (glob.as_str(), matcher.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        words.sort_unstable_by_key(|(glob, _)| *glob);
+        let combined_words = if words.len() >= MIN_COMBINED_WORD_GLOBS {
+            CombinedWordGlobs::combine(words).ok()
+        } else {
+            None
+        };
+
+        PrecompiledGlobs {
+            matchers,
+            combined_words,
+        }
+    }
+
+    /// The word globs combined into one `CombinedWordGlobs`, if there were
+    /// enough of them.
+    pub fn combined_words(&self) -> Option<&CombinedWordGlobs> {
+        self.combined_words.as_ref()
+    }
+
+    /// Returns the compiled glob, if it was precompiled.
+    pub fn get(&self, glob: &str, match_type: GlobMatchType) -> Option<&Matcher> {
+        self.matchers.get(&match_type)?.get(glob)
+    }
+
+    /// Checks if the glob matches the given haystack, using the precompiled
+    /// glob if there is one and `ThreadLocalGlobMatcherCache` otherwise.
+    pub fn is_match(
+        globs: Option<&PrecompiledGlobs>,
+        glob: &str,
+        match_type: GlobMatchType,
+        haystack: &str,
+    ) -> Result<bool, Error> {
+        PrecompiledGlobs::is_match_folded(globs, glob, match_type, &fold_case(haystack))
+    }
+
+    /// Checks if the glob matches the given haystack, which has already been
+    /// case-folded with `fold_case`, as per `is_match`.
+    pub fn is_match_folded(
+        globs: Option<&PrecompiledGlobs>,
+        glob: &str,
+        match_type: GlobMatchType,
+        haystack: &str,
+    ) -> Result<bool, Error> {
+        match globs.and_then(|globs| globs.get(glob, match_type)) {
+            Some(matcher) => matcher.is_match_folded(haystack),
+            None => ThreadLocalGlobMatcherCache::is_match_folded(glob, match_type, haystack),
+        }
+    }
+
+    /// The number of precompiled globs.
+    pub fn len(&self) -> usize {
+        self.matchers.values().map(HashMap::len).sum()
+    }
+
+    /// Whether there are no precompiled globs.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for PrecompiledGlobs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrecompiledGlobs")
+            .field("len", &self.len())
+            .field(
+                "combined_words",
+                &self
+                    .combined_words
+                    .as_ref()
+                    .map_or(0, CombinedWordGlobs::len),
+            )
+            .finish()
+    }
+}
+
+/// A bounded, thread safe LRU cache of compiled globs, so that the same
+/// patterns aren't recompiled for every event.
+pub struct GlobMatcherCache {
+    cache: Mutex<LruCache<(String, GlobMatchType), Matcher>>,
+}
+
+impl GlobMatcherCache {
+    /// Creates a cache holding at most `capacity` compiled globs.
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        GlobMatcherCache {
+            cache: Mutex::new(LruCache::new(capacity)),
+        }
+    }
+
+    /// Checks if the glob matches the given haystack, compiling the glob if it
+    /// isn't already cached.
+    pub fn is_match(
+        &self,
+        glob: &str,
+        match_type: GlobMatchType,
+        haystack: &str,
+    ) -> Result<bool, Error> {
+        self.is_match_folded(glob, match_type, &fold_case(haystack))
+    }
+
+    /// Checks if the glob matches the given haystack, which has already been
+    /// case-folded with `fold_case`, as per `is_match`.
+    pub fn is_match_folded(
+        &self,
+        glob: &str,
+        match_type: GlobMatchType,
+        haystack: &str,
+    ) -> Result<bool, Error> {
+        // The lock isn't held while matching, as the matcher is shared with
+        // the cache.
+        self.get(glob, match_type)?.is_match_folded(haystack)
+    }
+
+    /// Returns the compiled glob, compiling and caching it if it isn't already
+    /// cached.
+    pub fn get(&self, glob: &str, match_type: GlobMatchType) -> Result<Matcher, Error> {
+        let mut cache = self
+            .cache
+            .lock()
+            .map_err(|_| anyhow::anyhow!("glob matcher cache lock poisoned"))?;
+
+        let key = (glob.to_string(), match_type);
+        match cache.get(&key) {
+            Some(matcher) => Ok(matcher.clone()),
+            None => {
+                let matcher = get_glob_matcher(glob, match_type)?;
+                cache.put(key, matcher.clone());
+                Ok(matcher)
+            }
+        }
+    }
+
+    /// Compiles the glob fully (see `Matcher::compile_fully`) and adds it to
+    /// the cache, replacing any copy already cached.
+    pub fn insert(&self, glob: &str, match_type: GlobMatchType) -> Result<(), Error> {
+        let matcher = get_glob_matcher(glob, match_type)?;
+        matcher.compile_fully()?;
+
+        self.cache
+            .lock()
+            .map_err(|_| anyhow::anyhow!("glob matcher cache lock poisoned"))?
+            .put((glob.to_string(), match_type), matcher);
+        Ok(())
+    }
+
+    /// The number of compiled globs in the cache.
+    pub fn len(&self) -> usize {
+        self.cache.lock().map_or(0, |cache| cache.len())
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all compiled globs from the cache.
+    pub fn clear(&self) {
+        if let Ok(mut cache) = self.cache.lock() {
+            cache.clear();
+        }
+    }
+}
+
+/// The capacity of each thread's cache in `ThreadLocalGlobMatcherCache`.
+static THREAD_LOCAL_CACHE_CAPACITY: AtomicUsize = AtomicUsize::new(GLOB_MATCHER_CACHE_SIZE);
+
+/// Incremented to invalidate every thread's cache in
+/// `ThreadLocalGlobMatcherCache`, e.g. when it is cleared.
+static THREAD_LOCAL_CACHE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// A thread's cache in `ThreadLocalGlobMatcherCache`.
+struct ThreadGlobMatchers {
+    /// The `THREAD_LOCAL_CACHE_GENERATION` the cache is up to date with.
+    generation: u64,
+    cache: LruCache<(String, GlobMatchType), Matcher>,
+}
+
+thread_local! {
+    static THREAD_GLOB_MATCHERS: RefCell<ThreadGlobMatchers> = RefCell::new(ThreadGlobMatchers {
+        generation: THREAD_LOCAL_CACHE_GENERATION.load(Ordering::Acquire),
+        cache: LruCache::new(thread_local_cache_capacity()),
+    });
+}
+
+fn thread_local_cache_capacity() -> NonZeroUsize {
+    NonZeroUsize::new(THREAD_LOCAL_CACHE_CAPACITY.load(Ordering::Acquire))
+        .expect("non-zero cache size")
+}
+
+/// A bounded LRU cache of compiled globs per thread, in front of the shared
+/// `GLOB_MATCHER_CACHE`, so that evaluating push rules doesn't need a lock.
+///
+/// A thread only takes the shared cache's lock the first time it uses a glob,
+/// to fetch (or compile) it. Matchers are cheap to clone, so globs are still
+/// only compiled once, and warming the shared cache benefits every thread.
+pub struct ThreadLocalGlobMatcherCache;
+
+impl ThreadLocalGlobMatcherCache {
+    /// Checks if the glob matches the given haystack, fetching the glob from
+    /// the shared cache if it isn't already cached by this thread.
+    pub fn is_match(glob: &str, match_type: GlobMatchType, haystack: &str) -> Result<bool, Error> {
+        Self::is_match_folded(glob, match_type, &fold_case(haystack))
+    }
+
+    /// Checks if the glob matches the given haystack, which has already been
+    /// case-folded with `fold_case`, as per `is_match`.
+    pub fn is_match_folded(
+        glob: &str,
+        match_type: GlobMatchType,
+        haystack: &str,
+    ) -> Result<bool, Error> {
+        Self::get(glob, match_type)?.is_match_folded(haystack)
+    }
+
+    /// Returns the compiled glob, caching it for this thread if it isn't
+    /// already.
+    fn get(glob: &str, match_type: GlobMatchType) -> Result<Matcher, Error> {
+        let key = (glob.to_string(), match_type);
+        if let Some(matcher) = Self::with_cache(|cache| cache.get(&key).cloned()) {
+            return Ok(matcher);
+        }
+
+        // The thread's cache isn't borrowed while fetching from the shared
+        // cache, which may compile the glob.
+        let matcher = GLOB_MATCHER_CACHE.get(glob, match_type)?;
+        Self::with_cache(|cache| cache.put(key, matcher.clone()));
+        Ok(matcher)
+    }
+
+    /// Sets the number of compiled globs each thread caches. This clears the
+    /// cache of every thread.
+    pub fn set_capacity(capacity: NonZeroUsize) {
+        THREAD_LOCAL_CACHE_CAPACITY.store(capacity.get(), Ordering::Release);
+        Self::clear();
+    }
+
+    /// Removes all compiled globs from the cache of every thread. Other
+    /// threads' caches are cleared the next time they are used.
+    pub fn clear() {
+        THREAD_LOCAL_CACHE_GENERATION.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// The number of compiled globs in this thread's cache.
+    pub fn len() -> usize {
+        Self::with_cache(|cache| cache.len())
+    }
+
+    /// Whether this thread's cache is empty.
+    pub fn is_empty() -> bool {
+        Self::len() == 0
+    }
+
+    /// Calls `f` with this thread's cache, first bringing it up to date if it
+    /// was cleared or resized.
+    fn with_cache<T>(f: impl FnOnce(&mut LruCache<(String, GlobMatchType), Matcher>) -> T) -> T {
+        THREAD_GLOB_MATCHERS.with(|matchers| {
+            let mut matchers = matchers.borrow_mut();
+
+            let generation = THREAD_LOCAL_CACHE_GENERATION.load(Ordering::Acquire);
+            if matchers.generation != generation {
+                matchers.generation = generation;
+                matchers.cache.clear();
+                matchers.cache.resize(thread_local_cache_capacity());
+            }
+
+            f(&mut matchers.cache)
+        })
+    }
+}
+
+/// A compiled glob which can be matched against strings.
+pub trait GlobMatcher {
+    /// Checks if the glob matches the given haystack.
+    fn is_match(&self, haystack: &str) -> Result<bool, Error>;
+}
+
+impl GlobMatcher for Matcher {
+    fn is_match(&self, haystack: &str) -> Result<bool, Error> {
+        Matcher::is_match(self, haystack)
+    }
+}
+
+/// A way of compiling globs into [`GlobMatcher`]s, allowing alternative
+/// implementations to be compared against the default regex based one.
+pub trait GlobBackend {
+    type Matcher: GlobMatcher;
+
+    /// Compiles the glob into a matcher.
+    fn compile(glob: &str, match_type: GlobMatchType) -> Result<Self::Matcher, Error>;
+}
+
+/// The default backend, see [`get_glob_matcher`].
+pub struct RegexGlobBackend;
+
+impl GlobBackend for RegexGlobBackend {
+    type Matcher = Matcher;
+
+    fn compile(glob: &str, match_type: GlobMatchType) -> Result<Matcher, Error> {
+        get_glob_matcher(glob, match_type)
+    }
+}
+
+/// A backend which simulates an NFA for the glob directly, rather than going
+/// via a regex.
+pub struct NfaGlobBackend;
+
+impl GlobBackend for NfaGlobBackend {
+    type Matcher = NfaMatcher;
+
+    fn compile(glob: &str, match_type: GlobMatchType) -> Result<NfaMatcher, Error> {
+        GlobComplexityLimits::current().check(glob)?;
+
+        let tokens = glob_tokens(&normalize_unicode(glob).to_lowercase())?;
+
+        Ok(NfaMatcher { tokens, match_type })
+    }
+}
+
+/// A single element of a glob, see `glob_tokens`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum GlobToken {
+    /// Matches exactly the given character.
+    Char(char),
+    /// Matches any single character (`?`).
+    Any,
+    /// Matches any number of characters (`*`).
+    Star,
+    /// Matches a single character of a class (`[...]`).
+    Class(GlobClass),
+}
+
+/// A character class of a glob, see `glob_class`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GlobClass {
+    /// Whether the class matches characters which *aren't* members.
+    negated: bool,
+    /// The inclusive ranges of members, with single members being a range of
+    /// one character.
+    ranges: Vec<(char, char)>,
+}
+
+impl GlobClass {
+    /// Whether the class matches the character. As with wildcards, negated
+    /// classes don't match newlines.
+    fn is_match(&self, c: char) -> bool {
+        let is_member = self
+            .ranges
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&c));
+        if self.negated {
+            !is_member && c != '\n'
+        } else {
+            is_member
+        }
+    }
+
+    /// The equivalent regex character class.
+    fn regex_source(&self) -> String {
+        let mut source = String::from(if self.negated { r"[^\n" } else { "[" });
+        for (start, end) in &self.ranges {
+            source.push_str(&regex::escape(start.encode_utf8(&mut [0; 4])));
+            if start != end {
+                source.push('-');
+                source.push_str(&regex::escape(end.encode_utf8(&mut [0; 4])));
+            }
+        }
+        source.push(']');
+        source
+    }
+}
+
+/// Matches a glob by simulating an NFA whose states are positions in the
+/// glob. As with the regex backend, wildcards don't match newlines.
+pub struct NfaMatcher {
+    tokens: Vec<GlobToken>,
+    match_type: GlobMatchType,
+}
+
+impl NfaMatcher {
+    /// Adds the state, and any states reachable from it without consuming a
+    /// character (i.e. by skipping `*`s), to the set.
+    fn add_state(&self, states: &mut [bool], mut state: usize) {
+        while state < self.tokens.len() && !states[state] {
+            states[state] = true;
+            if self.tokens[state] != GlobToken::Star {
+                return;
+            }
+            state += 1;
+        }
+        if state == self.tokens.len() {
+            states[state] = true;
+        }
+    }
+
+    /// Returns the positions in `haystack` at which a match of the glob
+    /// starting at `start` can end.
+    fn match_ends(&self, haystack: &[char], start: usize) -> Vec<usize> {
+        let accept = self.tokens.len();
+        let mut states = vec![false; accept + 1];
+        self.add_state(&mut states, 0);
+
+        let mut ends = Vec::new();
+        for (offset, &c) in haystack[start..].iter().enumerate() {
+            if states[accept] {
+                ends.push(start + offset);
+            }
+
+            let mut next = vec![false; accept + 1];
+            for (state, token) in self.tokens.iter().enumerate() {
+                if !states[state] {
+                    continue;
+                }
+                match token {
+                    GlobToken::Char(expected) if *expected == c => {
+                        self.add_state(&mut next, state + 1)
+                    }
+                    GlobToken::Any if c != '\n' => self.add_state(&mut next, state + 1),
+                    GlobToken::Class(class) if class.is_match(c) => {
+                        self.add_state(&mut next, state + 1)
+                    }
+                    GlobToken::Star if c != '\n' => self.add_state(&mut next, state),
+                    _ => {}
+                }
+            }
+
+            if !next.contains(&true) {
+                return ends;
+            }
+            states = next;
+        }
+
+        if states[accept] {
+            ends.push(haystack.len());
+        }
+        ends
+    }
+}
+
+impl GlobMatcher for NfaMatcher {
+    fn is_match(&self, haystack: &str) -> Result<bool, Error> {
+        let haystack: Vec<char> = normalize_unicode(haystack).to_lowercase().chars().collect();
+
+        let matches = match self.match_type {
+            GlobMatchType::Whole => self.match_ends(&haystack, 0).contains(&haystack.len()),
+            GlobMatchType::Contains => {
+                (0..=haystack.len()).any(|start| !self.match_ends(&haystack, start).is_empty())
+            }
+            GlobMatchType::Word => {
+                // Mirrors the anchors used by `glob_to_regex`.
+                let is_word =
+                    |index: usize| haystack.get(index).map_or(false, |c| is_word_char(*c));
+                let is_unspaced = |index: usize| {
+                    haystack
+                        .get(index)
+                        .map_or(false, |c| is_unspaced_script(*c))
+                };
+                let is_boundary =
+                    |index: usize| (index > 0 && is_word(index - 1)) != is_word(index);
+                let is_unspaced_token = |token: Option<&GlobToken>| matches!(token, Some(GlobToken::Char(c)) if is_unspaced_script(*c));
+                let any_start = is_unspaced_token(self.tokens.first());
+                let any_end = is_unspaced_token(self.tokens.last());
+
+                (0..=haystack.len())
+                    .filter(|&start| {
+                        any_start
+                            || start == 0
+                            || is_boundary(start)
+                            || !is_word(start - 1)
+                            || is_unspaced(start - 1)
+                    })
+                    .any(|start| {
+                        self.match_ends(&haystack, start).into_iter().any(|end| {
+                            any_end
+                                || end == haystack.len()
+                                || is_boundary(end)
+                                || !is_word(end)
+                                || is_unspaced(end)
+                        })
+                    })
+            }
+        };
+
+        Ok(matches)
+    }
+}
+
+#[test]
+fn test_get_domain_from_id() {
+    get_localpart_from_id("").unwrap_err();
+    get_localpart_from_id(":").unwrap_err();
+    get_localpart_from_id(":asd").unwrap_err();
+    get_localpart_from_id("::as::asad").unwrap_err();
+
+    assert_eq!(
+        get_localpart_from_id("@alice"),
+        Err(InvalidIdError::MissingColon("@alice".to_string()))
+    );
+    assert_eq!(
+        get_localpart_from_id("alice:server"),
+        Err(InvalidIdError::MissingSigil("alice:server".to_string()))
+    );
+    assert_eq!(
+        get_localpart_from_id(""),
+        Err(InvalidIdError::MissingColon(String::new()))
+    );
+
+    assert_eq!(get_localpart_from_id("@test:foo").unwrap(), "test");
+    assert_eq!(get_localpart_from_id("@:").unwrap(), "");
+    assert_eq!(get_localpart_from_id("@test:foo:907").unwrap(), "test");
+}
+
+#[test]
+fn test_get_server_name_from_id() {
+    assert_eq!(get_server_name_from_id("@test:foo").unwrap(), "foo");
+    assert_eq!(
+        get_server_name_from_id("@test:foo:8448").unwrap(),
+        "foo:8448"
+    );
+    assert_eq!(get_server_name_from_id("!room:[::1]").unwrap(), "[::1]");
+
+    assert_eq!(
+        get_server_name_from_id("@alice"),
+        Err(InvalidIdError::MissingColon("@alice".to_string()))
+    );
+    assert_eq!(
+        get_server_name_from_id("alice:server"),
+        Err(InvalidIdError::MissingSigil("alice:server".to_string()))
+    );
+    assert_eq!(
+        get_server_name_from_id("@alice:"),
+        Err(InvalidIdError::EmptyServerName("@alice:".to_string()))
+    );
+}
+
+#[test]
+fn tset_glob() -> Result<(), Error> {
+    assert_eq!(
+        glob_to_regex("simple", GlobMatchType::Whole)?.as_str(),
+        r"\Asimple\z"
+    );
+    assert_eq!(
+        glob_to_regex("simple*", GlobMatchType::Whole)?.as_str(),
+        r"\Asimple.{0,}\z"
+    );
+    assert_eq!(
+        glob_to_regex("simple?", GlobMatchType::Whole)?.as_str(),
+        r"\Asimple.{1}\z"
+    );
+    assert_eq!(
+        glob_to_regex("simple?*?*", GlobMatchType::Whole)?.as_str(),
+        r"\Asimple.{2,}\z"
+    );
+    assert_eq!(
+        glob_to_regex("simple???", GlobMatchType::Whole)?.as_str(),
+        r"\Asimple.{3}\z"
+    );
+
+    assert_eq!(
+        glob_to_regex("escape.", GlobMatchType::Whole)?.as_str(),
+        r"\Aescape\.\z"
+    );
+
+    assert!(glob_to_regex("simple", GlobMatchType::Whole)?.is_match("simple"));
+    assert!(!glob_to_regex("simple", GlobMatchType::Whole)?.is_match("simples"));
+    assert!(glob_to_regex("simple*", GlobMatchType::Whole)?.is_match("simples"));
+    assert!(glob_to_regex("simple?", GlobMatchType::Whole)?.is_match("simples"));
+    assert!(glob_to_regex("simple*", GlobMatchType::Whole)?.is_match("simple"));
+
+    assert!(glob_to_regex("simple", GlobMatchType::Word)?.is_match("some simple."));
+    assert!(glob_to_regex("simple", GlobMatchType::Word)?.is_match("simple"));
+    assert!(!glob_to_regex("simple", GlobMatchType::Word)?.is_match("simples"));
+
+    assert!(glob_to_regex("@user:foo", GlobMatchType::Word)?.is_match("Some @user:foo test"));
+    assert!(glob_to_regex("@user:foo", GlobMatchType::Word)?.is_match("@user:foo"));
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_to_regex_source() {
+    for (glob, expected) in [
+        ("simple", r"\Asimple\z"),
+        // Regex metacharacters match literally.
+        ("a.b", r"\Aa\.b\z"),
+        ("a+b", r"\Aa\+b\z"),
+        ("(a|b)", r"\A\(a\|b\)\z"),
+        (r"\[a]{2}^$", r"\A\[a\]\{2\}\^\$\z"),
+        // Wildcards.
+        ("*", r"\A.{0,}\z"),
+        ("a?", r"\Aa.{1}\z"),
+        ("a*b?c", r"\Aa.{0,}b.{1}c\z"),
+        ("?**?", r"\A.{2,}\z"),
+        // Character classes.
+        ("[a]{2}", r"\A[a]\{2\}\z"),
+        ("a[b-d]*", r"\Aa[b-d].{0,}\z"),
+        ("[!^-]", r"\A[^\n\^\-]\z"),
+    ] {
+        assert_eq!(
+            glob_to_regex_source(glob, GlobMatchType::Whole).unwrap(),
+            expected,
+            "{glob}"
+        );
+    }
+
+    let word = glob_to_regex_source("a.b", GlobMatchType::Word).unwrap();
+    assert!(word.contains(r"a\.b"), "{word}");
+    assert!(!word.starts_with(r"\A"), "{word}");
+
+    // The source is the same as that of the compiled regex.
+    assert_eq!(
+        glob_to_regex("a+b*", GlobMatchType::Word).unwrap().as_str(),
+        glob_to_regex_source("a+b*", GlobMatchType::Word).unwrap()
+    );
+}
+
+#[test]
+fn test_glob_escaping() -> Result<(), Error> {
+    let is_match = |glob: &str, match_type, haystack: &str| -> Result<bool, Error> {
+        get_glob_matcher(glob, match_type)?.is_match(haystack)
+    };
+
+    assert!(is_match(r"\*", GlobMatchType::Whole, "*")?);
+    assert!(!is_match(r"\*", GlobMatchType::Whole, "x")?);
+    assert!(is_match(r"what\?", GlobMatchType::Word, "what? really")?);
+    assert!(!is_match(r"what\?", GlobMatchType::Word, "whatx really")?);
+    assert!(is_match(
+        r"ls \*.rs",
+        GlobMatchType::Contains,
+        "run ls *.rs"
+    )?);
+    assert!(!is_match(
+        r"ls \*.rs",
+        GlobMatchType::Contains,
+        "run ls main.rs"
+    )?);
+
+    // An escaped backslash is followed by a wildcard.
+    assert!(is_match(r"a\\*", GlobMatchType::Whole, r"a\bc")?);
+    assert!(!is_match(r"a\\*", GlobMatchType::Whole, "abc")?);
+
+    // Backslashes before other characters (or at the end) are still literal,
+    // so existing globs containing them match as before.
+    assert!(is_match(r"C:\Users", GlobMatchType::Whole, r"C:\Users")?);
+    assert!(is_match(r"\d+", GlobMatchType::Word, r"use \d+ here")?);
+    assert!(is_match(r"abc\", GlobMatchType::Whole, r"abc\")?);
+    assert!(is_match(r"a\b*", GlobMatchType::Whole, r"a\bc")?);
+
+    assert_eq!(
+        glob_to_regex_source(r"a\*?\\", GlobMatchType::Whole)?,
+        r"\Aa\*.{1}\\\z"
+    );
+
+    let text = r"*what?* \o/ [x]";
+    assert_eq!(escape_glob(text), r"\*what\?\* \\o/ \[x]");
+    for match_type in [
+        GlobMatchType::Whole,
+        GlobMatchType::Word,
+        GlobMatchType::Contains,
+    ] {
+        assert!(is_match(&escape_glob(text), match_type, text)?);
+        assert!(!is_match(&escape_glob(text), match_type, r"xwhatx* \o/ x")?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_thread_local_glob_matcher_cache() {
+    // Use a fresh thread, so that other tests running on this thread don't
+    // affect the cache.
+    std::thread::spawn(|| {
+        ThreadLocalGlobMatcherCache::clear();
+        assert!(ThreadLocalGlobMatcherCache::is_empty());
+
+        assert!(
+            ThreadLocalGlobMatcherCache::is_match("foo*", GlobMatchType::Whole, "FOOBAR").unwrap()
+        );
+        assert!(
+            !ThreadLocalGlobMatcherCache::is_match("foo*", GlobMatchType::Whole, "bar").unwrap()
+        );
+        assert!(
+            ThreadLocalGlobMatcherCache::is_match("bar", GlobMatchType::Word, "foo bar").unwrap()
+        );
+        assert_eq!(ThreadLocalGlobMatcherCache::len(), 2);
+
+        // Each thread has its own cache.
+        std::thread::spawn(|| assert!(ThreadLocalGlobMatcherCache::is_empty()))
+            .join()
+            .unwrap();
+
+        ThreadLocalGlobMatcherCache::clear();
+        assert!(ThreadLocalGlobMatcherCache::is_empty());
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn test_match_budget() {
+    let matcher = get_glob_matcher("*needle*", GlobMatchType::Whole).unwrap();
+    let haystack = format!("{}needle", "hay ".repeat(100));
+
+    // Unlimited outside of `with_match_budget`.
+    assert!(matcher.is_match(&haystack).unwrap());
+
+    assert_eq!(
+        with_match_budget(1_000, || matcher.is_match(&haystack).unwrap()),
+        Some(true)
+    );
+
+    // Matches which would overrun the budget fail without matching.
+    assert_eq!(
+        with_match_budget(100, || matcher.is_match(&haystack).is_err()),
+        None
+    );
+
+    // The budget covers every match, including those in nested calls.
+    assert_eq!(
+        with_match_budget(700, || {
+            let first = matcher.is_match(&haystack).unwrap();
+            with_match_budget(1_000, || matcher.is_match(&haystack).is_err())
+                .map(|second| (first, second))
+        }),
+        None
+    );
+
+    // The budget is removed afterwards.
+    assert!(matcher.is_match(&haystack).unwrap());
+}
+
+#[test]
+fn test_glob_backends() -> Result<(), Error> {
+    let patterns = [
+        "simple",
+        "simple*",
+        "simple?",
+        "*simple",
+        "sim?le",
+        "s*e",
+        "?**?",
+        "@user:foo",
+        "",
+        "東京",
+        "東*",
+        r"a\*b",
+        r"what\?",
+        r"C:\Users",
+        r"a\\*",
+        "h?llo",
+        "東?",
+    ];
+    let haystacks = [
+        "simple",
+        "Simple",
+        "simples",
+        "some simple.",
+        "not_simple",
+        "a simple\nline",
+        "Some @user:foo test",
+        "@user:foo",
+        "",
+        "x",
+        "sample smile",
+        "今天的simple很重要",
+        "我在東京工作",
+        "a*b",
+        "axb",
+        "what? really",
+        "whatx",
+        r"C:\Users",
+        r"a\b",
+        "héllo",
+        "東京",
+    ];
+
+    for match_type in [
+        GlobMatchType::Whole,
+        GlobMatchType::Word,
+        GlobMatchType::Contains,
+    ] {
+        for pattern in patterns {
+            let regex_matcher = RegexGlobBackend::compile(pattern, match_type)?;
+            let nfa_matcher = NfaGlobBackend::compile(pattern, match_type)?;
+
+            for haystack in haystacks {
+                assert_eq!(
+                    GlobMatcher::is_match(&regex_matcher, haystack)?,
+                    nfa_matcher.is_match(haystack)?,
+                    "{match_type:?} match of {pattern:?} against {haystack:?}",
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_match_types() -> Result<(), Error> {
+    let is_match = |glob, match_type| -> Result<bool, Error> {
+        get_glob_matcher(glob, match_type)?.is_match("The coffeemaker is broken")
+    };
+
+    // `Whole` is anchored to the entire value, `Word` to words of it, and
+    // `Contains` isn't anchored at all.
+    for glob in ["coffee", "Coffee", "c?ffee"] {
+        assert!(!is_match(glob, GlobMatchType::Whole)?, "{glob}");
+        assert!(!is_match(glob, GlobMatchType::Word)?, "{glob}");
+        assert!(is_match(glob, GlobMatchType::Contains)?, "{glob}");
+    }
+
+    assert!(!is_match("coffee*", GlobMatchType::Whole)?);
+    assert!(is_match("coffee*", GlobMatchType::Word)?);
+    assert!(is_match("coffee*", GlobMatchType::Contains)?);
+
+    assert!(is_match("the coffee*", GlobMatchType::Whole)?);
+    assert!(is_match("the coffee*", GlobMatchType::Word)?);
+    assert!(is_match("the coffee*", GlobMatchType::Contains)?);
+
+    assert!(!is_match("tea", GlobMatchType::Contains)?);
+    assert!(!is_match("coffee*tea", GlobMatchType::Contains)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_wildcards_match_characters() -> Result<(), Error> {
+    let is_match = |glob: &str, haystack: &str| -> Result<bool, Error> {
+        let regex_match = get_glob_matcher(glob, GlobMatchType::Whole)?.is_match(haystack)?;
+        let nfa_match = NfaGlobBackend::compile(glob, GlobMatchType::Whole)?.is_match(haystack)?;
+        assert_eq!(regex_match, nfa_match, "{glob:?} against {haystack:?}");
+        Ok(regex_match)
+    };
+
+    // `?` matches exactly one character, however many bytes it is.
+    assert!(is_match("h?llo", "hello")?);
+    assert!(is_match("h?llo", "héllo")?);
+    assert!(is_match("h?llo", "h😀llo")?);
+    assert!(!is_match("h?llo", "hllo")?);
+    assert!(!is_match("h?llo", "hééllo")?);
+    assert!(is_match("東?", "東京")?);
+    assert!(!is_match("東?", "東")?);
+    assert!(!is_match("東?", "東京都")?);
+    assert!(is_match("??", "東京")?);
+    assert!(is_match("?", "é")?);
+
+    // `*` matches any run of characters, including none.
+    assert!(is_match("h*llo", "hllo")?);
+    assert!(is_match("h*llo", "héééllo")?);
+    assert!(is_match("東*", "東")?);
+    assert!(is_match("東*", "東京都")?);
+    assert!(is_match("*", "")?);
+
+    // Neither matches newlines.
+    assert!(!is_match("a?b", "a\nb")?);
+    assert!(!is_match("a*b", "a\nb")?);
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_character_classes() -> Result<(), Error> {
+    let is_match = |glob: &str, haystack: &str| -> Result<bool, Error> {
+        let regex_match = get_glob_matcher(glob, GlobMatchType::Whole)?.is_match(haystack)?;
+        let nfa_match = NfaGlobBackend::compile(glob, GlobMatchType::Whole)?.is_match(haystack)?;
+        assert_eq!(regex_match, nfa_match, "{glob:?} against {haystack:?}");
+        Ok(regex_match)
+    };
+
+    assert!(is_match("gr[ae]y", "grey")?);
+    assert!(is_match("gr[ae]y", "GRAY")?);
+    assert!(!is_match("gr[ae]y", "groy")?);
+    assert!(!is_match("gr[ae]y", "gry")?);
+
+    // Ranges, which are case-insensitive like everything else.
+    assert!(is_match("v[0-9].[0-9]*", "v1.23")?);
+    assert!(!is_match("v[0-9]", "vx")?);
+    assert!(is_match("[a-c]", "B")?);
+    assert!(is_match("[A-C]", "b")?);
+    assert!(!is_match("[a-c]", "d")?);
+
+    // Negation.
+    assert!(is_match("[!abc]", "d")?);
+    assert!(!is_match("[!abc]", "a")?);
+    assert!(!is_match("[!abc]", "A")?);
+    assert!(!is_match("[!a-z]", "q")?);
+    assert!(!is_match("[!abc]", "\n")?);
+
+    // A leading `]` (or `-`) is a member, as is a trailing `-`.
+    assert!(is_match("[]a]", "]")?);
+    assert!(is_match("[]a]", "a")?);
+    assert!(!is_match("[]a]", "b")?);
+    assert!(!is_match("[!]]", "]")?);
+    assert!(is_match("[!]]", "x")?);
+    assert!(is_match("[-a]", "-")?);
+    assert!(is_match("[a-]", "-")?);
+    assert!(!is_match("[a-]", "b")?);
+
+    // Regex metacharacters in classes are members, not syntax.
+    assert!(is_match(r"[\^.]", "^")?);
+    assert!(is_match(r"[\^.]", r"\")?);
+    assert!(!is_match(r"[\^.]", "x")?);
+
+    // Escaped and unmatched brackets are literal.
+    assert!(is_match(r"\[a]", "[a]")?);
+    assert!(!is_match(r"\[a]", "a")?);
+    assert!(is_match("a]", "a]")?);
+
+    for (glob, err) in [
+        ("[abc", InvalidGlobError::UnclosedClass("[abc".to_string())),
+        ("[]", InvalidGlobError::UnclosedClass("[]".to_string())),
+        ("a[!]", InvalidGlobError::UnclosedClass("a[!]".to_string())),
+        (
+            "[z-a]",
+            InvalidGlobError::ReversedRange {
+                start: 'z',
+                end: 'a',
+            },
+        ),
+    ] {
+        assert_eq!(glob_tokens(glob), Err(err.clone()), "{glob}");
+        for match_type in [
+            GlobMatchType::Whole,
+            GlobMatchType::Word,
+            GlobMatchType::Contains,
+        ] {
+            let regex_err = get_glob_matcher(glob, match_type).err().unwrap();
+            assert_eq!(regex_err.downcast_ref(), Some(&err), "{glob}");
+            assert!(NfaGlobBackend::compile(glob, match_type).is_err());
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_matcher_unicode_case_insensitive() -> Result<(), Error> {
+    for match_type in [GlobMatchType::Whole, GlobMatchType::Word] {
+        // Literal globs, and globs with wildcards, which are matched via a regex.
+        for glob in ["Äpfel", "äpfel", "ÄPF*"] {
+            let matcher = get_glob_matcher(glob, match_type)?;
+            assert!(matcher.is_match("äpfel")?, "{glob} {match_type:?}");
+            assert!(matcher.is_match("ÄPFEL")?, "{glob} {match_type:?}");
+            assert!(matcher.is_match("Äpfel")?, "{glob} {match_type:?}");
+        }
+
+        let matcher = get_glob_matcher("Ωμέγα", match_type)?;
+        assert!(matcher.is_match("ΩΜΈΓΑ")?);
+        assert!(!matcher.is_match("apfel")?);
+    }
+
+    assert!(get_glob_matcher("alice", GlobMatchType::Word)?.is_match("Hi ALICE!")?);
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_matcher_unicode_words() -> Result<(), Error> {
+    let is_word_match = |glob: &str, haystack: &str| {
+        get_glob_matcher(glob, GlobMatchType::Word)
+            .and_then(|matcher| matcher.is_match(haystack))
+            .unwrap()
+    };
+
+    // Accented Latin.
+    assert!(is_word_match("naïve", "That's a naïve approach."));
+    assert!(!is_word_match("naïve", "naïveté"));
+    assert!(is_word_match("café", "(café)"));
+    assert!(!is_word_match("caf", "café"));
+
+    // Cyrillic.
+    assert!(is_word_match("привет", "Ну, привет!"));
+    assert!(!is_word_match("привет", "приветствие"));
+    assert!(is_word_match("прив*", "приветствие"));
+
+    // Mixed CJK and ASCII.
+    assert!(is_word_match("meeting", "今天的meeting很重要"));
+    assert!(is_word_match("東京", "我在東京工作"));
+    assert!(is_word_match("東京", "東京!"));
+    assert!(!is_word_match("東京", "我在大阪工作"));
+    assert!(!is_word_match("meet", "今天的meeting很重要"));
+
+    // Punctuation-adjacent matches.
+    assert!(is_word_match("foo", "foo!"));
+    assert!(is_word_match("foo", "(foo)"));
+    assert!(!is_word_match("foo", "foobar"));
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_matcher_emoji_and_rtl_words() -> Result<(), Error> {
+    // (glob, haystack, whether it is a word match)
+    let corpus = [
+        // Emoji-adjacent keywords.
+        ("coffee", "coffee☕", true),
+        ("coffee", "☕coffee", true),
+        ("coffee", "coffee 👍🏽", true),
+        ("coffee", "coffee👍🏽", true),
+        ("coffee", "👍🏽coffee", true),
+        ("coffee", "coffee👨\u{200D}💻", true),
+        ("coffee", "👨\u{200D}💻coffee", true),
+        ("coffee", "coffee🇬🇧", true),
+        ("coffee", "1\u{FE0F}\u{20E3}coffee", true),
+        ("coffee", "#\u{FE0F}\u{20E3}coffee", true),
+        ("coff*", "coffee😀", true),
+        ("coffee", "coffees😀", false),
+        // Variation selectors.
+        ("coffee", "coffee❤\u{FE0F}", true),
+        ("coffee", "❤\u{FE0F}coffee", true),
+        ("coffee", "coffee\u{FE0F}", true),
+        ("coffee", "coffee\u{FE0E}!", true),
+        ("coffee", "\u{FE0F}coffee", true),
+        ("coffee", "coffee\u{E0100}", true),
+        // RTL text and marks around Latin keywords.
+        ("coffee", "אני שותה coffee בבוקר", true),
+        ("coffee", "אני שותה\u{200F}coffee\u{200F}בבוקר", true),
+        ("coffee", "\u{200F}coffee\u{200E}", true),
+        ("coffee", "\u{2067}coffee\u{2069}", true),
+        ("coffee", "\u{202B}coffee\u{202C}", true),
+        ("coffee", "أحب coffee كثيرا", true),
+        ("coffee", "coffeeבבוקר", false),
+        ("שלום", "\u{200F}שלום\u{200F}!", true),
+        ("שלום", "שלום☕", true),
+        ("שלום", "שלומי", false),
+        // Combining marks are still part of words.
+        ("cafe", "cafe\u{301}", false),
+        ("שלום", "שלום\u{5BC}", false),
+    ];
+
+    for (glob, haystack, expected) in corpus {
+        let matcher = get_glob_matcher(glob, GlobMatchType::Word)?;
+        assert_eq!(
+            matcher.is_match(haystack)?,
+            expected,
+            "{glob:?} in {haystack:?}"
+        );
+
+        let regex_matcher = RegexGlobBackend::compile(glob, GlobMatchType::Word)?;
+        let nfa_matcher = NfaGlobBackend::compile(glob, GlobMatchType::Word)?;
+        assert_eq!(
+            GlobMatcher::is_match(&regex_matcher, haystack)?,
+            expected,
+            "regex backend: {glob:?} in {haystack:?}"
+        );
+        assert_eq!(
+            nfa_matcher.is_match(haystack)?,
+            expected,
+            "NFA backend: {glob:?} in {haystack:?}"
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "unicode-normalization")]
+#[test]
+fn test_glob_matcher_unicode_normalization() -> Result<(), Error> {
+    let composed = "caf\u{e9}";
+    let decomposed = "cafe\u{301}";
+
+    for (glob, haystack) in [(composed, decomposed), (decomposed, composed)] {
+        for match_type in [GlobMatchType::Whole, GlobMatchType::Word] {
+            let matcher = get_glob_matcher(glob, match_type)?;
+            assert!(matcher.is_match(haystack)?);
+
+            let wildcard = format!("{glob}*");
+            let matcher = get_glob_matcher(&wildcard, match_type)?;
+            assert!(matcher.is_match(&format!("{haystack}s"))?);
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_precompiled_globs() -> Result<(), Error> {
+    let globs = PrecompiledGlobs::compile([
+        ("simple", GlobMatchType::Word),
+        ("simple", GlobMatchType::Word),
+        ("simple", GlobMatchType::Whole),
+        ("sim*", GlobMatchType::Whole),
+    ]);
+    assert_eq!(globs.len(), 3);
+    assert!(globs.get("sim*", GlobMatchType::Word).is_none());
+
+    for (glob, match_type) in [
+        ("simple", GlobMatchType::Word),
+        ("simple", GlobMatchType::Whole),
+        ("sim*", GlobMatchType::Whole),
+    ] {
+        let matcher = globs.get(glob, match_type).unwrap();
+        for haystack in ["simple", "Some SIMPLE.", "simples", ""] {
+            assert_eq!(
+                matcher.is_match(haystack)?,
+                get_glob_matcher(glob, match_type)?.is_match(haystack)?,
+                "{glob} {match_type:?} {haystack}",
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_combined_word_globs() -> Result<(), Error> {
+    let globs = [
+        "coffee",
+        "Tea",
+        "café",
+        "r?st",
+        "release*",
+        "[bc]at",
+        "c++",
+        "猫",
+        "[unterminated",
+    ];
+    let combined = CombinedWordGlobs::compile(globs)?;
+    assert_eq!(combined.len(), globs.len() - 1);
+
+    // Each glob matches as it would on its own.
+    for haystack in [
+        "coffee?",
+        "coffees and TEA",
+        "Café au lait",
+        "a rust release-candidate",
+        "cat bat rat",
+        "I like c++, not c",
+        "我的猫",
+        "",
+    ] {
+        let folded = fold_case(haystack);
+        let matches = combined.matches_folded(&folded);
+        for glob in &globs[..globs.len() - 1] {
+            assert_eq!(
+                matches.is_match(glob),
+                Some(get_glob_matcher(glob, GlobMatchType::Word)?.is_match(haystack)?),
+                "{glob} {haystack}",
+            );
+        }
+        assert_eq!(matches.is_match("[unterminated"), None);
+    }
+
+    // Reports which globs matched.
+    let folded = fold_case("Tea or coffee?");
+    assert_eq!(
+        combined.matches_folded(&folded).iter().collect::<Vec<_>>(),
+        vec!["coffee", "Tea"]
+    );
+
+    // Word globs are only combined if there are several of them.
+    let globs = PrecompiledGlobs::compile([
+        ("coffee", GlobMatchType::Word),
+        ("tea", GlobMatchType::Whole),
+    ]);
+    assert!(globs.combined_words().is_none());
+    let globs = PrecompiledGlobs::compile([
+        ("coffee", GlobMatchType::Word),
+        ("tea", GlobMatchType::Word),
+    ]);
+    assert_eq!(globs.combined_words().map(CombinedWordGlobs::len), Some(2));
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_matcher_cache() -> Result<(), Error> {
+    let cache = GlobMatcherCache::new(NonZeroUsize::new(2).unwrap());
+
+    assert!(cache.is_match("simple", GlobMatchType::Word, "some simple.")?);
+    assert!(!cache.is_match("simple", GlobMatchType::Word, "simples")?);
+    assert!(cache.is_match("simple", GlobMatchType::Whole, "SIMPLE")?);
+    assert_eq!(cache.len(), 2);
+
+    // The cache is bounded, evicting the least recently used glob.
+    assert!(cache.is_match("sim*", GlobMatchType::Whole, "simple")?);
+    assert_eq!(cache.len(), 2);
+
+    cache.clear();
+    assert!(cache.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_matcher_is_send_sync() -> Result<(), Error> {
+    fn assert_send_sync<T: Send + Sync + Clone>() {}
+    assert_send_sync::<Matcher>();
+    assert_send_sync::<PrecompiledGlobs>();
+
+    // Clones share the lazily compiled regex of word matchers.
+    let matcher = get_glob_matcher("simple", GlobMatchType::Word)?;
+    let clone = matcher.clone();
+    assert!(clone.is_match("some simple.")?);
+    match (&matcher, &clone) {
+        (Matcher::Word { regex, .. }, Matcher::Word { regex: cloned, .. }) => {
+            assert!(Arc::ptr_eq(regex, cloned));
+            assert!(regex.get().is_some());
+        }
+        _ => panic!("not word matchers"),
+    }
+
+    // A matcher can be used from several threads at once.
+    let matcher = Arc::new(get_glob_matcher("sim*", GlobMatchType::Word)?);
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let matcher = Arc::clone(&matcher);
+            std::thread::spawn(move || matcher.is_match("some simple.").unwrap())
+        })
+        .collect();
+    for handle in handles {
+        assert!(handle.join().unwrap());
+    }
 
     Ok(())
 }
+
+#[test]
+fn test_glob_complexity_limits() {
+    let limits = GlobComplexityLimits::default();
+
+    // Collapsing the wildcards would make this cheap to compile, but it is
+    // still rejected rather than compiled.
+    let stars = "*".repeat(10_000);
+    let err = get_glob_matcher(&stars, GlobMatchType::Whole)
+        .err()
+        .expect("glob should be rejected");
+    assert_eq!(
+        err.downcast_ref::<GlobTooComplexError>(),
+        Some(&GlobTooComplexError::TooLong {
+            length: 10_000,
+            max_length: limits.max_length,
+        })
+    );
+    assert!(NfaGlobBackend::compile(&stars, GlobMatchType::Whole).is_err());
+
+    assert_eq!(
+        limits.check(&"a?".repeat(101)),
+        Err(GlobTooComplexError::TooManyWildcards {
+            wildcards: 101,
+            max_wildcards: limits.max_wildcards,
+        })
+    );
+    assert_eq!(limits.check(&"a?".repeat(100)), Ok(()));
+    assert_eq!(limits.check(&"a".repeat(limits.max_length)), Ok(()));
+
+    // Globs without wildcards are limited too.
+    let long_word = "a".repeat(limits.max_length + 1);
+    assert!(get_glob_matcher(&long_word, GlobMatchType::Word).is_err());
+
+    let strict = GlobComplexityLimits {
+        max_length: 10,
+        max_wildcards: 1,
+    };
+    assert!(strict.check("a*b").is_ok());
+    assert!(strict.check("a*b?").is_err());
+    assert!(strict.check("abcdefghijk").is_err());
+}
+
+#[test]
+fn test_edit_distance() {
+    assert_eq!(edit_distance("", ""), 0);
+    assert_eq!(edit_distance("abc", ""), 3);
+    assert_eq!(edit_distance("", "abc"), 3);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(edit_distance("flaw", "lawn"), 2);
+    assert_eq!(edit_distance("margaret", "margeret"), 1);
+    assert_eq!(edit_distance("東京", "京都"), 2);
+}
+
+#[test]
+fn test_get_flattened_value() {
+    let user_ids: Vec<SimpleJsonValue> = (0..150)
+        .map(|i| SimpleJsonValue::Str(format!("@user{i}:test").into()))
+        .collect();
+    let flattened: BTreeMap<FlattenedKey, JsonValue> = [
+        (
+            "content.body",
+            JsonValue::Value(SimpleJsonValue::Str("hi".into())),
+        ),
+        ("content.m\\.mentions.user_ids", JsonValue::Array(user_ids)),
+        (
+            "content.list",
+            JsonValue::Array(vec![SimpleJsonValue::Int(1)]),
+        ),
+        ("content.list.0", JsonValue::Value(SimpleJsonValue::Int(2))),
+        ("content.a\\.0", JsonValue::Value(SimpleJsonValue::Int(3))),
+        ("content.a", JsonValue::Array(vec![SimpleJsonValue::Int(4)])),
+    ]
+    .into_iter()
+    .map(|(key, value)| (FlattenedKey::from(key), value))
+    .collect();
+
+    let get = |key| get_flattened_value(&flattened, key);
+    let user_id = |i| Some(SimpleJsonValue::Str(format!("@user{i}:test").into()));
+
+    assert_eq!(
+        get("content.body"),
+        Some(&SimpleJsonValue::Str("hi".into()))
+    );
+    assert_eq!(get("content.m\\.mentions.user_ids"), None);
+    assert_eq!(get("content.m\\.mentions.user_ids.0").cloned(), user_id(0));
+    assert_eq!(
+        get("content.m\\.mentions.user_ids.99").cloned(),
+        user_id(99)
+    );
+
+    // Elements beyond the cap can't be addressed.
+    assert_eq!(get("content.m\\.mentions.user_ids.100"), None);
+    assert_eq!(get("content.m\\.mentions.user_ids.01"), None);
+    assert_eq!(get("content.m\\.mentions.user_ids.-1"), None);
+
+    // Actual keys take precedence over indexes, and escaped periods aren't
+    // separators.
+    assert_eq!(get("content.list.0"), Some(&SimpleJsonValue::Int(2)));
+    assert_eq!(get("content.a\\.0"), Some(&SimpleJsonValue::Int(3)));
+    assert_eq!(get("content.a\\.1"), None);
+    assert_eq!(get("content.a.0"), Some(&SimpleJsonValue::Int(4)));
+}
+
+#[test]
+fn test_insert_flattened_value() {
+    let body = |body: &'static str| JsonValue::Value(SimpleJsonValue::Str(body.into()));
+
+    let mut flattened = BTreeMap::new();
+    assert_eq!(
+        insert_flattened_value(&mut flattened, "content.body".into(), body("first")),
+        None
+    );
+    assert_eq!(
+        insert_flattened_value(&mut flattened, "content.body".into(), body("second")),
+        Some(body("first"))
+    );
+    assert_eq!(
+        insert_flattened_value(&mut flattened, "content.Body".into(), body("third")),
+        None
+    );
+
+    // The last value wins, and keys differing in case are distinct.
+    assert_eq!(flattened.len(), 2);
+    assert_eq!(flattened.get("content.body"), Some(&body("second")));
+    assert_eq!(flattened.get("content.Body"), Some(&body("third")));
+
+    // The same goes for deserialized events.
+    let flattened = deserialize_flattened(&mut serde_json::Deserializer::from_str(
+        r#"{"content.body": "first", "content.Body": "third", "content.body": "second"}"#,
+    ))
+    .unwrap();
+    assert_eq!(flattened.len(), 2);
+    assert_eq!(flattened.get("content.body"), Some(&body("second")));
+    assert_eq!(flattened.get("content.Body"), Some(&body("third")));
+}
+
+#[test]
+fn test_get_wildcard_flattened_values() {
+    let flattened: BTreeMap<FlattenedKey, JsonValue> = [
+        ("content.body", "hi"),
+        ("content.foo.a.bar", "a"),
+        ("content.foo.b.bar", "b"),
+        ("content.foo.c.baz", "c"),
+        ("content.foo.d.e.bar", "d"),
+        ("content.foo\\.x.bar", "x"),
+        ("content.foo.e\\.f.bar", "e.f"),
+        ("content.food.g.bar", "g"),
+    ]
+    .into_iter()
+    .map(|(key, value)| {
+        (
+            key.into(),
+            JsonValue::Value(SimpleJsonValue::Str(value.into())),
+        )
+    })
+    .collect();
+
+    let get = |key| {
+        get_wildcard_flattened_values(&flattened, key)
+            .into_iter()
+            .map(|value| match value {
+                SimpleJsonValue::Str(value) => value.to_string(),
+                _ => panic!("Unexpected value {value:?}"),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    assert!(is_wildcard_key("content.foo.*.bar"));
+    assert!(is_wildcard_key("*.body"));
+    assert!(!is_wildcard_key("content.body"));
+    assert!(!is_wildcard_key("content.foo*.bar"));
+    assert!(!is_wildcard_key("content.foo.\\*"));
+
+    // Escaped periods aren't separators, both in the key and the event.
+    assert_eq!(get("content.foo.*.bar"), ["a", "b", "e.f"]);
+    assert_eq!(get("content.foo.*.*"), ["a", "b", "c", "e.f"]);
+    assert_eq!(get("content.foo\\.x.*"), ["x"]);
+    assert_eq!(get("content.*.a.bar"), ["a"]);
+    assert_eq!(get("*.body"), ["hi"]);
+    assert!(get("content.foo.*").is_empty());
+    assert!(get("content.missing.*").is_empty());
+}
+
+#[test]
+fn test_selector_path() {
+    let flattened: BTreeMap<FlattenedKey, JsonValue> = [
+        (
+            "content.body",
+            JsonValue::Value(SimpleJsonValue::Str("hi".into())),
+        ),
+        (
+            "content.scores",
+            JsonValue::Array(vec![
+                SimpleJsonValue::Int(3),
+                SimpleJsonValue::Int(7),
+                SimpleJsonValue::Str("8".into()),
+            ]),
+        ),
+        (
+            "content.a.tags",
+            JsonValue::Array(vec![SimpleJsonValue::Str("x".into())]),
+        ),
+        (
+            "content.b.tags",
+            JsonValue::Array(vec![SimpleJsonValue::Str("y".into())]),
+        ),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.into(), value))
+    .collect();
+
+    let select = |path| SelectorPath::parse(path).unwrap().select(&flattened);
+
+    assert_eq!(
+        select("$.content.body"),
+        [&SimpleJsonValue::Str("hi".into())]
+    );
+    assert_eq!(select("$.content.scores").len(), 0);
+    assert_eq!(select("$.content.scores[*]").len(), 3);
+    assert_eq!(select("$.content.scores[1]"), [&SimpleJsonValue::Int(7)]);
+    assert_eq!(select("$.content.scores[3]").len(), 0);
+    assert_eq!(select("$.content.body[*]").len(), 0);
+    assert_eq!(select("$.content.*.tags[0]").len(), 2);
+    assert_eq!(select("$.content.missing[*]").len(), 0);
+
+    for (path, err) in [
+        (
+            "content.body",
+            InvalidSelectorError::MissingRoot as fn(_) -> _,
+        ),
+        ("$content.body", InvalidSelectorError::MissingRoot),
+        ("$..body", InvalidSelectorError::EmptySegment),
+        ("$.content.", InvalidSelectorError::EmptySegment),
+        ("$.", InvalidSelectorError::EmptySegment),
+        ("$.content[body]", InvalidSelectorError::Unsupported),
+        (
+            "$.content.scores[?(@ > 5)]",
+            InvalidSelectorError::Unsupported,
+        ),
+        ("$.content.scores[0:2]", InvalidSelectorError::Unsupported),
+        ("$.content.scores[-1]", InvalidSelectorError::Unsupported),
+        ("$.content.scores[01]", InvalidSelectorError::Unsupported),
+        ("$.content.scores[*][*]", InvalidSelectorError::Unsupported),
+        ("$.content.scores]", InvalidSelectorError::Unsupported),
+        ("$.content.scores[100]", InvalidSelectorError::BadIndex),
+    ] {
+        assert_eq!(
+            SelectorPath::parse(path),
+            Err(err(path.to_string())),
+            "{path}"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validation of user-submitted push rules, so that rules which can never be
+//! evaluated (e.g. with an uncompilable glob or a malformed `is` clause) can be
+//! rejected before they're stored.
+
+use pyo3::prelude::*;
+
+use super::evaluator::{event_match_type, parse_inequality, MINUTES_PER_DAY};
+use super::utils::{get_glob_matcher, GlobMatchType, SelectorPath};
+use super::{Action, Condition, KnownCondition, PushRule, RATE_LIMIT_TWEAK};
+
+/// A problem with one of the conditions of a push rule.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("condition {condition_index}: {message}")]
+pub struct ValidationError {
+    /// The index of the (top-level) condition in the rule.
+    pub condition_index: usize,
+    pub message: String,
+}
+
+/// Checks that every condition of the rule can be evaluated, returning every
+/// problem found.
+///
+/// Conditions whose patterns are only known at evaluation time (i.e. those
+/// with a `pattern_type`) are accepted as is, as are unknown conditions.
+pub fn validate_rule(rule: &PushRule) -> Result<(), Vec<ValidationError>> {
+    let mut errors = Vec::new();
+
+    for (condition_index, condition) in rule.conditions.iter().enumerate() {
+        let mut messages = Vec::new();
+        validate_condition(condition, &mut messages);
+
+        errors.extend(messages.into_iter().map(|message| ValidationError {
+            condition_index,
+            message,
+        }));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Checks the given rule, see `validate_rule`. Returns a description of each
+/// problem found, which is empty if the rule is valid.
+#[pyfunction]
+#[pyo3(name = "validate_rule")]
+pub fn py_validate_rule(rule: PushRule) -> Vec<String> {
+    let mut messages = match validate_rule(&rule) {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors.iter().map(ToString::to_string).collect(),
+    };
+    if let Err(errors) = validate_actions(&rule.actions) {
+        messages.extend(errors);
+    }
+    messages
+}
+
+/// Checks that the tweaks which the evaluator understands have valid values,
+/// returning a description of each problem found. Other actions are accepted
+/// as is.
+pub fn validate_actions(actions: &[Action]) -> Result<(), Vec<String>> {
+    let mut messages = Vec::new();
+
+    for (action_index, action) in actions.iter().enumerate() {
+        if let Action::SetTweak(tweak) = action {
+            if tweak.set_tweak == RATE_LIMIT_TWEAK && tweak.rate_limit().is_none() {
+                messages.push(format!(
+                    "action {action_index}: {RATE_LIMIT_TWEAK} tweak needs a positive integer value"
+                ));
+            }
+        }
+    }
+
+    if messages.is_empty() {
+        Ok(())
+    } else {
+        Err(messages)
+    }
+}
+
+/// Adds a message for each problem with the condition to `messages`.
+fn validate_condition(condition: &Condition, messages: &mut Vec<String>) {
+    let known_condition = match condition {
+        Condition::Known(known_condition) => known_condition,
+        Condition::Unknown(_) => return,
+    };
+
+    match known_condition {
+        KnownCondition::EventMatch(event_match) => {
+            let mut match_types: Vec<_> = event_match
+                .key
+                .iter()
+                .map(|key| event_match.match_type(key))
+                .collect();
+            match_types.dedup();
+            for match_type in match_types {
+                validate_glob(&event_match.pattern, match_type, messages);
+            }
+        }
+        KnownCondition::RelatedEventMatch(event_match) => {
+            if let (Some(key), Some(pattern)) = (&event_match.key, &event_match.pattern) {
+                validate_glob(pattern, event_match_type(key), messages);
+            }
+        }
+        KnownCondition::RoomMemberCount { is: Some(is) }
+        | KnownCondition::AtHandleCount { is: Some(is) }
+        | KnownCondition::MentionCount { is: Some(is) }
+        | KnownCondition::BodyLength { is: Some(is) }
+        | KnownCondition::ThreadDepth { is: Some(is) }
+        | KnownCondition::RoomNotificationsToday { is: Some(is) }
+        | KnownCondition::SenderEventRate { is: Some(is) }
+        | KnownCondition::RelationCount { is: Some(is), .. }
+        | KnownCondition::UnreadCount { is: Some(is), .. }
+        | KnownCondition::UserPowerLevel { is: Some(is), .. } => {
+            if let Err(err) = parse_inequality(is) {
+                messages.push(err.to_string());
+            }
+        }
+        KnownCondition::RoomIdMatch { pattern }
+        | KnownCondition::SenderServerMatch { pattern }
+        | KnownCondition::SenderDisplayNameMatch { pattern } => {
+            validate_glob(pattern, GlobMatchType::Whole, messages);
+        }
+        KnownCondition::StateKeyMatch {
+            pattern,
+            pattern_type,
+        } => match (pattern, pattern_type) {
+            (Some(pattern), None) => validate_glob(pattern, GlobMatchType::Whole, messages),
+            (None, None) => {
+                messages.push("state_key_match needs a pattern or pattern_type".to_string())
+            }
+            (_, Some(_)) => {}
+        },
+        KnownCondition::WithinTimeWindow {
+            start_minute,
+            end_minute,
+            tz_offset_minutes,
+        } => {
+            if *start_minute >= MINUTES_PER_DAY || *end_minute >= MINUTES_PER_DAY {
+                messages.push(format!(
+                    "time window minutes must be less than {MINUTES_PER_DAY}"
+                ));
+            }
+            if tz_offset_minutes.unsigned_abs() >= MINUTES_PER_DAY {
+                messages.push(format!(
+                    "time zone offset must be less than {MINUTES_PER_DAY} minutes"
+                ));
+            }
+        }
+        KnownCondition::EventSelector { path, .. } => {
+            if let Err(err) = SelectorPath::parse(path) {
+                messages.push(err.to_string());
+            }
+        }
+        KnownCondition::Not { condition } => validate_condition(condition, messages),
+        KnownCondition::AnyOf { conditions } => {
+            for condition in conditions {
+                validate_condition(condition, messages);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Adds a message to `messages` if the glob can't be compiled.
+fn validate_glob(glob: &str, match_type: GlobMatchType, messages: &mut Vec<String>) {
+    // Word matchers are compiled lazily, so make sure they're fully compiled.
+    let result = get_glob_matcher(glob, match_type).and_then(|matcher| matcher.compile_fully());
+
+    if let Err(err) = result {
+        messages.push(format!("bad glob pattern {glob:?}: {err}"));
+    }
+}
+
+#[test]
+fn test_validate_rule() {
+    let rule = |conditions: &str| {
+        PushRule::from_db(
+            "global/override/test".to_string(),
+            5,
+            conditions,
+            r#"["notify"]"#,
+        )
+        .unwrap()
+    };
+
+    assert_eq!(
+        validate_rule(&rule(
+            r#"[
+                {"kind": "event_match", "key": "content.body", "pattern": "coffee*"},
+                {"kind": "room_member_count", "is": "2..10"},
+                {"kind": "org.example.unknown"}
+            ]"#
+        )),
+        Ok(())
+    );
+
+    let errors = validate_rule(&rule(
+        r#"[
+            {"kind": "event_match", "key": "content.body", "pattern": "coffee"},
+            {"kind": "room_member_count", "is": "<<2"},
+            {"kind": "io.element.not", "condition": {"kind": "io.element.sender_event_rate", "is": "10..2"}}
+        ]"#,
+    ))
+    .unwrap_err();
+    assert_eq!(
+        errors
+            .iter()
+            .map(|error| error.condition_index)
+            .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    assert!(errors[1].message.contains("inverted range"), "{errors:?}");
+
+    let errors = validate_rule(&rule(
+        r#"[
+            {"kind": "io.element.within_time_window", "start_minute": 1320, "end_minute": 420},
+            {"kind": "io.element.within_time_window", "start_minute": 1320, "end_minute": 1440},
+            {"kind": "io.element.within_time_window", "start_minute": 0, "end_minute": 60, "tz_offset_minutes": -1440}
+        ]"#,
+    ))
+    .unwrap_err();
+    assert_eq!(
+        errors
+            .iter()
+            .map(|error| error.condition_index)
+            .collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+
+    let errors = validate_rule(&rule(
+        r#"[
+            {"kind": "io.element.event_selector", "path": "$.content.scores[*]", "op": ">", "value": 5},
+            {"kind": "io.element.event_selector", "path": "$..scores", "op": ">", "value": 5}
+        ]"#,
+    ))
+    .unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].condition_index, 1);
+}
+
+#[test]
+fn test_validate_actions() {
+    let actions = |json: &str| serde_json::from_str::<Vec<Action>>(json).unwrap();
+
+    assert_eq!(
+        validate_actions(&actions(
+            r#"["notify", {"set_tweak": "rate_limit", "value": 5}, {"custom": true}]"#
+        )),
+        Ok(())
+    );
+    assert_eq!(
+        validate_actions(&actions(
+            r#"["notify", {"set_tweak": "rate_limit", "value": 0}, {"set_tweak": "rate_limit"}]"#
+        )),
+        Err(vec![
+            "action 1: rate_limit tweak needs a positive integer value".to_string(),
+            "action 2: rate_limit tweak needs a positive integer value".to_string(),
+        ])
+    );
+}
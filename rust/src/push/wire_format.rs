@@ -0,0 +1,332 @@
+// Copyright 2023 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Golden tests of the JSON wire format of conditions and actions, as sent to
+//! and received from clients.
+//!
+//! Every `KnownCondition` variant must have a fixture here: `variant_index`
+//! doesn't compile until a new variant is added to it, and
+//! `test_condition_wire_format` checks that every index is covered.
+
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+
+use super::{
+    Action, Condition, EventMatchPatternType, EventMatchTypeCondition,
+    EventPropertyIsTypeCondition, KnownCondition, RelatedEventMatchTypeCondition,
+};
+
+/// The number of `KnownCondition` variants, see `variant_index`.
+const KNOWN_CONDITION_VARIANTS: usize = 60;
+
+/// Conditions which round trip, in the exact form we serialize them.
+const CONDITION_FIXTURES: &[&str] = &[
+    r#"{"kind":"event_match","key":"content.body","pattern":"coffee"}"#,
+    r#"{"kind":"event_match","key":["content.body","content.formatted_body"],"pattern":"coffee","ignore_quotes":true,"match_words":false}"#,
+    r#"{"kind":"event_match","key":"content.topic","pattern":"coffee","contains":true}"#,
+    r#"{"kind":"event_property_is","key":"content.value","value":5}"#,
+    r#"{"kind":"event_property_is","key":"content.value","value":null}"#,
+    r#"{"kind":"im.nheko.msc3664.related_event_match","key":"sender","pattern":"@bob:example.org","rel_type":"m.in_reply_to","include_fallbacks":false}"#,
+    r#"{"kind":"im.nheko.msc3664.related_event_match","rel_type":"m.in_reply_to"}"#,
+    r#"{"kind":"event_property_contains","key":"content.tags","value":"urgent"}"#,
+    r#"{"kind":"contains_display_name"}"#,
+    r#"{"kind":"room_member_count","is":"<=2"}"#,
+    r#"{"kind":"room_member_count"}"#,
+    r#"{"kind":"sender_notification_permission","key":"room"}"#,
+    r#"{"kind":"org.matrix.msc3952.is_user_mention"}"#,
+    r#"{"kind":"org.matrix.msc3952.is_room_mention"}"#,
+    r#"{"kind":"org.matrix.msc3931.room_version_supports","feature":"org.matrix.msc3932.extensible_events"}"#,
+    r#"{"kind":"org.matrix.msc3245.is_voice_message"}"#,
+    r#"{"kind":"io.element.at_handle_count","is":">=3"}"#,
+    r#"{"kind":"io.element.at_handle_count"}"#,
+    r#"{"kind":"io.element.is_unstable_event_type","prefixes":["org.example."]}"#,
+    r#"{"kind":"io.element.is_unstable_event_type"}"#,
+    r#"{"kind":"io.element.has_any_key","keys":["content.a","content.b"]}"#,
+    r#"{"kind":"io.element.has_all_keys","keys":["content.a","content.b"]}"#,
+    r#"{"kind":"io.element.quotes_me"}"#,
+    r#"{"kind":"io.element.body_sentiment","polarity":"negative"}"#,
+    r#"{"kind":"io.element.device_type_match","device_type":"mobile"}"#,
+    r#"{"kind":"io.element.mentions_room_name"}"#,
+    r#"{"kind":"io.element.sender_verified"}"#,
+    r#"{"kind":"io.element.body_contains_code"}"#,
+    r#"{"kind":"io.element.room_notifications_today","is":"<5"}"#,
+    r#"{"kind":"io.element.sender_event_rate","is":">10"}"#,
+    r#"{"kind":"io.element.is_duplicate_message"}"#,
+    r#"{"kind":"io.element.is_thread_start"}"#,
+    r#"{"kind":"io.element.is_encrypted"}"#,
+    r#"{"kind":"io.element.is_reply"}"#,
+    r#"{"kind":"io.element.body_length","is":">=1000"}"#,
+    r#"{"kind":"io.element.body_length"}"#,
+    r#"{"kind":"io.element.sender_server_match","pattern":"*.example.org"}"#,
+    r#"{"kind":"io.element.event_property_in","key":"content.msgtype","values":["m.text","m.notice",1,true,null]}"#,
+    r#"{"kind":"io.element.within_time_window","start_minute":1320,"end_minute":420,"tz_offset_minutes":-300}"#,
+    r#"{"kind":"io.element.thread_depth","is":"1"}"#,
+    r#"{"kind":"io.element.sender_is_self"}"#,
+    r#"{"kind":"io.element.event_selector","path":"$.content.scores[*]","op":">","value":5}"#,
+    r#"{"kind":"io.element.event_key_exists","key":"content.file","exists":true}"#,
+    r#"{"kind":"io.element.has_aggregation","rel_type":"m.annotation","key":"👍"}"#,
+    r#"{"kind":"io.element.has_aggregation","rel_type":"m.thread"}"#,
+    r#"{"kind":"io.element.keys_equal","key_a":"content.recipient","key_b":"sender"}"#,
+    r#"{"kind":"io.element.sender_display_name_match","pattern":"*Bot"}"#,
+    r#"{"kind":"io.element.is_direct_message"}"#,
+    r#"{"kind":"io.element.sender_is_server_notices"}"#,
+    r#"{"kind":"io.element.unread_count","count_kind":"notifications","is":"0"}"#,
+    r#"{"kind":"io.element.unread_count","count_kind":"highlights"}"#,
+    r#"{"kind":"io.element.has_formatted_body"}"#,
+    r#"{"kind":"io.element.user_power_level","user":"sender","is":">=50"}"#,
+    r#"{"kind":"io.element.user_power_level","user":"@alice:example.org"}"#,
+    r#"{"kind":"io.element.is_edit"}"#,
+    r#"{"kind":"io.element.room_has_tag","tag":"m.lowpriority"}"#,
+    r#"{"kind":"io.element.relates_to_event","event_id":"$root:example.org"}"#,
+    r#"{"kind":"io.element.mention_count","is":">10"}"#,
+    r#"{"kind":"io.element.mention_count"}"#,
+    r#"{"kind":"io.element.membership_transition","from":"join","to":"leave"}"#,
+    r#"{"kind":"io.element.relation_count","rel_type":"m.thread","is":">=10"}"#,
+    r#"{"kind":"io.element.relation_count","rel_type":"m.thread"}"#,
+    r#"{"kind":"io.element.event_value_compare","key":"content.value","op":">=","value":-3}"#,
+    r#"{"kind":"io.element.room_id_equals","room_id":"!room:example.org"}"#,
+    r#"{"kind":"io.element.room_id_match","pattern":"!*:example.org"}"#,
+    r#"{"kind":"io.element.event_age_less_than","ms":60000}"#,
+    r#"{"kind":"io.element.user_membership","membership":"join"}"#,
+    r#"{"kind":"io.element.state_key_match","pattern":"@*:example.org"}"#,
+    r#"{"kind":"io.element.state_key_match","pattern_type":"user_id"}"#,
+    r#"{"kind":"io.element.not","condition":{"kind":"room_member_count","is":"2"}}"#,
+    r#"{"kind":"io.element.not","condition":{"kind":"org.example.unknown","value":1}}"#,
+    r#"{"kind":"io.element.any_of","conditions":[{"kind":"io.element.is_encrypted"},{"kind":"io.element.not","condition":{"kind":"contains_display_name"}}]}"#,
+    r#"{"kind":"io.element.any_of","conditions":[]}"#,
+];
+
+/// Conditions which are only ever serialized, as they're built into the base
+/// rules. Their serialized form never deserializes back into the same variant
+/// (see e.g. `test_cannot_deserialize_event_match_condition_with_pattern_type`).
+fn serialize_only_fixtures() -> Vec<(KnownCondition, &'static str)> {
+    vec![
+        (
+            KnownCondition::EventMatchType(EventMatchTypeCondition {
+                key: Cow::Borrowed("content.body"),
+                pattern_type: Cow::Owned(EventMatchPatternType::UserLocalpart),
+            }),
+            r#"{"kind":"event_match","key":"content.body","pattern_type":"user_localpart"}"#,
+        ),
+        (
+            KnownCondition::RelatedEventMatchType(RelatedEventMatchTypeCondition {
+                key: Cow::Borrowed("sender"),
+                pattern_type: Cow::Owned(EventMatchPatternType::UserId),
+                rel_type: Cow::Borrowed("m.in_reply_to"),
+                include_fallbacks: None,
+            }),
+            r#"{"kind":"im.nheko.msc3664.related_event_match","key":"sender","pattern_type":"user_id","rel_type":"m.in_reply_to"}"#,
+        ),
+        (
+            KnownCondition::ExactEventPropertyContainsType(EventPropertyIsTypeCondition {
+                key: Cow::Borrowed("content.m\\.mentions.user_ids"),
+                value_type: Cow::Owned(EventMatchPatternType::UserId),
+            }),
+            r#"{"kind":"event_property_contains","key":"content.m\\.mentions.user_ids","value_type":"user_id"}"#,
+        ),
+    ]
+}
+
+/// Actions which round trip, in the exact form we serialize them.
+const ACTION_FIXTURES: &[&str] = &[
+    r#""notify""#,
+    r#""dont_notify""#,
+    r#""coalesce""#,
+    r#"{"set_tweak":"highlight"}"#,
+    r#"{"set_tweak":"highlight","value":false}"#,
+    r#"{"set_tweak":"sound","value":"default"}"#,
+    r#"{"set_tweak":"org.example.tweak","value":{"nested":[1,2]},"extra":"kept"}"#,
+    r#"{"org.example.action":{"value":1}}"#,
+];
+
+/// A unique index for each `KnownCondition` variant, between 0 and
+/// `KNOWN_CONDITION_VARIANTS`.
+fn variant_index(condition: &KnownCondition) -> usize {
+    match condition {
+        KnownCondition::EventMatch(_) => 0,
+        KnownCondition::EventMatchType(_) => 1,
+        KnownCondition::EventPropertyIs(_) => 2,
+        KnownCondition::RelatedEventMatch(_) => 3,
+        KnownCondition::RelatedEventMatchType(_) => 4,
+        KnownCondition::EventPropertyContains(_) => 5,
+        KnownCondition::ExactEventPropertyContainsType(_) => 6,
+        KnownCondition::ContainsDisplayName => 7,
+        KnownCondition::RoomMemberCount { .. } => 8,
+        KnownCondition::SenderNotificationPermission { .. } => 9,
+        KnownCondition::IsUserMention => 10,
+        KnownCondition::IsRoomMention => 11,
+        KnownCondition::RoomVersionSupports { .. } => 12,
+        KnownCondition::IsVoiceMessage => 13,
+        KnownCondition::AtHandleCount { .. } => 14,
+        KnownCondition::IsUnstableEventType { .. } => 15,
+        KnownCondition::HasAnyKey { .. } => 16,
+        KnownCondition::HasAllKeys { .. } => 17,
+        KnownCondition::QuotesMe => 18,
+        KnownCondition::BodySentiment { .. } => 19,
+        KnownCondition::DeviceTypeMatch { .. } => 20,
+        KnownCondition::MentionsRoomName => 21,
+        KnownCondition::SenderVerified => 22,
+        KnownCondition::BodyContainsCode => 23,
+        KnownCondition::RoomNotificationsToday { .. } => 24,
+        KnownCondition::SenderEventRate { .. } => 25,
+        KnownCondition::IsDuplicateMessage => 26,
+        KnownCondition::IsThreadStart => 27,
+        KnownCondition::MembershipTransition { .. } => 28,
+        KnownCondition::RelationCount { .. } => 29,
+        KnownCondition::EventValueCompare { .. } => 30,
+        KnownCondition::RoomIdEquals { .. } => 31,
+        KnownCondition::RoomIdMatch { .. } => 32,
+        KnownCondition::EventAgeLessThan { .. } => 33,
+        KnownCondition::Not { .. } => 34,
+        KnownCondition::UserMembership { .. } => 35,
+        KnownCondition::StateKeyMatch { .. } => 36,
+        KnownCondition::IsEncrypted => 37,
+        KnownCondition::AnyOf { .. } => 38,
+        KnownCondition::IsReply => 39,
+        KnownCondition::BodyLength { .. } => 40,
+        KnownCondition::SenderServerMatch { .. } => 41,
+        KnownCondition::EventPropertyIn { .. } => 42,
+        KnownCondition::WithinTimeWindow { .. } => 43,
+        KnownCondition::ThreadDepth { .. } => 44,
+        KnownCondition::SenderIsSelf => 45,
+        KnownCondition::EventSelector { .. } => 46,
+        KnownCondition::EventKeyExists { .. } => 47,
+        KnownCondition::HasAggregation { .. } => 48,
+        KnownCondition::KeysEqual { .. } => 49,
+        KnownCondition::SenderDisplayNameMatch { .. } => 50,
+        KnownCondition::IsDirectMessage => 51,
+        KnownCondition::SenderIsServerNotices => 52,
+        KnownCondition::UnreadCount { .. } => 53,
+        KnownCondition::HasFormattedBody => 54,
+        KnownCondition::UserPowerLevel { .. } => 55,
+        KnownCondition::IsEdit => 56,
+        KnownCondition::RoomHasTag { .. } => 57,
+        KnownCondition::RelatesToEvent { .. } => 58,
+        KnownCondition::MentionCount { .. } => 59,
+    }
+}
+
+/// `KnownCondition::kind` is written out by hand, so check it agrees with the
+/// `kind` that serde serializes.
+fn assert_kind_is_serialized_kind(condition: &KnownCondition) {
+    let serialized = serde_json::to_value(condition).unwrap();
+    assert_eq!(
+        serialized["kind"].as_str(),
+        Some(condition.kind()),
+        "{serialized}"
+    );
+    assert!(
+        KnownCondition::is_known_kind(condition.kind()),
+        "{serialized}"
+    );
+}
+
+#[test]
+fn test_condition_wire_format() {
+    let mut covered = BTreeSet::new();
+
+    for fixture in CONDITION_FIXTURES {
+        let condition: Condition = serde_json::from_str(fixture).unwrap();
+        match &condition {
+            Condition::Known(known_condition) => {
+                covered.insert(variant_index(known_condition));
+                assert_kind_is_serialized_kind(known_condition);
+            }
+            Condition::Unknown(_) => panic!("Fixture isn't a known condition: {fixture}"),
+        }
+
+        assert_eq!(&serde_json::to_string(&condition).unwrap(), fixture);
+        assert_eq!(
+            serde_json::from_str::<Condition>(fixture).unwrap(),
+            condition
+        );
+    }
+
+    for (known_condition, fixture) in serialize_only_fixtures() {
+        let index = variant_index(&known_condition);
+        covered.insert(index);
+        assert_kind_is_serialized_kind(&known_condition);
+
+        let condition = Condition::Known(known_condition);
+        assert_eq!(serde_json::to_string(&condition).unwrap(), fixture);
+        assert!(!matches!(
+            serde_json::from_str(fixture).unwrap(),
+            Condition::Known(known_condition) if variant_index(&known_condition) == index
+        ));
+    }
+
+    let missing: Vec<_> = (0..KNOWN_CONDITION_VARIANTS)
+        .filter(|index| !covered.contains(index))
+        .collect();
+    assert!(missing.is_empty(), "No fixtures for variants {missing:?}");
+
+    // Every recognized kind belongs to a variant.
+    let kinds: BTreeSet<_> = CONDITION_FIXTURES
+        .iter()
+        .map(|fixture| serde_json::from_str::<KnownCondition>(fixture).unwrap())
+        .chain(
+            serialize_only_fixtures()
+                .into_iter()
+                .map(|(known, _)| known),
+        )
+        .map(|known| known.kind())
+        .collect();
+    assert_eq!(kinds, KnownCondition::KINDS.iter().copied().collect());
+}
+
+#[test]
+fn test_unknown_condition_wire_format() {
+    // Unknown conditions (including known kinds with fields we don't
+    // understand) keep their raw JSON as is, though object keys may be
+    // reordered.
+    for fixture in [
+        r#"{"kind":"org.example.unknown","nested":{"list":[1,"two",null]},"flag":true}"#,
+        r#"{"kind":"room_member_count","is":2}"#,
+        r#"{"no_kind":true}"#,
+    ] {
+        let condition: Condition = serde_json::from_str(fixture).unwrap();
+        assert!(matches!(condition, Condition::Unknown(_)), "{fixture}");
+        assert_eq!(
+            serde_json::to_value(&condition).unwrap(),
+            serde_json::from_str::<serde_json::Value>(fixture).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_action_wire_format() {
+    for fixture in ACTION_FIXTURES {
+        let action: Action = serde_json::from_str(fixture).unwrap();
+        assert_eq!(&serde_json::to_string(&action).unwrap(), fixture);
+        assert_eq!(serde_json::from_str::<Action>(fixture).unwrap(), action);
+    }
+
+    // Check a few fixtures are parsed as the expected variants, rather than
+    // e.g. falling back to `Unknown`.
+    let parse = |json: &str| serde_json::from_str::<Action>(json).unwrap();
+    assert_eq!(parse(r#""notify""#), Action::Notify);
+    assert_eq!(parse(r#""dont_notify""#), Action::DontNotify);
+    assert_eq!(parse(r#""coalesce""#), Action::Coalesce);
+    assert!(matches!(
+        parse(r#"{"set_tweak":"highlight"}"#),
+        Action::SetTweak(_)
+    ));
+    assert!(matches!(
+        parse(r#"{"org.example.action":{"value":1}}"#),
+        Action::Unknown(_)
+    ));
+
+    // Unrecognized string actions are rejected rather than kept.
+    assert!(serde_json::from_str::<Action>(r#""org.example.action""#).is_err());
+}
@@ -12,7 +12,18 @@
 # See the License for the specific language governing permissions and
 # limitations under the License.
 
-from typing import Any, Collection, Dict, Mapping, Optional, Sequence, Tuple, Union
+from typing import (
+    Any,
+    Callable,
+    Collection,
+    Dict,
+    Iterable,
+    Mapping,
+    Optional,
+    Sequence,
+    Tuple,
+    Union,
+)
 
 from synapse.types import JsonDict, JsonValue
 
@@ -33,10 +44,12 @@ class PushRule:
     def from_db(
         rule_id: str, priority_class: int, conditions: str, actions: str
     ) -> "PushRule": ...
+    def normalize(self) -> "PushRule": ...
 
 class PushRules:
     def __init__(self, rules: Collection[PushRule]): ...
     def rules(self) -> Collection[PushRule]: ...
+    def normalize(self) -> "PushRules": ...
 
 class FilteredPushRules:
     def __init__(
@@ -49,8 +62,35 @@ class FilteredPushRules:
         msc4028_push_encrypted_events: bool,
     ): ...
     def rules(self) -> Collection[Tuple[PushRule, bool]]: ...
+    def normalize(self) -> "FilteredPushRules": ...
+    def precompiled(self) -> "FilteredPushRules": ...
+    def warm_glob_cache(self) -> Tuple[int, float]: ...
+    def condition_kinds(self) -> Collection[str]: ...
+    def with_server_rules(
+        self,
+        server_rules: Collection[PushRule],
+        non_disableable_rule_ids: Collection[str],
+    ) -> "FilteredPushRules": ...
+    @staticmethod
+    def merge(
+        server: "FilteredPushRules", user: "FilteredPushRules"
+    ) -> "FilteredPushRules": ...
 
 def get_base_rule_ids() -> Collection[str]: ...
+def get_base_rules(version: str) -> FilteredPushRules: ...
+
+class PushMetrics:
+    @property
+    def enabled(self) -> bool: ...
+    @property
+    def condition_counts(self) -> Mapping[str, int]: ...
+    @property
+    def condition_time_ns(self) -> Mapping[str, int]: ...
+    @property
+    def glob_compilations(self) -> int: ...
+
+def get_push_metrics() -> PushMetrics: ...
+def validate_rule(rule: PushRule) -> Sequence[str]: ...
 
 class PushRuleEvaluator:
     def __init__(
@@ -60,17 +100,196 @@ class PushRuleEvaluator:
         room_member_count: int,
         sender_power_level: Optional[int],
         notification_power_levels: Mapping[str, int],
-        related_events_flattened: Mapping[str, Mapping[str, JsonValue]],
+        related_events_flattened: Mapping[str, Sequence[Mapping[str, JsonValue]]],
         related_event_match_enabled: bool,
         room_version_feature_flags: Tuple[str, ...],
         msc3931_enabled: bool,
+        *,
+        room_notifications_today: Optional[int] = None,
+        sender_event_rate: Optional[int] = None,
+        condition_match_budget: Optional[int] = None,
+        sender_verified: bool = False,
+        room_name: Optional[str] = None,
+        device_type: Optional[str] = None,
+        body_sentiment_enabled: bool = False,
+        recent_user_messages: Mapping[str, Sequence[str]] = {},
+        recent_message_hashes: Sequence[str] = (),
+        is_thread_start: bool = False,
+        prev_content_flattened: Mapping[str, JsonValue] = {},
+        negate_unknown_conditions: bool = False,
+        event_origin_ts: Optional[int] = None,
+        now_ts: Optional[int] = None,
+        msc3952_intentional_mentions: bool = False,
+        relation_counts: Mapping[str, int] = {},
+        relation_count_enabled: bool = False,
+        unknown_condition_matches: bool = False,
+        user_memberships: Mapping[str, str] = {},
+        default_notification_power_level: int = 50,
+        display_name_max_edit_distance: Optional[int] = None,
+        thread_depth: Optional[int] = None,
+        max_rules_evaluated: int = 10000,
+        aggregations: Mapping[str, Mapping[str, int]] = {},
+        aggregation_match_enabled: bool = False,
+        sender_display_name: Optional[str] = None,
+        dm_user_ids: Sequence[str] = (),
+        server_notices_user_id: Optional[str] = None,
+        unread_notification_counts: Mapping[str, int] = {},
+        unread_highlight_counts: Mapping[str, int] = {},
+        max_body_match_length: int = 65536,
+        user_power_levels: Mapping[str, int] = {},
+        room_tags: Mapping[str, Sequence[str]] = {},
+        unknown_sender_power_level: Union[int, str] = "deny",
+        confusable_display_names: bool = False,
     ): ...
+    def inputs_to_json(self) -> str: ...
+    @staticmethod
+    def from_inputs_json(json: str) -> "PushRuleEvaluator": ...
     def run(
         self,
         push_rules: FilteredPushRules,
         user_id: Optional[str],
         display_name: Optional[str],
     ) -> Collection[Union[Mapping, str]]: ...
+    def run_with_matched_rule(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Optional[Tuple[str, Collection[Union[Mapping, str]]]]: ...
+    def run_with_disabled_rules(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+        disabled_rule_ids: Collection[str],
+    ) -> Collection[Union[Mapping, str]]: ...
+    def has_notify_action(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> bool: ...
+    def should_highlight(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> bool: ...
+    def run_raw(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Collection[Union[Mapping, str]]: ...
+    def run_resolved(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Collection[Union[Mapping, str]]: ...
+    def run_canonical(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Collection[Union[Mapping, str]]: ...
+    def run_batch(
+        self,
+        events: Sequence[Tuple[Mapping[str, JsonValue], bool]],
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Collection[Collection[Union[Mapping, str]]]: ...
+    def run_top_n(
+        self,
+        n: int,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Collection[Tuple[str, Collection[Union[Mapping, str]]]]: ...
+    def run_all_matches(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Collection[Tuple[str, Collection[Union[Mapping, str]]]]: ...
+    def run_outcome(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> JsonDict: ...
+    def run_summary(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> JsonDict: ...
+    def run_tweaks(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Mapping[str, Optional[Union[str, int, bool]]]: ...
+    def run_timed(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Tuple[Collection[Union[Mapping, str]], float, Optional[str]]: ...
+    def run_with_stats(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Tuple[Collection[Union[Mapping, str]], int, int]: ...
+    def run_strict(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Tuple[Collection[Union[Mapping, str]], Sequence[str]]: ...
+    def run_strict_conditions(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Collection[Union[Mapping, str]]: ...
+    def explain(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> JsonDict: ...
+    def dry_run(
+        self,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Sequence[JsonDict]: ...
+    def explain_non_match(
+        self,
+        rule_id: str,
+        push_rules: FilteredPushRules,
+        user_id: Optional[str],
+        display_name: Optional[str],
+    ) -> Optional[JsonDict]: ...
     def matches(
         self, condition: JsonDict, user_id: Optional[str], display_name: Optional[str]
     ) -> bool: ...
+    def match_single_condition(
+        self, condition: JsonDict, user_id: Optional[str], display_name: Optional[str]
+    ) -> bool: ...
+    def set_display_name_preprocessor(self, callback: Callable[[str], str]) -> None: ...
+    def set_sender_power_level(self, sender_power_level: Optional[int]) -> None: ...
+    def set_notification_power_levels(
+        self, notification_power_levels: Mapping[str, int]
+    ) -> None: ...
+    def register_custom_condition(
+        self,
+        kind: str,
+        callback: Callable[[JsonDict, Optional[str], Optional[str]], bool],
+    ) -> None: ...
+    def set_related_events_source(
+        self, callback: Callable[[str], Iterable[Mapping[str, JsonValue]]]
+    ) -> None: ...
@@ -332,6 +332,39 @@ class ExperimentalConfig(Config):
         # MSC3664: Pushrules to match on related events
         self.msc3664_enabled: bool = experimental.get("msc3664_enabled", False)
 
+        # MSC3952: the unstable intentional mentions push rule conditions.
+        self.msc3952_intentional_mentions: bool = experimental.get(
+            "msc3952_intentional_mentions", False
+        )
+
+        # Push rules on the estimated sentiment of a message's body.
+        self.body_sentiment_push_rules_enabled: bool = experimental.get(
+            "body_sentiment_push_rules_enabled", False
+        )
+
+        # If set, `contains_display_name` push rule conditions also match words
+        # within this edit distance of the display name, e.g. typos of it.
+        self.display_name_push_rules_max_edit_distance: Optional[int] = (
+            experimental.get("display_name_push_rules_max_edit_distance")
+        )
+
+        # Whether `contains_display_name` push rule conditions also match
+        # lookalikes of the display name, which use confusable characters.
+        self.confusable_display_name_push_rules_enabled: bool = experimental.get(
+            "confusable_display_name_push_rules_enabled", False
+        )
+
+        # Push rules on the number of events related to the event an event relates
+        # to, e.g. the number of replies in a thread.
+        self.relation_count_push_rules_enabled: bool = experimental.get(
+            "relation_count_push_rules_enabled", False
+        )
+
+        # Push rules on the reactions to the event an event relates to.
+        self.aggregation_push_rules_enabled: bool = experimental.get(
+            "aggregation_push_rules_enabled", False
+        )
+
         # MSC3848: Introduce errcodes for specific event sending failures
         self.msc3848_enabled: bool = experimental.get("msc3848_enabled", False)
 
@@ -13,6 +13,7 @@
 # See the License for the specific language governing permissions and
 # limitations under the License.
 
+import hashlib
 import logging
 from typing import (
     TYPE_CHECKING,
@@ -23,6 +24,7 @@ from typing import (
     Mapping,
     Optional,
     Sequence,
+    Set,
     Tuple,
     Union,
     cast,
@@ -34,6 +36,7 @@ from twisted.internet.defer import Deferred
 
 from synapse.api.constants import (
     MAIN_TIMELINE,
+    AccountDataTypes,
     EventContentFields,
     EventTypes,
     Membership,
@@ -76,6 +79,15 @@ STATE_EVENT_TYPES_TO_MARK_UNREAD = {
     EventTypes.Tombstone,
 }
 
+# The period over which the sender's event rate is counted, and the number of the
+# room's latest events which are looked at to count it.
+SENDER_EVENT_RATE_PERIOD_MS = 60 * 1000
+SENDER_EVENT_RATE_MAX_EVENTS = 100
+
+# The number of the room's latest messages which are looked at for conditions on
+# recent messages.
+RECENT_MESSAGES_LIMIT = 100
+
 
 SENTINEL = object()
 
@@ -127,6 +139,24 @@ class BulkPushRuleEvaluator:
         self.should_calculate_push_rules = self.hs.config.push.enable_push
 
         self._related_event_match_enabled = self.hs.config.experimental.msc3664_enabled
+        self._intentional_mentions_enabled = (
+            self.hs.config.experimental.msc3952_intentional_mentions
+        )
+        self._body_sentiment_enabled = (
+            self.hs.config.experimental.body_sentiment_push_rules_enabled
+        )
+        self._display_name_max_edit_distance = (
+            self.hs.config.experimental.display_name_push_rules_max_edit_distance
+        )
+        self._confusable_display_names = (
+            self.hs.config.experimental.confusable_display_name_push_rules_enabled
+        )
+        self._relation_count_enabled = (
+            self.hs.config.experimental.relation_count_push_rules_enabled
+        )
+        self._aggregation_match_enabled = (
+            self.hs.config.experimental.aggregation_push_rules_enabled
+        )
 
         self.room_push_rule_cache_metrics = register_cache(
             "cache",
@@ -262,13 +292,13 @@ class BulkPushRuleEvaluator:
 
     async def _related_events(
         self, event: EventBase
-    ) -> Dict[str, Dict[str, JsonValue]]:
+    ) -> Dict[str, List[Dict[str, JsonValue]]]:
         """Fetches the related events for 'event'. Sets the im.vector.is_falling_back key if the event is from a fallback relation
 
         Returns:
-            Mapping of relation type to flattened events.
+            Mapping of relation type to the flattened related events of that type.
         """
-        related_events: Dict[str, Dict[str, JsonValue]] = {}
+        related_events: Dict[str, List[Dict[str, JsonValue]]] = {}
         if self._related_event_match_enabled:
             related_event_id = event.content.get("m.relates_to", {}).get("event_id")
             relation_type = event.content.get("m.relates_to", {}).get("rel_type")
@@ -277,7 +307,7 @@ class BulkPushRuleEvaluator:
                     related_event_id, allow_none=True
                 )
                 if related_event is not None:
-                    related_events[relation_type] = _flatten_dict(related_event)
+                    related_events[relation_type] = [_flatten_dict(related_event)]
 
             reply_event_id = (
                 event.content.get("m.relates_to", {})
@@ -292,18 +322,398 @@ class BulkPushRuleEvaluator:
                 )
 
                 if related_event is not None:
-                    related_events["m.in_reply_to"] = _flatten_dict(related_event)
+                    reply_event = _flatten_dict(related_event)
 
                     # indicate that this is from a fallback relation.
                     if relation_type == "m.thread" and event.content.get(
                         "m.relates_to", {}
                     ).get("is_falling_back", False):
-                        related_events["m.in_reply_to"][
-                            "im.vector.is_falling_back"
-                        ] = ""
+                        reply_event["im.vector.is_falling_back"] = ""
+
+                    related_events["m.in_reply_to"] = [reply_event]
 
         return related_events
 
+    async def _is_sender_device_verified(self, event: EventBase) -> bool:
+        """Whether the device which sent 'event' is signed by its user's
+        self-signing key.
+
+        The sending device is only known for events sent by local users, so events
+        from other servers are never from a verified device.
+        """
+        device_id = getattr(event.internal_metadata, "device_id", None)
+        if device_id is None:
+            return False
+
+        self_signing_key = await self.store.get_e2e_cross_signing_key(
+            event.sender, "self_signing"
+        )
+        if self_signing_key is None:
+            return False
+
+        devices = await self.store.get_e2e_device_keys_and_signatures(
+            [(event.sender, device_id)]
+        )
+        device = devices.get(event.sender, {}).get(device_id)
+        if device is None or device.keys is None:
+            return False
+
+        # Signatures are checked when they're uploaded, so it's enough that there
+        # is one by the self-signing key.
+        signatures = device.keys.get("signatures", {}).get(event.sender, {})
+        return any(key_id in signatures for key_id in self_signing_key["keys"])
+
+    async def _room_name(
+        self, event: EventBase, context: EventContext
+    ) -> Optional[str]:
+        """Gets the name of the room 'event' was sent to, if it has one."""
+        if event.internal_metadata.is_outlier():
+            return None
+
+        prev_state_ids = await context.get_prev_state_ids(
+            StateFilter.from_types([(EventTypes.Name, "")])
+        )
+        name_event_id = prev_state_ids.get((EventTypes.Name, ""))
+        if name_event_id is None:
+            return None
+
+        name_event = await self.store.get_event(name_event_id, allow_none=True)
+        if name_event is None:
+            return None
+
+        name = name_event.content.get("name")
+        return name if isinstance(name, str) else None
+
+    async def _recent_user_messages(
+        self, event: EventBase, user_ids: Collection[str]
+    ) -> Dict[str, List[str]]:
+        """Gets the bodies of the given users' recent messages in the room of
+        'event', latest first.
+        """
+        latest = await self.store.get_latest_event_ids_and_senders(
+            event.room_id, EventTypes.Message, RECENT_MESSAGES_LIMIT
+        )
+        messages = await self.store.get_events_as_list(
+            [event_id for event_id, sender in latest if sender in user_ids]
+        )
+
+        bodies: Dict[str, List[str]] = {}
+        for message in messages:
+            body = message.content.get("body")
+            if isinstance(body, str):
+                bodies.setdefault(message.sender, []).append(body)
+        return bodies
+
+    async def _recent_message_hashes(self, event: EventBase) -> List[str]:
+        """Gets the hashes of the bodies of the recent messages in the room of
+        'event', as computed by `message_body_hash` in the Rust code.
+        """
+        latest = await self.store.get_latest_event_ids_and_senders(
+            event.room_id, EventTypes.Message, RECENT_MESSAGES_LIMIT
+        )
+        messages = await self.store.get_events_as_list(
+            [event_id for event_id, _ in latest]
+        )
+
+        hashes: Set[str] = set()
+        for message in messages:
+            body = message.content.get("body")
+            if isinstance(body, str):
+                hashes.add(
+                    hashlib.blake2s(body.strip().lower().encode("utf-8")).hexdigest()
+                )
+        return list(hashes)
+
+    async def _prev_membership_content(
+        self, event: EventBase, context: EventContext
+    ) -> Optional[Dict[str, JsonValue]]:
+        """Gets the flattened content of the membership event replaced by the
+        membership event 'event', if known.
+        """
+        if event.internal_metadata.is_outlier():
+            return None
+
+        state_key = (EventTypes.Member, event.state_key)
+        prev_state_ids = await context.get_prev_state_ids(
+            StateFilter.from_types([state_key])
+        )
+        prev_event_id = prev_state_ids.get(state_key)
+        if prev_event_id is None:
+            # The user has never been in the room.
+            return {"membership": Membership.LEAVE}
+
+        prev_event = await self.store.get_event(prev_event_id, allow_none=True)
+        if prev_event is None:
+            return None
+        return _flatten_dict(prev_event.content)
+
+    async def _sender_display_name(
+        self, event: EventBase, context: EventContext
+    ) -> Optional[str]:
+        """Gets the display name of the sender of 'event' in its room, before the
+        event, if they have one.
+        """
+        if event.internal_metadata.is_outlier():
+            return None
+
+        state_key = (EventTypes.Member, event.sender)
+        prev_state_ids = await context.get_prev_state_ids(
+            StateFilter.from_types([state_key])
+        )
+        member_event_id = prev_state_ids.get(state_key)
+        if member_event_id is None:
+            return None
+
+        member_event = await self.store.get_event(member_event_id, allow_none=True)
+        if member_event is None:
+            return None
+        display_name = member_event.content.get("displayname")
+        return display_name if isinstance(display_name, str) else None
+
+    async def _user_memberships(
+        self, event: EventBase, context: EventContext, user_ids: Collection[str]
+    ) -> Dict[str, str]:
+        """Gets the memberships of the given users in the room of 'event', before
+        the event.
+        """
+        if event.internal_metadata.is_outlier():
+            return {}
+
+        prev_state_ids = await context.get_prev_state_ids(
+            StateFilter.from_types((EventTypes.Member, user_id) for user_id in user_ids)
+        )
+        memberships = await self.store.get_membership_from_event_ids(
+            prev_state_ids.values()
+        )
+
+        # Users without a membership event have never been in the room.
+        result = {user_id: Membership.LEAVE for user_id in user_ids}
+        for membership in memberships.values():
+            if membership is not None:
+                result[membership.user_id] = membership.membership
+        return result
+
+    async def _is_direct_message_for_user(self, room_id: str, user_id: str) -> bool:
+        """Whether the room is listed as a direct message in the user's `m.direct`
+        account data.
+        """
+        direct_rooms = await self.store.get_global_account_data_by_type_for_user(
+            user_id, AccountDataTypes.DIRECT
+        )
+        if not isinstance(direct_rooms, Mapping):
+            return False
+
+        return any(
+            isinstance(room_ids, (list, tuple)) and room_id in room_ids
+            for room_ids in direct_rooms.values()
+        )
+
+    async def _unread_counts(
+        self, room_id: str, user_ids: Collection[str]
+    ) -> Tuple[Dict[str, int], Dict[str, int]]:
+        """Gets the number of unread notifications and highlights of the given users
+        in the room, across the main timeline and all threads.
+
+        Returns:
+            A tuple of the notification counts and the highlight counts, by user ID.
+        """
+        notification_counts = {}
+        highlight_counts = {}
+        for user_id in user_ids:
+            counts = await self.store.get_unread_event_push_actions_by_room_for_user(
+                room_id, user_id
+            )
+            all_counts = [counts.main_timeline, *counts.threads.values()]
+            notification_counts[user_id] = sum(c.notify_count for c in all_counts)
+            highlight_counts[user_id] = sum(c.highlight_count for c in all_counts)
+        return notification_counts, highlight_counts
+
+    async def _evaluator_inputs(
+        self,
+        event: EventBase,
+        context: EventContext,
+        power_levels: Mapping[str, Any],
+        users_by_condition_kind: Mapping[str, Collection[str]],
+    ) -> Dict[str, Any]:
+        """Gathers the optional inputs of the push rule evaluator which are
+        needed to evaluate the push rules of the recipients of 'event'.
+
+        Args:
+            event: The event being evaluated.
+            context: The event's context.
+            power_levels: The content of the room's power levels before the event.
+            users_by_condition_kind: The recipients whose push rules use each kind
+                of condition.
+
+        Returns:
+            The keyword arguments to pass to `PushRuleEvaluator`.
+        """
+        inputs: Dict[str, Any] = {}
+        relation = relation_from_event(event)
+
+        if "io.element.room_notifications_today" in users_by_condition_kind:
+            now = self.clock.time_msec()
+            midnight = now - now % (24 * 60 * 60 * 1000)
+            count = await self.store.count_room_notifications_since(
+                event.room_id, midnight
+            )
+            inputs["room_notifications_today"] = count
+
+        if "io.element.sender_event_rate" in users_by_condition_kind:
+            count = await self.store.count_recent_events_by_sender(
+                event.room_id,
+                event.sender,
+                self.clock.time_msec() - SENDER_EVENT_RATE_PERIOD_MS,
+                SENDER_EVENT_RATE_MAX_EVENTS,
+            )
+            inputs["sender_event_rate"] = count
+
+        if "io.element.sender_verified" in users_by_condition_kind:
+            inputs["sender_verified"] = await self._is_sender_device_verified(event)
+
+        if (
+            self._body_sentiment_enabled
+            and "io.element.body_sentiment" in users_by_condition_kind
+        ):
+            inputs["body_sentiment_enabled"] = True
+
+        if "contains_display_name" in users_by_condition_kind:
+            if self._display_name_max_edit_distance is not None:
+                inputs["display_name_max_edit_distance"] = (
+                    self._display_name_max_edit_distance
+                )
+            if self._confusable_display_names:
+                inputs["confusable_display_names"] = True
+
+        if "io.element.mentions_room_name" in users_by_condition_kind:
+            inputs["room_name"] = await self._room_name(event, context)
+
+        if "io.element.quotes_me" in users_by_condition_kind:
+            inputs["recent_user_messages"] = await self._recent_user_messages(
+                event, users_by_condition_kind["io.element.quotes_me"]
+            )
+
+        if "io.element.is_duplicate_message" in users_by_condition_kind:
+            inputs["recent_message_hashes"] = await self._recent_message_hashes(event)
+
+        if "io.element.is_thread_start" in users_by_condition_kind:
+            if relation and relation.rel_type == RelationTypes.THREAD:
+                # The event isn't persisted yet, so it starts the thread if there
+                # aren't any other replies.
+                summaries = await self.store.get_thread_summaries([relation.parent_id])
+                inputs["is_thread_start"] = not summaries.get(relation.parent_id)
+
+        if (
+            "io.element.thread_depth" in users_by_condition_kind
+            and relation
+            and relation.rel_type == RelationTypes.THREAD
+        ):
+            # Threads are flat: every event in a thread relates to its root.
+            inputs["thread_depth"] = 1
+
+        if (
+            "io.element.membership_transition" in users_by_condition_kind
+            and event.type == EventTypes.Member
+        ):
+            prev_content = await self._prev_membership_content(event, context)
+            if prev_content is not None:
+                inputs["prev_content_flattened"] = prev_content
+
+        if "io.element.event_age_less_than" in users_by_condition_kind:
+            # Events from other servers may have any timestamp, so ignore those
+            # which can't be an age.
+            origin_ts = event.origin_server_ts
+            if type(origin_ts) is int and origin_ts >= 0:  # noqa: E721
+                inputs["event_origin_ts"] = origin_ts
+                inputs["now_ts"] = self.clock.time_msec()
+
+        if (
+            self._relation_count_enabled
+            and "io.element.relation_count" in users_by_condition_kind
+            and relation
+        ):
+            # Nothing relates to the event yet, so count the relations of its
+            # parent, including the event.
+            counts = await self.store.get_relation_counts(relation.parent_id)
+            counts[relation.rel_type] = counts.get(relation.rel_type, 0) + 1
+            inputs["relation_counts"] = counts
+            inputs["relation_count_enabled"] = True
+
+        if (
+            self._aggregation_match_enabled
+            and "io.element.has_aggregation" in users_by_condition_kind
+            and relation
+        ):
+            # As above, include the event in its parent's annotations.
+            annotations = await self.store.get_annotation_counts(relation.parent_id)
+            key = relation.aggregation_key
+            if relation.rel_type == RelationTypes.ANNOTATION and key:
+                annotations[key] = annotations.get(key, 0) + 1
+            inputs["aggregations"] = {RelationTypes.ANNOTATION: annotations}
+            inputs["aggregation_match_enabled"] = True
+
+        if self._intentional_mentions_enabled and (
+            "org.matrix.msc3952.is_user_mention" in users_by_condition_kind
+            or "org.matrix.msc3952.is_room_mention" in users_by_condition_kind
+        ):
+            inputs["msc3952_intentional_mentions"] = True
+
+        if "io.element.user_power_level" in users_by_condition_kind:
+            user_levels = power_levels.get("users", {})
+            inputs["user_power_levels"] = {}
+            if isinstance(user_levels, Mapping):
+                # As with the notification levels, old room versions may have
+                # non-integer power levels.
+                for user_id, level in user_levels.items():
+                    try:
+                        inputs["user_power_levels"][user_id] = int(level)
+                    except (TypeError, ValueError):
+                        pass
+
+        if "io.element.sender_is_server_notices" in users_by_condition_kind:
+            inputs["server_notices_user_id"] = (
+                self.hs.config.servernotices.server_notices_mxid
+            )
+
+        if "io.element.sender_display_name_match" in users_by_condition_kind:
+            inputs["sender_display_name"] = await self._sender_display_name(
+                event, context
+            )
+
+        if "io.element.user_membership" in users_by_condition_kind:
+            inputs["user_memberships"] = await self._user_memberships(
+                event, context, users_by_condition_kind["io.element.user_membership"]
+            )
+
+        if "io.element.is_direct_message" in users_by_condition_kind:
+            inputs["dm_user_ids"] = [
+                user_id
+                for user_id in users_by_condition_kind["io.element.is_direct_message"]
+                if await self._is_direct_message_for_user(event.room_id, user_id)
+            ]
+
+        if "io.element.unread_count" in users_by_condition_kind:
+            (
+                inputs["unread_notification_counts"],
+                inputs["unread_highlight_counts"],
+            ) = await self._unread_counts(
+                event.room_id, users_by_condition_kind["io.element.unread_count"]
+            )
+
+        if "io.element.room_has_tag" in users_by_condition_kind:
+            inputs["room_tags"] = {
+                user_id: list(
+                    (await self.store.get_tags_for_user(user_id)).get(event.room_id, {})
+                )
+                for user_id in users_by_condition_kind["io.element.room_has_tag"]
+            }
+
+        # The device type isn't passed: the actions are stored once per user and
+        # then pushed to all of their devices, so device_type_match conditions
+        # never match.
+
+        return inputs
+
     async def action_for_events_by_user(
         self, events_and_context: List[Tuple[EventBase, EventContext]]
     ) -> None:
@@ -362,7 +772,7 @@ class BulkPushRuleEvaluator:
             profiles,
         ) = await make_deferred_yieldable(
             cast(
-                "Deferred[Tuple[int, Tuple[dict, Optional[int]], Dict[str, Dict[str, JsonValue]], Mapping[str, ProfileInfo]]]",
+                "Deferred[Tuple[int, Tuple[dict, Optional[int]], Dict[str, List[Dict[str, JsonValue]]], Mapping[str, ProfileInfo]]]",
                 gather_results(
                     (
                         run_in_background(  # type: ignore[call-arg]
@@ -419,6 +829,15 @@ class BulkPushRuleEvaluator:
         # Pull out any user and room mentions.
         has_mentions = EventContentFields.MENTIONS in event.content
 
+        # Only gather the inputs for conditions which someone's rules use.
+        users_by_condition_kind: Dict[str, Set[str]] = {}
+        for uid, rules in rules_by_user.items():
+            for kind in rules.condition_kinds():
+                users_by_condition_kind.setdefault(kind, set()).add(uid)
+        inputs = await self._evaluator_inputs(
+            event, context, power_levels, users_by_condition_kind
+        )
+
         evaluator = PushRuleEvaluator(
             _flatten_dict(event),
             has_mentions,
@@ -429,6 +848,7 @@ class BulkPushRuleEvaluator:
             self._related_event_match_enabled,
             event.room_version.msc3931_push_features,
             self.hs.config.experimental.msc1767_enabled,  # MSC3931 flag
+            **inputs,
         )
 
         for uid, rules in rules_by_user.items():
@@ -515,6 +935,8 @@ def _flatten_dict(
     flatten it into a single layer dictionary by combining the keys & sub-keys.
 
     String, integer, boolean, null or lists of those values are kept. All others are dropped.
+    Push rules can match elements of the lists by index, e.g. "foo.0", see
+    `get_flattened_value` in the Rust code.
 
     Transforms:
 
@@ -547,6 +969,11 @@ def _flatten_dict(
         elif isinstance(value, (list, tuple)):
             result[".".join(prefix + [key])] = [v for v in value if _is_simple_value(v)]
         elif isinstance(value, Mapping):
+            if prefix == ["content"] and key == "org\\.matrix\\.msc3245\\.voice":
+                # The MSC3245 voice message marker is usually an empty object, which
+                # leaves no keys behind once flattened. Record its presence so that
+                # the `is_voice_message` condition can match.
+                result[".".join(prefix + [key])] = ""
             # do not set `room_version` due to recursion considerations below
             _flatten_dict(value, prefix=(prefix + [key]), result=result)
 
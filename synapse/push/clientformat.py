@@ -65,11 +65,14 @@ def format_push_rules_for_user(
 
 def _convert_type_to_value(rule_or_cond: Dict[str, Any], user: UserID) -> None:
     for type_key in ("pattern", "value"):
-        type_value = rule_or_cond.pop(f"{type_key}_type", None)
+        type_value = rule_or_cond.get(f"{type_key}_type")
         if type_value == "user_id":
+            del rule_or_cond[f"{type_key}_type"]
             rule_or_cond[type_key] = user.to_string()
         elif type_value == "user_localpart":
+            del rule_or_cond[f"{type_key}_type"]
             rule_or_cond[type_key] = user.localpart
+        # Other types (e.g. "sender") depend on the event, so are left as is.
 
 
 def _add_empty_priority_class_arrays(d: Dict[str, list]) -> Dict[str, list]:
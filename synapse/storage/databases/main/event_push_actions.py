@@ -1075,6 +1075,38 @@ class EventPushActionsWorkerStore(ReceiptsWorkerStore, StreamWorkerStore, SQLBas
             _get_if_maybe_push_in_range_for_user_txn,
         )
 
+    async def count_room_notifications_since(self, room_id: str, since_ts: int) -> int:
+        """Counts the events in the room received since the given time which
+        notified at least one user.
+
+        Push actions are only kept for a day once they have been rotated, so
+        `since_ts` should be less than a day ago.
+
+        Args:
+            room_id: The room to count notifications in.
+            since_ts: The timestamp (in ms) to count from.
+
+        Returns:
+            The number of events which notified someone.
+        """
+
+        def _count_room_notifications_since_txn(txn: LoggingTransaction) -> int:
+            sql = """
+                SELECT COUNT(DISTINCT ep.event_id)
+                FROM event_push_actions AS ep
+                JOIN events AS e USING (room_id, event_id)
+                WHERE ep.room_id = ? AND ep.stream_ordering > ? AND ep.notif = 1
+                    AND e.received_ts >= ?
+            """
+
+            txn.execute(sql, (room_id, self.stream_ordering_day_ago or 0, since_ts))
+            return cast(Tuple[int], txn.fetchone())[0]
+
+        return await self.db_pool.runInteraction(
+            "count_room_notifications_since",
+            _count_room_notifications_since_txn,
+        )
+
     async def add_push_actions_to_staging(
         self,
         event_id: str,
@@ -1726,6 +1726,65 @@ class EventsWorkerStore(SQLBaseStore):
 
         return {"v1": complexity_v1}
 
+    async def count_recent_events_by_sender(
+        self, room_id: str, sender: str, since_ts: int, limit: int
+    ) -> int:
+        """
+        Counts the events the sender sent in the room since the given time,
+        looking at no more than the latest `limit` events in the room.
+
+        Args:
+            room_id: The room ID to query.
+            sender: The user ID of the sender.
+            since_ts: The timestamp (in ms) to count from.
+            limit: The number of the room's latest events to look at.
+
+        Returns:
+            The number of events sent by the sender.
+        """
+
+        def _count_recent_events_by_sender_txn(txn: LoggingTransaction) -> int:
+            sql = """
+                SELECT COUNT(*) FROM (
+                    SELECT sender, received_ts FROM events
+                    WHERE room_id = ?
+                    ORDER BY stream_ordering DESC
+                    LIMIT ?
+                ) AS recent
+                WHERE sender = ? AND received_ts >= ?
+            """
+            txn.execute(sql, (room_id, limit, sender, since_ts))
+            return cast(Tuple[int], txn.fetchone())[0]
+
+        return await self.db_pool.runInteraction(
+            "count_recent_events_by_sender", _count_recent_events_by_sender_txn
+        )
+
+    async def get_latest_event_ids_and_senders(
+        self, room_id: str, event_type: str, limit: int
+    ) -> List[Tuple[str, str]]:
+        """
+        Gets the latest events of the given type in the room.
+
+        Args:
+            room_id: The room ID to query.
+            event_type: The type of events to get.
+            limit: The maximum number of events to get.
+
+        Returns:
+            The event IDs and senders of the events, latest first.
+        """
+        sql = """
+            SELECT event_id, sender FROM events
+            WHERE room_id = ? AND type = ?
+            ORDER BY stream_ordering DESC
+            LIMIT ?
+        """
+        rows = await self.db_pool.execute(
+            "get_latest_event_ids_and_senders", sql, room_id, event_type, limit
+        )
+        return [(event_id, sender) for event_id, sender in rows]
+
     async def get_all_new_forward_event_rows(
         self, instance_name: str, last_id: int, current_id: int, limit: int
     ) -> List[Tuple[int, str, str, str, str, str, str, str, bool, bool]]:
@@ -465,6 +465,43 @@ class RelationsWorkerStore(SQLBaseStore):
         return result is not None
 
     @cached()  # type: ignore[synapse-@cached-mutable]
+    async def get_relation_counts(self, event_id: str) -> Dict[str, int]:
+        """Get the number of events related to the given event.
+
+        Args:
+            event_id: The event to count the relations of.
+
+        Returns:
+            A map of relation type to the number of events related by it.
+        """
+        sql = """
+            SELECT relation_type, COUNT(*) FROM event_relations
+            WHERE relates_to_id = ?
+            GROUP BY relation_type
+        """
+        rows = await self.db_pool.execute("get_relation_counts", sql, event_id)
+        return {relation_type: count for relation_type, count in rows}
+
+    async def get_annotation_counts(self, event_id: str) -> Dict[str, int]:
+        """Get the number of each annotation (e.g. reaction) of the given event.
+
+        Args:
+            event_id: The event to count the annotations of.
+
+        Returns:
+            A map of annotation key to the number of events annotating with it.
+        """
+        sql = """
+            SELECT aggregation_key, COUNT(*) FROM event_relations
+            WHERE relates_to_id = ? AND relation_type = ?
+                AND aggregation_key IS NOT NULL
+            GROUP BY aggregation_key
+        """
+        rows = await self.db_pool.execute(
+            "get_annotation_counts", sql, event_id, RelationTypes.ANNOTATION
+        )
+        return {key: count for key, count in rows}
+
     async def get_references_for_event(self, event_id: str) -> List[JsonDict]:
         raise NotImplementedError()
 
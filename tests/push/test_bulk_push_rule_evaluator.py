@@ -19,12 +19,17 @@ from parameterized import parameterized
 
 from twisted.test.proto_helpers import MemoryReactor
 
-from synapse.api.constants import EventContentFields, RelationTypes
+from synapse.api.constants import EventContentFields, EventTypes, RelationTypes
 from synapse.api.room_versions import RoomVersions
 from synapse.push.bulk_push_rule_evaluator import BulkPushRuleEvaluator
 from synapse.rest import admin
 from synapse.rest.client import login, register, room
 from synapse.server import HomeServer
+from synapse.storage.databases.main.end_to_end_keys import DeviceKeyLookupResult
+from synapse.storage.databases.main.event_push_actions import (
+    NotifCounts,
+    RoomNotifCounts,
+)
 from synapse.types import JsonDict, create_requester
 from synapse.util import Clock
 
@@ -437,3 +442,776 @@ class TestBulkPushRuleEvaluator(HomeserverTestCase):
                 },
             )
         )
+
+    def test_room_notifications_today(self) -> None:
+        """The number of notifications in the room today is passed to the evaluator
+        if a push rule needs it."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "quiet_room",
+                priority_class=5,
+                conditions=[
+                    {"kind": "io.element.room_notifications_today", "is": "<3"}
+                ],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        # Nothing has notified in the room yet.
+        self.assertTrue(self._create_and_process(bulk_evaluator))
+
+        with patch.object(
+            self.hs.get_datastores().main,
+            "count_room_notifications_since",
+            new=AsyncMock(return_value=3),
+        ) as count:
+            self.assertFalse(self._create_and_process(bulk_evaluator))
+
+        # The count starts at midnight.
+        now = self.clock.time_msec()
+        count.assert_called_once_with(self.room_id, now - now % (24 * 60 * 60 * 1000))
+
+    def test_sender_event_rate(self) -> None:
+        """The number of events the sender recently sent to the room is passed to
+        the evaluator if a push rule needs it."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "flood",
+                priority_class=5,
+                conditions=[{"kind": "io.element.sender_event_rate", "is": "<10"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        # The sender hasn't sent anything yet.
+        self.assertTrue(self._create_and_process(bulk_evaluator))
+
+        with patch.object(
+            self.hs.get_datastores().main,
+            "count_recent_events_by_sender",
+            new=AsyncMock(return_value=20),
+        ):
+            self.assertFalse(self._create_and_process(bulk_evaluator))
+
+    def test_sender_verified(self) -> None:
+        """Whether the sending device is cross-signed is passed to the evaluator if a
+        push rule needs it."""
+        store = self.hs.get_datastores().main
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "verified",
+                priority_class=5,
+                conditions=[{"kind": "io.element.sender_verified"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        # The sending device isn't known.
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+
+        bob = f"@bob:{self.hs.hostname}"
+        self.requester = create_requester(self.alice, device_id="DEVICE")
+        self_signing_key = {"user_id": bob, "keys": {"ed25519:ssk": "ssk"}}
+        with patch.object(
+            store,
+            "get_e2e_cross_signing_key",
+            new=AsyncMock(return_value=self_signing_key),
+        ):
+            for signatures, verified in (({}, False), ({"ed25519:ssk": "sig"}, True)):
+                device_keys = {
+                    bob: {
+                        "DEVICE": DeviceKeyLookupResult(
+                            None, {"signatures": {bob: signatures}}
+                        )
+                    }
+                }
+                with patch.object(
+                    store,
+                    "get_e2e_device_keys_and_signatures",
+                    new=AsyncMock(return_value=device_keys),
+                ):
+                    self.assertEqual(self._create_and_process(bulk_evaluator), verified)
+
+    @override_config(
+        {"experimental_features": {"body_sentiment_push_rules_enabled": True}}
+    )
+    def test_body_sentiment(self) -> None:
+        """The body sentiment condition is evaluated if enabled."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "negative",
+                priority_class=5,
+                conditions=[
+                    {"kind": "io.element.body_sentiment", "polarity": "negative"}
+                ],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertFalse(
+            self._create_and_process(
+                bulk_evaluator, {"body": "thanks, that was wonderful"}
+            )
+        )
+        self.assertTrue(
+            self._create_and_process(
+                bulk_evaluator, {"body": "this is awful, you are pathetic"}
+            )
+        )
+
+    def test_body_sentiment_disabled(self) -> None:
+        """The body sentiment condition never matches unless enabled."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "negative",
+                priority_class=5,
+                conditions=[
+                    {"kind": "io.element.body_sentiment", "polarity": "negative"}
+                ],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertFalse(
+            self._create_and_process(
+                bulk_evaluator, {"body": "this is awful, you are pathetic"}
+            )
+        )
+
+    @override_config(
+        {"experimental_features": {"display_name_push_rules_max_edit_distance": 1}}
+    )
+    def test_display_name_max_edit_distance(self) -> None:
+        """Typos of the recipient's display name match if enabled."""
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertTrue(self._create_and_process(bulk_evaluator, {"body": "hi alise"}))
+        self.assertFalse(
+            self._create_and_process(bulk_evaluator, {"body": "hi alyse!"})
+        )
+
+    def test_display_name_max_edit_distance_disabled(self) -> None:
+        """Typos of the recipient's display name don't match unless enabled."""
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertFalse(self._create_and_process(bulk_evaluator, {"body": "hi alise"}))
+
+    @override_config(
+        {"experimental_features": {"confusable_display_name_push_rules_enabled": True}}
+    )
+    def test_confusable_display_names(self) -> None:
+        """Lookalikes of the recipient's display name match if enabled."""
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        # With a Cyrillic "а".
+        self.assertTrue(
+            self._create_and_process(bulk_evaluator, {"body": "hi \u0430lice"})
+        )
+
+    def test_confusable_display_names_disabled(self) -> None:
+        """Lookalikes of the recipient's display name don't match unless enabled."""
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertFalse(
+            self._create_and_process(bulk_evaluator, {"body": "hi \u0430lice"})
+        )
+
+    def test_mentions_room_name(self) -> None:
+        """The room's name is passed to the evaluator if a push rule needs it."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "room_name",
+                priority_class=5,
+                conditions=[{"kind": "io.element.mentions_room_name"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        # The room doesn't have a name yet.
+        self.assertFalse(
+            self._create_and_process(bulk_evaluator, {"body": "Welcome to the club"})
+        )
+
+        self.helper.send_state(
+            self.room_id, "m.room.name", {"name": "Coffee Club"}, tok=self.token
+        )
+        self.assertTrue(
+            self._create_and_process(bulk_evaluator, {"body": "Hi coffee club!"})
+        )
+        self.assertFalse(
+            self._create_and_process(bulk_evaluator, {"body": "Welcome to the club"})
+        )
+
+    def test_quotes_me(self) -> None:
+        """The recipients' recent messages are passed to the evaluator if their push
+        rules need them."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "quotes_me",
+                priority_class=5,
+                conditions=[{"kind": "io.element.quotes_me"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.helper.send(self.room_id, body="shall we get lunch?", tok=self.token)
+
+        self.assertTrue(
+            self._create_and_process(
+                bulk_evaluator, {"body": "> shall we get lunch?\n\nsounds good"}
+            )
+        )
+        self.assertFalse(
+            self._create_and_process(
+                bulk_evaluator, {"body": "> shall we get dinner?\n\nsounds good"}
+            )
+        )
+
+    def test_is_duplicate_message(self) -> None:
+        """The hashes of the room's recent messages are passed to the evaluator if a
+        push rule needs them."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "duplicate",
+                priority_class=5,
+                conditions=[{"kind": "io.element.is_duplicate_message"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.helper.send(self.room_id, body="Buy cheap stuff!", tok=self.token)
+
+        # The body is trimmed and lowercased before being compared.
+        self.assertTrue(
+            self._create_and_process(bulk_evaluator, {"body": " buy CHEAP stuff! "})
+        )
+        self.assertFalse(
+            self._create_and_process(bulk_evaluator, {"body": "Buy cheap stuff?"})
+        )
+
+    def test_is_thread_start(self) -> None:
+        """Whether the event is the first reply in a thread is passed to the evaluator
+        if a push rule needs it."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "thread_start",
+                priority_class=5,
+                conditions=[{"kind": "io.element.is_thread_start"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        res = self.helper.send(self.room_id, body="root", tok=self.token)
+        root_id = res["event_id"]
+        thread_reply = {
+            "body": "reply",
+            "m.relates_to": {"rel_type": RelationTypes.THREAD, "event_id": root_id},
+        }
+
+        self.assertFalse(self._create_and_process(bulk_evaluator, {"body": "hi"}))
+        self.assertTrue(self._create_and_process(bulk_evaluator, thread_reply))
+
+        self.helper.send_event(
+            self.room_id, "m.room.message", content=thread_reply, tok=self.token
+        )
+        self.assertFalse(self._create_and_process(bulk_evaluator, thread_reply))
+
+    def test_thread_depth(self) -> None:
+        """The depth of the event in its thread is passed to the evaluator if a push
+        rule needs it."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "in_thread",
+                priority_class=5,
+                conditions=[{"kind": "io.element.thread_depth", "is": "1"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        res = self.helper.send(self.room_id, body="root", tok=self.token)
+        thread_reply = {
+            "body": "reply",
+            "m.relates_to": {
+                "rel_type": RelationTypes.THREAD,
+                "event_id": res["event_id"],
+            },
+        }
+
+        self.assertFalse(self._create_and_process(bulk_evaluator, {"body": "hi"}))
+        self.assertTrue(self._create_and_process(bulk_evaluator, thread_reply))
+
+    def test_membership_transition(self) -> None:
+        """The previous membership is passed to the evaluator if a push rule needs
+        it."""
+        store = self.hs.get_datastores().main
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "invite_accepted",
+                priority_class=5,
+                conditions=[
+                    {
+                        "kind": "io.element.membership_transition",
+                        "from": "invite",
+                        "to": "join",
+                    }
+                ],
+                actions=["notify"],
+            )
+        )
+
+        def _notified(event_id: str) -> bool:
+            rows = self.get_success(
+                store.db_pool.simple_select_list(
+                    table="event_push_actions",
+                    keyvalues={"event_id": event_id, "user_id": self.alice},
+                    retcols=("notif",),
+                )
+            )
+            return len(rows) > 0
+
+        bob = self.register_user("bob", "pass")
+        bob_token = self.login("bob", "pass")
+        self.helper.invite(self.room_id, self.alice, bob, tok=self.token)
+
+        join = self.helper.send_state(
+            self.room_id,
+            "m.room.member",
+            {"membership": "join"},
+            tok=bob_token,
+            state_key=bob,
+        )
+        self.assertTrue(_notified(join["event_id"]))
+
+        # A display name change isn't a transition from invite to join.
+        rename = self.helper.send_state(
+            self.room_id,
+            "m.room.member",
+            {"membership": "join", "displayname": "Bob"},
+            tok=bob_token,
+            state_key=bob,
+        )
+        self.assertFalse(_notified(rename["event_id"]))
+
+    def test_event_age_less_than(self) -> None:
+        """The event's timestamp and the current time are passed to the evaluator if
+        a push rule needs them."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "recent",
+                priority_class=5,
+                conditions=[{"kind": "io.element.event_age_less_than", "ms": 60000}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertTrue(self._create_and_process(bulk_evaluator))
+
+        # Pretend the event is processed two minutes after it was sent.
+        now = self.clock.time_msec()
+        with patch.object(bulk_evaluator, "clock") as clock:
+            clock.time_msec.return_value = now + 120000
+            self.assertFalse(self._create_and_process(bulk_evaluator))
+
+    @override_config(
+        {"experimental_features": {"relation_count_push_rules_enabled": True}}
+    )
+    def test_relation_count(self) -> None:
+        """The number of events related to the event's parent is passed to the
+        evaluator if a push rule needs it."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "second_reply",
+                priority_class=5,
+                conditions=[
+                    {
+                        "kind": "io.element.relation_count",
+                        "rel_type": "m.thread",
+                        "is": "2",
+                    }
+                ],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        res = self.helper.send(self.room_id, body="root", tok=self.token)
+        root_id = res["event_id"]
+        thread_reply = {
+            "body": "reply",
+            "m.relates_to": {"rel_type": RelationTypes.THREAD, "event_id": root_id},
+        }
+
+        # The event is counted.
+        self.assertFalse(self._create_and_process(bulk_evaluator, thread_reply))
+
+        self.helper.send_event(
+            self.room_id, "m.room.message", content=thread_reply, tok=self.token
+        )
+        self.assertTrue(self._create_and_process(bulk_evaluator, thread_reply))
+
+    def test_relation_count_disabled(self) -> None:
+        """Relation counts aren't passed unless enabled."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "first_reply",
+                priority_class=5,
+                conditions=[
+                    {
+                        "kind": "io.element.relation_count",
+                        "rel_type": "m.thread",
+                        "is": "1",
+                    }
+                ],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        res = self.helper.send(self.room_id, body="root", tok=self.token)
+        root_id = res["event_id"]
+        thread_reply = {
+            "body": "reply",
+            "m.relates_to": {"rel_type": RelationTypes.THREAD, "event_id": root_id},
+        }
+        self.assertFalse(self._create_and_process(bulk_evaluator, thread_reply))
+
+    @override_config(
+        {"experimental_features": {"msc3952_intentional_mentions": True}}
+    )
+    def test_msc3952_user_mention(self) -> None:
+        """The unstable MSC3952 conditions are evaluated if enabled."""
+        store = self.hs.get_datastores().main
+        # Disable the stable rule, which would notify too.
+        self.get_success(
+            store.set_push_rule_enabled(
+                self.alice, "global/override/.m.rule.is_user_mention", False, True
+            )
+        )
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "mentioned",
+                priority_class=5,
+                conditions=[{"kind": "org.matrix.msc3952.is_user_mention"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+        self.assertTrue(
+            self._create_and_process(
+                bulk_evaluator,
+                {EventContentFields.MENTIONS: {"user_ids": [self.alice]}},
+            )
+        )
+
+    def test_msc3952_user_mention_disabled(self) -> None:
+        """The unstable MSC3952 conditions never match unless enabled."""
+        store = self.hs.get_datastores().main
+        # Disable the stable rule, which would notify too.
+        self.get_success(
+            store.set_push_rule_enabled(
+                self.alice, "global/override/.m.rule.is_user_mention", False, True
+            )
+        )
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "mentioned",
+                priority_class=5,
+                conditions=[{"kind": "org.matrix.msc3952.is_user_mention"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertFalse(
+            self._create_and_process(
+                bulk_evaluator,
+                {EventContentFields.MENTIONS: {"user_ids": [self.alice]}},
+            )
+        )
+
+    @override_config(
+        {"experimental_features": {"aggregation_push_rules_enabled": True}}
+    )
+    def test_has_aggregation(self) -> None:
+        """The reactions to the event's parent are passed to the evaluator if a push
+        rule needs them."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "thumbs_up",
+                priority_class=5,
+                conditions=[
+                    {
+                        "kind": "io.element.has_aggregation",
+                        "rel_type": RelationTypes.ANNOTATION,
+                        "key": "👍",
+                    }
+                ],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        res = self.helper.send(self.room_id, body="root", tok=self.token)
+        root_id = res["event_id"]
+
+        # The parent has no reactions.
+        reference = {
+            "m.relates_to": {"rel_type": RelationTypes.REFERENCE, "event_id": root_id}
+        }
+        self.assertFalse(self._create_and_process(bulk_evaluator, reference))
+
+        # The event is included in its parent's reactions.
+        reaction = {
+            "m.relates_to": {
+                "rel_type": RelationTypes.ANNOTATION,
+                "event_id": root_id,
+                "key": "👍",
+            }
+        }
+        self.assertTrue(self._create_and_process(bulk_evaluator, reaction))
+
+        self.helper.send_event(self.room_id, "m.reaction", reaction, tok=self.token)
+        self.assertTrue(self._create_and_process(bulk_evaluator, reference))
+
+    def test_user_power_level(self) -> None:
+        """The users' power levels are passed to the evaluator if a push rule needs
+        them."""
+        store = self.hs.get_datastores().main
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "not_admin",
+                priority_class=5,
+                conditions=[
+                    {
+                        "kind": "io.element.user_power_level",
+                        "user": "recipient",
+                        "is": "<100",
+                    }
+                ],
+                actions=["notify"],
+            )
+        )
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+
+        # Alice created the room, so is an admin.
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "admin",
+                priority_class=5,
+                conditions=[
+                    {
+                        "kind": "io.element.user_power_level",
+                        "user": "recipient",
+                        "is": ">=100",
+                    }
+                ],
+                actions=["notify"],
+            )
+        )
+        self.assertTrue(self._create_and_process(bulk_evaluator))
+
+    @override_config({"server_notices": {"system_mxid_localpart": "bob"}})
+    def test_sender_is_server_notices(self) -> None:
+        """The server notices user is passed to the evaluator if a push rule needs
+        it."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "notice",
+                priority_class=5,
+                conditions=[{"kind": "io.element.sender_is_server_notices"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertTrue(self._create_and_process(bulk_evaluator))
+
+    def test_sender_is_server_notices_disabled(self) -> None:
+        """Nobody is the server notices user unless it's configured."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "notice",
+                priority_class=5,
+                conditions=[{"kind": "io.element.sender_is_server_notices"}],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+
+    def test_sender_display_name(self) -> None:
+        """The sender's display name is passed to the evaluator if a push rule needs
+        it."""
+        self.get_success(
+            self.hs.get_datastores().main.add_push_rule(
+                self.alice,
+                "bobby",
+                priority_class=5,
+                conditions=[
+                    {"kind": "io.element.sender_display_name_match", "pattern": "Bobby"}
+                ],
+                actions=["notify"],
+            )
+        )
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        # Bob isn't in the room, so doesn't have a display name.
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+
+        bob = self.register_user("bob", "pass")
+        bob_token = self.login(bob, "pass")
+        self.helper.send_state(
+            self.room_id,
+            EventTypes.Member,
+            {"membership": "join", "displayname": "Bobby"},
+            tok=bob_token,
+            state_key=bob,
+        )
+        self.assertTrue(self._create_and_process(bulk_evaluator))
+
+    def test_user_membership(self) -> None:
+        """Each recipient's membership is passed to the evaluator if their push rules
+        need it."""
+        store = self.hs.get_datastores().main
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "invited",
+                priority_class=5,
+                conditions=[
+                    {"kind": "io.element.user_membership", "membership": "invite"}
+                ],
+                actions=["notify"],
+            )
+        )
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "joined",
+                priority_class=5,
+                conditions=[
+                    {"kind": "io.element.user_membership", "membership": "join"}
+                ],
+                actions=["notify"],
+            )
+        )
+        self.assertTrue(self._create_and_process(bulk_evaluator))
+
+    def test_is_direct_message(self) -> None:
+        """A room is only a DM for the recipients whose `m.direct` lists it."""
+        store = self.hs.get_datastores().main
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "dm",
+                priority_class=5,
+                conditions=[{"kind": "io.element.is_direct_message"}],
+                actions=["notify"],
+            )
+        )
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+
+        self.get_success(
+            store.add_account_data_for_user(
+                self.alice, "m.direct", {"@bob:test": [self.room_id]}
+            )
+        )
+        self.assertTrue(self._create_and_process(bulk_evaluator))
+
+    def test_unread_count(self) -> None:
+        """Each recipient's unread counts, including those of threads, are passed to
+        the evaluator if their push rules need them."""
+        store = self.hs.get_datastores().main
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "busy",
+                priority_class=5,
+                conditions=[
+                    {
+                        "kind": "io.element.unread_count",
+                        "count_kind": "notifications",
+                        "is": ">=3",
+                    }
+                ],
+                actions=["notify"],
+            )
+        )
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+
+        counts = RoomNotifCounts(
+            NotifCounts(notify_count=2), {"$thread": NotifCounts(notify_count=1)}
+        )
+        with patch.object(
+            store,
+            "get_unread_event_push_actions_by_room_for_user",
+            new=AsyncMock(return_value=counts),
+        ):
+            self.assertTrue(self._create_and_process(bulk_evaluator))
+
+    def test_room_has_tag(self) -> None:
+        """Each recipient's tags for the room are passed to the evaluator if their
+        push rules need them."""
+        store = self.hs.get_datastores().main
+        bulk_evaluator = BulkPushRuleEvaluator(self.hs)
+
+        self.get_success(
+            store.add_push_rule(
+                self.alice,
+                "favourite",
+                priority_class=5,
+                conditions=[{"kind": "io.element.room_has_tag", "tag": "m.favourite"}],
+                actions=["notify"],
+            )
+        )
+        self.assertFalse(self._create_and_process(bulk_evaluator))
+
+        self.get_success(
+            store.add_tag_to_room(self.alice, self.room_id, "m.favourite", {})
+        )
+        self.assertTrue(self._create_and_process(bulk_evaluator))
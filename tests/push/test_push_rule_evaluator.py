@@ -99,6 +99,61 @@ class FlattenDictTestCase(unittest.TestCase):
         }
         self.assertEqual(expected, _flatten_dict(event))
 
+    def test_state_event(self) -> None:
+        """The state key of state events is flattened."""
+        event = make_event_from_dict(
+            {
+                "room_id": "!test:test",
+                "type": "m.room.member",
+                "sender": "@alice:test",
+                "state_key": "@bob:test",
+                "content": {"membership": "invite"},
+            },
+            room_version=RoomVersions.V8,
+        )
+        expected = {
+            "content.membership": "invite",
+            "room_id": "!test:test",
+            "sender": "@alice:test",
+            "state_key": "@bob:test",
+            "type": "m.room.member",
+        }
+        self.assertEqual(expected, _flatten_dict(event))
+
+    def test_voice_message(self) -> None:
+        """The (empty) MSC3245 voice message marker is kept."""
+        event = make_event_from_dict(
+            {
+                "room_id": "!test:test",
+                "type": "m.room.message",
+                "sender": "@alice:test",
+                "content": {
+                    "msgtype": "m.audio",
+                    "body": "Voice message",
+                    "org.matrix.msc1767.audio": {"duration": 1000, "waveform": [1, 2]},
+                    "org.matrix.msc3245.voice": {},
+                },
+            },
+            room_version=RoomVersions.V8,
+        )
+        expected = {
+            "content.msgtype": "m.audio",
+            "content.body": "Voice message",
+            "content.org\\.matrix\\.msc1767\\.audio.duration": 1000,
+            "content.org\\.matrix\\.msc1767\\.audio.waveform": [1, 2],
+            "content.org\\.matrix\\.msc3245\\.voice": "",
+            "room_id": "!test:test",
+            "sender": "@alice:test",
+            "type": "m.room.message",
+        }
+        self.assertEqual(expected, _flatten_dict(event))
+
+        # Only the marker in the content is kept.
+        self.assertEqual(
+            {"org\\.matrix\\.msc3245\\.voice.foo": "bar"},
+            _flatten_dict({"org.matrix.msc3245.voice": {"foo": "bar"}}),
+        )
+
     def test_extensible_events(self) -> None:
         """Extensible events has compatibility behaviour."""
         event_dict = {
@@ -570,6 +625,29 @@ class PushRuleEvaluatorTestCase(unittest.TestCase):
             evaluator = self._get_evaluator({"body": body})
             self.assertFalse(evaluator.matches(condition, "@user:test", "foo"))
 
+    def test_is_voice_message(self) -> None:
+        """Voice messages are recognised once flattened."""
+        condition = {"kind": "org.matrix.msc3245.is_voice_message"}
+
+        evaluator = self._get_evaluator(
+            {
+                "msgtype": "m.audio",
+                "body": "Voice message",
+                "org.matrix.msc1767.audio": {"duration": 1000},
+                "org.matrix.msc3245.voice": {},
+            }
+        )
+        self.assertTrue(evaluator.matches(condition, "@user:test", "foo"))
+
+        evaluator = self._get_evaluator(
+            {
+                "msgtype": "m.audio",
+                "body": "Some audio",
+                "org.matrix.msc1767.audio": {"duration": 1000},
+            }
+        )
+        self.assertFalse(evaluator.matches(condition, "@user:test", "foo"))
+
     def test_tweaks_for_actions(self) -> None:
         """
         This tests the behaviour of tweaks_for_actions.
@@ -599,22 +677,26 @@ class PushRuleEvaluatorTestCase(unittest.TestCase):
                 }
             },
             related_events={
-                "m.in_reply_to": {
-                    "event_id": "$parent_event_id",
-                    "type": "m.room.message",
-                    "sender": "@other_user:test",
-                    "room_id": "!room:test",
-                    "content.msgtype": "m.text",
-                    "content.body": "Original message",
-                },
-                "m.annotation": {
-                    "event_id": "$parent_event_id",
-                    "type": "m.room.message",
-                    "sender": "@other_user:test",
-                    "room_id": "!room:test",
-                    "content.msgtype": "m.text",
-                    "content.body": "Original message",
-                },
+                "m.in_reply_to": [
+                    {
+                        "event_id": "$parent_event_id",
+                        "type": "m.room.message",
+                        "sender": "@other_user:test",
+                        "room_id": "!room:test",
+                        "content.msgtype": "m.text",
+                        "content.body": "Original message",
+                    }
+                ],
+                "m.annotation": [
+                    {
+                        "event_id": "$parent_event_id",
+                        "type": "m.room.message",
+                        "sender": "@other_user:test",
+                        "room_id": "!room:test",
+                        "content.msgtype": "m.text",
+                        "content.body": "Original message",
+                    }
+                ],
             },
         )
         self.assertTrue(
@@ -699,23 +781,27 @@ class PushRuleEvaluatorTestCase(unittest.TestCase):
                 }
             },
             related_events={
-                "m.in_reply_to": {
-                    "event_id": "$parent_event_id",
-                    "type": "m.room.message",
-                    "sender": "@other_user:test",
-                    "room_id": "!room:test",
-                    "content.msgtype": "m.text",
-                    "content.body": "Original message",
-                    "im.vector.is_falling_back": "",
-                },
-                "m.thread": {
-                    "event_id": "$parent_event_id",
-                    "type": "m.room.message",
-                    "sender": "@other_user:test",
-                    "room_id": "!room:test",
-                    "content.msgtype": "m.text",
-                    "content.body": "Original message",
-                },
+                "m.in_reply_to": [
+                    {
+                        "event_id": "$parent_event_id",
+                        "type": "m.room.message",
+                        "sender": "@other_user:test",
+                        "room_id": "!room:test",
+                        "content.msgtype": "m.text",
+                        "content.body": "Original message",
+                        "im.vector.is_falling_back": "",
+                    }
+                ],
+                "m.thread": [
+                    {
+                        "event_id": "$parent_event_id",
+                        "type": "m.room.message",
+                        "sender": "@other_user:test",
+                        "room_id": "!room:test",
+                        "content.msgtype": "m.text",
+                        "content.body": "Original message",
+                    }
+                ],
             },
         )
         self.assertTrue(
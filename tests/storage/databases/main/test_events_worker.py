@@ -533,3 +533,64 @@ class GetEventCancellationTestCase(unittest.HomeserverTestCase):
             unblock.callback(None)
             # The first `get_event` call should complete successfully.
             self.get_success(get_event1)
+
+
+class RecentEventsTestCase(unittest.HomeserverTestCase):
+    servlets = [
+        admin.register_servlets,
+        room.register_servlets,
+        login.register_servlets,
+    ]
+
+    def prepare(self, reactor: MemoryReactor, clock: Clock, hs: HomeServer) -> None:
+        self.store: EventsWorkerStore = hs.get_datastores().main
+
+        self.user = self.register_user("user", "pass")
+        self.token = self.login(self.user, "pass")
+        self.other = self.register_user("other", "pass")
+        self.other_token = self.login(self.other, "pass")
+
+        self.room = self.helper.create_room_as(self.user, tok=self.token)
+        self.helper.join(self.room, self.other, tok=self.other_token)
+
+    def _count(self, sender: str, since_ts: int, limit: int = 100) -> int:
+        return self.get_success(
+            self.store.count_recent_events_by_sender(self.room, sender, since_ts, limit)
+        )
+
+    def test_count(self) -> None:
+        """Only the sender's events since the given time are counted."""
+        self.reactor.advance(10)
+        since_ts = self.clock.time_msec()
+        self.assertEqual(self._count(self.other, since_ts), 0)
+
+        for _ in range(3):
+            self.helper.send(self.room, tok=self.other_token)
+        self.helper.send(self.room, tok=self.token)
+
+        self.assertEqual(self._count(self.other, since_ts), 3)
+        self.assertEqual(self._count(self.user, since_ts), 1)
+
+    def test_limit(self) -> None:
+        """Only the room's latest events are looked at."""
+        for _ in range(3):
+            self.helper.send(self.room, tok=self.other_token)
+        self.helper.send(self.room, tok=self.token)
+
+        self.assertEqual(self._count(self.other, 0, limit=3), 2)
+
+    def test_latest_event_ids_and_senders(self) -> None:
+        """The latest events of the given type are returned, latest first."""
+        first = self.helper.send(self.room, tok=self.other_token)["event_id"]
+        second = self.helper.send(self.room, tok=self.token)["event_id"]
+        self.helper.send_state(self.room, "m.room.topic", {"topic": "hi"}, self.token)
+
+        latest = self.get_success(
+            self.store.get_latest_event_ids_and_senders(self.room, "m.room.message", 5)
+        )
+        self.assertEqual(latest, [(second, self.user), (first, self.other)])
+
+        latest = self.get_success(
+            self.store.get_latest_event_ids_and_senders(self.room, "m.room.message", 1)
+        )
+        self.assertEqual(latest, [(second, self.user)])
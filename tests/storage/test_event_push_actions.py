@@ -805,3 +805,31 @@ class EventPushActionsStoreTestCase(HomeserverTestCase):
         add_event(0, 5)
         r = self.get_success(self.store.find_first_stream_ordering_after_ts(1))
         self.assertEqual(r, 0)
+
+    def test_count_room_notifications_since(self) -> None:
+        """Events which notified anyone in the room are counted once."""
+        user_id = self.register_user("user1235", "pass")
+        token = self.login("user1235", "pass")
+        other_id = self.register_user("other", "pass")
+        other_token = self.login("other", "pass")
+
+        room_id = self.helper.create_room_as(user_id, tok=token)
+        self.helper.join(room_id, other_id, tok=other_token)
+
+        def _count(since_ts: int) -> int:
+            return self.get_success(
+                self.store.count_room_notifications_since(room_id, since_ts)
+            )
+
+        self.assertEqual(_count(0), 0)
+
+        # Messages in a one-to-one room notify under the default push rules.
+        self.helper.send(room_id, body="one", tok=other_token)
+        self.helper.send(room_id, body="two", tok=token)
+        self.assertEqual(_count(0), 2)
+
+        # Only events received since the given time are counted.
+        self.reactor.advance(10)
+        since_ts = self.clock.time_msec()
+        self.helper.send(room_id, body="three", tok=other_token)
+        self.assertEqual(_count(since_ts), 1)